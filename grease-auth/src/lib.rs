@@ -0,0 +1,1173 @@
+//! # auth - An HTTP authentication task
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! This task sits on top of the `http` task and only lets through requests
+//! with good credentials. A `ReqBind` asks us to bind an HTTP server, with
+//! an `AuthConfig` giving the realm and the `Verifier`s which check the
+//! credentials in a request's `Authorization` header. Requests they accept
+//! are passed on to whoever bound the server, in an `IndRxRequest` which
+//! also carries the `Principal` - who the request is from. We answer the
+//! rest ourselves, with a `401 Unauthorized` and a `WWW-Authenticate`
+//! challenge for each scheme we take. Paths under one of the config's
+//! `public` prefixes are passed on whatever their credentials, with no
+//! principal if they're missing or bad.
+//!
+//! There are verifiers for `Basic` credentials checked against an
+//! htpasswd file (`Htpasswd`, which takes bcrypt and `{SHA}` hashes), and
+//! for `Bearer` tokens which are either in a fixed list (`StaticTokens`) or
+//! signed with a key we hold (`SignedTokens`). Implement the trait yourself
+//! to check credentials some other way. Verifiers run on this task's
+//! thread, so a slow one (and bcrypt is slow on purpose) holds up every
+//! request behind it.
+//!
+//! As with the router, send your responses straight to the http task, using
+//! the connection handle in the `http::IndRxRequest`. We forward the request
+//! body, in `IndRxBody`, and the `IndClosed` when the connection closes.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#![cfg_attr(feature = "cargo-clippy", allow(if_not_else))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate base64;
+extern crate bcrypt;
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+extern crate hmac;
+#[macro_use]
+extern crate log;
+extern crate sha1;
+extern crate subtle;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderValue, HttpResponseStatus};
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Messages
+//
+// ****************************************************************************
+
+/// Offers the `grease::Service` for this module.
+pub struct Service;
+
+impl grease::Service for Service {
+	type Request = Request;
+	type Confirm = Confirm;
+	type Indication = Indication;
+	type Response = ();
+}
+
+/// Requests that can be sent to the auth task.
+#[derive(Debug)]
+pub enum Request {
+	/// Start an HTTP server on a given port
+	Bind(ReqBind),
+}
+
+make_wrapper!(ReqBind, Request, Request::Bind);
+
+/// Confirms sent back from the auth task.
+#[derive(Debug)]
+pub enum Confirm {
+	/// Whether the ReqBind was successful
+	Bind(CfmBind),
+}
+
+make_wrapper!(CfmBind, Confirm, Confirm::Bind);
+
+/// Indications that come out of the auth task.
+#[derive(Debug)]
+pub enum Indication {
+	/// A new HTTP request has been received
+	RxRequest(IndRxRequest),
+	/// Some of the body of a request you were sent
+	RxBody(IndRxBody),
+	/// The connection for a request you were sent has been dropped
+	Closed(IndClosed),
+}
+
+make_wrapper!(IndRxRequest, Indication, Indication::RxRequest);
+make_wrapper!(IndRxBody, Indication, Indication::RxBody);
+make_wrapper!(IndClosed, Indication, Indication::Closed);
+
+/// Start an HTTP server on a given port, which needs credentials.
+#[derive(Debug)]
+pub struct ReqBind {
+	/// Which address to bind.
+	pub addr: net::SocketAddr,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Passed on to the http task
+	pub config: http::ServerConfig,
+	/// How requests are checked
+	pub auth: AuthConfig,
+}
+
+/// Whether the `ReqBind` was successful
+#[derive(Debug)]
+pub struct CfmBind {
+	pub context: Context,
+	pub result: Result<ServerHandle, Error>,
+}
+
+/// A new HTTP request has been received, and its credentials are good (or
+/// it's for a public path). Send the response to the http task, using
+/// `request.connection_handle`.
+#[derive(Debug)]
+pub struct IndRxRequest {
+	/// Who the request is from. Only None for public paths.
+	pub principal: Option<Principal>,
+	/// The request, as received from the http task
+	pub request: http::IndRxRequest,
+}
+
+/// Some of the body of a request you were sent, as received from the http
+/// task
+#[derive(Debug)]
+pub struct IndRxBody {
+	pub body: http::IndRxBody,
+}
+
+/// The connection for a request you were sent has been dropped
+#[derive(Debug)]
+pub struct IndClosed {
+	pub handle: http::ConnHandle,
+}
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Represents something an auth service user can hold on to to send us
+/// message.
+pub struct Handle(mpsc::Sender<Incoming>);
+
+/// Servers are identified by the handle the http task gave us
+pub type ServerHandle = http::ServerHandle;
+
+/// How a server checks its requests
+#[derive(Debug)]
+pub struct AuthConfig {
+	/// Sent in the challenges, to tell the user what they're logging in to
+	pub realm: String,
+	/// Tried in order until one accepts the credentials. There must be at
+	/// least one.
+	pub verifiers: Vec<Box<Verifier>>,
+	/// Path prefixes which don't need credentials, like `/static`. A prefix
+	/// matches whole segments, so `/static` doesn't cover `/statics`.
+	pub public: Vec<String>,
+}
+
+/// The HTTP authentication schemes we know
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Scheme {
+	/// RFC 7617 - a user name and password
+	Basic,
+	/// RFC 6750 - an opaque token
+	Bearer,
+}
+
+/// The credentials from an `Authorization` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+	Basic { user: String, password: String },
+	Bearer(String),
+}
+
+/// Who a request is from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+	/// The name the verifier gave - the user name, for `Basic`
+	pub name: String,
+	/// How they proved it
+	pub scheme: Scheme,
+}
+
+/// Something which checks credentials
+pub trait Verifier: Send + fmt::Debug {
+	/// The scheme this verifier takes. We only give it credentials which
+	/// use this scheme.
+	fn scheme(&self) -> Scheme;
+	/// The principal's name, if the credentials are good
+	fn verify(&self, credentials: &Credentials) -> Option<String>;
+}
+
+/// Checks `Basic` credentials against the users in an htpasswd file. Each
+/// line is `user:hash`, where the hash is bcrypt (`$2y$...`, from
+/// `htpasswd -B`) or salt-less SHA-1 (`{SHA}...`, from `htpasswd -s`).
+/// Blank lines and lines starting `#` are ignored.
+pub struct Htpasswd {
+	users: HashMap<String, String>,
+}
+
+/// Why an htpasswd file couldn't be used
+#[derive(Debug)]
+pub enum HtpasswdError {
+	/// The file couldn't be read
+	Io(io::Error),
+	/// This line (counting from 1) isn't `user:hash`
+	BadLine(usize),
+	/// This line (counting from 1) has a hash we can't check, like MD5
+	/// (`$apr1$`) or `crypt`
+	UnsupportedHash(usize),
+}
+
+/// Checks `Bearer` tokens against a fixed list
+#[derive(Default)]
+pub struct StaticTokens {
+	tokens: Vec<(String, String)>,
+}
+
+/// Checks `Bearer` tokens made by `SignedTokens::sign`. The token holds the
+/// principal's name and an expiry time, and an HMAC of them made with our
+/// key, so anyone with the key can issue tokens and nobody else can.
+pub struct SignedTokens {
+	key: Vec<u8>,
+}
+
+/// All possible auth task errors
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+	/// The `AuthConfig` isn't usable - say there are no verifiers, or the
+	/// realm can't go in a header.
+	BadConfig,
+	/// The http task rejected our request
+	Http(http::Error),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The shortest signing key `SignedTokens` will take
+pub const MIN_KEY_LEN: usize = 16;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+service_map! {
+	generate: Incoming,
+	service: Service,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd)
+	}
+}
+
+struct Server {
+	config: AuthConfig,
+	/// Who to tell about requests
+	ind_to: grease::ServiceUserHandle<Service>,
+}
+
+struct TaskContext {
+	/// Who we send http messages to
+	http: grease::ServiceProviderHandle<http::Service>,
+	/// How the tasks we use send messages to us
+	reply_to: Handle,
+	/// Binds we're waiting for, indexed by the context we sent down
+	pending_binds: HashMap<Context, (ReplyContext, AuthConfig)>,
+	/// The servers we've bound
+	servers: HashMap<ServerHandle, Server>,
+	/// Who to tell when each connection closes
+	connections: HashMap<http::ConnHandle, grease::ServiceUserHandle<Service>>,
+	/// The next context we use for downward messages
+	next_ctx: Context,
+}
+
+type ReplyContext = grease::ReplyContext<Service>;
+
+/// A bcrypt hash we check passwords for unknown users against. It has the
+/// cost `htpasswd -B` uses by default.
+const DUMMY_HASH: &str = "$2y$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Creates a new auth task. Returns an object that can be used to send
+/// this task messages.
+pub fn make_task(http: grease::ServiceProviderHandle<http::Service>) -> Handle {
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx.clone());
+	std::thread::spawn(move || {
+		let mut t = TaskContext::new(http, handle);
+		for msg in rx.iter() {
+			t.handle(msg);
+		}
+		panic!("This task should never die!");
+	});
+	Handle(tx)
+}
+
+/// Get the credentials from a request's `Authorization` header. None if
+/// there isn't one, or it's not `Basic` or `Bearer`, or it's malformed.
+pub fn parse_authorization(headers: &HeaderMap) -> Option<Credentials> {
+	let value = headers.get("authorization")?.to_str().ok()?.trim();
+	let space = value.find(' ')?;
+	let (scheme, rest) = (&value[..space], value[space..].trim());
+	if scheme.eq_ignore_ascii_case("basic") {
+		let decoded = base64::decode(rest).ok()?;
+		let decoded = String::from_utf8(decoded).ok()?;
+		let colon = decoded.find(':')?;
+		Some(Credentials::Basic {
+			user: decoded[..colon].to_owned(),
+			password: decoded[colon + 1..].to_owned(),
+		})
+	} else if scheme.eq_ignore_ascii_case("bearer") && !rest.is_empty() {
+		Some(Credentials::Bearer(rest.to_owned()))
+	} else {
+		None
+	}
+}
+
+impl Credentials {
+	pub fn scheme(&self) -> Scheme {
+		match *self {
+			Credentials::Basic { .. } => Scheme::Basic,
+			Credentials::Bearer(_) => Scheme::Bearer,
+		}
+	}
+}
+
+impl AuthConfig {
+	/// An `AuthConfig` with no public paths
+	pub fn new(realm: &str, verifiers: Vec<Box<Verifier>>) -> AuthConfig {
+		AuthConfig {
+			realm: realm.to_owned(),
+			verifiers,
+			public: Vec::new(),
+		}
+	}
+
+	/// Is this decoded path under one of our public prefixes?
+	fn is_public(&self, path: &str) -> bool {
+		self.public.iter().any(|prefix| {
+			let prefix = prefix.trim_end_matches('/');
+			path == prefix || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+		})
+	}
+
+	/// Check the config can make a valid challenge
+	fn is_valid(&self) -> bool {
+		!self.verifiers.is_empty()
+			&& !self.realm
+				.contains(|c: char| c == '"' || c == '\\' || c.is_control())
+			&& self.public.iter().all(|prefix| prefix.starts_with('/'))
+	}
+
+	/// The `WWW-Authenticate` headers for a 401. `bad_token` says the
+	/// request gave a `Bearer` token which we didn't accept.
+	fn challenges(&self, bad_token: bool) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		let mut schemes: Vec<Scheme> = Vec::new();
+		for verifier in &self.verifiers {
+			if !schemes.contains(&verifier.scheme()) {
+				schemes.push(verifier.scheme());
+			}
+		}
+		for scheme in schemes {
+			let challenge = match scheme {
+				Scheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+				Scheme::Bearer if bad_token => {
+					format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm)
+				}
+				Scheme::Bearer => format!("Bearer realm=\"{}\"", self.realm),
+			};
+			if let Ok(value) = HeaderValue::from_str(&challenge) {
+				headers.append("www-authenticate", value);
+			}
+		}
+		headers
+	}
+}
+
+impl Htpasswd {
+	/// Read an htpasswd file
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Htpasswd, HtpasswdError> {
+		let contents = fs::read_to_string(path).map_err(HtpasswdError::Io)?;
+		Htpasswd::parse(&contents)
+	}
+
+	/// Parse the contents of an htpasswd file
+	pub fn parse(contents: &str) -> Result<Htpasswd, HtpasswdError> {
+		let mut users = HashMap::new();
+		for (idx, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let colon = match line.find(':') {
+				Some(colon) if colon > 0 => colon,
+				_ => return Err(HtpasswdError::BadLine(idx + 1)),
+			};
+			let hash = &line[colon + 1..];
+			if !hash.starts_with("$2") && !hash.starts_with("{SHA}") {
+				return Err(HtpasswdError::UnsupportedHash(idx + 1));
+			}
+			users.insert(line[..colon].to_owned(), hash.to_owned());
+		}
+		Ok(Htpasswd { users })
+	}
+
+	/// How many users there are
+	pub fn len(&self) -> usize {
+		self.users.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.users.is_empty()
+	}
+}
+
+impl Verifier for Htpasswd {
+	fn scheme(&self) -> Scheme {
+		Scheme::Basic
+	}
+
+	fn verify(&self, credentials: &Credentials) -> Option<String> {
+		let (user, password) = match *credentials {
+			Credentials::Basic {
+				ref user,
+				ref password,
+			} => (user, password),
+			Credentials::Bearer(_) => return None,
+		};
+		let hash = match self.users.get(user) {
+			Some(hash) => hash,
+			None => {
+				// Take as long as a wrong password would, so nobody can
+				// tell which users exist by timing us
+				let _ = bcrypt::verify(password, DUMMY_HASH);
+				return None;
+			}
+		};
+		let good = if let Some(encoded) = hash.strip_prefix("{SHA}") {
+			let digest = Sha1::digest(password.as_bytes());
+			let expected = base64::decode(encoded).unwrap_or_default();
+			constant_time_eq(&digest, &expected)
+		} else {
+			bcrypt::verify(password, hash).unwrap_or(false)
+		};
+		if good {
+			Some(user.clone())
+		} else {
+			None
+		}
+	}
+}
+
+impl fmt::Debug for Htpasswd {
+	/// Lists the users, but not their hashes
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Htpasswd")
+			.field("users", &self.users.keys().collect::<Vec<_>>())
+			.finish()
+	}
+}
+
+impl fmt::Display for HtpasswdError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			HtpasswdError::Io(ref e) => write!(f, "{}", e),
+			HtpasswdError::BadLine(line) => write!(f, "line {}: not user:hash", line),
+			HtpasswdError::UnsupportedHash(line) => {
+				write!(f, "line {}: hash must be bcrypt or SHA-1", line)
+			}
+		}
+	}
+}
+
+impl StaticTokens {
+	pub fn new() -> StaticTokens {
+		StaticTokens::default()
+	}
+
+	/// Accept `token` as being from `principal`
+	pub fn add(&mut self, token: &str, principal: &str) {
+		self.tokens.push((token.to_owned(), principal.to_owned()));
+	}
+}
+
+impl Verifier for StaticTokens {
+	fn scheme(&self) -> Scheme {
+		Scheme::Bearer
+	}
+
+	fn verify(&self, credentials: &Credentials) -> Option<String> {
+		let token = match *credentials {
+			Credentials::Bearer(ref token) => token,
+			Credentials::Basic { .. } => return None,
+		};
+		// Check every token, so the time taken doesn't say which was close
+		let mut found = None;
+		for (candidate, principal) in &self.tokens {
+			if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+				found = Some(principal.clone());
+			}
+		}
+		found
+	}
+}
+
+impl fmt::Debug for StaticTokens {
+	/// Lists the principals, but not their tokens
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("StaticTokens")
+			.field(
+				"principals",
+				&self.tokens.iter().map(|t| &t.1).collect::<Vec<_>>(),
+			)
+			.finish()
+	}
+}
+
+impl SignedTokens {
+	/// Check tokens signed with `key`, which must be at least
+	/// `MIN_KEY_LEN` bytes.
+	pub fn new(key: &[u8]) -> Option<SignedTokens> {
+		if key.len() >= MIN_KEY_LEN {
+			Some(SignedTokens { key: key.to_vec() })
+		} else {
+			None
+		}
+	}
+
+	/// Make a token for `principal`, which is good for `lifetime`
+	pub fn sign(&self, principal: &str, lifetime: Duration) -> String {
+		let expires = SystemTime::now() + lifetime;
+		let expires = expires
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		let claims = format!("{}:{}", expires, principal);
+		let claims = base64::encode_config(claims.as_bytes(), base64::URL_SAFE_NO_PAD);
+		let mac = self.mac_for(&claims).result().code();
+		format!("{}.{}", claims, base64::encode_config(&mac, base64::URL_SAFE_NO_PAD))
+	}
+
+	/// An HMAC-SHA1 of the encoded claims
+	fn mac_for(&self, claims: &str) -> Hmac<Sha1> {
+		let mut mac = Hmac::<Sha1>::new_varkey(&self.key).expect("HMAC takes keys of any length");
+		mac.input(claims.as_bytes());
+		mac
+	}
+}
+
+impl Verifier for SignedTokens {
+	fn scheme(&self) -> Scheme {
+		Scheme::Bearer
+	}
+
+	fn verify(&self, credentials: &Credentials) -> Option<String> {
+		let token = match *credentials {
+			Credentials::Bearer(ref token) => token,
+			Credentials::Basic { .. } => return None,
+		};
+		let dot = token.rfind('.')?;
+		let claims = &token[..dot];
+		let mac = base64::decode_config(&token[dot + 1..], base64::URL_SAFE_NO_PAD).ok()?;
+		self.mac_for(claims).verify(&mac).ok()?;
+		let claims = base64::decode_config(claims, base64::URL_SAFE_NO_PAD).ok()?;
+		let claims = String::from_utf8(claims).ok()?;
+		let colon = claims.find(':')?;
+		let expires = UNIX_EPOCH + Duration::from_secs(claims[..colon].parse().ok()?);
+		if expires > SystemTime::now() {
+			Some(claims[colon + 1..].to_owned())
+		} else {
+			None
+		}
+	}
+}
+
+impl fmt::Debug for SignedTokens {
+	/// Doesn't show the key
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("SignedTokens")
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// All our handler functions are methods on this `TaskContext` structure.
+impl TaskContext {
+	/// Create a new TaskContext
+	fn new(http: grease::ServiceProviderHandle<http::Service>, us: Handle) -> Self {
+		Self {
+			http,
+			reply_to: us,
+			pending_binds: HashMap::new(),
+			servers: HashMap::new(),
+			connections: HashMap::new(),
+			// This number is arbitrary
+			next_ctx: grease::Context::new(6_000),
+		}
+	}
+
+	/// Handle an incoming message. It might a `Request` for us,
+	/// or it might be a `Confirm` or `Indication` from a lower layer.
+	fn handle(&mut self, msg: Incoming) {
+		match msg {
+			// We only handle our own requests and responses
+			Incoming::Request(x, reply_to) => {
+				debug!("Rx: {:?}", x);
+				self.handle_auth_req(x, reply_to);
+			}
+			Incoming::HttpCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_cfm(x);
+			}
+			Incoming::HttpInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_ind(x);
+			}
+			Incoming::Response(x) => {
+				debug!("Rx: {:?}", x);
+			}
+		}
+	}
+
+	fn handle_auth_req(&mut self, req: Request, reply_to: grease::ServiceUserHandle<Service>) {
+		match req {
+			Request::Bind(x) => self.handle_bind(x, reply_to),
+		}
+	}
+
+	fn handle_http_cfm(&mut self, cfm: http::Confirm) {
+		match cfm {
+			http::Confirm::Bind(x) => self.handle_http_cfm_bind(x),
+			// These are for the 401s we send
+			http::Confirm::ResponseStart(_) | http::Confirm::ResponseBody(_) => {}
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_http_ind(&mut self, ind: http::Indication) {
+		match ind {
+			http::Indication::RxRequest(x) => self.handle_http_ind_rx_request(x),
+			http::Indication::RxBody(x) => self.handle_http_ind_rx_body(x),
+			http::Indication::Closed(x) => self.handle_http_ind_closed(x),
+			// WebSocket messages go straight to whoever accepted the upgrade
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_bind(&mut self, req_bind: ReqBind, reply_to: grease::ServiceUserHandle<Service>) {
+		if !req_bind.auth.is_valid() {
+			reply_to.send_confirm(
+				CfmBind {
+					context: req_bind.context,
+					result: Err(Error::BadConfig),
+				}.into(),
+			);
+			return;
+		}
+		let ctx = self.next_ctx.take();
+		self.pending_binds.insert(
+			ctx,
+			(
+				ReplyContext {
+					context: req_bind.context,
+					reply_to,
+				},
+				req_bind.auth,
+			),
+		);
+		self.http.send_request(
+			http::ReqBind {
+				addr: req_bind.addr,
+				context: ctx,
+				config: req_bind.config,
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	fn handle_http_cfm_bind(&mut self, cfm_bind: http::CfmBind) {
+		if let Some((reply_ctx, config)) = self.pending_binds.remove(&cfm_bind.context) {
+			let result = match cfm_bind.result {
+				Ok(server) => {
+					self.servers.insert(
+						server,
+						Server {
+							config,
+							ind_to: reply_ctx.reply_to.clone(),
+						},
+					);
+					Ok(server)
+				}
+				Err(e) => Err(Error::Http(e)),
+			};
+			reply_ctx.reply_to.send_confirm(
+				CfmBind {
+					context: reply_ctx.context,
+					result,
+				}.into(),
+			);
+		} else {
+			warn!("Context {} not found", cfm_bind.context);
+		}
+	}
+
+	fn handle_http_ind_rx_request(&mut self, ind: http::IndRxRequest) {
+		let (principal, public, challenges, ind_to) = match self.servers.get(&ind.server_handle) {
+			Some(server) => {
+				let credentials = parse_authorization(&ind.headers);
+				let principal = credentials.as_ref().and_then(|credentials| {
+					server
+						.config
+						.verifiers
+						.iter()
+						.filter(|v| v.scheme() == credentials.scheme())
+						.filter_map(|v| v.verify(credentials))
+						.next()
+						.map(|name| Principal {
+							name,
+							scheme: credentials.scheme(),
+						})
+				});
+				let public = ind.path()
+					.map(|path| server.config.is_public(&path))
+					.unwrap_or(false);
+				let bad_token = match credentials {
+					Some(Credentials::Bearer(_)) => principal.is_none(),
+					_ => false,
+				};
+				let challenges = server.config.challenges(bad_token);
+				(principal, public, challenges, server.ind_to.clone())
+			}
+			None => {
+				warn!("Request for unknown server {}", ind.server_handle);
+				return;
+			}
+		};
+		if principal.is_none() && !public {
+			debug!("Refusing request on {}", ind.connection_handle);
+			self.send_unauthorized(ind.connection_handle, challenges);
+			return;
+		}
+		self.connections
+			.insert(ind.connection_handle, ind_to.clone());
+		ind_to.send_indication(
+			IndRxRequest {
+				principal,
+				request: ind,
+			}.into(),
+		);
+	}
+
+	fn handle_http_ind_rx_body(&mut self, ind: http::IndRxBody) {
+		// Bodies of requests we refused go nowhere
+		if let Some(ind_to) = self.connections.get(&ind.handle) {
+			ind_to.send_indication(IndRxBody { body: ind }.into());
+		}
+	}
+
+	fn handle_http_ind_closed(&mut self, ind: http::IndClosed) {
+		if let Some(ind_to) = self.connections.remove(&ind.handle) {
+			ind_to.send_indication(IndClosed { handle: ind.handle }.into());
+		}
+	}
+
+	/// Send a `401 Unauthorized`, with these challenges
+	fn send_unauthorized(&mut self, handle: http::ConnHandle, headers: HeaderMap) {
+		let ctx = self.next_ctx.take();
+		let status = HttpResponseStatus::Unauthorized;
+		http::send_error(&*self.http, &self.reply_to, handle, ctx, status, headers);
+	}
+}
+
+/// Compare two byte strings, taking the same time however much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.ct_eq(b).unwrap_u8() == 1
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::mpsc;
+
+	use grease::prelude::*;
+
+	enum TestIncoming {
+		AuthCfm(Confirm),
+		AuthInd(Indication),
+		HttpReq(http::Request, grease::ServiceUserHandle<http::Service>),
+		HttpRsp(http::Response),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+	/// alice (password `U*U*`) with bcrypt, bob (`hunter2`) with SHA-1
+	const HTPASSWD: &str = "# Users\n\
+	                        alice:$2y$05$CCCCCCCCCCCCCCCCCCCCC.VGOzA784oUp/Z0DY336zx7pLYAy0lwK\n\
+	                        \n\
+	                        bob:{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=\n";
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::AuthCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: Indication) {
+			self.0.send(TestIncoming::AuthInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<http::Service> for TestHandle {
+		fn send_request(&self, req: http::Request, reply_to: &grease::ServiceUser<http::Service>) {
+			self.0
+				.send(TestIncoming::HttpReq(req, reply_to.clone()))
+				.unwrap();
+		}
+		fn send_response(&self, rsp: http::Response) {
+			self.0.send(TestIncoming::HttpRsp(rsp)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceProviderHandle<http::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
+		let (test_tx, rx) = mpsc::channel();
+		(TestHandle(test_tx), rx)
+	}
+
+	fn basic(user: &str, password: &str) -> String {
+		format!("Basic {}", base64::encode(&format!("{}:{}", user, password)))
+	}
+
+	/// Bind a server through the auth task, using ourselves as the http
+	/// task.
+	fn bind(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		auth: &Handle,
+		server: ServerHandle,
+		config: AuthConfig,
+	) -> grease::ServiceUserHandle<http::Service> {
+		auth.send_request(
+			ReqBind {
+				addr: "127.0.0.1:8000".parse().unwrap(),
+				context: Context::new(1),
+				config: http::ServerConfig::default(),
+				auth: config,
+			}.into(),
+			this_thread,
+		);
+		let http_south = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::Bind(ref x), ref reply_to) => {
+				reply_to.send_confirm(
+					http::CfmBind {
+						context: x.context,
+						result: Ok(server),
+					}.into(),
+				);
+				(*reply_to).clone()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::AuthCfm(Confirm::Bind(ref x)) => {
+				assert_eq!(x.context, Context::new(1));
+				assert_eq!(x.result.unwrap(), server);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		http_south
+	}
+
+	/// Send a request for `url` with this `Authorization` header
+	fn send_request(
+		http_south: &grease::ServiceUserHandle<http::Service>,
+		server: ServerHandle,
+		conn: http::ConnHandle,
+		url: &str,
+		authorization: Option<&str>,
+	) {
+		let mut headers = HeaderMap::new();
+		if let Some(authorization) = authorization {
+			headers.insert("authorization", authorization.parse().unwrap());
+		}
+		http_south.send_indication(
+			http::IndRxRequest {
+				server_handle: server,
+				connection_handle: conn,
+				..http::IndRxRequest::for_test(http::Method::GET, url, headers)
+			}.into(),
+		);
+	}
+
+	/// The request should be passed up; return its principal
+	fn expect_accepted(test_rx: &mpsc::Receiver<TestIncoming>) -> Option<Principal> {
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::AuthInd(Indication::RxRequest(x)) => x.principal,
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	/// The request should get a 401; return its challenges
+	fn expect_refused(test_rx: &mpsc::Receiver<TestIncoming>) -> Vec<String> {
+		let challenges = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.status, HttpResponseStatus::Unauthorized);
+				x.headers
+					.get_all("www-authenticate")
+					.iter()
+					.map(|v| v.to_str().unwrap().to_owned())
+					.collect()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(ref x), _) => {
+				assert_eq!(x.data, b"401 Unauthorized\r\n");
+			}
+			_ => panic!("Unexpected message"),
+		}
+		challenges
+	}
+
+	#[test]
+	fn authorization() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(parse_authorization(&headers), None);
+		headers.insert("authorization", basic("alice", "pass:word").parse().unwrap());
+		assert_eq!(
+			parse_authorization(&headers),
+			Some(Credentials::Basic {
+				user: String::from("alice"),
+				password: String::from("pass:word"),
+			})
+		);
+		headers.insert("authorization", "bearer  abc.def ".parse().unwrap());
+		assert_eq!(
+			parse_authorization(&headers),
+			Some(Credentials::Bearer(String::from("abc.def")))
+		);
+		for bad in &["Basic", "Basic !!!", "Basic Ym9i", "Bearer ", "Digest x=y"] {
+			headers.insert("authorization", bad.parse().unwrap());
+			assert_eq!(parse_authorization(&headers), None, "{}", bad);
+		}
+	}
+
+	#[test]
+	fn htpasswd() {
+		let users = Htpasswd::parse(HTPASSWD).unwrap();
+		assert_eq!(users.len(), 2);
+		let check = |user: &str, password: &str| {
+			users.verify(&Credentials::Basic {
+				user: user.to_owned(),
+				password: password.to_owned(),
+			})
+		};
+		assert_eq!(check("alice", "U*U*"), Some(String::from("alice")));
+		assert_eq!(check("alice", "U*U"), None);
+		assert_eq!(check("bob", "hunter2"), Some(String::from("bob")));
+		assert_eq!(check("bob", "hunter3"), None);
+		assert_eq!(check("carol", "U*U*"), None);
+		// Unknown users are checked against a real hash
+		assert!(bcrypt::verify("U*U*", DUMMY_HASH).is_ok());
+		assert_eq!(users.verify(&Credentials::Bearer(String::from("x"))), None);
+		assert!(!format!("{:?}", users).contains("$2y$"));
+
+		assert!(match Htpasswd::parse("alice:$2y$05$x\n:nobody") {
+			Err(HtpasswdError::BadLine(2)) => true,
+			_ => false,
+		});
+		assert!(match Htpasswd::parse("alice:$apr1$abc$def") {
+			Err(HtpasswdError::UnsupportedHash(1)) => true,
+			_ => false,
+		});
+		assert!(match Htpasswd::load("/does/not/exist") {
+			Err(HtpasswdError::Io(_)) => true,
+			_ => false,
+		});
+	}
+
+	#[test]
+	fn tokens() {
+		let mut fixed = StaticTokens::new();
+		fixed.add("t0k3n", "robot");
+		let bearer = |token: &str| Credentials::Bearer(token.to_owned());
+		assert_eq!(fixed.verify(&bearer("t0k3n")), Some(String::from("robot")));
+		assert_eq!(fixed.verify(&bearer("t0k3")), None);
+		assert!(!format!("{:?}", fixed).contains("t0k3n"));
+
+		assert!(SignedTokens::new(b"short").is_none());
+		let signed = SignedTokens::new(b"0123456789abcdef").unwrap();
+		let token = signed.sign("carol:admin", Duration::from_secs(60));
+		assert_eq!(signed.verify(&bearer(&token)), Some(String::from("carol:admin")));
+		let other = SignedTokens::new(b"fedcba9876543210").unwrap();
+		assert_eq!(other.verify(&bearer(&token)), None);
+		assert_eq!(signed.verify(&bearer(&token[1..])), None);
+		assert_eq!(signed.verify(&bearer(&token[..token.len() - 1])), None);
+		let expired = signed.sign("carol", Duration::from_secs(0));
+		assert_eq!(signed.verify(&bearer(&expired)), None);
+	}
+
+	#[test]
+	fn requests() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(grease::ServiceProvider::clone(&reply_to));
+		let mut fixed = StaticTokens::new();
+		fixed.add("t0k3n", "robot");
+		let config = AuthConfig {
+			public: vec![String::from("/static/")],
+			..AuthConfig::new(
+				"Test",
+				vec![
+					Box::new(Htpasswd::parse(HTPASSWD).unwrap()),
+					Box::new(fixed),
+				],
+			)
+		};
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &task, server, config);
+
+		// Good credentials are passed up with their principal
+		send_request(&http_south, server, Context::new(30), "/", Some(&basic("bob", "hunter2")));
+		assert_eq!(
+			expect_accepted(&test_rx),
+			Some(Principal {
+				name: String::from("bob"),
+				scheme: Scheme::Basic,
+			})
+		);
+		send_request(&http_south, server, Context::new(31), "/", Some("Bearer t0k3n"));
+		assert_eq!(
+			expect_accepted(&test_rx),
+			Some(Principal {
+				name: String::from("robot"),
+				scheme: Scheme::Bearer,
+			})
+		);
+
+		// The body and close are passed on
+		http_south.send_indication(
+			http::IndRxBody {
+				handle: Context::new(31),
+				data: b"abc".to_vec(),
+				last: true,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::AuthInd(Indication::RxBody(ref x)) => {
+				assert_eq!(x.body.data, b"abc");
+			}
+			_ => panic!("Unexpected message"),
+		}
+		http_south.send_indication(
+			http::IndClosed {
+				handle: Context::new(31),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::AuthInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, Context::new(31));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// Missing or bad credentials get a 401
+		send_request(&http_south, server, Context::new(32), "/", None);
+		assert_eq!(
+			expect_refused(&test_rx),
+			vec![
+				"Basic realm=\"Test\", charset=\"UTF-8\"",
+				"Bearer realm=\"Test\"",
+			]
+		);
+		send_request(&http_south, server, Context::new(33), "/", Some(&basic("bob", "x")));
+		assert_eq!(expect_refused(&test_rx).len(), 2);
+		send_request(&http_south, server, Context::new(34), "/", Some("Bearer nope"));
+		assert_eq!(
+			expect_refused(&test_rx)[1],
+			"Bearer realm=\"Test\", error=\"invalid_token\""
+		);
+		send_request(&http_south, server, Context::new(35), "/statics", None);
+		expect_refused(&test_rx);
+
+		// Public paths get through without credentials
+		send_request(&http_south, server, Context::new(36), "/static/a.css", None);
+		assert_eq!(expect_accepted(&test_rx), None);
+		send_request(&http_south, server, Context::new(37), "/static", Some("Bearer t0k3n"));
+		assert!(expect_accepted(&test_rx).is_some());
+	}
+
+	#[test]
+	fn bad_config() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(grease::ServiceProvider::clone(&reply_to));
+		let configs = vec![
+			AuthConfig::new("Test", Vec::new()),
+			AuthConfig::new("\"Test\"", vec![Box::new(StaticTokens::new())]),
+		];
+		for config in configs {
+			task.send_request(
+				ReqBind {
+					addr: "127.0.0.1:8000".parse().unwrap(),
+					context: Context::new(1),
+					config: http::ServerConfig::default(),
+					auth: config,
+				}.into(),
+				&reply_to,
+			);
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::AuthCfm(Confirm::Bind(ref x)) => {
+					assert!(match x.result {
+						Err(Error::BadConfig) => true,
+						_ => false,
+					});
+				}
+				_ => panic!("Unexpected message"),
+			}
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,125 @@
+//! # files - a grease example serving the current directory over HTTP
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate env_logger;
+#[macro_use]
+extern crate grease;
+extern crate grease_files as files;
+extern crate grease_http as http;
+extern crate grease_router as router;
+extern crate grease_socket as socket;
+#[macro_use]
+extern crate log;
+
+use std::net;
+use std::sync::mpsc;
+
+use grease::prelude::*;
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+struct Handle(mpsc::Sender<Incoming>);
+
+app_map! {
+	generate: Incoming,
+	handle: Handle,
+	used: {
+		files: (Service, FilesCfm, FilesInd),
+		router: (Service, RouterCfm, RouterInd)
+	}
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Start of our example program
+fn main() {
+	env_logger::init();
+	let bind_addr: net::SocketAddr = "0.0.0.0:8000".parse().unwrap();
+
+	info!("Hello, this is the grease file server example.");
+	info!("Serving the current directory on {}", bind_addr);
+
+	let socket_thread = socket::make_task();
+	let http_thread = http::make_task(grease::ServiceProvider::clone(&socket_thread));
+	let router_thread = router::make_task(grease::ServiceProvider::clone(&http_thread));
+	let files_thread = files::make_task(
+		grease::ServiceProvider::clone(&http_thread),
+		grease::ServiceProvider::clone(&router_thread),
+	);
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx);
+
+	router_thread.send_request(
+		router::ReqBind {
+			addr: bind_addr,
+			context: Context::default(),
+			config: http::ServerConfig::default(),
+		}.into(),
+		&handle,
+	);
+
+	for msg in rx.iter() {
+		match msg {
+			Incoming::RouterCfm(router::Confirm::Bind(cfm)) => {
+				files_thread.send_request(
+					files::ReqMount {
+						server: cfm.result.unwrap(),
+						context: Context::default(),
+						prefix: String::from("/"),
+						path: ".".into(),
+						index_files: vec![String::from("index.html")],
+					}.into(),
+					&handle,
+				);
+			}
+			Incoming::FilesCfm(files::Confirm::Mount(cfm)) => {
+				info!("Mounted: {:?}", cfm.result);
+			}
+			_ => {}
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1318 @@
+//! # files - A static file serving task
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! This task serves files from the local filesystem over HTTP. It uses the
+//! `router` task to receive requests and sends its responses directly to
+//! the `http` task.
+//!
+//! A `ReqMount` maps a URL prefix on a router server to a directory. We add
+//! `GET` and `HEAD` routes for everything under that prefix. When a request
+//! comes in, the rest of the path is percent-decoded and checked - any `..`
+//! segments, or anything that resolves (perhaps through a symlink) to
+//! somewhere outside the mounted directory, gets a 403. Directories are
+//! served using the first index file (e.g. `index.html`) that exists in
+//! them. The `Content-Type` is picked from the file extension.
+//!
+//! Every file is sent with an `ETag` (made from its length and modification
+//! time) and a `Last-Modified`, so clients can revalidate it - a matching
+//! `If-None-Match` or `If-Modified-Since` gets a 304. `Range` requests get
+//! a 206, with a single range sent as it is and several sent as
+//! `multipart/byteranges`. `If-Range` is honoured, and a request for ranges
+//! which are all past the end of the file gets a 416.
+//!
+//! Files are never read into memory whole. We send `CHUNK_SIZE` bytes in a
+//! `ReqResponseBody` and read the next chunk only when the `CfmResponseBody`
+//! for the previous one comes back. If a read fails part way through, we
+//! send a `ReqAbort`, so the client sees the transfer fail rather than
+//! waiting for the rest.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#![cfg_attr(feature = "cargo-clippy", allow(if_not_else))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+extern crate grease_router as router;
+#[macro_use]
+extern crate log;
+
+#[cfg(test)]
+extern crate grease_socket as socket;
+
+use std::collections::HashMap;
+use std::fs;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::{EntityTag, HeaderMap, HeaderValue, HttpResponseStatus, Method, Precondition,
+           RangeOutcome, UriError};
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Messages
+//
+// ****************************************************************************
+
+/// Offers the `grease::Service` for this module.
+pub struct Service;
+
+impl grease::Service for Service {
+	type Request = Request;
+	type Confirm = Confirm;
+	type Indication = Indication;
+	type Response = ();
+}
+
+/// Requests that can be sent to the files task.
+#[derive(Debug)]
+pub enum Request {
+	/// Serve a directory under a URL prefix
+	Mount(ReqMount),
+}
+
+make_wrapper!(ReqMount, Request, Request::Mount);
+
+/// Confirms sent back from the files task.
+#[derive(Debug)]
+pub enum Confirm {
+	/// Whether the ReqMount was successful
+	Mount(CfmMount),
+}
+
+make_wrapper!(CfmMount, Confirm, Confirm::Mount);
+
+/// Indications that come out of the files task.
+#[derive(Debug)]
+pub enum Indication {}
+
+/// Serve the files in `path` to requests under `prefix`.
+#[derive(Debug)]
+pub struct ReqMount {
+	/// The router server (from a `router::CfmBind`) to mount on
+	pub server: router::ServerHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// The URL prefix, e.g. "/static". Use "/" to serve everything.
+	pub prefix: String,
+	/// The directory to serve
+	pub path: PathBuf,
+	/// Files to look for when a directory is requested, in order of
+	/// preference, e.g. "index.html". If none exist, the request gets a 404.
+	pub index_files: Vec<String>,
+}
+
+/// Whether the `ReqMount` was successful
+#[derive(Debug)]
+pub struct CfmMount {
+	pub context: Context,
+	pub result: Result<MountHandle, Error>,
+}
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Represents something a files service user can hold on to to send us
+/// message.
+pub struct Handle(mpsc::Sender<Incoming>);
+
+/// A new one of these is allocated for every mount
+pub type MountHandle = Context;
+
+/// All possible files task errors
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+	/// The prefix must start with `/` and not contain any router parameters
+	/// or wildcards
+	BadPrefix,
+	/// The path isn't a directory we can read
+	BadPath,
+	/// The router rejected our routes
+	Router(router::Error),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// How much of a file we send in each `ReqResponseBody`
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+service_map! {
+	generate: Incoming,
+	service: Service,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd),
+		router: (Service, RouterCfm, RouterInd)
+	}
+}
+
+struct Mount {
+	/// The canonical form of the directory we serve
+	root: PathBuf,
+	index_files: Vec<String>,
+	/// The routes we've added for this mount
+	routes: Vec<router::RouteHandle>,
+	/// How many `CfmAddRoute` we're still waiting for
+	pending_routes: usize,
+	/// Set if any of our routes were rejected
+	failed: bool,
+	/// Who to send the `CfmMount` to. None once it has been sent.
+	reply_ctx: Option<ReplyContext>,
+}
+
+/// Something we can send parts of - a file, except in tests
+trait Source: Read + Seek {}
+
+impl<T: Read + Seek> Source for T {}
+
+/// A file we're part way through sending
+struct Transfer {
+	file: Box<Source>,
+	/// What's left to send, in order
+	parts: VecDeque<Part>,
+}
+
+/// A piece of a response body
+enum Part {
+	/// Some bytes we made up, like the framing between ranges
+	Data(Vec<u8>),
+	/// Part of the file
+	File {
+		/// Where to read from, if we're not already there
+		seek: Option<u64>,
+		/// How many bytes we've yet to send
+		remaining: u64,
+	},
+}
+
+/// What a request path turned out to refer to
+#[derive(Debug, PartialEq)]
+enum Resolved {
+	/// A file we can send
+	File(PathBuf),
+	/// A directory, but the URL didn't end in '/'
+	Redirect,
+	/// Trying to get outside the root
+	Forbidden,
+	NotFound,
+}
+
+struct TaskContext {
+	/// Who we send http messages to
+	http: grease::ServiceProviderHandle<http::Service>,
+	/// Who we send router messages to
+	router: grease::ServiceProviderHandle<router::Service>,
+	/// How the tasks we use send messages to us
+	reply_to: Handle,
+	/// Our mounts, indexed by handle. The handle is also the context on all
+	/// of the mount's routes.
+	mounts: HashMap<MountHandle, Mount>,
+	/// Files being sent, indexed by HTTP connection
+	transfers: HashMap<http::ConnHandle, Transfer>,
+	/// The next context we use for downward messages
+	next_ctx: Context,
+}
+
+type ReplyContext = grease::ReplyContext<Service>;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Creates a new files task. Returns an object that can be used
+/// to send this task messages.
+pub fn make_task(
+	http: grease::ServiceProviderHandle<http::Service>,
+	router: grease::ServiceProviderHandle<router::Service>,
+) -> Handle {
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx.clone());
+	std::thread::spawn(move || {
+		let mut t = TaskContext::new(http, router, handle);
+		for msg in rx.iter() {
+			t.handle(msg);
+		}
+		panic!("This task should never die!");
+	});
+	Handle(tx)
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// All our handler functions are methods on this `TaskContext` structure.
+impl TaskContext {
+	/// Create a new TaskContext
+	fn new(
+		http: grease::ServiceProviderHandle<http::Service>,
+		router: grease::ServiceProviderHandle<router::Service>,
+		us: Handle,
+	) -> Self {
+		Self {
+			http,
+			router,
+			reply_to: us,
+			mounts: HashMap::new(),
+			transfers: HashMap::new(),
+			// This number is arbitrary
+			next_ctx: grease::Context::new(5_000),
+		}
+	}
+
+	/// Handle an incoming message. It might a `Request` for us,
+	/// or it might be a `Confirm` or `Indication` from a lower layer.
+	fn handle(&mut self, msg: Incoming) {
+		match msg {
+			// We only handle our own requests and responses
+			Incoming::Request(x, reply_to) => {
+				debug!("Rx: {:?}", x);
+				self.handle_files_req(x, reply_to);
+			}
+			Incoming::HttpCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_cfm(x);
+			}
+			Incoming::HttpInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_ind(x);
+			}
+			Incoming::RouterCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_router_cfm(x);
+			}
+			Incoming::RouterInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_router_ind(x);
+			}
+			Incoming::Response(x) => {
+				debug!("Rx: {:?}", x);
+			}
+		}
+	}
+
+	fn handle_files_req(&mut self, req: Request, reply_to: grease::ServiceUserHandle<Service>) {
+		match req {
+			Request::Mount(x) => self.handle_mount(x, reply_to),
+		}
+	}
+
+	fn handle_http_cfm(&mut self, cfm: http::Confirm) {
+		match cfm {
+			http::Confirm::ResponseStart(x) => self.handle_http_cfm_response_start(x),
+			http::Confirm::ResponseBody(x) => self.handle_http_cfm_response_body(x),
+			http::Confirm::Abort(_) => {}
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_http_ind(&mut self, ind: http::Indication) {
+		match ind {
+			http::Indication::Closed(x) => self.handle_closed(x.handle),
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_router_cfm(&mut self, cfm: router::Confirm) {
+		match cfm {
+			router::Confirm::AddRoute(x) => self.handle_router_cfm_add_route(x),
+			router::Confirm::Bind(_) | router::Confirm::RemoveRoute(_) => {}
+		}
+	}
+
+	fn handle_router_ind(&mut self, ind: router::Indication) {
+		match ind {
+			router::Indication::RxRequest(x) => self.handle_router_ind_rx_request(x),
+			router::Indication::Closed(x) => self.handle_closed(x.handle),
+			// We only serve GET and HEAD, which have no body
+			router::Indication::RxBody(_) => {}
+		}
+	}
+
+	fn handle_mount(&mut self, req_mount: ReqMount, reply_to: grease::ServiceUserHandle<Service>) {
+		let pattern = match route_pattern(&req_mount.prefix) {
+			Some(p) => p,
+			None => {
+				reply_to.send_confirm(
+					CfmMount {
+						context: req_mount.context,
+						result: Err(Error::BadPrefix),
+					}.into(),
+				);
+				return;
+			}
+		};
+		let root = match fs::canonicalize(&req_mount.path) {
+			Ok(ref root) if root.is_dir() => root.clone(),
+			_ => {
+				reply_to.send_confirm(
+					CfmMount {
+						context: req_mount.context,
+						result: Err(Error::BadPath),
+					}.into(),
+				);
+				return;
+			}
+		};
+		let handle = self.next_ctx.take();
+		let methods = [Method::GET, Method::HEAD];
+		for method in &methods {
+			self.router.send_request(
+				router::ReqAddRoute {
+					server: req_mount.server,
+					context: handle,
+					method: method.clone(),
+					pattern: pattern.clone(),
+				}.into(),
+				&self.reply_to,
+			);
+		}
+		self.mounts.insert(
+			handle,
+			Mount {
+				root,
+				index_files: req_mount.index_files,
+				routes: Vec::new(),
+				pending_routes: methods.len(),
+				failed: false,
+				reply_ctx: Some(ReplyContext {
+					context: req_mount.context,
+					reply_to,
+				}),
+			},
+		);
+	}
+
+	fn handle_router_cfm_add_route(&mut self, cfm: router::CfmAddRoute) {
+		let finished = if let Some(mount) = self.mounts.get_mut(&cfm.context) {
+			mount.pending_routes -= 1;
+			match cfm.result {
+				Ok(route) => mount.routes.push(route),
+				Err(e) => {
+					mount.failed = true;
+					if let Some(reply_ctx) = mount.reply_ctx.take() {
+						reply_ctx.reply_to.send_confirm(
+							CfmMount {
+								context: reply_ctx.context,
+								result: Err(Error::Router(e)),
+							}.into(),
+						);
+					}
+				}
+			}
+			mount.pending_routes == 0
+		} else {
+			warn!("Context {} not found", cfm.context);
+			false
+		};
+		if !finished {
+			return;
+		}
+		if self.mounts[&cfm.context].failed {
+			// Tidy up any routes which did get added
+			let mount = self.mounts.remove(&cfm.context).unwrap();
+			for route in mount.routes {
+				self.router.send_request(
+					router::ReqRemoveRoute {
+						handle: route,
+						context: Context::default(),
+					}.into(),
+					&self.reply_to,
+				);
+			}
+		} else if let Some(reply_ctx) = self.mounts
+			.get_mut(&cfm.context)
+			.and_then(|m| m.reply_ctx.take())
+		{
+			reply_ctx.reply_to.send_confirm(
+				CfmMount {
+					context: reply_ctx.context,
+					result: Ok(cfm.context),
+				}.into(),
+			);
+		}
+	}
+
+	fn handle_router_ind_rx_request(&mut self, ind: router::IndRxRequest) {
+		let handle = ind.request.connection_handle;
+		let resolved = match self.mounts.get(&ind.context) {
+			Some(mount) => {
+				let rest = ind.params.get("*").map_or("", |s| s.as_str());
+				let slash = ind.request.url.path().ends_with('/');
+				resolve(&mount.root, rest, slash, &mount.index_files)
+			}
+			None => {
+				warn!("Request for unknown mount {}", ind.context);
+				Resolved::NotFound
+			}
+		};
+		debug!("{} resolved to {:?}", ind.request.url, resolved);
+		match resolved {
+			Resolved::File(path) => {
+				self.start_file(&ind.request, &path);
+			}
+			Resolved::Redirect => {
+				let mut headers = HeaderMap::new();
+				let location = format!("{}/", ind.request.url.path());
+				match location.parse() {
+					Ok(value) => {
+						headers.insert("Location", value);
+						self.send_error(handle, HttpResponseStatus::MovedPermanently, headers);
+					}
+					Err(_) => {
+						self.send_error(handle, HttpResponseStatus::BadRequest, headers);
+					}
+				}
+			}
+			Resolved::Forbidden => {
+				self.send_error(handle, HttpResponseStatus::Forbidden, HeaderMap::new());
+			}
+			Resolved::NotFound => {
+				self.send_error(handle, HttpResponseStatus::NotFound, HeaderMap::new());
+			}
+		}
+	}
+
+	/// Open the file, work out what the request should get, and send the
+	/// headers. The body follows once the `CfmResponseStart` comes back.
+	fn start_file(&mut self, request: &http::IndRxRequest, path: &Path) {
+		let handle = request.connection_handle;
+		let file_and_meta = fs::File::open(path).and_then(|f| {
+			let meta = f.metadata()?;
+			Ok((f, meta))
+		});
+		let (file, meta) = match file_and_meta {
+			Ok(x) => x,
+			Err(e) => {
+				warn!("Failed to open {:?}: {}", path, e);
+				let status = if e.kind() == io::ErrorKind::PermissionDenied {
+					HttpResponseStatus::Forbidden
+				} else {
+					HttpResponseStatus::NotFound
+				};
+				self.send_error(handle, status, HeaderMap::new());
+				return;
+			}
+		};
+		let len = meta.len();
+		let modified = meta.modified().ok();
+		let etag = entity_tag(len, modified);
+		let mut headers = HeaderMap::new();
+		headers.insert("ETag", etag.to_header());
+		if let Some(modified) = modified {
+			if let Ok(value) = HeaderValue::from_str(&http::http_date(modified)) {
+				headers.insert("Last-Modified", value);
+			}
+		}
+
+		match request.preconditions(Some(&etag), modified) {
+			Precondition::Proceed => {}
+			Precondition::NotModified => {
+				self.send_start(handle, HttpResponseStatus::NotModified, "", 0, headers);
+				return;
+			}
+			Precondition::Failed => {
+				self.send_error(handle, HttpResponseStatus::PreconditionFailed, headers);
+				return;
+			}
+		}
+		headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+		let content_type = mime_type(path);
+		let (status, content_type, length, parts) =
+			match request.select_ranges(len, Some(&etag), modified) {
+				RangeOutcome::Full => {
+					let part = Part::File {
+						seek: None,
+						remaining: len,
+					};
+					(HttpResponseStatus::OK, String::from(content_type), len, vec![part])
+				}
+				RangeOutcome::Partial(ref ranges) if ranges.len() == 1 => {
+					let (first, last) = ranges[0];
+					headers.insert("Content-Range", http::content_range(first, last, len));
+					let part = Part::File {
+						seek: Some(first),
+						remaining: last - first + 1,
+					};
+					(
+						HttpResponseStatus::PartialContent,
+						String::from(content_type),
+						last - first + 1,
+						vec![part],
+					)
+				}
+				RangeOutcome::Partial(ranges) => {
+					let multipart = http::ByteRanges::new(content_type, len, ranges);
+					let mut parts = Vec::new();
+					for (i, &(first, last)) in multipart.ranges().iter().enumerate() {
+						parts.push(Part::Data(multipart.part_head(i)));
+						parts.push(Part::File {
+							seek: Some(first),
+							remaining: last - first + 1,
+						});
+					}
+					parts.push(Part::Data(multipart.trailer()));
+					(
+						HttpResponseStatus::PartialContent,
+						multipart.content_type(),
+						multipart.length(),
+						parts,
+					)
+				}
+				RangeOutcome::NotSatisfiable => {
+					headers.insert("Content-Range", http::unsatisfied_range(len));
+					self.send_error(
+						handle,
+						HttpResponseStatus::RequestedRangeNotSatisfiable,
+						headers,
+					);
+					return;
+				}
+			};
+		let length = if request.method == Method::HEAD {
+			// Announce the length, but send no body
+			headers.insert("Content-Length", length.into());
+			0
+		} else {
+			length
+		};
+		self.send_start(handle, status, &content_type, length, headers);
+		if length != 0 {
+			self.transfers.insert(
+				handle,
+				Transfer {
+					file: Box::new(file),
+					parts: parts.into_iter().collect(),
+				},
+			);
+		}
+	}
+
+	fn send_start(
+		&mut self,
+		handle: http::ConnHandle,
+		status: HttpResponseStatus,
+		content_type: &str,
+		length: u64,
+		headers: HeaderMap,
+	) {
+		self.http.send_request(
+			http::ReqResponseStart {
+				handle,
+				context: self.next_ctx.take(),
+				status,
+				content_type: String::from(content_type),
+				length: Some(length as usize),
+				headers,
+				compress: None,
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	fn handle_http_cfm_response_start(&mut self, cfm: http::CfmResponseStart) {
+		if cfm.result.is_ok() {
+			self.send_next_chunk(cfm.handle);
+		} else {
+			self.transfers.remove(&cfm.handle);
+		}
+	}
+
+	fn handle_http_cfm_response_body(&mut self, cfm: http::CfmResponseBody) {
+		if cfm.result.is_ok() {
+			self.send_next_chunk(cfm.handle);
+		} else {
+			self.transfers.remove(&cfm.handle);
+		}
+	}
+
+	/// Send the next piece of the body, if there is any left.
+	fn send_next_chunk(&mut self, handle: http::ConnHandle) {
+		let chunk = if let Some(transfer) = self.transfers.get_mut(&handle) {
+			match read_part(transfer) {
+				Ok(data) => Some(data),
+				Err(e) => {
+					// We've already promised the length, so all we can do
+					// is make sure the client can tell it was cut short
+					warn!("Failed to read file for {}: {}", handle, e);
+					None
+				}
+			}
+		} else {
+			// Error responses and HEAD requests don't have a transfer
+			return;
+		};
+		match chunk {
+			Some(data) => {
+				if self.transfers[&handle].parts.is_empty() {
+					self.transfers.remove(&handle);
+				}
+				self.http.send_request(
+					http::ReqResponseBody {
+						handle,
+						context: self.next_ctx.take(),
+						data,
+					}.into(),
+					&self.reply_to,
+				);
+			}
+			None => {
+				self.transfers.remove(&handle);
+				self.http.send_request(
+					http::ReqAbort {
+						handle,
+						context: self.next_ctx.take(),
+					}.into(),
+					&self.reply_to,
+				);
+			}
+		}
+	}
+
+	fn handle_closed(&mut self, handle: http::ConnHandle) {
+		if self.transfers.remove(&handle).is_some() {
+			debug!("Connection {} closed mid-transfer", handle);
+		}
+	}
+
+	/// Answer a request with an error (or redirect) status.
+	fn send_error(
+		&mut self,
+		handle: http::ConnHandle,
+		status: HttpResponseStatus,
+		headers: HeaderMap,
+	) {
+		let ctx = self.next_ctx.take();
+		http::send_error(&*self.http, &self.reply_to, handle, ctx, status, headers);
+	}
+}
+
+/// Take the next chunk off the front of a transfer. Reads no more than
+/// `CHUNK_SIZE` bytes of the file.
+fn read_part(transfer: &mut Transfer) -> io::Result<Vec<u8>> {
+	let (data, finished) = match transfer.parts.front_mut() {
+		Some(&mut Part::Data(ref mut data)) => (::std::mem::replace(data, Vec::new()), true),
+		Some(&mut Part::File {
+			ref mut seek,
+			ref mut remaining,
+		}) => {
+			if let Some(offset) = seek.take() {
+				transfer.file.seek(SeekFrom::Start(offset))?;
+			}
+			let size = std::cmp::min(*remaining, CHUNK_SIZE as u64) as usize;
+			let mut data = vec![0u8; size];
+			transfer.file.read_exact(&mut data)?;
+			*remaining -= size as u64;
+			(data, *remaining == 0)
+		}
+		None => (Vec::new(), false),
+	};
+	if finished {
+		transfer.parts.pop_front();
+	}
+	Ok(data)
+}
+
+/// A strong validator for a file. If it's modified in place within the
+/// same nanosecond and keeps its length, clients will miss the change, but
+/// that's the price of not reading the whole file.
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> EntityTag {
+	let stamp = modified
+		.and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+		.map_or(0, |d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()));
+	EntityTag::strong(&format!("{:x}-{:x}", len, stamp))
+}
+
+/// Convert a mount prefix into a router pattern, e.g. "/static" becomes
+/// "/static/*".
+fn route_pattern(prefix: &str) -> Option<String> {
+	if !prefix.starts_with('/') {
+		return None;
+	}
+	let prefix = prefix.trim_end_matches('/');
+	if prefix
+		.split('/')
+		.any(|s| s.starts_with(':') || s.starts_with('*'))
+	{
+		return None;
+	}
+	Some(format!("{}/*", prefix))
+}
+
+/// Work out which file the remainder of a URL path (after the mount
+/// prefix) refers to. `slash` says whether the full URL path ended in '/'.
+fn resolve(root: &Path, rest: &str, slash: bool, index_files: &[String]) -> Resolved {
+	let decoded = match http::decode_path(&format!("/{}", rest)) {
+		Ok(d) => d,
+		Err(UriError::DotDotSegment) | Err(UriError::BadSegment) => return Resolved::Forbidden,
+		Err(_) => return Resolved::NotFound,
+	};
+	let mut path = root.to_path_buf();
+	for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+		path.push(segment);
+	}
+	// Follow any symlinks, then make sure we're still under the root
+	let path = match fs::canonicalize(&path) {
+		Ok(p) => p,
+		Err(_) => return Resolved::NotFound,
+	};
+	if !path.starts_with(root) {
+		return Resolved::Forbidden;
+	}
+	if path.is_dir() {
+		if !slash {
+			// Otherwise relative links in the index won't work
+			return Resolved::Redirect;
+		}
+		for index in index_files {
+			let candidate = path.join(index);
+			if candidate.is_file() {
+				return Resolved::File(candidate);
+			}
+		}
+		return Resolved::NotFound;
+	}
+	Resolved::File(path)
+}
+
+/// Pick a `Content-Type` based on the file extension.
+fn mime_type(path: &Path) -> &'static str {
+	let ext = path.extension()
+		.and_then(|e| e.to_str())
+		.map(|e| e.to_ascii_lowercase());
+	match ext.as_ref().map(|e| e.as_str()) {
+		Some("html") | Some("htm") => "text/html; charset=utf-8",
+		Some("css") => "text/css; charset=utf-8",
+		Some("js") | Some("mjs") => "application/javascript",
+		Some("json") | Some("map") => "application/json",
+		Some("txt") => "text/plain; charset=utf-8",
+		Some("csv") => "text/csv",
+		Some("xml") => "application/xml",
+		Some("svg") => "image/svg+xml",
+		Some("png") => "image/png",
+		Some("jpg") | Some("jpeg") => "image/jpeg",
+		Some("gif") => "image/gif",
+		Some("ico") => "image/x-icon",
+		Some("webp") => "image/webp",
+		Some("woff") => "font/woff",
+		Some("woff2") => "font/woff2",
+		Some("ttf") => "font/ttf",
+		Some("otf") => "font/otf",
+		Some("wasm") => "application/wasm",
+		Some("pdf") => "application/pdf",
+		Some("zip") => "application/zip",
+		Some("gz") => "application/gzip",
+		Some("mp4") => "video/mp4",
+		Some("webm") => "video/webm",
+		Some("mp3") => "audio/mpeg",
+		Some("wav") => "audio/wav",
+		_ => "application/octet-stream",
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::prelude::*;
+	use std::net;
+	use std::sync::atomic;
+	use std::sync::mpsc;
+
+	use grease::prelude::*;
+
+	enum TestIncoming {
+		FilesCfm(Confirm),
+		RouterCfm(router::Confirm),
+		HttpReq(http::Request),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	static PORT_NUMBER: atomic::AtomicUsize = atomic::AtomicUsize::new(8200);
+
+	static DIR_NUMBER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+	fn allocate_test_port() -> net::SocketAddr {
+		let port = PORT_NUMBER.fetch_add(1, atomic::Ordering::SeqCst);
+		net::SocketAddr::new(
+			net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 1, 1)),
+			port as u16,
+		)
+	}
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::FilesCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, _ind: Indication) {}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<http::Service> for TestHandle {
+		fn send_request(&self, req: http::Request, _reply_to: &grease::ServiceUser<http::Service>) {
+			self.0.send(TestIncoming::HttpReq(req)).unwrap();
+		}
+		fn send_response(&self, _rsp: http::Response) {}
+		fn clone(&self) -> grease::ServiceProviderHandle<http::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceUser<router::Service> for TestHandle {
+		fn send_confirm(&self, cfm: router::Confirm) {
+			self.0.send(TestIncoming::RouterCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, _ind: router::Indication) {}
+		fn clone(&self) -> grease::ServiceUserHandle<router::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	/// Gives `good` zero bytes, then fails
+	struct FailingReader {
+		good: usize,
+	}
+
+	impl Read for FailingReader {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			if self.good == 0 {
+				return Err(io::Error::new(io::ErrorKind::Other, "disk on fire"));
+			}
+			let n = ::std::cmp::min(buf.len(), self.good);
+			for b in &mut buf[..n] {
+				*b = 0;
+			}
+			self.good -= n;
+			Ok(n)
+		}
+	}
+
+	impl Seek for FailingReader {
+		fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+			Ok(0)
+		}
+	}
+
+	/// A directory made by `make_test_dir`, which derefs to the `www`
+	/// directory inside it. The whole thing is removed on drop.
+	struct TestDir {
+		base: PathBuf,
+		www: PathBuf,
+	}
+
+	impl ::std::ops::Deref for TestDir {
+		type Target = Path;
+		fn deref(&self) -> &Path {
+			&self.www
+		}
+	}
+
+	impl Drop for TestDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.base);
+		}
+	}
+
+	/// Make a fresh directory with some files in it:
+	///
+	/// * www/index.html
+	/// * www/big.bin (a few chunks long)
+	/// * www/sub/a%20b.txt
+	/// * secret.txt (outside the root)
+	fn make_test_dir() -> TestDir {
+		let base = ::std::env::temp_dir().join(format!(
+			"grease-files-{}-{}",
+			::std::process::id(),
+			DIR_NUMBER.fetch_add(1, atomic::Ordering::SeqCst)
+		));
+		let www = base.join("www");
+		fs::create_dir_all(www.join("sub")).unwrap();
+		fs::write(www.join("index.html"), "<h1>Hello</h1>").unwrap();
+		fs::write(www.join("big.bin"), big_contents()).unwrap();
+		fs::write(www.join("sub").join("a b.txt"), "spaced").unwrap();
+		fs::write(base.join("secret.txt"), "secret").unwrap();
+		TestDir { base, www }
+	}
+
+	fn big_contents() -> Vec<u8> {
+		(0..(CHUNK_SIZE * 3 + 100)).map(|x| (x % 251) as u8).collect()
+	}
+
+	#[test]
+	/// A file which fails to read part way through gets its connection reset
+	fn failed_read() {
+		let (tx, test_rx) = mpsc::channel();
+		let (us, _) = mpsc::channel();
+		let router_thread = router::make_task(Box::new(TestHandle(tx.clone())));
+		let mut t = TaskContext::new(
+			Box::new(TestHandle(tx)),
+			grease::ServiceProvider::clone(&router_thread),
+			Handle(us),
+		);
+		let handle = Context::new(7);
+		let parts = vec![Part::File {
+			seek: None,
+			remaining: 2 * CHUNK_SIZE as u64,
+		}];
+		t.transfers.insert(
+			handle,
+			Transfer {
+				file: Box::new(FailingReader { good: CHUNK_SIZE }),
+				parts: parts.into_iter().collect(),
+			},
+		);
+
+		t.handle_http_cfm_response_start(http::CfmResponseStart {
+			handle,
+			context: Context::new(1),
+			result: Ok(()),
+		});
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(x)) => {
+				assert_eq!(x.handle, handle);
+				assert_eq!(x.data.len(), CHUNK_SIZE);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		t.handle_http_cfm_response_body(http::CfmResponseBody {
+			handle,
+			context: Context::new(2),
+			result: Ok(()),
+		});
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::Abort(x)) => assert_eq!(x.handle, handle),
+			_ => panic!("Unexpected message"),
+		}
+		assert!(t.transfers.is_empty());
+	}
+
+	/// Send a raw request and read until the server closes the connection.
+	fn fetch(addr: net::SocketAddr, method: &str, path: &str) -> (String, Vec<u8>) {
+		fetch_with(addr, method, path, "")
+	}
+
+	/// As `fetch`, with some extra header lines.
+	fn fetch_with(
+		addr: net::SocketAddr,
+		method: &str,
+		path: &str,
+		headers: &str,
+	) -> (String, Vec<u8>) {
+		let mut stream = net::TcpStream::connect(addr).unwrap();
+		stream.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+		write!(
+			stream,
+			"{} {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+			method, path, headers
+		).unwrap();
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response).unwrap();
+		let split = response
+			.windows(4)
+			.position(|w| w == b"\r\n\r\n")
+			.unwrap();
+		let body = response.split_off(split + 4);
+		(String::from_utf8(response).unwrap(), body)
+	}
+
+	#[test]
+	fn route_patterns() {
+		assert_eq!(route_pattern("/"), Some(String::from("/*")));
+		assert_eq!(route_pattern("/static"), Some(String::from("/static/*")));
+		assert_eq!(route_pattern("/static/"), Some(String::from("/static/*")));
+		assert_eq!(route_pattern("static"), None);
+		assert_eq!(route_pattern("/user/:id"), None);
+		assert_eq!(route_pattern("/*"), None);
+	}
+
+	#[test]
+	fn mime_types() {
+		assert_eq!(mime_type(Path::new("a/index.HTML")), "text/html; charset=utf-8");
+		assert_eq!(mime_type(Path::new("app.js")), "application/javascript");
+		assert_eq!(mime_type(Path::new("logo.svg")), "image/svg+xml");
+		assert_eq!(mime_type(Path::new("README")), "application/octet-stream");
+	}
+
+	#[test]
+	fn resolving() {
+		let www = make_test_dir();
+		let root = fs::canonicalize(&*www).unwrap();
+		let index = vec![String::from("index.html")];
+		assert_eq!(
+			resolve(&root, "", true, &index),
+			Resolved::File(root.join("index.html"))
+		);
+		assert_eq!(
+			resolve(&root, "sub/a%20b.txt", false, &index),
+			Resolved::File(root.join("sub").join("a b.txt"))
+		);
+		assert_eq!(resolve(&root, "sub", false, &index), Resolved::Redirect);
+		assert_eq!(resolve(&root, "sub/", true, &index), Resolved::NotFound);
+		assert_eq!(resolve(&root, "missing.txt", false, &index), Resolved::NotFound);
+		assert_eq!(resolve(&root, "../secret.txt", false, &index), Resolved::Forbidden);
+		assert_eq!(
+			resolve(&root, "sub/%2e%2e/%2e%2e/secret.txt", false, &index),
+			Resolved::Forbidden
+		);
+		assert_eq!(resolve(&root, "..%5csecret.txt", false, &index), Resolved::Forbidden);
+		assert_eq!(resolve(&root, "sub%2fa%20b.txt", false, &index), Resolved::Forbidden);
+		assert_eq!(resolve(&root, "bad%zz", false, &index), Resolved::NotFound);
+		#[cfg(unix)]
+		{
+			::std::os::unix::fs::symlink(www.join("..").join("secret.txt"), www.join("link.txt"))
+				.unwrap();
+			assert_eq!(resolve(&root, "link.txt", false, &index), Resolved::Forbidden);
+		}
+	}
+
+	/// Start the whole stack, with `www` mounted on "/static".
+	fn serve(
+		www: &Path,
+	) -> (
+		net::SocketAddr,
+		router::ServerHandle,
+		Handle,
+		TestHandle,
+		mpsc::Receiver<TestIncoming>,
+	) {
+		let (tx, test_rx) = mpsc::channel();
+		let this_thread = TestHandle(tx);
+		let socket_thread = socket::make_task();
+		let http_thread = http::make_task(grease::ServiceProvider::clone(&socket_thread));
+		let router_thread = router::make_task(grease::ServiceProvider::clone(&http_thread));
+		let files_thread = make_task(
+			grease::ServiceProvider::clone(&http_thread),
+			grease::ServiceProvider::clone(&router_thread),
+		);
+		let addr = allocate_test_port();
+
+		router_thread.send_request(
+			router::ReqBind {
+				addr,
+				context: Context::new(1),
+				config: http::ServerConfig::default(),
+			}.into(),
+			&this_thread,
+		);
+		let server = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterCfm(router::Confirm::Bind(x)) => x.result.unwrap(),
+			_ => panic!("Unexpected message"),
+		};
+
+		files_thread.send_request(
+			ReqMount {
+				server,
+				context: Context::new(2),
+				prefix: String::from("/static"),
+				path: www.to_path_buf(),
+				index_files: vec![String::from("index.html")],
+			}.into(),
+			&this_thread,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::FilesCfm(Confirm::Mount(x)) => {
+				assert_eq!(x.context, Context::new(2));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		}
+		(addr, server, files_thread, this_thread, test_rx)
+	}
+
+	/// Find a header in a response head, whatever case it was sent in.
+	fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+		head.split("\r\n").filter_map(|line| {
+			let colon = line.find(':')?;
+			if line[..colon].eq_ignore_ascii_case(name) {
+				Some(line[colon + 1..].trim())
+			} else {
+				None
+			}
+		}).next()
+	}
+
+	#[test]
+	fn serve_files() {
+		let www = make_test_dir();
+		let (addr, server, files_thread, this_thread, test_rx) = serve(&www);
+
+		let (head, body) = fetch(addr, "GET", "/static/big.bin");
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(head.contains("Content-Type: application/octet-stream\r\n"));
+		assert!(head.contains(&format!("Content-Length: {}\r\n", big_contents().len())));
+		assert!(body == big_contents());
+
+		let (head, body) = fetch(addr, "HEAD", "/static/big.bin");
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(head.contains(&format!("content-length: {}\r\n", big_contents().len())));
+		assert!(body.is_empty());
+
+		let (head, body) = fetch(addr, "GET", "/static/");
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(head.contains("Content-Type: text/html; charset=utf-8\r\n"));
+		assert_eq!(body, b"<h1>Hello</h1>");
+
+		let (head, _) = fetch(addr, "GET", "/static");
+		assert!(head.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+		assert!(head.contains("location: /static/\r\n"));
+
+		let (head, body) = fetch(addr, "GET", "/static/sub/a%20b.txt");
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert_eq!(body, b"spaced");
+
+		let (head, _) = fetch(addr, "GET", "/static/%2e%2e/secret.txt");
+		assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+		let (head, _) = fetch(addr, "GET", "/static/nope.txt");
+		assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+		let (head, _) = fetch(addr, "POST", "/static/big.bin");
+		assert!(head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+		// Mounting a missing directory fails
+		files_thread.send_request(
+			ReqMount {
+				server,
+				context: Context::new(3),
+				prefix: String::from("/other"),
+				path: www.join("missing"),
+				index_files: Vec::new(),
+			}.into(),
+			&this_thread,
+		);
+		loop {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::FilesCfm(Confirm::Mount(x)) => {
+					assert_eq!(x.context, Context::new(3));
+					assert!(x.result.is_err());
+					break;
+				}
+				_ => {}
+			}
+		}
+	}
+
+	#[test]
+	fn conditional_and_ranges() {
+		let www = make_test_dir();
+		let (addr, _, _files_thread, _this_thread, _test_rx) = serve(&www);
+		let big = big_contents();
+		let (head, _) = fetch(addr, "GET", "/static/big.bin");
+		assert_eq!(header(&head, "Accept-Ranges"), Some("bytes"));
+		let etag = header(&head, "ETag").unwrap().to_owned();
+		let modified = header(&head, "Last-Modified").unwrap().to_owned();
+		assert!(etag.starts_with('"'));
+
+		// Revalidation
+		let get = |headers: &str| fetch_with(addr, "GET", "/static/big.bin", headers);
+		let (head, body) = get(&format!("If-None-Match: \"other\", {}\r\n", etag));
+		assert!(head.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+		assert_eq!(header(&head, "ETag"), Some(&etag[..]));
+		assert!(body.is_empty());
+		let (head, _) = get(&format!("If-Modified-Since: {}\r\n", modified));
+		assert!(head.starts_with("HTTP/1.1 304 "));
+		let (head, _) = get("If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n");
+		assert!(head.starts_with("HTTP/1.1 200 "));
+		let (head, _) = get("If-Match: \"other\"\r\n");
+		assert!(head.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+
+		// One range, crossing chunks
+		let last = CHUNK_SIZE * 2 + 7;
+		let (head, body) = get(&format!("Range: bytes=100-{}\r\n", last));
+		assert!(head.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+		assert_eq!(
+			header(&head, "Content-Range").unwrap(),
+			format!("bytes 100-{}/{}", last, big.len())
+		);
+		assert_eq!(header(&head, "Content-Length").unwrap(), (last - 99).to_string());
+		assert!(body[..] == big[100..last + 1]);
+		let (head, body) = get("Range: bytes=-10\r\n");
+		assert!(head.starts_with("HTTP/1.1 206 "));
+		assert!(body[..] == big[big.len() - 10..]);
+
+		// Several ranges
+		let (head, body) = get("Range: bytes=0-1, -2\r\n");
+		assert!(head.starts_with("HTTP/1.1 206 "));
+		let content_type = header(&head, "Content-Type").unwrap();
+		let boundary = &content_type["multipart/byteranges; boundary=".len()..];
+		let mut expected = Vec::new();
+		for &(first, last) in &[(0, 1), (big.len() - 2, big.len() - 1)] {
+			expected.extend(
+				format!(
+					"\r\n--{}\r\nContent-Type: application/octet-stream\r\n\
+					 Content-Range: bytes {}-{}/{}\r\n\r\n",
+					boundary,
+					first,
+					last,
+					big.len()
+				).into_bytes(),
+			);
+			expected.extend_from_slice(&big[first..last + 1]);
+		}
+		expected.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+		assert_eq!(header(&head, "Content-Length").unwrap(), expected.len().to_string());
+		assert!(body == expected);
+
+		// Nothing we can send
+		let (head, _) = get(&format!("Range: bytes={}-\r\n", big.len()));
+		assert!(head.starts_with("HTTP/1.1 416 "));
+		assert_eq!(
+			header(&head, "Content-Range").unwrap(),
+			format!("bytes */{}", big.len())
+		);
+
+		// If-Range
+		let (head, body) = get(&format!("Range: bytes=0-9\r\nIf-Range: {}\r\n", etag));
+		assert!(head.starts_with("HTTP/1.1 206 "));
+		assert_eq!(body.len(), 10);
+		let (head, body) = get("Range: bytes=0-9\r\nIf-Range: \"stale\"\r\n");
+		assert!(head.starts_with("HTTP/1.1 200 "));
+		assert!(body == big);
+
+		// HEAD tells you about the range without sending it
+		let (head, body) = fetch_with(addr, "HEAD", "/static/big.bin", "Range: bytes=0-9\r\n");
+		assert!(head.starts_with("HTTP/1.1 200 "));
+		assert!(body.is_empty());
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
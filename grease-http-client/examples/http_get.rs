@@ -0,0 +1,129 @@
+//! # http_client - a grease example fetching a URL with the http_client task
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate env_logger;
+#[macro_use]
+extern crate grease;
+extern crate grease_http_client as http_client;
+extern crate grease_socket as socket;
+#[macro_use]
+extern crate log;
+
+use std::io::prelude::*;
+use std::sync::mpsc;
+
+use grease::prelude::*;
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+struct Handle(mpsc::Sender<Incoming>);
+
+app_map! {
+	generate: Incoming,
+	handle: Handle,
+	used: {
+		http_client: (Service, HttpCfm, HttpInd)
+	}
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Start of our example program
+fn main() {
+	env_logger::init();
+	let uri = std::env::args()
+		.nth(1)
+		.unwrap_or_else(|| String::from("http://localhost:8000/"));
+
+	info!("Hello, this is the grease HTTP client example.");
+	info!("Fetching {}", uri);
+
+	let socket_thread = socket::make_task();
+	let client_thread = http_client::make_task(socket_thread.clone());
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx);
+
+	client_thread.send_request(
+		http_client::ReqHttpRequest {
+			context: Context::default(),
+			method: http_client::Method::GET,
+			uri: uri.parse().unwrap(),
+			headers: http_client::HeaderMap::new(),
+			body: None,
+			stream_body: false,
+		}.into(),
+		&handle,
+	);
+
+	for msg in rx.iter() {
+		match msg {
+			Incoming::HttpCfm(http_client::Confirm::HttpRequest(cfm)) => {
+				if let Err(e) = cfm.result {
+					error!("Request failed: {:?}", e);
+					break;
+				}
+			}
+			Incoming::HttpCfm(http_client::Confirm::RequestBody(_)) => {
+				// We don't stream a body
+			}
+			Incoming::HttpInd(http_client::Indication::ResponseStart(ind)) => {
+				info!("Got response {} {:?}", ind.status, ind.headers);
+			}
+			Incoming::HttpInd(http_client::Indication::ResponseBody(ind)) => {
+				std::io::stdout().write_all(&ind.data).unwrap();
+				if ind.last {
+					break;
+				}
+				client_thread.send_response(http_client::RspResponseBody { handle: ind.handle }.into());
+			}
+			Incoming::HttpInd(http_client::Indication::Failed(ind)) => {
+				error!("Response failed: {:?}", ind.error);
+				break;
+			}
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
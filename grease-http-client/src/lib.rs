@@ -0,0 +1,1568 @@
+//! # http_client - An HTTP client task
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! This task implements a basic HTTP/1.1 client. It depends on the `socket`
+//! task to interface with the network, and is the client-side counterpart to
+//! the `http` task.
+//!
+//! The user sends a `ReqHttpRequest` with an absolute `http://` URI. We
+//! connect to the server (or re-use an idle connection to that server), send
+//! the request, and then send a `CfmHttpRequest` containing the handle for
+//! this request. The response follows as an `IndResponseStart` (status and
+//! headers), then one or more `IndResponseBody`. The final
+//! `IndResponseBody` has `last` set. For flow control, the user must send a
+//! `RspResponseBody` after every other `IndResponseBody` before the next one
+//! will be sent. If the connection fails after the `CfmHttpRequest` but
+//! before the response is complete, an `IndFailed` is sent instead.
+//!
+//! To stream a request body rather than give it all at once, set
+//! `stream_body` and send the body afterwards in `ReqRequestBody`s, using
+//! the handle from the `CfmHttpRequest`. The last one has `last` set. Each
+//! is confirmed with a `CfmRequestBody` once it has been sent, so wait for
+//! that before sending the next. Give a `Content-Length` header if you know
+//! the body's length; otherwise it is sent chunked. The response may start
+//! arriving before the body has all been sent.
+//!
+//! Once a response is complete, the connection is kept open (if the server
+//! will let us) and re-used for subsequent requests to the same host and
+//! port. We never send more than one request at a time on a connection.
+//!
+//! Response heads are parsed with the `http` task's `ResponseParser`, so a
+//! response framed in a way we can't be sure of (e.g. with lengths which
+//! disagree, or both `Content-Length` and `Transfer-Encoding`) fails with
+//! `Error::BadResponse`.
+//!
+//! Host names are resolved on the task's own thread, so the task will block
+//! while a lookup is in progress.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#![cfg_attr(feature = "cargo-clippy", allow(if_not_else))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http_server;
+extern crate grease_socket as socket;
+extern crate http;
+#[macro_use]
+extern crate log;
+extern crate multi_map;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net;
+use std::net::ToSocketAddrs;
+use std::sync::mpsc;
+
+use http_server::{encode_chunk, ChunkedDecoder, ParseLimits, Parsed, ResponseParser, StatusHead};
+use multi_map::MultiMap;
+
+pub use http::{HeaderMap, Method, StatusCode, Uri};
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Messages
+//
+// ****************************************************************************
+
+/// Offers the `grease::Service` for this module.
+pub struct Service;
+
+impl grease::Service for Service {
+	type Request = Request;
+	type Confirm = Confirm;
+	type Indication = Indication;
+	type Response = Response;
+}
+
+/// Requests that can be sent to the http_client task.
+#[derive(Debug)]
+pub enum Request {
+	/// Send an HTTP request to a server
+	HttpRequest(ReqHttpRequest),
+	/// Send some of a streamed request body
+	RequestBody(ReqRequestBody),
+}
+
+make_wrapper!(ReqHttpRequest, Request, Request::HttpRequest);
+make_wrapper!(ReqRequestBody, Request, Request::RequestBody);
+
+/// Confirms sent back from the http_client task.
+#[derive(Debug)]
+pub enum Confirm {
+	/// Whether the ReqHttpRequest was sent
+	HttpRequest(CfmHttpRequest),
+	/// Whether the ReqRequestBody was sent
+	RequestBody(CfmRequestBody),
+}
+
+make_wrapper!(CfmHttpRequest, Confirm, Confirm::HttpRequest);
+make_wrapper!(CfmRequestBody, Confirm, Confirm::RequestBody);
+
+/// Indications that come out of the http_client task.
+#[derive(Debug)]
+pub enum Indication {
+	/// The status and headers of a response have been received
+	ResponseStart(IndResponseStart),
+	/// Some of the body of a response has been received
+	ResponseBody(IndResponseBody),
+	/// The connection failed before the response was complete
+	Failed(IndFailed),
+}
+
+make_wrapper!(IndResponseStart, Indication, Indication::ResponseStart);
+make_wrapper!(IndResponseBody, Indication, Indication::ResponseBody);
+make_wrapper!(IndFailed, Indication, Indication::Failed);
+
+/// Responses that must be sent back to the http_client task.
+#[derive(Debug)]
+pub enum Response {
+	/// Unblocks the response so more IndResponseBody can be sent
+	ResponseBody(RspResponseBody),
+}
+
+make_wrapper!(RspResponseBody, Response, Response::ResponseBody);
+
+/// Send an HTTP request. A Host header is added automatically, as is a
+/// Content-Length if there is a body, but you can add arbitrary other
+/// headers in the header map.
+pub struct ReqHttpRequest {
+	/// Reflected back in the cfm, and in the `IndResponseStart`
+	pub context: Context,
+	/// The method to use, e.g. GET
+	pub method: Method,
+	/// The absolute URI to fetch, e.g. "http://localhost:8000/index.html"
+	pub uri: Uri,
+	/// Any other headers required.
+	pub headers: HeaderMap,
+	/// The request body, if any.
+	pub body: Option<Vec<u8>>,
+	/// If set, `body` must be None, and the body follows in
+	/// `ReqRequestBody`s.
+	pub stream_body: bool,
+}
+
+/// Send some of the body of a request made with `stream_body` set. Note
+/// that this type has a custom `std::fmt::Debug` implementation so it
+/// doesn't print the (lengthy) contents of `data`.
+pub struct ReqRequestBody {
+	/// The handle from the `CfmHttpRequest`
+	pub handle: RequestHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	pub data: Vec<u8>,
+	/// If true, this is the end of the body
+	pub last: bool,
+}
+
+/// Whether the `ReqHttpRequest` was sent. The response follows as
+/// indications.
+#[derive(Debug)]
+pub struct CfmHttpRequest {
+	pub context: Context,
+	pub result: Result<RequestHandle, Error>,
+}
+
+/// Whether the `ReqRequestBody` was sent
+#[derive(Debug)]
+pub struct CfmRequestBody {
+	pub handle: RequestHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// The status and headers of a response have been received. One or more
+/// `IndResponseBody` will follow.
+#[derive(Debug)]
+pub struct IndResponseStart {
+	pub handle: RequestHandle,
+	/// Reflected from the `ReqHttpRequest`
+	pub context: Context,
+	pub status: StatusCode,
+	pub headers: HeaderMap,
+	/// The length of the body, if the server told us in advance
+	pub length: Option<usize>,
+}
+
+/// Some of the body of a response. Unless `last` is set, send a
+/// `RspResponseBody` to get the next one. Note that this type has a custom
+/// `std::fmt::Debug` implementation so it doesn't print the (lengthy)
+/// contents of `data`.
+pub struct IndResponseBody {
+	pub handle: RequestHandle,
+	pub data: Vec<u8>,
+	/// If true, this is the end of the response and the handle is no
+	/// longer valid.
+	pub last: bool,
+}
+
+/// The connection failed before the response was complete. The handle is
+/// no longer valid.
+#[derive(Debug)]
+pub struct IndFailed {
+	pub handle: RequestHandle,
+	pub error: Error,
+}
+
+/// Tell the task that more of the response body can now be sent.
+#[derive(Debug)]
+pub struct RspResponseBody {
+	pub handle: RequestHandle,
+}
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Represents something an http_client service user can hold on to to send
+/// us message.
+pub struct Handle(mpsc::Sender<Incoming>);
+
+/// A new one of these is allocated for every `ReqHttpRequest`
+pub type RequestHandle = Context;
+
+/// All possible http_client task errors
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+	/// The URI wasn't an absolute `http://` URI
+	BadUri,
+	/// The host in the URI could not be resolved
+	Resolve,
+	/// The server sent something we couldn't parse
+	BadResponse,
+	/// The server closed the connection before the response was complete
+	Dropped,
+	/// Socket connect or send failed
+	Socket(socket::SocketError),
+	/// A `ReqRequestBody` was sent for a request which isn't streaming its
+	/// body, or has finished it, or has already had its response
+	BadHandle,
+	/// `stream_body` was set, but so was `body`
+	BadBody,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+service_map! {
+	generate: Incoming,
+	service: Service,
+	handle: Handle,
+	used: {
+		socket: (Service, SocketCfm, SocketInd)
+	}
+}
+
+/// Connections are pooled per lower-case host name and port.
+type HostKey = (String, u16);
+
+/// How we know where the response body ends.
+enum BodyLength {
+	/// Exactly this many more bytes
+	Fixed(usize),
+	/// Chunked transfer-encoding
+	Chunked(ChunkedDecoder),
+	/// Everything until the server closes the connection
+	UntilClose,
+}
+
+enum State {
+	/// Waiting for the socket `CfmConnect`
+	Connecting,
+	/// Waiting for the socket `CfmSend`
+	Sending,
+	/// Waiting for the response status line and headers
+	Head,
+	/// Passing up the response body
+	Body(BodyLength),
+}
+
+/// The status line and headers of a response.
+struct ResponseHead {
+	/// True for HTTP/1.1, false for HTTP/1.0
+	http11: bool,
+	status: StatusCode,
+	headers: HeaderMap,
+	length: BodyLength,
+}
+
+/// How a request body is being streamed
+struct Streaming {
+	/// Wrap each piece in chunked transfer encoding
+	chunked: bool,
+	/// Some of the body has gone out, so we can't retry the request
+	started: bool,
+	/// The user has sent the last piece
+	finished: bool,
+}
+
+/// One of these for every `ReqHttpRequest` we're working on.
+struct Transaction {
+	/// The handle by which the upper layer refers to us
+	our_handle: RequestHandle,
+	/// Who to send the cfm and indications to
+	reply_ctx: ReplyContext,
+	/// Which connection pool this request is for
+	host: HostKey,
+	/// Where we connect to if there's no pooled connection
+	addr: net::SocketAddr,
+	/// HEAD responses never have a body
+	is_head: bool,
+	/// The method is idempotent, so the request can be sent again even if
+	/// the server may have seen it
+	idempotent: bool,
+	/// The `CfmHttpRequest` has been sent
+	confirmed: bool,
+	/// Parses the response head
+	parser: ResponseParser,
+	/// How the request body is being streamed, if it is
+	streaming: Option<Streaming>,
+	/// The rendered request. We keep it until the response arrives in case
+	/// a pooled connection turns out to be dead and we need to retry.
+	request: Vec<u8>,
+	/// Set once we have a connection
+	socket_handle: Option<socket::ConnHandle>,
+	/// True if the connection came out of the pool
+	reused: bool,
+	state: State,
+	/// Data received that we haven't passed up yet
+	buffer: Vec<u8>,
+	/// True if the connection can go back in the pool afterwards
+	keep_alive: bool,
+	/// True if the socket is waiting for a `RspReceived`
+	socket_blocked: bool,
+	/// True if we're waiting for a `RspResponseBody`
+	user_blocked: bool,
+	/// True if the socket has been dropped
+	eof: bool,
+}
+
+struct TaskContext {
+	/// Who we send socket messages to
+	socket: grease::ServiceProviderHandle<socket::Service>,
+	/// How the tasks we use send messages to us
+	reply_to: Handle,
+	/// Requests waiting for a new connection, indexed by the context we
+	/// sent in the `socket::ReqConnect`
+	connecting: HashMap<Context, Transaction>,
+	/// Requests with a connection, indexed by the handle given in the
+	/// `CfmHttpRequest` and by the socket handle
+	transactions: MultiMap<RequestHandle, socket::ConnHandle, Transaction>,
+	/// Streamed request bodies being sent, indexed by the context we gave
+	/// the socket task, with the handle and context for the cfm
+	body_sends: HashMap<Context, (RequestHandle, ReplyContext)>,
+	/// Idle connections, for each host
+	pool: HashMap<HostKey, Vec<socket::ConnHandle>>,
+	/// The host each idle connection is for
+	idle: HashMap<socket::ConnHandle, HostKey>,
+	/// The next context we use for downward messages
+	next_ctx: Context,
+}
+
+type ReplyContext = grease::ReplyContext<Service>;
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Limits on a response head. We give up on one which breaks them.
+const HEAD_LIMITS: ParseLimits = ParseLimits {
+	max_head_bytes: 64 * 1024,
+	max_headers: 100,
+	// Only requests have a target
+	max_target_len: 0,
+};
+
+/// We close connections rather than have more than this many idle
+/// connections to any one host.
+const MAX_IDLE_PER_HOST: usize = 4;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Creates a new http_client task. Returns an object that can be used
+/// to send this task messages.
+pub fn make_task(socket: grease::ServiceProviderHandle<socket::Service>) -> Handle {
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx.clone());
+	std::thread::spawn(move || {
+		let mut t = TaskContext::new(socket, handle);
+		for msg in rx.iter() {
+			t.handle(msg);
+		}
+		panic!("This task should never die!");
+	});
+	Handle(tx)
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// All our handler functions are methods on this `TaskContext` structure.
+impl TaskContext {
+	/// Create a new TaskContext
+	fn new(socket: grease::ServiceProviderHandle<socket::Service>, us: Handle) -> Self {
+		Self {
+			socket,
+			reply_to: us,
+			connecting: HashMap::new(),
+			transactions: MultiMap::new(),
+			body_sends: HashMap::new(),
+			pool: HashMap::new(),
+			idle: HashMap::new(),
+			// This number is arbitrary
+			next_ctx: grease::Context::new(3_000),
+		}
+	}
+
+	/// Handle an incoming message. It might a `Request` or `Response` for
+	/// us, or it might be a `Confirm` or `Indication` from a lower layer.
+	fn handle(&mut self, msg: Incoming) {
+		match msg {
+			Incoming::Request(x, reply_to) => {
+				debug!("Rx: {:?}", x);
+				self.handle_client_req(x, reply_to);
+			}
+			Incoming::Response(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_client_rsp(x);
+			}
+			Incoming::SocketCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_socket_cfm(x);
+			}
+			Incoming::SocketInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_socket_ind(x);
+			}
+		}
+	}
+
+	fn handle_socket_cfm(&mut self, cfm: socket::Confirm) {
+		match cfm {
+			socket::Confirm::Bind(x) => warn!("Unexpected {:?}", x),
+			socket::Confirm::Connect(x) => self.handle_socket_cfm_connect(x),
+			socket::Confirm::Close(_) => {
+				// Nothing to do - we've already forgotten about the connection
+			}
+			socket::Confirm::Send(x) => self.handle_socket_cfm_send(x),
+		}
+	}
+
+	fn handle_socket_ind(&mut self, ind: socket::Indication) {
+		match ind {
+			socket::Indication::Connected(x) => warn!("Unexpected {:?}", x),
+			socket::Indication::Dropped(x) => self.handle_socket_ind_dropped(x),
+			socket::Indication::Received(x) => self.handle_socket_ind_received(x),
+		}
+	}
+
+	fn handle_client_req(&mut self, req: Request, reply_to: grease::ServiceUserHandle<Service>) {
+		match req {
+			Request::HttpRequest(x) => self.handle_http_request(x, reply_to),
+			Request::RequestBody(x) => self.handle_request_body(x, reply_to),
+		}
+	}
+
+	fn handle_client_rsp(&mut self, rsp: Response) {
+		match rsp {
+			Response::ResponseBody(x) => self.handle_rsp_response_body(x),
+		}
+	}
+
+	fn handle_http_request(
+		&mut self,
+		req: ReqHttpRequest,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let target = if req.stream_body && req.body.is_some() {
+			Err(Error::BadBody)
+		} else {
+			Self::lookup(&req.uri)
+		};
+		match target {
+			Ok((host, addr)) => {
+				let t = Transaction {
+					our_handle: self.next_ctx.take(),
+					reply_ctx: ReplyContext {
+						context: req.context,
+						reply_to,
+					},
+					is_head: req.method == Method::HEAD,
+					idempotent: req.method.is_idempotent(),
+					confirmed: false,
+					parser: ResponseParser::new(HEAD_LIMITS),
+					streaming: if req.stream_body {
+						Some(Streaming {
+							chunked: !req.headers.contains_key(http::header::CONTENT_LENGTH),
+							started: false,
+							finished: false,
+						})
+					} else {
+						None
+					},
+					request: Self::render_request(&req),
+					host,
+					addr,
+					socket_handle: None,
+					reused: false,
+					state: State::Connecting,
+					buffer: Vec::new(),
+					keep_alive: false,
+					socket_blocked: false,
+					user_blocked: false,
+					eof: false,
+				};
+				self.start(t);
+			}
+			Err(e) => {
+				reply_to.send_confirm(
+					CfmHttpRequest {
+						context: req.context,
+						result: Err(e),
+					}.into(),
+				);
+			}
+		}
+	}
+
+	/// Work out which server a URI refers to.
+	fn lookup(uri: &Uri) -> Result<(HostKey, net::SocketAddr), Error> {
+		if uri.scheme_str() != Some("http") {
+			return Err(Error::BadUri);
+		}
+		let host = uri.host().ok_or(Error::BadUri)?;
+		let port = uri.port_u16().unwrap_or(80);
+		// IPv6 literals come with square brackets
+		let name = host.trim_matches(|c| c == '[' || c == ']');
+		let addr = (name, port)
+			.to_socket_addrs()
+			.map_err(|_| Error::Resolve)?
+			.next()
+			.ok_or(Error::Resolve)?;
+		Ok(((host.to_lowercase(), port), addr))
+	}
+
+	/// Render the request line, headers and body, ready to send.
+	fn render_request(req: &ReqHttpRequest) -> Vec<u8> {
+		let path = req.uri
+			.path_and_query()
+			.map(|p| p.as_str())
+			.filter(|p| !p.is_empty())
+			.unwrap_or("/");
+		let mut s = format!("{} {} HTTP/1.1\r\n", req.method.as_str(), path);
+		if !req.headers.contains_key(http::header::HOST) {
+			if let Some(host) = req.uri.host() {
+				match req.uri.port_u16() {
+					Some(port) => s.push_str(&format!("Host: {}:{}\r\n", host, port)),
+					None => s.push_str(&format!("Host: {}\r\n", host)),
+				}
+			}
+		}
+		if let Some(ref body) = req.body {
+			if !req.headers.contains_key(http::header::CONTENT_LENGTH) {
+				s.push_str(&format!("Content-Length: {}\r\n", body.len()));
+			}
+		} else if req.stream_body && !req.headers.contains_key(http::header::CONTENT_LENGTH) {
+			s.push_str("Transfer-Encoding: chunked\r\n");
+		}
+		let mut output = s.into_bytes();
+		for (k, v) in req.headers.iter() {
+			output.extend_from_slice(k.as_str().as_bytes());
+			output.extend_from_slice(b": ");
+			output.extend_from_slice(v.as_bytes());
+			output.extend_from_slice(b"\r\n");
+		}
+		output.extend_from_slice(b"\r\n");
+		if let Some(ref body) = req.body {
+			output.extend_from_slice(body);
+		}
+		output
+	}
+
+	/// Send the request on a pooled connection, or open a new one.
+	fn start(&mut self, mut t: Transaction) {
+		let pooled = self.pool.get_mut(&t.host).and_then(|v| v.pop());
+		if let Some(skt) = pooled {
+			debug!("Re-using connection {} for {:?}", skt, t.host);
+			self.idle.remove(&skt);
+			t.reused = true;
+			self.send_request_on(t, skt);
+		} else {
+			self.connect(t);
+		}
+	}
+
+	/// Open a new connection for this request.
+	fn connect(&mut self, mut t: Transaction) {
+		t.reused = false;
+		t.socket_handle = None;
+		t.state = State::Connecting;
+		let req = socket::ReqConnect {
+			addr: t.addr,
+			context: self.next_ctx.take(),
+			conn_type: socket::ConnectionType::Stream,
+		};
+		self.connecting.insert(req.context, t);
+		self.socket.send_request(req.into(), &self.reply_to);
+	}
+
+	fn send_request_on(&mut self, mut t: Transaction, skt: socket::ConnHandle) {
+		t.state = State::Sending;
+		t.socket_handle = Some(skt);
+		let req = socket::ReqSend {
+			handle: skt,
+			context: self.next_ctx.take(),
+			data: t.request.clone(),
+		};
+		self.transactions.insert(t.our_handle, skt, t);
+		self.socket.send_request(req.into(), &self.reply_to);
+	}
+
+	fn close_socket(&mut self, skt: socket::ConnHandle) {
+		let req = socket::ReqClose {
+			handle: skt,
+			context: self.next_ctx.take(),
+			reset: false,
+		};
+		self.socket.send_request(req.into(), &self.reply_to);
+	}
+
+	fn handle_socket_cfm_connect(&mut self, cfm: socket::CfmConnect) {
+		if let Some(t) = self.connecting.remove(&cfm.context) {
+			match cfm.result {
+				Ok(skt) => self.send_request_on(t, skt),
+				Err(e) => Self::fail(t, Error::Socket(e)),
+			}
+		} else {
+			warn!("Context {} not found", cfm.context);
+		}
+	}
+
+	fn handle_socket_cfm_send(&mut self, cfm: socket::CfmSend) {
+		if let Some((handle, reply_ctx)) = self.body_sends.remove(&cfm.context) {
+			reply_ctx.reply_to.send_confirm(
+				CfmRequestBody {
+					handle,
+					context: reply_ctx.context,
+					result: cfm.result.map(|_| ()).map_err(Error::Socket),
+				}.into(),
+			);
+			return;
+		}
+		match cfm.result {
+			Ok(_) => {
+				if let Some(t) = self.transactions.get_mut_alt(&cfm.handle) {
+					t.state = State::Head;
+					// A retried request has had its cfm already
+					if !t.confirmed {
+						t.confirmed = true;
+						t.reply_ctx.reply_to.send_confirm(
+							CfmHttpRequest {
+								context: t.reply_ctx.context,
+								result: Ok(t.our_handle),
+							}.into(),
+						);
+					}
+				}
+				self.pump(cfm.handle);
+			}
+			Err(e) => {
+				if let Some(t) = self.transactions.remove_alt(&cfm.handle) {
+					if !t.eof {
+						self.close_socket(cfm.handle);
+					}
+					self.retry_or_fail(t, Error::Socket(e));
+				}
+			}
+		}
+	}
+
+	fn handle_socket_ind_dropped(&mut self, ind: socket::IndDropped) {
+		if let Some(host) = self.idle.remove(&ind.handle) {
+			debug!("Idle connection {} to {:?} dropped", ind.handle, host);
+			if let Some(v) = self.pool.get_mut(&host) {
+				v.retain(|&x| x != ind.handle);
+			}
+		} else if let Some(t) = self.transactions.get_mut_alt(&ind.handle) {
+			t.eof = true;
+			t.socket_blocked = false;
+		}
+		self.pump(ind.handle);
+	}
+
+	fn handle_socket_ind_received(&mut self, ind: socket::IndReceived) {
+		if let Some(host) = self.idle.remove(&ind.handle) {
+			// Servers shouldn't send us anything when we haven't asked
+			warn!("Unexpected data on idle connection to {:?}", host);
+			if let Some(v) = self.pool.get_mut(&host) {
+				v.retain(|&x| x != ind.handle);
+			}
+			self.close_socket(ind.handle);
+		} else if let Some(t) = self.transactions.get_mut_alt(&ind.handle) {
+			t.buffer.extend_from_slice(&ind.data);
+			t.socket_blocked = true;
+		} else {
+			warn!("Data on non-existant socket handle");
+		}
+		self.pump(ind.handle);
+	}
+
+	fn handle_request_body(
+		&mut self,
+		req: ReqRequestBody,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let send = match self.transactions.get_mut(&req.handle) {
+			Some(ref mut t) if t.socket_handle.is_some() => match t.streaming {
+				Some(ref mut streaming) if !streaming.finished => {
+					streaming.started = true;
+					streaming.finished = req.last;
+					let mut data = req.data;
+					if streaming.chunked {
+						data = encode_chunk(&data, req.last);
+					}
+					t.socket_handle.map(|skt| (skt, data))
+				}
+				_ => None,
+			},
+			_ => None,
+		};
+		match send {
+			// Nothing to send, so it's sent already
+			Some((_, ref data)) if data.is_empty() => {
+				reply_to.send_confirm(
+					CfmRequestBody {
+						handle: req.handle,
+						context: req.context,
+						result: Ok(()),
+					}.into(),
+				);
+			}
+			Some((skt, data)) => {
+				let ctx = self.next_ctx.take();
+				self.body_sends.insert(
+					ctx,
+					(
+						req.handle,
+						ReplyContext {
+							context: req.context,
+							reply_to,
+						},
+					),
+				);
+				self.socket.send_request(
+					socket::ReqSend {
+						handle: skt,
+						context: ctx,
+						data,
+					}.into(),
+					&self.reply_to,
+				);
+			}
+			None => {
+				reply_to.send_confirm(
+					CfmRequestBody {
+						handle: req.handle,
+						context: req.context,
+						result: Err(Error::BadHandle),
+					}.into(),
+				);
+			}
+		}
+	}
+
+	fn handle_rsp_response_body(&mut self, rsp: RspResponseBody) {
+		let skt = self.transactions.get_mut(&rsp.handle).and_then(|t| {
+			t.user_blocked = false;
+			t.socket_handle
+		});
+		if let Some(skt) = skt {
+			self.pump(skt);
+		} else {
+			warn!("RspResponseBody on non-existant handle {}", rsp.handle);
+		}
+	}
+
+	/// A request failed. If we were using a pooled connection and nothing
+	/// has come back yet, the server probably closed the connection while it
+	/// was idle, so try again on a new connection. Once the request has
+	/// gone, the server may have acted on it, so only idempotent requests
+	/// are tried again. Otherwise tell the user.
+	fn retry_or_fail(&mut self, t: Transaction, error: Error) {
+		let untouched = match t.state {
+			State::Sending => t.buffer.is_empty(),
+			State::Head => t.idempotent && t.buffer.is_empty(),
+			_ => false,
+		} && !t.streaming.as_ref().map_or(false, |s| s.started);
+		if t.reused && untouched {
+			debug!("Retrying {} on a new connection", t.our_handle);
+			let mut t = t;
+			t.eof = false;
+			t.socket_blocked = false;
+			self.connect(t);
+		} else {
+			Self::fail(t, error);
+		}
+	}
+
+	/// Tell the user a request has failed - in the cfm if they haven't had
+	/// it yet, otherwise with an `IndFailed`.
+	fn fail(t: Transaction, error: Error) {
+		if t.confirmed {
+			t.reply_ctx.reply_to.send_indication(
+				IndFailed {
+					handle: t.our_handle,
+					error,
+				}.into(),
+			);
+		} else {
+			t.reply_ctx.reply_to.send_confirm(
+				CfmHttpRequest {
+					context: t.reply_ctx.context,
+					result: Err(error),
+				}.into(),
+			);
+		}
+	}
+
+	/// Pass up as much of the response as we can, given what's in the
+	/// buffer and whether the user is ready for more.
+	fn pump(&mut self, skt: socket::ConnHandle) {
+		loop {
+			let step = match self.transactions.get_mut_alt(&skt) {
+				Some(t) => Self::step(t),
+				None => return,
+			};
+			match step {
+				Step::Again => {}
+				Step::Wait => {
+					if let Some(t) = self.transactions.get_mut_alt(&skt) {
+						if t.socket_blocked && !t.user_blocked {
+							t.socket_blocked = false;
+							self.socket.send_response(
+								socket::RspReceived { handle: skt }.into(),
+							);
+						}
+					}
+					return;
+				}
+				Step::Failed(e) => {
+					let t = self.transactions.remove_alt(&skt).unwrap();
+					if !t.eof {
+						self.close_socket(skt);
+					}
+					self.retry_or_fail(t, e);
+					return;
+				}
+				Step::Done => {
+					let t = self.transactions.remove_alt(&skt).unwrap();
+					self.finished(skt, t);
+					return;
+				}
+			}
+		}
+	}
+
+	/// Do one step of processing on a transaction.
+	fn step(t: &mut Transaction) -> Step {
+		let out_of_data = match t.state {
+			State::Connecting | State::Sending => true,
+			State::Head => match Self::read_head(t) {
+				Ok(Some(head)) => {
+					if head.status.is_informational() {
+						// e.g. 100 Continue - there's a real response to come.
+						return Step::Again;
+					}
+					t.keep_alive = match head.length {
+						BodyLength::UntilClose => false,
+						_ if head.http11 => !has_token(&head.headers, "connection", "close"),
+						_ => has_token(&head.headers, "connection", "keep-alive"),
+					};
+					t.reply_ctx.reply_to.send_indication(
+						IndResponseStart {
+							handle: t.our_handle,
+							context: t.reply_ctx.context,
+							status: head.status,
+							headers: head.headers,
+							length: match head.length {
+								BodyLength::Fixed(n) => Some(n),
+								_ => None,
+							},
+						}.into(),
+					);
+					t.request = Vec::new();
+					t.state = State::Body(head.length);
+					return Step::Again;
+				}
+				Ok(None) => true,
+				Err(e) => return Step::Failed(e),
+			},
+			State::Body(_) if t.user_blocked => return Step::Wait,
+			State::Body(ref mut length) => {
+				let (data, last) = match *length {
+					BodyLength::Fixed(ref mut n) => {
+						let take = std::cmp::min(*n, t.buffer.len());
+						*n -= take;
+						(t.buffer.drain(..take).collect(), *n == 0)
+					}
+					BodyLength::Chunked(ref mut decoder) => {
+						let mut data = Vec::new();
+						match decoder.decode(&t.buffer, &mut data) {
+							Ok(used) => {
+								t.buffer.drain(..used);
+							}
+							Err(_) => return Step::Failed(Error::BadResponse),
+						}
+						(data, decoder.is_done())
+					}
+					BodyLength::UntilClose => (t.buffer.drain(..).collect(), t.eof),
+				};
+				if !data.is_empty() || last {
+					t.user_blocked = !last;
+					t.reply_ctx.reply_to.send_indication(
+						IndResponseBody {
+							handle: t.our_handle,
+							data,
+							last,
+						}.into(),
+					);
+					return if last { Step::Done } else { Step::Wait };
+				}
+				true
+			}
+		};
+		if out_of_data && t.eof {
+			Step::Failed(Error::Dropped)
+		} else {
+			Step::Wait
+		}
+	}
+
+	/// Take the response head out of the buffer, if it has all arrived.
+	fn read_head(t: &mut Transaction) -> Result<Option<ResponseHead>, Error> {
+		let (head, used) = match t.parser.parse(&t.buffer) {
+			Ok(Parsed::Complete { head, rest }) => {
+				let response = ResponseHead {
+					http11: head.version == http::Version::HTTP_11,
+					status: head.status,
+					headers: head.header_map().map_err(|_| Error::BadResponse)?,
+					length: Self::body_length(t.is_head, &head)?,
+				};
+				(response, t.buffer.len() - rest.len())
+			}
+			Ok(Parsed::Partial) => return Ok(None),
+			Err(_) => return Err(Error::BadResponse),
+		};
+		t.buffer.drain(..used);
+		t.parser.reset();
+		Ok(Some(head))
+	}
+
+	/// Work out how the body is delimited (RFC 7230 section 3.3.3).
+	fn body_length(is_head: bool, head: &StatusHead) -> Result<BodyLength, Error> {
+		match head.body_length(is_head) {
+			Ok(http_server::BodyLength::Fixed(n)) => Ok(BodyLength::Fixed(n as usize)),
+			Ok(http_server::BodyLength::Chunked) => Ok(BodyLength::Chunked(ChunkedDecoder::new())),
+			Ok(http_server::BodyLength::UntilClose) => Ok(BodyLength::UntilClose),
+			Err(_) => Err(Error::BadResponse),
+		}
+	}
+
+	/// A response is complete. Put the connection back in the pool, or
+	/// close it.
+	fn finished(&mut self, skt: socket::ConnHandle, t: Transaction) {
+		if t.eof {
+			return;
+		}
+		let idle_count = self.pool.get(&t.host).map_or(0, |v| v.len());
+		// If the server answered before we'd sent the whole body, the rest
+		// of it is still to come, so the connection can't be re-used
+		let body_sent = t.streaming.as_ref().map_or(true, |s| s.finished);
+		if t.keep_alive && body_sent && t.buffer.is_empty() && idle_count < MAX_IDLE_PER_HOST {
+			debug!("Returning connection {} to pool for {:?}", skt, t.host);
+			if t.socket_blocked {
+				self.socket
+					.send_response(socket::RspReceived { handle: skt }.into());
+			}
+			self.idle.insert(skt, t.host.clone());
+			self.pool.entry(t.host).or_insert_with(Vec::new).push(skt);
+		} else {
+			self.close_socket(skt);
+		}
+	}
+}
+
+/// What `TaskContext::step` did.
+enum Step {
+	/// Made some progress - call again
+	Again,
+	/// Need more data from the socket, or a `RspResponseBody` from the user
+	Wait,
+	/// The response is complete
+	Done,
+	/// The response is broken
+	Failed(Error),
+}
+
+/// Get all the comma-separated tokens in all the headers with this name.
+fn tokens(headers: &HeaderMap, name: &str) -> Vec<String> {
+	headers
+		.get_all(name)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.map(|t| t.trim().to_lowercase())
+		.filter(|t| !t.is_empty())
+		.collect()
+}
+
+/// Does this comma-separated header contain this token?
+fn has_token(headers: &HeaderMap, name: &str, token: &str) -> bool {
+	tokens(headers, name).iter().any(|t| t == token)
+}
+
+/// Don't log the contents of the body
+impl fmt::Debug for ReqHttpRequest {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"ReqHttpRequest {{ context: {}, method: {}, uri: {}, headers: {:?}, body.len: {:?}, \
+			 stream_body: {} }}",
+			self.context,
+			self.method.as_str(),
+			self.uri,
+			self.headers,
+			self.body.as_ref().map(|b| b.len()),
+			self.stream_body
+		)
+	}
+}
+
+/// Don't log the contents of the body
+impl fmt::Debug for ReqRequestBody {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"ReqRequestBody {{ handle: {}, context: {}, data.len: {}, last: {} }}",
+			self.handle,
+			self.context,
+			self.data.len(),
+			self.last
+		)
+	}
+}
+
+/// Don't log the contents of the body
+impl fmt::Debug for IndResponseBody {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"IndResponseBody {{ handle: {}, data.len: {}, last: {} }}",
+			self.handle,
+			self.data.len(),
+			self.last
+		)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::prelude::*;
+	use std::net;
+	use std::sync::atomic;
+	use std::sync::mpsc;
+	use std::thread;
+	use std::time::Duration;
+
+	use grease::prelude::*;
+	use http::header::HeaderValue;
+
+	enum TestIncoming {
+		ClientCfm(Confirm),
+		ClientInd(Indication),
+		HttpCfm(http_server::Confirm),
+		HttpInd(http_server::Indication),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	static PORT_NUMBER: atomic::AtomicUsize = atomic::AtomicUsize::new(8100);
+
+	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+	fn allocate_test_port() -> net::SocketAddr {
+		let port = PORT_NUMBER.fetch_add(1, atomic::Ordering::SeqCst);
+		net::SocketAddr::new(
+			net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 1, 1)),
+			port as u16,
+		)
+	}
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::ClientCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: Indication) {
+			self.0.send(TestIncoming::ClientInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceUser<http_server::Service> for TestHandle {
+		fn send_confirm(&self, cfm: http_server::Confirm) {
+			self.0.send(TestIncoming::HttpCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: http_server::Indication) {
+			self.0.send(TestIncoming::HttpInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<http_server::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
+		let (test_tx, rx) = mpsc::channel();
+		(TestHandle(test_tx), rx)
+	}
+
+	fn get(client: &Handle, reply_to: &TestHandle, uri: &str, ctx: Context) {
+		client.send_request(
+			ReqHttpRequest {
+				context: ctx,
+				method: Method::GET,
+				uri: uri.parse().unwrap(),
+				headers: HeaderMap::new(),
+				body: None,
+				stream_body: false,
+			}.into(),
+			reply_to,
+		);
+	}
+
+	/// Collect a whole response, passing anything else to `other`
+	fn collect_response<F>(
+		client: &Handle,
+		rx: &mpsc::Receiver<TestIncoming>,
+		ctx: Context,
+		mut other: F,
+	) -> (StatusCode, HeaderMap, Vec<u8>)
+	where
+		F: FnMut(TestIncoming),
+	{
+		let mut handle = None;
+		let mut start = None;
+		let mut body = Vec::new();
+		loop {
+			match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::ClientCfm(Confirm::HttpRequest(x)) => {
+					assert_eq!(x.context, ctx);
+					assert!(handle.is_none(), "Second cfm for {}", ctx);
+					handle = Some(x.result.unwrap());
+				}
+				TestIncoming::ClientInd(Indication::ResponseStart(x)) => {
+					assert_eq!(Some(x.handle), handle);
+					assert_eq!(x.context, ctx);
+					start = Some((x.status, x.headers));
+				}
+				TestIncoming::ClientInd(Indication::ResponseBody(x)) => {
+					assert_eq!(Some(x.handle), handle);
+					body.extend_from_slice(&x.data);
+					if x.last {
+						break;
+					}
+					client.send_response(RspResponseBody { handle: x.handle }.into());
+				}
+				TestIncoming::ClientInd(Indication::Failed(x)) => {
+					panic!("Request failed: {:?}", x);
+				}
+				msg => other(msg),
+			}
+		}
+		let (status, headers) = start.unwrap();
+		(status, headers, body)
+	}
+
+	#[test]
+	fn response_body_length() {
+		let length = |input: &[u8]| match ResponseParser::new(HEAD_LIMITS).parse(input) {
+			Ok(Parsed::Complete { head, .. }) => match TaskContext::body_length(false, &head) {
+				Ok(BodyLength::Fixed(n)) => Some(n),
+				Ok(_) => panic!("Not a fixed length"),
+				Err(_) => None,
+			},
+			_ => panic!("Bad head"),
+		};
+		assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"), Some(5));
+		let repeated = b"HTTP/1.1 200 OK\r\nContent-Length: 5, 5\r\nContent-Length: 5\r\n\r\n";
+		assert_eq!(length(repeated), Some(5));
+		let conflicting = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+		assert_eq!(length(conflicting), None);
+		assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: +5\r\n\r\n"), None);
+		let both = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+		assert_eq!(length(both), None);
+	}
+
+	#[test]
+	/// Fetches a page from a grease-http server
+	fn get_from_http_server() {
+		let (reply_to, rx) = make_test_channel();
+		let addr = allocate_test_port();
+		let socket_thread = socket::make_task();
+		let server = http_server::make_task(ServiceProvider::clone(&socket_thread));
+		let client = make_task(ServiceProvider::clone(&socket_thread));
+
+		server.send_request(
+			http_server::ReqBind {
+				addr,
+				context: Context::new(1),
+				config: http_server::ServerConfig::default(),
+			}.into(),
+			&reply_to,
+		);
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(http_server::Confirm::Bind(ref x)) => {
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Bad match"),
+		}
+
+		get(&client, &reply_to, &format!("http://{}/foo?bar=1", addr), Context::new(2));
+
+		let test_body = b"One two, Rust on my shoe";
+		let (status, headers, body) = collect_response(&client, &rx, Context::new(2), |msg| {
+			if let TestIncoming::HttpInd(http_server::Indication::RxRequest(x)) = msg {
+				assert_eq!(x.url, "/foo?bar=1");
+				assert_eq!(x.method, Method::GET);
+				assert_eq!(x.headers.get("host").unwrap(), &format!("{}", addr));
+				server.send_request(
+					http_server::ReqResponseStart {
+						handle: x.connection_handle,
+						context: Context::new(3),
+						status: http_server::HttpResponseStatus::OK,
+						content_type: String::from("text/plain"),
+						length: Some(test_body.len()),
+						headers: HeaderMap::new(),
+						compress: None,
+					}.into(),
+					&reply_to,
+				);
+				server.send_request(
+					http_server::ReqResponseBody {
+						handle: x.connection_handle,
+						context: Context::new(4),
+						data: test_body.to_vec(),
+					}.into(),
+					&reply_to,
+				);
+			}
+		});
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(headers.get("content-type").unwrap(), "text/plain");
+		assert_eq!(body, test_body);
+	}
+
+	#[test]
+	/// Checks the second request re-uses the first connection, and that we
+	/// understand chunked responses.
+	fn pooled_connection() {
+		let (reply_to, rx) = make_test_channel();
+		let addr = allocate_test_port();
+		let listener = net::TcpListener::bind(&addr).unwrap();
+		let server = thread::spawn(move || {
+			// Only accept one connection
+			let (mut stream, _) = listener.accept().unwrap();
+			let responses: [&[u8]; 2] = [
+				b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+				b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+				  3\r\nwor\r\n2\r\nld\r\n0\r\n\r\n",
+			];
+			for response in responses.iter() {
+				let mut request = Vec::new();
+				while !request.ends_with(b"\r\n\r\n") {
+					let mut byte = [0u8; 1];
+					stream.read_exact(&mut byte).unwrap();
+					request.push(byte[0]);
+				}
+				stream.write_all(response).unwrap();
+			}
+		});
+
+		let socket_thread = socket::make_task();
+		let client = make_task(ServiceProvider::clone(&socket_thread));
+
+		get(&client, &reply_to, &format!("http://{}/a", addr), Context::new(10));
+		let (status, _, body) = collect_response(&client, &rx, Context::new(10), |_| {});
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(body, b"hello");
+
+		get(&client, &reply_to, &format!("http://{}/b", addr), Context::new(11));
+		let (status, _, body) = collect_response(&client, &rx, Context::new(11), |_| {});
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(body, b"world");
+
+		server.join().unwrap();
+	}
+
+	#[test]
+	/// A pooled connection which drops after the request has gone: the GET
+	/// is tried again on a new connection, the POST isn't.
+	fn dropped_after_send() {
+		let (reply_to, rx) = make_test_channel();
+		let addr = allocate_test_port();
+		let listener = net::TcpListener::bind(&addr).unwrap();
+		let read_request = |stream: &mut net::TcpStream, end: &[u8]| {
+			let mut request = Vec::new();
+			while !request.ends_with(end) {
+				let mut byte = [0u8; 1];
+				stream.read_exact(&mut byte).unwrap();
+				request.push(byte[0]);
+			}
+			String::from_utf8(request).unwrap()
+		};
+		let server = thread::spawn(move || {
+			let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+			// Answer one request, then drop the connection with the second
+			// unanswered
+			let (mut stream, _) = listener.accept().unwrap();
+			read_request(&mut stream, b"\r\n\r\n");
+			stream.write_all(ok).unwrap();
+			read_request(&mut stream, b"\r\n\r\n");
+			drop(stream);
+			// The retry, and then a POST which is also left unanswered
+			let (mut stream, _) = listener.accept().unwrap();
+			let retry = read_request(&mut stream, b"\r\n\r\n");
+			stream.write_all(ok).unwrap();
+			read_request(&mut stream, b"\r\n\r\nx");
+			drop(stream);
+			// Which isn't sent again
+			thread::sleep(Duration::from_millis(200));
+			listener.set_nonblocking(true).unwrap();
+			assert!(listener.accept().is_err());
+			retry
+		});
+
+		let socket_thread = socket::make_task();
+		let client = make_task(ServiceProvider::clone(&socket_thread));
+		get(&client, &reply_to, &format!("http://{}/a", addr), Context::new(40));
+		collect_response(&client, &rx, Context::new(40), |_| {});
+		get(&client, &reply_to, &format!("http://{}/b", addr), Context::new(41));
+		let (status, _, body) = collect_response(&client, &rx, Context::new(41), |_| {});
+		assert_eq!((status, &body[..]), (StatusCode::OK, &b"ok"[..]));
+
+		client.send_request(
+			ReqHttpRequest {
+				context: Context::new(42),
+				method: Method::POST,
+				uri: format!("http://{}/c", addr).parse().unwrap(),
+				headers: HeaderMap::new(),
+				body: Some(b"x".to_vec()),
+				stream_body: false,
+			}.into(),
+			&reply_to,
+		);
+		let handle = match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientCfm(Confirm::HttpRequest(x)) => x.result.unwrap(),
+			_ => panic!("Bad match"),
+		};
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientInd(Indication::Failed(x)) => assert_eq!(x.handle, handle),
+			_ => panic!("Bad match"),
+		}
+		assert!(server.join().unwrap().starts_with("GET /b HTTP/1.1\r\n"));
+		assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+	}
+
+	#[test]
+	/// Streams two request bodies, one chunked and one with a length
+	fn streamed_request_body() {
+		let (reply_to, rx) = make_test_channel();
+		let addr = allocate_test_port();
+		let listener = net::TcpListener::bind(&addr).unwrap();
+		let server = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut requests = Vec::new();
+			for end in [&b"0\r\n\r\n"[..], &b"world"[..]].iter() {
+				let mut request = Vec::new();
+				while !request.ends_with(end) {
+					let mut byte = [0u8; 1];
+					stream.read_exact(&mut byte).unwrap();
+					request.push(byte[0]);
+				}
+				stream
+					.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+					.unwrap();
+				requests.push(String::from_utf8(request).unwrap());
+			}
+			requests
+		});
+
+		let socket_thread = socket::make_task();
+		let client = make_task(ServiceProvider::clone(&socket_thread));
+		let post = |ctx: Context, headers: HeaderMap| {
+			client.send_request(
+				ReqHttpRequest {
+					context: ctx,
+					method: Method::POST,
+					uri: format!("http://{}/upload", addr).parse().unwrap(),
+					headers,
+					body: None,
+					stream_body: true,
+				}.into(),
+				&reply_to,
+			);
+			match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::ClientCfm(Confirm::HttpRequest(x)) => x.result.unwrap(),
+				_ => panic!("Bad match"),
+			}
+		};
+		let send_body = |handle: RequestHandle, data: &[u8], last: bool| {
+			client.send_request(
+				ReqRequestBody {
+					handle,
+					context: Context::new(30),
+					data: data.to_vec(),
+					last,
+				}.into(),
+				&reply_to,
+			);
+		};
+		let body_cfm = |msg: TestIncoming| match msg {
+			TestIncoming::ClientCfm(Confirm::RequestBody(x)) => {
+				assert_eq!(x.context, Context::new(30));
+				x.result
+			}
+			_ => panic!("Bad match"),
+		};
+		let next_body_cfm = || body_cfm(rx.recv_timeout(DEFAULT_TIMEOUT).unwrap());
+		// The response can overtake the last cfm, so take both in any order
+		let finish = |handle: RequestHandle| {
+			let mut status = None;
+			let mut done = false;
+			let mut cfm = false;
+			while !done || !cfm {
+				match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+					TestIncoming::ClientInd(Indication::ResponseStart(x)) => {
+						assert_eq!(x.handle, handle);
+						status = Some(x.status);
+					}
+					TestIncoming::ClientInd(Indication::ResponseBody(x)) => {
+						assert_eq!(x.handle, handle);
+						assert!(x.last);
+						done = true;
+					}
+					msg => {
+						body_cfm(msg).unwrap();
+						cfm = true;
+					}
+				}
+			}
+			status.unwrap()
+		};
+
+		let handle = post(Context::new(31), HeaderMap::new());
+		send_body(handle, b"hello", false);
+		next_body_cfm().unwrap();
+		send_body(handle, b"", false);
+		next_body_cfm().unwrap();
+		send_body(handle, b" there", true);
+		assert_eq!(finish(handle), StatusCode::CREATED);
+		// That was the last of it
+		send_body(handle, b"more", true);
+		assert!(match next_body_cfm() {
+			Err(Error::BadHandle) => true,
+			_ => false,
+		});
+
+		let mut headers = HeaderMap::new();
+		headers.insert("content-length", HeaderValue::from_static("11"));
+		let handle = post(Context::new(32), headers);
+		send_body(handle, b"hello ", false);
+		next_body_cfm().unwrap();
+		send_body(handle, b"world", true);
+		assert_eq!(finish(handle), StatusCode::CREATED);
+
+		let requests = server.join().unwrap();
+		assert!(requests[0].starts_with("POST /upload HTTP/1.1\r\n"));
+		assert!(requests[0].contains("Transfer-Encoding: chunked\r\n"));
+		assert!(requests[0].ends_with("\r\n\r\n5\r\nhello\r\n6\r\n there\r\n0\r\n\r\n"));
+		assert!(!requests[1].contains("Transfer-Encoding"));
+		assert!(requests[1].ends_with("content-length: 11\r\n\r\nhello world"));
+
+		// Bodies can't be both given and streamed
+		client.send_request(
+			ReqHttpRequest {
+				context: Context::new(33),
+				method: Method::POST,
+				uri: format!("http://{}/upload", addr).parse().unwrap(),
+				headers: HeaderMap::new(),
+				body: Some(b"x".to_vec()),
+				stream_body: true,
+			}.into(),
+			&reply_to,
+		);
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientCfm(Confirm::HttpRequest(ref x)) => match x.result {
+				Err(Error::BadBody) => {}
+				_ => panic!("Expected BadBody"),
+			},
+			_ => panic!("Bad match"),
+		}
+	}
+
+	#[test]
+	fn bad_uri() {
+		let (reply_to, rx) = make_test_channel();
+		let socket_thread = socket::make_task();
+		let client = make_task(ServiceProvider::clone(&socket_thread));
+		get(&client, &reply_to, "https://localhost/", Context::new(20));
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientCfm(Confirm::HttpRequest(ref x)) => {
+				assert_eq!(x.context, Context::new(20));
+				assert!(x.result.is_err());
+			}
+			_ => panic!("Bad match"),
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -75,6 +75,10 @@ fn main() {
 		http::ReqBind {
 			addr: bind_addr,
 			context: Context::default(),
+			config: http::ServerConfig {
+				http2: Some(http::Http2Config::default()),
+				..Default::default()
+			},
 		}.into(),
 		&handle,
 	);
@@ -94,6 +98,7 @@ fn main() {
 					content_type: String::from("text/plain"),
 					length: Some(body_msg.len()),
 					headers: http::HeaderMap::new(),
+					compress: None,
 				};
 				http_thread.send_request(start.into(), &handle);
 				let body = http::ReqResponseBody {
@@ -0,0 +1,356 @@
+//! # access_log - recording the requests a server has answered
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! Each request a server answers produces one `AccessRecord`, written when
+//! the response is complete (or the connection has gone). The record is
+//! formatted as a line of Common Log Format, Combined Log Format or JSON,
+//! then handed to a sink - the `log` crate, a file, or a channel.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fs;
+use std::io::{self, Write};
+use std::net;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::head::{civil_date, MONTHS};
+use super::{Method, Uri, Version};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// How a server should log its requests, given in `ServerConfig`.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+	pub format: LogFormat,
+	pub sink: LogSink,
+}
+
+/// How each record is written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+	/// `client - - [date] "request" status bytes`
+	Common,
+	/// Common, followed by the quoted referer and user-agent
+	Combined,
+	/// One JSON object per line, with every field of the `AccessRecord`
+	Json,
+}
+
+/// Where each record goes
+#[derive(Debug, Clone)]
+pub enum LogSink {
+	/// The `log` crate, at info level, with the target
+	/// "grease_http::access"
+	Log,
+	/// Appended to this file, which is opened (or created) when the server
+	/// is bound
+	File(PathBuf),
+	/// Sent down this channel, one line (without a line ending) at a time
+	Channel(mpsc::Sender<String>),
+}
+
+/// Everything we know about one request and its response
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+	/// The far end of the connection
+	pub peer: net::SocketAddr,
+	/// Who the request was really from - see `IndRxRequest::client`
+	pub client: net::IpAddr,
+	/// The request line. None if the request was refused before we could
+	/// make sense of it.
+	pub method: Option<Method>,
+	pub uri: Option<Uri>,
+	pub version: Option<Version>,
+	/// The status we answered with
+	pub status: u16,
+	/// How many bytes of response body went out (after any compression)
+	pub bytes_sent: u64,
+	pub referer: Option<String>,
+	pub user_agent: Option<String>,
+	/// When the request started to arrive
+	pub time: SystemTime,
+	/// How long it took from then until the response was done
+	pub duration: Duration,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// An `AccessLog` with its sink ready to write to
+#[derive(Debug)]
+pub struct Logger {
+	format: LogFormat,
+	sink: Sink,
+}
+
+#[derive(Debug)]
+enum Sink {
+	Log,
+	File(fs::File),
+	Channel(mpsc::Sender<String>),
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl AccessRecord {
+	/// Format this record as one line, without a line ending.
+	pub fn format(&self, format: LogFormat) -> String {
+		match format {
+			LogFormat::Common => self.common(),
+			LogFormat::Combined => format!(
+				"{} \"{}\" \"{}\"",
+				self.common(),
+				self.referer.as_ref().map_or(String::from("-"), |r| escape(r)),
+				self.user_agent.as_ref().map_or(String::from("-"), |u| escape(u))
+			),
+			LogFormat::Json => self.json(),
+		}
+	}
+
+	fn common(&self) -> String {
+		let request = match (&self.method, &self.uri, &self.version) {
+			(&Some(ref method), &Some(ref uri), &Some(ref version)) => {
+				escape(&format!("{} {} {:?}", method, uri, version))
+			}
+			_ => String::from("-"),
+		};
+		let bytes = if self.bytes_sent == 0 {
+			String::from("-")
+		} else {
+			self.bytes_sent.to_string()
+		};
+		format!(
+			"{} - - [{}] \"{}\" {} {}",
+			self.client,
+			clf_date(self.time),
+			request,
+			self.status,
+			bytes
+		)
+	}
+
+	fn json(&self) -> String {
+		let string = |s: Option<String>| s.map_or(String::from("null"), |s| json_string(&s));
+		format!(
+			"{{\"time\":{},\"peer\":{},\"client\":{},\"method\":{},\"uri\":{},\
+			 \"version\":{},\"status\":{},\"bytes_sent\":{},\"referer\":{},\
+			 \"user_agent\":{},\"duration_us\":{}}}",
+			json_string(&iso_date(self.time)),
+			json_string(&self.peer.to_string()),
+			json_string(&self.client.to_string()),
+			string(self.method.as_ref().map(|m| m.to_string())),
+			string(self.uri.as_ref().map(|u| u.to_string())),
+			string(self.version.map(|v| format!("{:?}", v))),
+			self.status,
+			self.bytes_sent,
+			string(self.referer.clone()),
+			string(self.user_agent.clone()),
+			self.duration.as_secs() * 1_000_000 + u64::from(self.duration.subsec_micros())
+		)
+	}
+}
+
+impl Logger {
+	/// Get ready to write to the sink. Fails if it's a file we can't open.
+	pub fn open(config: &AccessLog) -> io::Result<Logger> {
+		let sink = match config.sink {
+			LogSink::Log => Sink::Log,
+			LogSink::File(ref path) => Sink::File(fs::OpenOptions::new()
+				.append(true)
+				.create(true)
+				.open(path)?),
+			LogSink::Channel(ref tx) => Sink::Channel(tx.clone()),
+		};
+		Ok(Logger {
+			format: config.format,
+			sink,
+		})
+	}
+
+	/// Write one record. Failures are warned about, but otherwise ignored.
+	pub fn write(&self, record: &AccessRecord) {
+		let line = record.format(self.format);
+		match self.sink {
+			Sink::Log => info!(target: "grease_http::access", "{}", line),
+			Sink::File(ref file) => {
+				// One write per line, so lines from different servers
+				// sharing a file don't get mixed up
+				let mut file: &fs::File = file;
+				if let Err(e) = file.write_all(format!("{}\n", line).as_bytes()) {
+					warn!("Failed to write access log: {}", e);
+				}
+			}
+			Sink::Channel(ref tx) => {
+				if tx.send(line).is_err() {
+					warn!("Access log receiver has gone");
+				}
+			}
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Split a time into (year, month, day, hour, minute, second), in UTC.
+fn utc(time: SystemTime) -> (u64, u64, u64, u64, u64, u64) {
+	let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+	let (year, month, day) = civil_date(secs / 86_400);
+	let secs_of_day = secs % 86_400;
+	(
+		year,
+		month,
+		day,
+		secs_of_day / 3_600,
+		(secs_of_day / 60) % 60,
+		secs_of_day % 60,
+	)
+}
+
+/// e.g. "10/Oct/2000:13:55:36 +0000"
+fn clf_date(time: SystemTime) -> String {
+	let (year, month, day, hour, min, sec) = utc(time);
+	format!(
+		"{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+		day,
+		MONTHS[(month - 1) as usize],
+		year,
+		hour,
+		min,
+		sec
+	)
+}
+
+/// e.g. "2000-10-10T13:55:36Z"
+fn iso_date(time: SystemTime) -> String {
+	let (year, month, day, hour, min, sec) = utc(time);
+	format!(
+		"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+		year, month, day, hour, min, sec
+	)
+}
+
+/// Make something safe to put between quotes in a log line. Clients
+/// choose the request line, referer and user-agent, so they mustn't be
+/// able to forge a record of their own.
+fn escape(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn record() -> AccessRecord {
+		AccessRecord {
+			peer: "10.0.0.1:4567".parse().unwrap(),
+			client: "192.0.2.7".parse().unwrap(),
+			method: Some(Method::GET),
+			uri: Some("/apache_pb.gif?x=1".parse().unwrap()),
+			version: Some(Version::HTTP_11),
+			status: 200,
+			bytes_sent: 2326,
+			referer: Some(String::from("http://www.example.com/start.html")),
+			user_agent: Some(String::from("Mozilla/4.08 [en] (Win98; I ;Nav)")),
+			time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+			duration: Duration::from_micros(1_500),
+		}
+	}
+
+	#[test]
+	fn formats() {
+		let r = record();
+		assert_eq!(
+			r.format(LogFormat::Common),
+			"192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?x=1 HTTP/1.1\" \
+			 200 2326"
+		);
+		assert_eq!(
+			r.format(LogFormat::Combined),
+			"192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?x=1 HTTP/1.1\" \
+			 200 2326 \"http://www.example.com/start.html\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\""
+		);
+		assert_eq!(
+			r.format(LogFormat::Json),
+			"{\"time\":\"2000-10-10T13:55:36Z\",\"peer\":\"10.0.0.1:4567\",\
+			 \"client\":\"192.0.2.7\",\"method\":\"GET\",\"uri\":\"/apache_pb.gif?x=1\",\
+			 \"version\":\"HTTP/1.1\",\"status\":200,\"bytes_sent\":2326,\
+			 \"referer\":\"http://www.example.com/start.html\",\
+			 \"user_agent\":\"Mozilla/4.08 [en] (Win98; I ;Nav)\",\"duration_us\":1500}"
+		);
+	}
+
+	#[test]
+	fn missing_and_hostile_fields() {
+		let mut r = record();
+		r.method = None;
+		r.bytes_sent = 0;
+		r.status = 400;
+		r.referer = None;
+		r.user_agent = Some(String::from("evil\" \\ \n"));
+		assert_eq!(
+			r.format(LogFormat::Combined),
+			"192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 - \"-\" \
+			 \"evil\\\" \\\\ \\x0a\""
+		);
+		let json = r.format(LogFormat::Json);
+		assert!(json.contains("\"method\":null,"));
+		assert!(json.contains("\"referer\":null,"));
+		assert!(json.contains("\"user_agent\":\"evil\\\" \\\\ \\n\","));
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
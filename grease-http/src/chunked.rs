@@ -0,0 +1,250 @@
+//! # chunked - encoding and decoding chunked bodies
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! Strips the chunked transfer coding (RFC 7230 section 4.1) from a body
+//! as it arrives. Chunk extensions and trailers are read and thrown away.
+//! As with the head, a bare LF is accepted as a line ending. Going the
+//! other way, `encode_chunk` frames a body as it's sent.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use parser::ParseError;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Decodes one chunked body, however it's split up
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChunkedDecoder {
+	state: State,
+	/// The size of the current chunk, or what's left of it
+	size: u64,
+	/// How far along the current size or trailer line we are
+	line: usize,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+	/// Reading the hex digits of a chunk size
+	Size,
+	/// Skipping a chunk extension, up to the end of the line
+	Extension,
+	/// Had the CR at the end of the size line
+	SizeLf,
+	/// Reading `size` more bytes of chunk data
+	Data,
+	/// Expecting the line ending after the chunk data
+	DataEnd,
+	/// Had the CR after the chunk data
+	DataLf,
+	/// Skipping trailer lines until an empty one
+	Trailer,
+	/// The last chunk and trailers are done
+	Done,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The longest size line (with extensions) or trailer line we'll take
+pub const MAX_LINE: usize = 4096;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl ChunkedDecoder {
+	pub fn new() -> ChunkedDecoder {
+		ChunkedDecoder {
+			state: State::Size,
+			size: 0,
+			line: 0,
+		}
+	}
+
+	/// Decode some more of the body, appending the chunk data to `out`.
+	/// Returns how much of `data` was used, which is all of it unless the
+	/// body ends part way through.
+	pub fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<usize, ParseError> {
+		let mut i = 0;
+		while i < data.len() {
+			let b = data[i];
+			match self.state {
+				State::Data => {
+					let take = (data.len() - i).min(self.size as usize);
+					out.extend_from_slice(&data[i..i + take]);
+					self.size -= take as u64;
+					i += take;
+					if self.size == 0 {
+						self.state = State::DataEnd;
+					}
+					continue;
+				}
+				State::Size => match (b as char).to_digit(16) {
+					Some(_) if self.line == 15 => return Err(ParseError::BadChunk),
+					Some(digit) => {
+						self.size = self.size * 16 + u64::from(digit);
+						self.line += 1;
+					}
+					None if self.line == 0 => return Err(ParseError::BadChunk),
+					None => match b {
+						b';' | b' ' | b'\t' => self.state = State::Extension,
+						b'\r' => self.state = State::SizeLf,
+						b'\n' => self.size_line_done(),
+						_ => return Err(ParseError::BadChunk),
+					},
+				},
+				State::Extension => match b {
+					b'\r' => self.state = State::SizeLf,
+					b'\n' => self.size_line_done(),
+					_ if self.line == MAX_LINE => return Err(ParseError::BadChunk),
+					_ => self.line += 1,
+				},
+				State::SizeLf => match b {
+					b'\n' => self.size_line_done(),
+					_ => return Err(ParseError::BadChunk),
+				},
+				State::DataEnd | State::DataLf => match b {
+					b'\r' if self.state == State::DataEnd => self.state = State::DataLf,
+					b'\n' => self.state = State::Size,
+					_ => return Err(ParseError::BadChunk),
+				},
+				State::Trailer => match b {
+					b'\n' if self.line == 0 => self.state = State::Done,
+					b'\n' => self.line = 0,
+					b'\r' => {}
+					_ if self.line == MAX_LINE => return Err(ParseError::BadChunk),
+					_ => self.line += 1,
+				},
+				State::Done => break,
+			}
+			i += 1;
+		}
+		Ok(i)
+	}
+
+	/// Have we had the last chunk and the trailers?
+	pub fn is_done(&self) -> bool {
+		self.state == State::Done
+	}
+}
+
+/// Wrap some of a body in the chunked transfer coding, ending the body if
+/// this is the last of it. An empty piece makes no chunk, as that would end
+/// the body.
+pub fn encode_chunk(data: &[u8], last: bool) -> Vec<u8> {
+	let mut output = Vec::with_capacity(data.len() + 16);
+	if !data.is_empty() {
+		output.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+		output.extend_from_slice(data);
+		output.extend_from_slice(b"\r\n");
+	}
+	if last {
+		output.extend_from_slice(b"0\r\n\r\n");
+	}
+	output
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl ChunkedDecoder {
+	fn size_line_done(&mut self) {
+		self.line = 0;
+		self.state = if self.size == 0 {
+			State::Trailer
+		} else {
+			State::Data
+		};
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn decode_all(pieces: &[&[u8]]) -> Result<(Vec<u8>, bool), ParseError> {
+		let mut decoder = ChunkedDecoder::new();
+		let mut out = Vec::new();
+		for piece in pieces {
+			decoder.decode(piece, &mut out)?;
+		}
+		Ok((out, decoder.is_done()))
+	}
+
+	#[test]
+	fn whole() {
+		let body = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: yes\r\n\r\nGET";
+		assert_eq!(
+			decode_all(&[body]),
+			Ok((b"hello, world".to_vec(), true))
+		);
+		// What follows the body is left alone
+		let mut out = Vec::new();
+		assert_eq!(ChunkedDecoder::new().decode(body, &mut out), Ok(body.len() - 3));
+	}
+
+	#[test]
+	fn byte_by_byte() {
+		let body = b"A\nabcdefghij\n0\n\n";
+		let pieces: Vec<&[u8]> = body.chunks(1).collect();
+		assert_eq!(
+			decode_all(&pieces),
+			Ok((b"abcdefghij".to_vec(), true))
+		);
+		assert_eq!(
+			decode_all(&pieces[..pieces.len() - 1]),
+			Ok((b"abcdefghij".to_vec(), false))
+		);
+	}
+
+	#[test]
+	fn encode() {
+		let mut body = encode_chunk(b"hello, world", false);
+		assert_eq!(body, b"c\r\nhello, world\r\n".to_vec());
+		assert!(encode_chunk(b"", false).is_empty());
+		body.extend(encode_chunk(b"!", true));
+		assert_eq!(decode_all(&[&body]), Ok((b"hello, world!".to_vec(), true)));
+	}
+
+	#[test]
+	fn bad() {
+		assert_eq!(decode_all(&[b"\r\n"]), Err(ParseError::BadChunk));
+		assert_eq!(decode_all(&[b"g\r\n"]), Err(ParseError::BadChunk));
+		assert_eq!(decode_all(&[b"3\r\nabcd\r\n"]), Err(ParseError::BadChunk));
+		assert_eq!(decode_all(&[b"3\rabc"]), Err(ParseError::BadChunk));
+		assert_eq!(
+			decode_all(&[b"1000000000000000\r\n"]),
+			Err(ParseError::BadChunk)
+		);
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
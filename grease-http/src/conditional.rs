@@ -0,0 +1,754 @@
+//! # conditional - conditional and range requests
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! Typed forms of the request headers from RFC 7232 (`If-Match`,
+//! `If-None-Match`, `If-Modified-Since`, `If-Unmodified-Since`) and RFC
+//! 7233 (`Range`, `If-Range`), and the logic which decides what a request
+//! carrying them should get - a 304 or 412, the whole thing, some ranges of
+//! it, or a 416.
+//!
+//! A response with more than one range is sent as `multipart/byteranges`.
+//! `ByteRanges` works out the part headers and the total length, so the
+//! user only has to send them in between the bytes of each range.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header;
+
+use super::head::parse_http_date;
+use super::{HeaderMap, HeaderValue, Method};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// An entity tag, as found in `ETag`, `If-Match` and friends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+	/// Weak tags only say two representations are equivalent, not that
+	/// they are byte for byte identical
+	pub weak: bool,
+	/// The tag, without its quotes
+	pub tag: String,
+}
+
+/// The value of an `If-Match` or `If-None-Match` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntityTagMatch {
+	/// `*` - anything at all
+	Any,
+	/// Any of these tags
+	Tags(Vec<EntityTag>),
+}
+
+/// The value of an `If-Range` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfRange {
+	Tag(EntityTag),
+	Date(SystemTime),
+}
+
+/// One range from a `Range: bytes=...` header
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+	/// `first-last`, inclusive
+	FromTo(u64, u64),
+	/// `first-`, to the end
+	From(u64),
+	/// `-count`, the last `count` bytes
+	Last(u64),
+}
+
+/// What the preconditions on a request say should happen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Precondition {
+	/// Carry on and answer the request
+	Proceed,
+	/// Answer with a 304 Not Modified
+	NotModified,
+	/// Answer with a 412 Precondition Failed
+	Failed,
+}
+
+/// What a request asked for, out of a representation of known length
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeOutcome {
+	/// Send all of it, with a 200
+	Full,
+	/// Send these (inclusive) byte ranges, with a 206
+	Partial(Vec<(u64, u64)>),
+	/// None of the ranges overlap the representation. Answer with a 416
+	/// and a `Content-Range` from `unsatisfied_range`.
+	NotSatisfiable,
+}
+
+/// The framing for a `multipart/byteranges` body. Send `part_head(0)`, the
+/// bytes of the first range, `part_head(1)`, the bytes of the second, and
+/// so on, then `trailer()`.
+#[derive(Debug, Clone)]
+pub struct ByteRanges {
+	boundary: String,
+	content_type: String,
+	total: u64,
+	ranges: Vec<(u64, u64)>,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Requests with more ranges than this get the whole thing. Lots of tiny
+/// ranges cost us far more than they save.
+pub const MAX_RANGES: usize = 16;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Makes each boundary we generate different
+static BOUNDARY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl EntityTag {
+	pub fn strong(tag: &str) -> EntityTag {
+		EntityTag {
+			weak: false,
+			tag: tag.to_owned(),
+		}
+	}
+
+	pub fn weak(tag: &str) -> EntityTag {
+		EntityTag {
+			weak: true,
+			tag: tag.to_owned(),
+		}
+	}
+
+	/// Parse a single tag, like `"xyzzy"` or `W/"xyzzy"`.
+	pub fn parse(value: &str) -> Option<EntityTag> {
+		let value = value.trim();
+		let (weak, quoted) = if value.starts_with("W/") {
+			(true, &value[2..])
+		} else {
+			(false, value)
+		};
+		if quoted.len() < 2 || !quoted.starts_with('"') || !quoted.ends_with('"') {
+			return None;
+		}
+		let tag = &quoted[1..quoted.len() - 1];
+		if tag.bytes().all(|b| b == 0x21 || (b >= 0x23 && b != 0x7f)) {
+			Some(EntityTag {
+				weak,
+				tag: tag.to_owned(),
+			})
+		} else {
+			None
+		}
+	}
+
+	/// Both strong, and the same tag. Used for `If-Match` and `If-Range`.
+	pub fn strong_eq(&self, other: &EntityTag) -> bool {
+		!self.weak && !other.weak && self.tag == other.tag
+	}
+
+	/// The same tag, weak or not. Used for `If-None-Match`.
+	pub fn weak_eq(&self, other: &EntityTag) -> bool {
+		self.tag == other.tag
+	}
+
+	/// This tag as a header value, for an `ETag` header.
+	pub fn to_header(&self) -> HeaderValue {
+		// We checked the characters when we parsed it, and if it was built
+		// by hand, from_str will catch anything nasty.
+		HeaderValue::from_str(&self.to_string())
+			.unwrap_or_else(|_| HeaderValue::from_static("\"\""))
+	}
+}
+
+impl fmt::Display for EntityTag {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.weak {
+			write!(f, "W/\"{}\"", self.tag)
+		} else {
+			write!(f, "\"{}\"", self.tag)
+		}
+	}
+}
+
+impl EntityTagMatch {
+	/// Parse an `If-Match` or `If-None-Match` value. A list with any
+	/// malformed tags in it is refused as a whole.
+	pub fn parse(value: &str) -> Option<EntityTagMatch> {
+		if value.trim() == "*" {
+			return Some(EntityTagMatch::Any);
+		}
+		let tags = split_tags(value)?
+			.into_iter()
+			.map(EntityTag::parse)
+			.collect::<Option<Vec<_>>>()?;
+		if tags.is_empty() {
+			None
+		} else {
+			Some(EntityTagMatch::Tags(tags))
+		}
+	}
+
+	fn matches(&self, etag: Option<&EntityTag>, strong: bool) -> bool {
+		match (self, etag) {
+			(&EntityTagMatch::Any, _) => true,
+			(&EntityTagMatch::Tags(ref tags), Some(etag)) => tags.iter().any(|t| {
+				if strong {
+					t.strong_eq(etag)
+				} else {
+					t.weak_eq(etag)
+				}
+			}),
+			(&EntityTagMatch::Tags(_), None) => false,
+		}
+	}
+}
+
+impl IfRange {
+	/// Parse an `If-Range` value - either an entity tag or a date.
+	pub fn parse(value: &str) -> Option<IfRange> {
+		match EntityTag::parse(value) {
+			Some(tag) => Some(IfRange::Tag(tag)),
+			None => parse_http_date(value).map(IfRange::Date),
+		}
+	}
+}
+
+impl ByteRange {
+	/// Parse a `Range` value. Only `bytes` ranges are understood, and if any
+	/// range is malformed, the whole header is ignored.
+	pub fn parse(value: &str) -> Option<Vec<ByteRange>> {
+		let value = value.trim();
+		let eq = value.find('=')?;
+		if !value[..eq].trim().eq_ignore_ascii_case("bytes") {
+			return None;
+		}
+		let mut ranges = Vec::new();
+		for spec in value[eq + 1..].split(',').map(str::trim) {
+			if spec.is_empty() {
+				// Empty list elements are allowed
+				continue;
+			}
+			let dash = spec.find('-')?;
+			let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+			let range = match (first.is_empty(), last.is_empty()) {
+				(true, true) => return None,
+				(true, false) => ByteRange::Last(parse_digits(last)?),
+				(false, true) => ByteRange::From(parse_digits(first)?),
+				(false, false) => {
+					let (first, last) = (parse_digits(first)?, parse_digits(last)?);
+					if last < first {
+						return None;
+					}
+					ByteRange::FromTo(first, last)
+				}
+			};
+			ranges.push(range);
+		}
+		if ranges.is_empty() {
+			None
+		} else {
+			Some(ranges)
+		}
+	}
+
+	/// The first and last (inclusive) bytes this range covers in a
+	/// representation `length` bytes long, or None if it covers none.
+	pub fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+		match *self {
+			ByteRange::FromTo(first, _) | ByteRange::From(first) if first >= length => None,
+			ByteRange::FromTo(first, last) => Some((first, last.min(length - 1))),
+			ByteRange::From(first) => Some((first, length - 1)),
+			ByteRange::Last(0) => None,
+			ByteRange::Last(_) if length == 0 => None,
+			ByteRange::Last(count) => Some((length - count.min(length), length - 1)),
+		}
+	}
+}
+
+impl ByteRanges {
+	/// Frame `ranges` of a representation which is `total` bytes long and
+	/// of the given type.
+	pub fn new(content_type: &str, total: u64, ranges: Vec<(u64, u64)>) -> ByteRanges {
+		let nanos = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.subsec_nanos())
+			.unwrap_or(0);
+		let count = BOUNDARY_COUNT.fetch_add(1, Ordering::Relaxed);
+		ByteRanges {
+			boundary: format!("grease-{:08x}-{:08x}", nanos, count),
+			content_type: content_type.to_owned(),
+			total,
+			ranges,
+		}
+	}
+
+	/// The `Content-Type` for the response
+	pub fn content_type(&self) -> String {
+		format!("multipart/byteranges; boundary={}", self.boundary)
+	}
+
+	/// What goes before the bytes of range `index`
+	pub fn part_head(&self, index: usize) -> Vec<u8> {
+		let (first, last) = self.ranges[index];
+		let mut head = format!("\r\n--{}\r\n", self.boundary);
+		if !self.content_type.is_empty() {
+			head.push_str(&format!("Content-Type: {}\r\n", self.content_type));
+		}
+		head.push_str(&format!(
+			"Content-Range: bytes {}-{}/{}\r\n\r\n",
+			first, last, self.total
+		));
+		head.into_bytes()
+	}
+
+	/// What goes after the last range
+	pub fn trailer(&self) -> Vec<u8> {
+		format!("\r\n--{}--\r\n", self.boundary).into_bytes()
+	}
+
+	/// The length of the whole body, framing and all
+	pub fn length(&self) -> u64 {
+		let parts: u64 = (0..self.ranges.len())
+			.map(|i| self.part_head(i).len() as u64)
+			.sum();
+		let bytes: u64 = self.ranges.iter().map(|&(f, l)| l - f + 1).sum();
+		parts + bytes + self.trailer().len() as u64
+	}
+
+	/// The ranges being sent
+	pub fn ranges(&self) -> &[(u64, u64)] {
+		&self.ranges
+	}
+}
+
+/// The `Content-Range` for a 206 carrying one range.
+pub fn content_range(first: u64, last: u64, total: u64) -> HeaderValue {
+	HeaderValue::from_str(&format!("bytes {}-{}/{}", first, last, total)).unwrap()
+}
+
+/// The `Content-Range` for a 416.
+pub fn unsatisfied_range(total: u64) -> HeaderValue {
+	HeaderValue::from_str(&format!("bytes */{}", total)).unwrap()
+}
+
+/// Evaluate `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since`, in the order RFC 7232 section 6 gives, against the
+/// current validators of the resource. Headers which don't parse are
+/// ignored.
+pub fn preconditions(
+	method: &Method,
+	headers: &HeaderMap,
+	etag: Option<&EntityTag>,
+	modified: Option<SystemTime>,
+) -> Precondition {
+	let modified = modified.map(whole_seconds);
+	let get_or_head = *method == Method::GET || *method == Method::HEAD;
+	if let Some(if_match) = tag_match(headers, header::IF_MATCH) {
+		if !if_match.matches(etag, true) {
+			return Precondition::Failed;
+		}
+	} else if let (Some(since), Some(modified)) =
+		(date(headers, header::IF_UNMODIFIED_SINCE), modified)
+	{
+		if modified > since {
+			return Precondition::Failed;
+		}
+	}
+	if let Some(if_none_match) = tag_match(headers, header::IF_NONE_MATCH) {
+		if if_none_match.matches(etag, false) {
+			return if get_or_head {
+				Precondition::NotModified
+			} else {
+				Precondition::Failed
+			};
+		}
+	} else if let (true, Some(since), Some(modified)) = (
+		get_or_head,
+		date(headers, header::IF_MODIFIED_SINCE),
+		modified,
+	) {
+		if modified <= since {
+			return Precondition::NotModified;
+		}
+	}
+	Precondition::Proceed
+}
+
+/// Work out which parts of a representation `length` bytes long a GET
+/// should get, taking `If-Range` into account. Call this once the
+/// preconditions have said to proceed.
+pub fn select_ranges(
+	method: &Method,
+	headers: &HeaderMap,
+	length: u64,
+	etag: Option<&EntityTag>,
+	modified: Option<SystemTime>,
+) -> RangeOutcome {
+	if *method != Method::GET {
+		return RangeOutcome::Full;
+	}
+	let ranges = match headers
+		.get(header::RANGE)
+		.and_then(|v| v.to_str().ok())
+		.and_then(ByteRange::parse)
+	{
+		Some(ref ranges) if ranges.len() > MAX_RANGES => return RangeOutcome::Full,
+		Some(ranges) => ranges,
+		None => return RangeOutcome::Full,
+	};
+	if let Some(value) = headers.get(header::IF_RANGE) {
+		let current = match value.to_str().ok().and_then(IfRange::parse) {
+			// Only a strong match will do
+			Some(IfRange::Tag(ref tag)) => etag.map_or(false, |e| tag.strong_eq(e)),
+			Some(IfRange::Date(date)) => modified.map_or(false, |m| whole_seconds(m) == date),
+			None => false,
+		};
+		if !current {
+			return RangeOutcome::Full;
+		}
+	}
+	let resolved: Vec<_> = ranges.iter().filter_map(|r| r.resolve(length)).collect();
+	if resolved.is_empty() {
+		RangeOutcome::NotSatisfiable
+	} else {
+		RangeOutcome::Partial(resolved)
+	}
+}
+
+/// All the `If-Match` (or `If-None-Match`) headers, as one list. If any
+/// of them are malformed, we ignore the lot.
+pub fn tag_match(headers: &HeaderMap, name: header::HeaderName) -> Option<EntityTagMatch> {
+	let values = headers
+		.get_all(name)
+		.iter()
+		.map(|v| v.to_str().ok())
+		.collect::<Option<Vec<_>>>()?;
+	if values.is_empty() {
+		None
+	} else {
+		EntityTagMatch::parse(&values.join(","))
+	}
+}
+
+/// Parse a `Content-Range` for a 206 - `bytes first-last/total` (where the
+/// total may be `*`). Returns the first and last bytes, and the total if
+/// given.
+pub fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+	let value = value.trim();
+	if !value.starts_with("bytes ") {
+		return None;
+	}
+	let mut parts = value[6..].trim().splitn(2, '/');
+	let (range, total) = (parts.next()?, parts.next()?);
+	let mut ends = range.splitn(2, '-');
+	let first = parse_digits(ends.next()?)?;
+	let last = parse_digits(ends.next()?)?;
+	let total = if total == "*" {
+		None
+	} else {
+		Some(parse_digits(total)?)
+	};
+	if last < first || total.map_or(false, |t| last >= t) {
+		None
+	} else {
+		Some((first, last, total))
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Digits only - no signs or spaces, which `parse` would let through
+fn parse_digits(value: &str) -> Option<u64> {
+	if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+		value.parse().ok()
+	} else {
+		None
+	}
+}
+
+/// Split a list of entity tags on the commas between them. Tags can't hold
+/// a `"`, but can hold a comma, so we have to watch the quotes.
+fn split_tags(value: &str) -> Option<Vec<&str>> {
+	let mut tags = Vec::new();
+	let mut start = 0;
+	let mut quoted = false;
+	for (i, c) in value.char_indices() {
+		match c {
+			'"' => quoted = !quoted,
+			',' if !quoted => {
+				tags.push(value[start..i].trim());
+				start = i + 1;
+			}
+			_ => {}
+		}
+	}
+	if quoted {
+		return None;
+	}
+	tags.push(value[start..].trim());
+	tags.retain(|t| !t.is_empty());
+	Some(tags)
+}
+
+fn date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+	headers
+		.get(name)
+		.and_then(|v| v.to_str().ok())
+		.and_then(parse_http_date)
+}
+
+/// HTTP dates only go to the second
+fn whole_seconds(time: SystemTime) -> SystemTime {
+	match time.duration_since(UNIX_EPOCH) {
+		Ok(d) => UNIX_EPOCH + ::std::time::Duration::from_secs(d.as_secs()),
+		Err(_) => UNIX_EPOCH,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use head::http_date;
+	use std::time::Duration;
+
+	fn headers(list: &[(&'static str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for &(name, value) in list {
+			headers.append(name, value.parse().unwrap());
+		}
+		headers
+	}
+
+	fn at(secs: u64) -> SystemTime {
+		UNIX_EPOCH + Duration::from_secs(secs)
+	}
+
+	#[test]
+	fn entity_tags() {
+		assert_eq!(EntityTag::parse("\"xyzzy\""), Some(EntityTag::strong("xyzzy")));
+		assert_eq!(EntityTag::parse(" W/\"xyzzy\" "), Some(EntityTag::weak("xyzzy")));
+		assert_eq!(EntityTag::parse("\"\""), Some(EntityTag::strong("")));
+		for bad in &["xyzzy", "\"xyzzy", "w/\"xyzzy\"", "\"xy\"zy\"", "\"xy zy\"", "W/"] {
+			assert_eq!(EntityTag::parse(bad), None, "{}", bad);
+		}
+		assert_eq!(EntityTag::weak("a").to_string(), "W/\"a\"");
+		assert_eq!(EntityTag::strong("a").to_header(), "\"a\"");
+		assert!(EntityTag::strong("a").strong_eq(&EntityTag::strong("a")));
+		assert!(!EntityTag::weak("a").strong_eq(&EntityTag::strong("a")));
+		assert!(EntityTag::weak("a").weak_eq(&EntityTag::strong("a")));
+		assert!(!EntityTag::weak("a").weak_eq(&EntityTag::weak("b")));
+
+		assert_eq!(EntityTagMatch::parse(" * "), Some(EntityTagMatch::Any));
+		assert_eq!(
+			EntityTagMatch::parse("\"a,b\", W/\"c\",,"),
+			Some(EntityTagMatch::Tags(vec![
+				EntityTag::strong("a,b"),
+				EntityTag::weak("c"),
+			]))
+		);
+		assert_eq!(EntityTagMatch::parse("\"a\", b"), None);
+		assert_eq!(EntityTagMatch::parse("\"a"), None);
+		assert_eq!(EntityTagMatch::parse(""), None);
+
+		assert_eq!(
+			IfRange::parse("\"a\""),
+			Some(IfRange::Tag(EntityTag::strong("a")))
+		);
+		assert_eq!(
+			IfRange::parse("Sun, 06 Nov 1994 08:49:37 GMT"),
+			Some(IfRange::Date(at(784_111_777)))
+		);
+		assert_eq!(IfRange::parse("soon"), None);
+	}
+
+	#[test]
+	fn byte_ranges() {
+		assert_eq!(
+			ByteRange::parse("bytes=0-499, 500-999,-500 , 9500-"),
+			Some(vec![
+				ByteRange::FromTo(0, 499),
+				ByteRange::FromTo(500, 999),
+				ByteRange::Last(500),
+				ByteRange::From(9500),
+			])
+		);
+		assert_eq!(ByteRange::parse("Bytes = 1-1"), Some(vec![ByteRange::FromTo(1, 1)]));
+		for bad in &[
+			"bytes=", "bytes=-", "bytes=5-4", "lines=1-2", "bytes=1-2,x", "bytes=+1-2", "1-2"
+		] {
+			assert_eq!(ByteRange::parse(bad), None, "{}", bad);
+		}
+
+		assert_eq!(ByteRange::FromTo(0, 499).resolve(10_000), Some((0, 499)));
+		assert_eq!(ByteRange::FromTo(9_000, 20_000).resolve(10_000), Some((9_000, 9_999)));
+		assert_eq!(ByteRange::FromTo(10_000, 20_000).resolve(10_000), None);
+		assert_eq!(ByteRange::From(9_500).resolve(10_000), Some((9_500, 9_999)));
+		assert_eq!(ByteRange::From(0).resolve(0), None);
+		assert_eq!(ByteRange::Last(500).resolve(10_000), Some((9_500, 9_999)));
+		assert_eq!(ByteRange::Last(20_000).resolve(10_000), Some((0, 9_999)));
+		assert_eq!(ByteRange::Last(0).resolve(10_000), None);
+		assert_eq!(ByteRange::Last(5).resolve(0), None);
+
+		assert_eq!(parse_content_range("bytes 0-499/1234"), Some((0, 499, Some(1234))));
+		assert_eq!(parse_content_range("bytes 0-499/*"), Some((0, 499, None)));
+		for bad in &["bytes 5-4/10", "bytes 0-10/10", "bytes */10", "items 0-1/2", "bytes 0-1"] {
+			assert_eq!(parse_content_range(bad), None, "{}", bad);
+		}
+	}
+
+	#[test]
+	fn precondition_order() {
+		let etag = EntityTag::strong("v2");
+		let modified = UNIX_EPOCH + Duration::new(1_000, 500);
+		let check = |method: Method, list: &[(&'static str, &str)]| {
+			preconditions(&method, &headers(list), Some(&etag), Some(modified))
+		};
+		let before = http_date(at(999));
+		let same = http_date(at(1_000));
+
+		assert_eq!(check(Method::GET, &[]), Precondition::Proceed);
+		// If-None-Match
+		assert_eq!(
+			check(Method::GET, &[("If-None-Match", "\"v1\", W/\"v2\"")]),
+			Precondition::NotModified
+		);
+		assert_eq!(check(Method::HEAD, &[("If-None-Match", "*")]), Precondition::NotModified);
+		assert_eq!(check(Method::GET, &[("If-None-Match", "\"v1\"")]), Precondition::Proceed);
+		assert_eq!(check(Method::PUT, &[("If-None-Match", "*")]), Precondition::Failed);
+		// If-Modified-Since, ignored when there's an If-None-Match
+		assert_eq!(check(Method::GET, &[("If-Modified-Since", &same)]), Precondition::NotModified);
+		assert_eq!(check(Method::GET, &[("If-Modified-Since", &before)]), Precondition::Proceed);
+		assert_eq!(check(Method::POST, &[("If-Modified-Since", &same)]), Precondition::Proceed);
+		assert_eq!(check(Method::GET, &[("If-Modified-Since", "garbage")]), Precondition::Proceed);
+		assert_eq!(
+			check(
+				Method::GET,
+				&[("If-None-Match", "\"v1\""), ("If-Modified-Since", &same)]
+			),
+			Precondition::Proceed
+		);
+		// If-Match is strong, and comes first
+		assert_eq!(check(Method::PUT, &[("If-Match", "\"v2\"")]), Precondition::Proceed);
+		assert_eq!(check(Method::PUT, &[("If-Match", "W/\"v2\"")]), Precondition::Failed);
+		assert_eq!(
+			check(Method::GET, &[("If-Match", "\"v1\""), ("If-None-Match", "\"v2\"")]),
+			Precondition::Failed
+		);
+		assert_eq!(
+			preconditions(&Method::PUT, &headers(&[("If-Match", "*")]), None, None),
+			Precondition::Proceed
+		);
+		// If-Unmodified-Since
+		assert_eq!(check(Method::PUT, &[("If-Unmodified-Since", &same)]), Precondition::Proceed);
+		assert_eq!(check(Method::PUT, &[("If-Unmodified-Since", &before)]), Precondition::Failed);
+		assert_eq!(
+			check(
+				Method::PUT,
+				&[("If-Match", "\"v2\""), ("If-Unmodified-Since", &before)]
+			),
+			Precondition::Proceed
+		);
+	}
+
+	#[test]
+	fn range_selection() {
+		let etag = EntityTag::strong("v2");
+		let modified = at(1_000);
+		let select = |method: Method, list: &[(&'static str, &str)]| {
+			select_ranges(&method, &headers(list), 10_000, Some(&etag), Some(modified))
+		};
+		assert_eq!(select(Method::GET, &[]), RangeOutcome::Full);
+		assert_eq!(
+			select(Method::GET, &[("Range", "bytes=0-99,-100")]),
+			RangeOutcome::Partial(vec![(0, 99), (9_900, 9_999)])
+		);
+		assert_eq!(select(Method::HEAD, &[("Range", "bytes=0-99")]), RangeOutcome::Full);
+		assert_eq!(select(Method::GET, &[("Range", "bytes=0-x")]), RangeOutcome::Full);
+		assert_eq!(
+			select(Method::GET, &[("Range", "bytes=20000-")]),
+			RangeOutcome::NotSatisfiable
+		);
+		// Unsatisfiable ranges are dropped if others are fine
+		assert_eq!(
+			select(Method::GET, &[("Range", "bytes=20000-,5-5")]),
+			RangeOutcome::Partial(vec![(5, 5)])
+		);
+		let many = format!("bytes={}", vec!["1-1"; MAX_RANGES + 1].join(","));
+		assert_eq!(select(Method::GET, &[("Range", &many)]), RangeOutcome::Full);
+		// If-Range
+		let same = http_date(modified);
+		let before = http_date(at(999));
+		let partial = RangeOutcome::Partial(vec![(0, 0)]);
+		assert_eq!(select(Method::GET, &[("Range", "bytes=0-0"), ("If-Range", "\"v2\"")]), partial);
+		assert_eq!(select(Method::GET, &[("Range", "bytes=0-0"), ("If-Range", &same)]), partial);
+		for stale in &["\"v1\"", "W/\"v2\"", &before, "nonsense"] {
+			assert_eq!(
+				select(Method::GET, &[("Range", "bytes=0-0"), ("If-Range", stale)]),
+				RangeOutcome::Full,
+				"{}",
+				stale
+			);
+		}
+	}
+
+	#[test]
+	fn multipart() {
+		let ranges = ByteRanges::new("text/plain", 26, vec![(0, 2), (24, 25)]);
+		let boundary = ranges.content_type()["multipart/byteranges; boundary=".len()..].to_owned();
+		let alphabet = b"abcdefghijklmnopqrstuvwxyz";
+		let mut body = Vec::new();
+		for (i, &(first, last)) in ranges.ranges().iter().enumerate() {
+			body.extend(ranges.part_head(i));
+			body.extend_from_slice(&alphabet[first as usize..last as usize + 1]);
+		}
+		body.extend(ranges.trailer());
+		assert_eq!(body.len() as u64, ranges.length());
+		assert_eq!(
+			String::from_utf8(body).unwrap(),
+			format!(
+				"\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/26\r\n\r\nabc\
+				 \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 24-25/26\r\n\r\nyz\
+				 \r\n--{0}--\r\n",
+				boundary
+			)
+		);
+		// Each response gets its own boundary
+		assert!(ByteRanges::new("", 1, vec![(0, 0)]).content_type() != ranges.content_type());
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
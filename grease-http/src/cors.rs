@@ -0,0 +1,409 @@
+//! # cors - cross-origin resource sharing
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! A browser only lets a script on one origin read a response from another
+//! if the response says it may, with `Access-Control-*` headers. Before
+//! anything but the simplest requests it sends a preflight - an `OPTIONS`
+//! with `Origin` and `Access-Control-Request-Method` headers - to ask.
+//! Given a server's `CorsConfig`, `CorsConfig::check` says how to answer a
+//! preflight, or what to add to the response to any other request.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::time::Duration;
+
+use super::{HeaderMap, HeaderValue, Method};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// How a server answers cross-origin requests
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+	/// The origins which may make requests
+	pub origins: CorsOrigins,
+	/// The methods a preflight may ask for
+	pub methods: Vec<Method>,
+	/// The request headers a preflight may ask for, beyond those a browser
+	/// always allows (like `Accept` and `Content-Language`). Matched
+	/// without regard to case.
+	pub headers: Vec<String>,
+	/// The response headers scripts may read, beyond those a browser always
+	/// allows (like `Content-Type` and `Cache-Control`)
+	pub expose_headers: Vec<String>,
+	/// Let requests carry cookies and HTTP authentication. Can't be used
+	/// with `CorsOrigins::Any`.
+	pub credentials: bool,
+	/// How long a browser may cache a preflight's answer. None leaves it to
+	/// the browser, which typically means a few seconds.
+	pub max_age: Option<Duration>,
+}
+
+/// Which origins may make cross-origin requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsOrigins {
+	/// Any origin at all
+	Any,
+	/// Only these, each written like `https://example.com:8443` - a scheme,
+	/// host and optional port, with no path. Matched without regard to
+	/// case.
+	List(Vec<String>),
+}
+
+/// What to do about a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cors {
+	/// Pass it up, and add these headers to the response. They're empty if
+	/// this isn't a cross-origin request, or it's from an origin we don't
+	/// allow.
+	Pass(HeaderMap),
+	/// A preflight we allow. Answer it with a 204 and these headers.
+	Allow(HeaderMap),
+	/// A preflight we don't allow. Answer it with a 403 and these headers.
+	Refuse(HeaderMap),
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Default for CorsConfig {
+	/// Any origin may make `GET`, `HEAD` and `POST` requests, without
+	/// credentials.
+	fn default() -> CorsConfig {
+		CorsConfig {
+			origins: CorsOrigins::Any,
+			methods: vec![Method::GET, Method::HEAD, Method::POST],
+			headers: Vec::new(),
+			expose_headers: Vec::new(),
+			credentials: false,
+			max_age: None,
+		}
+	}
+}
+
+impl CorsConfig {
+	/// Check the config makes sense and gives headers we can send
+	pub fn is_valid(&self) -> bool {
+		let origins_ok = match self.origins {
+			CorsOrigins::Any => !self.credentials,
+			CorsOrigins::List(ref origins) => origins.iter().all(|o| valid_origin(o)),
+		};
+		origins_ok && !self.methods.is_empty()
+			&& self.headers.iter().all(|h| is_token(h))
+			&& self.expose_headers.iter().all(|h| is_token(h))
+	}
+
+	/// Work out what to do about a request
+	pub fn check(&self, method: &Method, headers: &HeaderMap) -> Cors {
+		let mut response = HeaderMap::new();
+		if let CorsOrigins::List(_) = self.origins {
+			// The answer depends on who's asking, so caches must know
+			response.insert("Vary", HeaderValue::from_static("Origin"));
+		}
+		let origin = headers.get("origin").and_then(|o| o.to_str().ok());
+		let requested = headers
+			.get("access-control-request-method")
+			.and_then(|m| Method::from_bytes(m.as_bytes()).ok());
+		let preflight = *method == Method::OPTIONS && origin.is_some()
+			&& headers.contains_key("access-control-request-method");
+		let origin = match origin {
+			Some(origin) if self.allows_origin(origin) => origin,
+			_ if preflight => return Cors::Refuse(response),
+			_ => return Cors::Pass(response),
+		};
+		let allow_origin = if self.origins == CorsOrigins::Any {
+			HeaderValue::from_static("*")
+		} else {
+			match HeaderValue::from_str(origin) {
+				Ok(origin) => origin,
+				Err(_) => return Cors::Pass(response),
+			}
+		};
+		response.insert("Access-Control-Allow-Origin", allow_origin);
+		if self.credentials {
+			response.insert(
+				"Access-Control-Allow-Credentials",
+				HeaderValue::from_static("true"),
+			);
+		}
+		if !preflight {
+			if !self.expose_headers.is_empty() {
+				insert_list(&mut response, "Access-Control-Expose-Headers", &self.expose_headers);
+			}
+			return Cors::Pass(response);
+		}
+
+		let method_ok = requested.map_or(false, |m| self.methods.contains(&m));
+		let headers_ok = headers
+			.get_all("access-control-request-headers")
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(','))
+			.map(|h| h.trim())
+			.filter(|h| !h.is_empty())
+			.all(|h| self.headers.iter().any(|a| a.eq_ignore_ascii_case(h)));
+		if !method_ok || !headers_ok {
+			response.remove("Access-Control-Allow-Origin");
+			response.remove("Access-Control-Allow-Credentials");
+			return Cors::Refuse(response);
+		}
+		let methods: Vec<&str> = self.methods.iter().map(|m| m.as_str()).collect();
+		insert_list(&mut response, "Access-Control-Allow-Methods", &methods);
+		if !self.headers.is_empty() {
+			insert_list(&mut response, "Access-Control-Allow-Headers", &self.headers);
+		}
+		if let Some(max_age) = self.max_age {
+			let max_age = HeaderValue::from_str(&max_age.as_secs().to_string());
+			response.insert("Access-Control-Max-Age", max_age.unwrap());
+		}
+		Cors::Allow(response)
+	}
+
+	fn allows_origin(&self, origin: &str) -> bool {
+		match self.origins {
+			CorsOrigins::Any => true,
+			CorsOrigins::List(ref origins) => {
+				origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+			}
+		}
+	}
+}
+
+/// Add the headers from `CorsConfig::check` to a response's headers.
+/// `Vary` is added to; the others are only set if the response doesn't
+/// have them already.
+pub fn add_headers(response: &mut HeaderMap, cors: &HeaderMap) {
+	for (name, value) in cors.iter() {
+		if name == "vary" {
+			response.append(name, value.clone());
+		} else if !response.contains_key(name) {
+			response.insert(name, value.clone());
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Is this a serialised origin, like `https://example.com:8443`?
+fn valid_origin(origin: &str) -> bool {
+	let mut parts = origin.splitn(2, "://");
+	let (scheme, host) = match (parts.next(), parts.next()) {
+		(Some(scheme), Some(host)) => (scheme, host),
+		_ => return false,
+	};
+	!scheme.is_empty()
+		&& scheme
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'-' || b == b'.')
+		&& !host.is_empty()
+		&& host.bytes().all(|b| b.is_ascii_graphic() && b != b'/' && b != b',')
+}
+
+/// Is this an HTTP token, as header names must be?
+fn is_token(s: &str) -> bool {
+	!s.is_empty() && s.bytes()
+		.all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Set a header to a comma separated list. Everything in it has been
+/// checked, so it makes a valid value.
+fn insert_list<S: AsRef<str>>(headers: &mut HeaderMap, name: &'static str, items: &[S]) {
+	let list = items
+		.iter()
+		.map(|i| i.as_ref())
+		.collect::<Vec<_>>()
+		.join(", ");
+	headers.insert(name, HeaderValue::from_str(&list).unwrap());
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn request(pairs: &[(&'static str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for &(name, value) in pairs {
+			headers.append(name, value.parse().unwrap());
+		}
+		headers
+	}
+
+	fn get<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+		headers.get(name).map(|v| v.to_str().unwrap())
+	}
+
+	#[test]
+	fn validation() {
+		assert!(CorsConfig::default().is_valid());
+		let list = |origins: &[&str]| CorsConfig {
+			origins: CorsOrigins::List(origins.iter().map(|o| o.to_string()).collect()),
+			credentials: true,
+			..Default::default()
+		};
+		assert!(list(&["https://example.com", "http://localhost:8080"]).is_valid());
+		assert!(list(&[]).is_valid());
+		for bad in &["example.com", "https://", "https://example.com/", "https://a b", "://x"] {
+			assert!(!list(&[bad]).is_valid(), "{}", bad);
+		}
+		let bad = [
+			CorsConfig {
+				credentials: true,
+				..Default::default()
+			},
+			CorsConfig {
+				methods: Vec::new(),
+				..Default::default()
+			},
+			CorsConfig {
+				headers: vec![String::from("X-Bad Header")],
+				..Default::default()
+			},
+			CorsConfig {
+				expose_headers: vec![String::from("")],
+				..Default::default()
+			},
+		];
+		for config in bad.iter() {
+			assert!(!config.is_valid(), "{:?}", config);
+		}
+	}
+
+	#[test]
+	fn any_origin() {
+		let config = CorsConfig {
+			expose_headers: vec![String::from("ETag"), String::from("X-Total")],
+			..Default::default()
+		};
+		// Not cross-origin at all
+		assert_eq!(config.check(&Method::GET, &HeaderMap::new()), Cors::Pass(HeaderMap::new()));
+		let headers = match config.check(&Method::GET, &request(&[("origin", "http://a.test")])) {
+			Cors::Pass(headers) => headers,
+			x => panic!("Unexpected {:?}", x),
+		};
+		assert_eq!(get(&headers, "access-control-allow-origin"), Some("*"));
+		assert_eq!(get(&headers, "access-control-expose-headers"), Some("ETag, X-Total"));
+		assert_eq!(get(&headers, "access-control-allow-credentials"), None);
+		assert_eq!(get(&headers, "vary"), None);
+
+		// A plain OPTIONS isn't a preflight
+		match config.check(&Method::OPTIONS, &request(&[("origin", "http://a.test")])) {
+			Cors::Pass(_) => {}
+			x => panic!("Unexpected {:?}", x),
+		}
+		let preflight = request(&[
+			("origin", "http://a.test"),
+			("access-control-request-method", "POST"),
+		]);
+		let headers = match config.check(&Method::OPTIONS, &preflight) {
+			Cors::Allow(headers) => headers,
+			x => panic!("Unexpected {:?}", x),
+		};
+		assert_eq!(get(&headers, "access-control-allow-origin"), Some("*"));
+		assert_eq!(get(&headers, "access-control-allow-methods"), Some("GET, HEAD, POST"));
+		assert_eq!(get(&headers, "access-control-allow-headers"), None);
+		assert_eq!(get(&headers, "access-control-max-age"), None);
+		assert_eq!(get(&headers, "access-control-expose-headers"), None);
+	}
+
+	#[test]
+	fn listed_origins() {
+		let config = CorsConfig {
+			origins: CorsOrigins::List(vec![String::from("https://app.example.com")]),
+			methods: vec![Method::GET, Method::PUT],
+			headers: vec![String::from("Content-Type"), String::from("X-Token")],
+			credentials: true,
+			max_age: Some(Duration::from_secs(600)),
+			..Default::default()
+		};
+		let preflight = |origin: &str, method: &str, headers: &str| {
+			config.check(
+				&Method::OPTIONS,
+				&request(&[
+					("origin", origin),
+					("access-control-request-method", method),
+					("access-control-request-headers", headers),
+				]),
+			)
+		};
+		let headers = match preflight("https://APP.example.com", "PUT", "x-token, content-type") {
+			Cors::Allow(headers) => headers,
+			x => panic!("Unexpected {:?}", x),
+		};
+		assert_eq!(
+			get(&headers, "access-control-allow-origin"),
+			Some("https://APP.example.com")
+		);
+		assert_eq!(get(&headers, "access-control-allow-credentials"), Some("true"));
+		assert_eq!(get(&headers, "access-control-allow-methods"), Some("GET, PUT"));
+		assert_eq!(
+			get(&headers, "access-control-allow-headers"),
+			Some("Content-Type, X-Token")
+		);
+		assert_eq!(get(&headers, "access-control-max-age"), Some("600"));
+		assert_eq!(get(&headers, "vary"), Some("Origin"));
+
+		// Things we don't allow
+		let refused = [
+			preflight("https://evil.example.com", "PUT", ""),
+			preflight("https://app.example.com", "DELETE", ""),
+			preflight("https://app.example.com", "PUT", "X-Other"),
+		];
+		for cors in refused.iter() {
+			match *cors {
+				Cors::Refuse(ref headers) => {
+					assert!(!headers.contains_key("access-control-allow-origin"));
+					assert_eq!(get(headers, "vary"), Some("Origin"));
+				}
+				ref x => panic!("Unexpected {:?}", x),
+			}
+		}
+
+		// Other requests from other origins get nothing but the Vary
+		let headers = match config.check(&Method::GET, &request(&[("origin", "http://x.test")])) {
+			Cors::Pass(headers) => headers,
+			x => panic!("Unexpected {:?}", x),
+		};
+		assert_eq!(headers.len(), 1);
+		assert_eq!(get(&headers, "vary"), Some("Origin"));
+	}
+
+	#[test]
+	fn adding_headers() {
+		let mut cors = request(&[("vary", "Origin"), ("access-control-allow-origin", "*")]);
+		cors.insert("access-control-max-age", "5".parse().unwrap());
+		let mut response = request(&[
+			("vary", "Accept-Encoding"),
+			("access-control-allow-origin", "https://mine.test"),
+		]);
+		add_headers(&mut response, &cors);
+		let vary: Vec<_> = response.get_all("vary").iter().collect();
+		assert_eq!(vary, vec!["Accept-Encoding", "Origin"]);
+		assert_eq!(
+			get(&response, "access-control-allow-origin"),
+			Some("https://mine.test")
+		);
+		assert_eq!(get(&response, "access-control-max-age"), Some("5"));
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
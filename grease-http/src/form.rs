@@ -0,0 +1,678 @@
+//! # form - decoding HTML form submissions
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! Decodes `application/x-www-form-urlencoded` and `multipart/form-data`
+//! request bodies as they arrive. Make a `FormDecoder` from the request
+//! headers when you get the `IndRxRequest`, then feed it the data from each
+//! `IndRxBody`. Out come `FormEvent`s - the start of each part (its field
+//! name, and for file uploads the filename and content type), its content
+//! in pieces, and its end. Only part headers and urlencoded field names are
+//! buffered; content is passed on as soon as we know it isn't the start of
+//! a boundary, so uploads needn't be held in memory.
+//!
+//! A urlencoded body looks like a series of parts with no filename or
+//! content type, with the values percent-decoded. The limits in
+//! `FormLimits` are checked as we go, and a `FormError` says what status to
+//! refuse the request with if one is broken.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+
+use http;
+
+use super::{HeaderMap, StatusCode};
+use uri::percent_decode;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Limits on what a form may contain
+#[derive(Debug, Clone)]
+pub struct FormLimits {
+	/// The most parts (or urlencoded fields) we'll take
+	pub max_parts: usize,
+	/// The biggest ordinary field value, in bytes
+	pub max_field_bytes: u64,
+	/// The biggest file (a part with a filename), in bytes
+	pub max_file_bytes: u64,
+	/// The most bytes of headers a part may have, which is also the
+	/// longest urlencoded field name
+	pub max_head_bytes: usize,
+}
+
+/// The start of a part of a form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormPart {
+	/// The field name
+	pub name: String,
+	/// For a file upload, the name of the file
+	pub filename: Option<String>,
+	/// The part's `Content-Type`, if it gave one
+	pub content_type: Option<String>,
+}
+
+/// Something decoded from a form body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormEvent {
+	/// A new part starts
+	Part(FormPart),
+	/// Some of the current part's content
+	Data(Vec<u8>),
+	/// The current part is complete
+	PartEnd,
+}
+
+/// Why a form couldn't be decoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FormError {
+	/// The request's `Content-Type` isn't a form we can decode
+	NotAForm,
+	/// A `multipart/form-data` request without a usable boundary
+	BadBoundary,
+	/// A part has malformed headers, or no field name
+	BadPartHead,
+	/// A bad percent escape, or a field name which isn't UTF-8
+	BadEncoding,
+	/// More than `max_parts` parts
+	TooManyParts,
+	/// A field name or part head which is too long, or a value bigger than
+	/// `max_field_bytes` or `max_file_bytes`
+	PartTooLarge,
+	/// The body ended part way through
+	Truncated,
+}
+
+/// Decodes one form body, however it's split up
+#[derive(Debug)]
+pub struct FormDecoder {
+	limits: FormLimits,
+	/// For multipart bodies, CRLF, "--" and the boundary
+	delimiter: Option<Vec<u8>>,
+	state: State,
+	/// Input we can't deal with yet
+	buffer: Vec<u8>,
+	/// How many parts we've started
+	parts: usize,
+	/// How much content the current part has had, and how much it may have
+	part_bytes: u64,
+	part_limit: u64,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+	/// Urlencoded - reading a field name
+	Name,
+	/// Urlencoded - reading a field value
+	Value,
+	/// Multipart - skipping anything before the first boundary
+	Preamble,
+	/// Multipart - just had a boundary, so either a part or the end follows
+	Boundary,
+	/// Multipart - reading a part's headers
+	PartHead,
+	/// Multipart - reading a part's content
+	PartBody,
+	/// Multipart - past the closing boundary, so ignoring everything
+	Epilogue,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Default for FormLimits {
+	fn default() -> FormLimits {
+		FormLimits {
+			max_parts: 100,
+			max_field_bytes: 64 * 1024,
+			max_file_bytes: 64 * 1024 * 1024,
+			max_head_bytes: 8 * 1024,
+		}
+	}
+}
+
+impl FormError {
+	/// The status to refuse the request with
+	pub fn status(&self) -> StatusCode {
+		match *self {
+			FormError::NotAForm => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+			FormError::TooManyParts | FormError::PartTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+			_ => StatusCode::BAD_REQUEST,
+		}
+	}
+}
+
+impl fmt::Display for FormError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match *self {
+			FormError::NotAForm => "not a form",
+			FormError::BadBoundary => "bad multipart boundary",
+			FormError::BadPartHead => "bad part headers",
+			FormError::BadEncoding => "bad encoding",
+			FormError::TooManyParts => "too many parts",
+			FormError::PartTooLarge => "part too large",
+			FormError::Truncated => "form truncated",
+		};
+		f.write_str(s)
+	}
+}
+
+impl FormDecoder {
+	/// Make a decoder for a request with these headers. Fails with
+	/// `NotAForm` unless the `Content-Type` is
+	/// `application/x-www-form-urlencoded` or `multipart/form-data` (with a
+	/// boundary).
+	pub fn new(headers: &HeaderMap, limits: FormLimits) -> Result<FormDecoder, FormError> {
+		let content_type = headers
+			.get(http::header::CONTENT_TYPE)
+			.and_then(|v| v.to_str().ok())
+			.ok_or(FormError::NotAForm)?;
+		let (media_type, params) = parameters(content_type).ok_or(FormError::NotAForm)?;
+		let (delimiter, state) = match &media_type[..] {
+			"application/x-www-form-urlencoded" => (None, State::Name),
+			"multipart/form-data" => {
+				let boundary = params
+					.iter()
+					.find(|param| param.0 == "boundary")
+					.map(|param| &param.1)
+					.ok_or(FormError::BadBoundary)?;
+				if boundary.is_empty() || boundary.len() > 70 {
+					return Err(FormError::BadBoundary);
+				}
+				(Some(format!("\r\n--{}", boundary).into_bytes()), State::Preamble)
+			}
+			_ => return Err(FormError::NotAForm),
+		};
+		Ok(FormDecoder {
+			limits,
+			// So that a boundary right at the start matches the delimiter
+			buffer: if delimiter.is_some() {
+				b"\r\n".to_vec()
+			} else {
+				Vec::new()
+			},
+			delimiter,
+			state,
+			parts: 0,
+			part_bytes: 0,
+			part_limit: 0,
+		})
+	}
+
+	/// Decode some more of the body. Set `last` on the final piece (as in
+	/// `IndRxBody::last`).
+	pub fn decode(&mut self, data: &[u8], last: bool) -> Result<Vec<FormEvent>, FormError> {
+		let mut events = Vec::new();
+		if self.delimiter.is_some() {
+			self.buffer.extend_from_slice(data);
+			self.decode_multipart(&mut events)?;
+			if last && self.state != State::Epilogue {
+				return Err(FormError::Truncated);
+			}
+		} else {
+			let mut value = Vec::new();
+			for &b in data {
+				self.decode_urlencoded(b, &mut value, &mut events)?;
+			}
+			if last {
+				self.decode_urlencoded(b'&', &mut value, &mut events)?;
+			} else if !value.is_empty() {
+				events.push(FormEvent::Data(value));
+			}
+		}
+		Ok(events)
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl FormDecoder {
+	/// Take one byte of a urlencoded body. Decoded value bytes are gathered
+	/// in `value`.
+	fn decode_urlencoded(
+		&mut self,
+		b: u8,
+		value: &mut Vec<u8>,
+		events: &mut Vec<FormEvent>,
+	) -> Result<(), FormError> {
+		match (self.state, b) {
+			(State::Name, b'&') if self.buffer.is_empty() => {}
+			(State::Name, b'&') | (State::Name, b'=') => {
+				let name = percent_decode(&self.buffer, true).ok_or(FormError::BadEncoding)?;
+				let name = String::from_utf8(name).map_err(|_| FormError::BadEncoding)?;
+				self.buffer.clear();
+				let limit = self.limits.max_field_bytes;
+				events.push(self.start_part(name, None, None, limit)?);
+				if b == b'&' {
+					events.push(FormEvent::PartEnd);
+				} else {
+					self.state = State::Value;
+				}
+			}
+			(State::Name, _) => {
+				if self.buffer.len() == self.limits.max_head_bytes {
+					return Err(FormError::PartTooLarge);
+				}
+				self.buffer.push(b);
+			}
+			(_, b'&') => {
+				if !self.buffer.is_empty() {
+					return Err(FormError::BadEncoding);
+				}
+				if !value.is_empty() {
+					events.push(FormEvent::Data(value.split_off(0)));
+				}
+				events.push(FormEvent::PartEnd);
+				self.state = State::Name;
+			}
+			(_, b'%') if self.buffer.is_empty() => self.buffer.push(b),
+			(_, _) if !self.buffer.is_empty() => {
+				self.buffer.push(b);
+				if self.buffer.len() == 3 {
+					let decoded = percent_decode(&self.buffer, true).ok_or(FormError::BadEncoding)?;
+					self.buffer.clear();
+					self.push_content(&decoded, value)?;
+				}
+			}
+			(_, b'+') => self.push_content(b" ", value)?,
+			(_, _) => self.push_content(&[b], value)?,
+		}
+		Ok(())
+	}
+
+	/// Work through as much of the buffered multipart body as we can.
+	fn decode_multipart(&mut self, events: &mut Vec<FormEvent>) -> Result<(), FormError> {
+		let delimiter = self.delimiter.clone().unwrap_or_default();
+		// Enough to hold the start of a delimiter we can't see all of yet
+		let keep = delimiter.len() - 1;
+		loop {
+			match self.state {
+				State::Preamble => match find(&self.buffer, &delimiter) {
+					Some(i) => {
+						self.buffer.drain(..i + delimiter.len());
+						self.state = State::Boundary;
+					}
+					None => {
+						let len = self.buffer.len();
+						self.buffer.drain(..len.saturating_sub(keep));
+						return Ok(());
+					}
+				},
+				State::Boundary => {
+					if self.buffer.starts_with(b"--") {
+						self.buffer.clear();
+						self.state = State::Epilogue;
+						continue;
+					}
+					// The boundary line may have trailing whitespace
+					let padding = self.buffer
+						.iter()
+						.take_while(|&&b| b == b' ' || b == b'\t')
+						.count();
+					if padding > self.limits.max_head_bytes {
+						return Err(FormError::BadPartHead);
+					}
+					match self.buffer.get(padding..padding + 2) {
+						Some(line) if line == b"\r\n" => {
+							self.buffer.drain(..padding + 2);
+							self.state = State::PartHead;
+						}
+						Some(_) => return Err(FormError::BadPartHead),
+						None => return Ok(()),
+					}
+				}
+				State::PartHead => {
+					let end = if self.buffer.starts_with(b"\r\n") {
+						return Err(FormError::BadPartHead);
+					} else {
+						find(&self.buffer, b"\r\n\r\n")
+					};
+					let end = match end {
+						Some(end) if end <= self.limits.max_head_bytes => end,
+						None if self.buffer.len() <= self.limits.max_head_bytes => return Ok(()),
+						_ => return Err(FormError::PartTooLarge),
+					};
+					let (name, filename, content_type) = part_head(&self.buffer[..end])?;
+					self.buffer.drain(..end + 4);
+					let limit = if filename.is_some() {
+						self.limits.max_file_bytes
+					} else {
+						self.limits.max_field_bytes
+					};
+					events.push(self.start_part(name, filename, content_type, limit)?);
+					self.state = State::PartBody;
+				}
+				State::PartBody => {
+					let (content, end) = match find(&self.buffer, &delimiter) {
+						Some(i) => (i, Some(i + delimiter.len())),
+						None => (self.buffer.len().saturating_sub(keep), None),
+					};
+					if content > 0 {
+						let data: Vec<u8> = self.buffer.drain(..content).collect();
+						self.count_content(data.len())?;
+						events.push(FormEvent::Data(data));
+					}
+					match end {
+						Some(end) => {
+							self.buffer.drain(..end - content);
+							events.push(FormEvent::PartEnd);
+							self.state = State::Boundary;
+						}
+						None => return Ok(()),
+					}
+				}
+				_ => {
+					self.buffer.clear();
+					return Ok(());
+				}
+			}
+		}
+	}
+
+	fn start_part(
+		&mut self,
+		name: String,
+		filename: Option<String>,
+		content_type: Option<String>,
+		limit: u64,
+	) -> Result<FormEvent, FormError> {
+		if self.parts == self.limits.max_parts {
+			return Err(FormError::TooManyParts);
+		}
+		self.parts += 1;
+		self.part_bytes = 0;
+		self.part_limit = limit;
+		Ok(FormEvent::Part(FormPart {
+			name,
+			filename,
+			content_type,
+		}))
+	}
+
+	fn count_content(&mut self, len: usize) -> Result<(), FormError> {
+		self.part_bytes += len as u64;
+		if self.part_bytes > self.part_limit {
+			Err(FormError::PartTooLarge)
+		} else {
+			Ok(())
+		}
+	}
+
+	fn push_content(&mut self, data: &[u8], value: &mut Vec<u8>) -> Result<(), FormError> {
+		self.count_content(data.len())?;
+		value.extend_from_slice(data);
+		Ok(())
+	}
+}
+
+/// Where `needle` first appears in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Get the field name, filename and content type from a part's headers.
+fn part_head(head: &[u8]) -> Result<(String, Option<String>, Option<String>), FormError> {
+	let head = ::std::str::from_utf8(head).map_err(|_| FormError::BadEncoding)?;
+	let mut disposition = None;
+	let mut content_type = None;
+	for line in head.split("\r\n") {
+		let colon = line.find(':').ok_or(FormError::BadPartHead)?;
+		let value = line[colon + 1..].trim();
+		match line[..colon].to_ascii_lowercase().as_str() {
+			"content-disposition" => {
+				disposition = Some(parameters(value).ok_or(FormError::BadPartHead)?)
+			}
+			"content-type" => content_type = Some(value.to_string()),
+			_ => {}
+		}
+	}
+	let (kind, params) = disposition.ok_or(FormError::BadPartHead)?;
+	if kind != "form-data" {
+		return Err(FormError::BadPartHead);
+	}
+	let param = |wanted: &str| {
+		params
+			.iter()
+			.find(|param| param.0 == wanted)
+			.map(|param| param.1.clone())
+	};
+	let name = param("name").ok_or(FormError::BadPartHead)?;
+	Ok((name, param("filename"), content_type))
+}
+
+/// Split a header value like `form-data; name="a"` into the lower-cased
+/// first item and the parameters (with lower-cased names, and quotes
+/// removed from the values).
+fn parameters(value: &str) -> Option<(String, Vec<(String, String)>)> {
+	let mut items = value.splitn(2, ';');
+	let first = items.next()?.trim().to_ascii_lowercase();
+	let mut rest = items.next().unwrap_or("");
+	let mut params = Vec::new();
+	loop {
+		rest = rest.trim_start_matches(&[' ', '\t', ';'][..]);
+		if rest.is_empty() {
+			return Some((first, params));
+		}
+		let equals = rest.find('=')?;
+		let name = rest[..equals].trim().to_ascii_lowercase();
+		rest = rest[equals + 1..].trim_start();
+		let value = if rest.starts_with('"') {
+			let mut value = String::new();
+			let mut chars = rest.char_indices().skip(1);
+			let end = loop {
+				match chars.next()? {
+					(_, '\\') => value.push(chars.next()?.1),
+					(i, '"') => break i + 1,
+					(_, c) => value.push(c),
+				}
+			};
+			rest = &rest[end..];
+			value
+		} else {
+			let end = rest.find(';').unwrap_or(rest.len());
+			let value = rest[..end].trim().to_string();
+			rest = &rest[end..];
+			value
+		};
+		params.push((name, value));
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn decoder(content_type: &str, limits: FormLimits) -> FormDecoder {
+		let mut headers = HeaderMap::new();
+		headers.insert(http::header::CONTENT_TYPE, content_type.parse().unwrap());
+		FormDecoder::new(&headers, limits).unwrap()
+	}
+
+	/// Decode the body whole and byte by byte, joining up the `Data` so the
+	/// two can be compared.
+	fn decode(content_type: &str, body: &[u8]) -> Result<Vec<FormEvent>, FormError> {
+		let mut whole = decoder(content_type, FormLimits::default());
+		let whole = join(whole.decode(body, true)?);
+		let mut bytes = decoder(content_type, FormLimits::default());
+		let mut events = Vec::new();
+		for b in body.chunks(1) {
+			events.extend(bytes.decode(b, false)?);
+		}
+		events.extend(bytes.decode(&[], true)?);
+		assert_eq!(join(events), whole);
+		Ok(whole)
+	}
+
+	fn join(events: Vec<FormEvent>) -> Vec<FormEvent> {
+		let mut joined: Vec<FormEvent> = Vec::new();
+		for event in events {
+			match (joined.last_mut(), event) {
+				(Some(&mut FormEvent::Data(ref mut data)), FormEvent::Data(more)) => {
+					data.extend(more)
+				}
+				(_, event) => joined.push(event),
+			}
+		}
+		joined
+	}
+
+	fn part(name: &str, filename: Option<&str>, content_type: Option<&str>) -> FormEvent {
+		FormEvent::Part(FormPart {
+			name: name.to_string(),
+			filename: filename.map(String::from),
+			content_type: content_type.map(String::from),
+		})
+	}
+
+	fn data(value: &str) -> FormEvent {
+		FormEvent::Data(value.as_bytes().to_vec())
+	}
+
+	#[test]
+	fn urlencoded() {
+		let ct = "application/x-www-form-urlencoded";
+		assert_eq!(
+			decode(ct, b"a=1&b%20c=x+y%21&&flag&empty="),
+			Ok(vec![
+				part("a", None, None),
+				data("1"),
+				FormEvent::PartEnd,
+				part("b c", None, None),
+				data("x y!"),
+				FormEvent::PartEnd,
+				part("flag", None, None),
+				FormEvent::PartEnd,
+				part("empty", None, None),
+				FormEvent::PartEnd,
+			])
+		);
+		assert_eq!(decode(ct, b""), Ok(vec![]));
+		assert_eq!(decode(ct, b"a=%2"), Err(FormError::BadEncoding));
+		assert_eq!(decode(ct, b"a=%zz"), Err(FormError::BadEncoding));
+		assert_eq!(decode(ct, b"%ff=1"), Err(FormError::BadEncoding));
+	}
+
+	#[test]
+	fn multipart() {
+		let ct = "multipart/form-data; boundary=\"XyZ\"";
+		let body = b"preamble\r\n--XyZ\r\n\
+			Content-Disposition: form-data; name=\"title\"\r\n\
+			\r\n\
+			Hello\r\n--XyZ \r\n\
+			content-disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\n\
+			Content-Type: text/plain\r\n\
+			\r\n\
+			line one\r\n--XY\r\n--XyZ--\r\nepilogue";
+		assert_eq!(
+			decode(ct, body),
+			Ok(vec![
+				part("title", None, None),
+				data("Hello"),
+				FormEvent::PartEnd,
+				part("upload", Some("a \"b\".txt"), Some("text/plain")),
+				data("line one\r\n--XY"),
+				FormEvent::PartEnd,
+			])
+		);
+		// A boundary right at the start, and an empty part
+		let body = b"--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\n\r\n--XyZ--";
+		assert_eq!(
+			decode(ct, body),
+			Ok(vec![part("a", None, None), FormEvent::PartEnd])
+		);
+		assert_eq!(decode(ct, b"--XyZ\r\n\r\n\r\n--XyZ--"), Err(FormError::BadPartHead));
+		assert_eq!(
+			decode(ct, b"--XyZ\r\nContent-Type: text/plain\r\n\r\n\r\n--XyZ--"),
+			Err(FormError::BadPartHead)
+		);
+		assert_eq!(
+			decode(ct, b"--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\nabc"),
+			Err(FormError::Truncated)
+		);
+	}
+
+	#[test]
+	fn limits() {
+		let limits = FormLimits {
+			max_parts: 2,
+			max_field_bytes: 3,
+			max_file_bytes: 5,
+			max_head_bytes: 60,
+		};
+		let ct = "application/x-www-form-urlencoded";
+		let mut form = decoder(ct, limits.clone());
+		assert_eq!(form.decode(b"a=1&b=2&c=3", true), Err(FormError::TooManyParts));
+		let mut form = decoder(ct, limits.clone());
+		assert_eq!(form.decode(b"a=1234", false), Err(FormError::PartTooLarge));
+
+		let ct = "multipart/form-data; boundary=B";
+		let head = |filename: &str| {
+			format!(
+				"\r\n--B\r\nContent-Disposition: form-data; name=f{}\r\n\r\n",
+				filename
+			)
+		};
+		let mut form = decoder(ct, limits.clone());
+		let body = format!("{}12345{}--", head("; filename=f"), head(""));
+		assert_eq!(form.decode(body.as_bytes(), false).map(|e| e.len()), Ok(4));
+		assert_eq!(form.decode(b"123456", false), Err(FormError::PartTooLarge));
+		let mut form = decoder(ct, limits);
+		let body = format!("{}\r\n--B\r\nX-Padding: {}", head(""), "x".repeat(60));
+		assert_eq!(form.decode(body.as_bytes(), false), Err(FormError::PartTooLarge));
+	}
+
+	#[test]
+	fn not_a_form() {
+		let new = |content_type: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(http::header::CONTENT_TYPE, content_type.parse().unwrap());
+			FormDecoder::new(&headers, FormLimits::default()).map(|_| ())
+		};
+		assert_eq!(new("text/plain"), Err(FormError::NotAForm));
+		assert_eq!(new("multipart/form-data"), Err(FormError::BadBoundary));
+		assert_eq!(new("multipart/form-data; boundary=\"\""), Err(FormError::BadBoundary));
+		assert_eq!(new("Application/X-WWW-Form-Urlencoded; charset=utf-8"), Ok(()));
+		assert_eq!(
+			FormDecoder::new(&HeaderMap::new(), FormLimits::default()).map(|_| ()),
+			Err(FormError::NotAForm)
+		);
+		assert_eq!(FormError::NotAForm.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+		assert_eq!(FormError::PartTooLarge.status(), StatusCode::PAYLOAD_TOO_LARGE);
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1351 @@
+//! # h2 - HTTP/2 framing, streams and flow control
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! A `Session` is the server side of one HTTP/2 connection (RFC 7540). Feed
+//! it whatever arrives on the socket and it hands back `Event`s - a request
+//! head, some of a request body, or a stream the client has given up on -
+//! answering SETTINGS and PINGs as it goes. Tell it when you've `consumed`
+//! some request body, and it opens the flow control windows for the client
+//! to send more. Responses are queued with `send_headers` and `send_data`,
+//! and `flush` gives back the bytes which can go out now. DATA frames only
+//! go out as the client's windows allow, taking turns between the streams.
+//!
+//! Each piece queued can carry a tag, which comes back from `flush` with the
+//! last of the bytes it went out in - so the caller can tell when to confirm
+//! it. We never push, and never use the dynamic table for our own headers.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::collections::{BTreeMap, VecDeque};
+
+use base64;
+use grease::Context;
+use http::header::{HeaderName, HeaderValue};
+
+use super::hpack;
+use super::{HeaderMap, Method, Uri};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// What we tell the client in our SETTINGS
+#[derive(Debug, Copy, Clone)]
+pub struct Settings {
+	/// How many requests the client may have open at once
+	pub max_concurrent_streams: u32,
+	/// How much request body the client may send on each stream before it
+	/// hears from us. Never less than the protocol default of 65,535.
+	pub initial_window_size: u32,
+	/// The largest request head we'll take, counted as RFC 7540 counts it.
+	/// Header lists bigger than this are a connection error.
+	pub max_header_list_size: u32,
+}
+
+/// Something the caller needs to know about
+#[derive(Debug)]
+pub enum Event {
+	/// A new request. `end_stream` says there's no body.
+	Request {
+		stream: u32,
+		method: Method,
+		uri: Uri,
+		headers: HeaderMap,
+		end_stream: bool,
+	},
+	/// Some of a request body. Trailers end the body, but aren't passed on.
+	Data {
+		stream: u32,
+		data: Vec<u8>,
+		end_stream: bool,
+	},
+	/// The stream has been reset, by the client or because it broke the
+	/// rules. Nothing more can be sent on it.
+	Reset { stream: u32 },
+}
+
+/// The server side of an HTTP/2 connection
+pub struct Session {
+	/// What we've received but not yet made a frame of
+	buffer: Vec<u8>,
+	/// Set until we've had the client's connection preface
+	preface: bool,
+	/// Set once we've had the client's first SETTINGS
+	settled: bool,
+	decoder: hpack::Decoder,
+	settings: Settings,
+	/// The window each new stream starts with, as the client's SETTINGS say
+	peer_window: u32,
+	/// The largest frame the client will take
+	peer_max_frame: usize,
+	/// What we may send, and what the client may send, on the connection
+	send_window: i64,
+	recv_window: i64,
+	/// Request body consumed since we last opened the connection's window
+	released: i64,
+	streams: BTreeMap<u32, Stream>,
+	/// The highest stream the client has opened
+	last_stream: u32,
+	/// A header block still waiting for its CONTINUATION frames - the
+	/// stream, whether it ends the stream, and the block so far
+	continuation: Option<(u32, bool, Vec<u8>)>,
+	/// Frames which can go as soon as they're flushed, with their tags
+	output: VecDeque<(Vec<u8>, Option<Context>)>,
+	/// Set once either side has sent a GOAWAY
+	going_away: bool,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// What an HTTP/2 client sends first, with prior knowledge or after an
+/// upgrade
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Error codes, for `RST_STREAM` and `GOAWAY`
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const ENHANCE_YOUR_CALM: u32 = 0xB;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+/// Frame flags
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// Setting identifiers
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const FRAME_HEADER_LEN: usize = 9;
+
+/// The window everything starts with, and the largest a window can be
+const DEFAULT_WINDOW: u32 = 65_535;
+const MAX_WINDOW: i64 = 0x7FFF_FFFF;
+
+/// The largest frame anyone must take, which is all we'll take, and the
+/// largest anyone may ask for
+const DEFAULT_MAX_FRAME: usize = 16_384;
+const MAX_MAX_FRAME: usize = 0xFF_FFFF;
+
+/// We keep the default dynamic table size
+const HEADER_TABLE_SIZE: usize = 4096;
+
+/// Headers which only mean something on an HTTP/1 connection
+const CONNECTION_HEADERS: [&str; 5] = [
+	"connection",
+	"keep-alive",
+	"proxy-connection",
+	"transfer-encoding",
+	"upgrade",
+];
+
+/// One request, and the response to it
+struct Stream {
+	/// What we may send, and what the client may send
+	send_window: i64,
+	recv_window: i64,
+	/// Request body consumed since we last opened the window
+	released: i64,
+	/// The client has finished the request
+	remote_closed: bool,
+	/// Response DATA waiting for the windows to open, with whether it ends
+	/// the stream and its tag
+	queue: VecDeque<(Vec<u8>, bool, Option<Context>)>,
+	/// The request's `Content-Length`, if it gave one
+	expected: Option<u64>,
+	/// How much request body has arrived
+	received: u64,
+}
+
+/// Why a header block doesn't make a request
+type Malformed = ();
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Session {
+	/// A session on a connection where the client started with HTTP/2. Our
+	/// SETTINGS are ready to flush straight away.
+	pub fn new(settings: Settings) -> Session {
+		let settings = Settings {
+			initial_window_size: settings.initial_window_size.max(DEFAULT_WINDOW),
+			..settings
+		};
+		let mut session = Session {
+			buffer: Vec::new(),
+			preface: true,
+			settled: false,
+			decoder: hpack::Decoder::new(
+				HEADER_TABLE_SIZE,
+				settings.max_header_list_size as usize,
+			),
+			settings,
+			peer_window: DEFAULT_WINDOW,
+			peer_max_frame: DEFAULT_MAX_FRAME,
+			send_window: i64::from(DEFAULT_WINDOW),
+			recv_window: i64::from(settings.initial_window_size),
+			released: 0,
+			streams: BTreeMap::new(),
+			last_stream: 0,
+			continuation: None,
+			output: VecDeque::new(),
+			going_away: false,
+		};
+		let mut payload = Vec::new();
+		for &(id, value) in &[
+			(SETTINGS_MAX_CONCURRENT_STREAMS, settings.max_concurrent_streams),
+			(SETTINGS_INITIAL_WINDOW_SIZE, settings.initial_window_size),
+			(SETTINGS_MAX_HEADER_LIST_SIZE, settings.max_header_list_size),
+		] {
+			payload.extend_from_slice(&[(id >> 8) as u8, id as u8]);
+			payload.extend_from_slice(&be32(value));
+		}
+		session.queue(frame(SETTINGS, 0, 0, &payload));
+		// The connection window can only be changed with a WINDOW_UPDATE
+		let increment = settings.initial_window_size - DEFAULT_WINDOW;
+		if increment > 0 {
+			session.queue(frame(WINDOW_UPDATE, 0, 0, &be32(increment)));
+		}
+		session
+	}
+
+	/// A session on a connection upgraded from HTTP/1.1 with `Upgrade:
+	/// h2c`, given the request's `HTTP2-Settings` header. The request
+	/// becomes stream 1, which is already closed from the client's end.
+	/// Returns None if the header isn't valid.
+	pub fn upgraded(settings: Settings, http2_settings: &[u8]) -> Option<Session> {
+		let trimmed: Vec<u8> = http2_settings
+			.iter()
+			.cloned()
+			.filter(|&b| b != b'=')
+			.collect();
+		let payload = base64::decode_config(&trimmed, base64::URL_SAFE_NO_PAD).ok()?;
+		let mut session = Session::new(settings);
+		session.apply_settings(&payload).ok()?;
+		let mut stream = session.new_stream();
+		stream.remote_closed = true;
+		session.streams.insert(1, stream);
+		session.last_stream = 1;
+		Some(session)
+	}
+
+	/// Deal with some data from the client. An `Err` is the code to send
+	/// with a GOAWAY, as the connection is beyond saving.
+	pub fn receive(&mut self, data: &[u8]) -> Result<Vec<Event>, u32> {
+		self.buffer.extend_from_slice(data);
+		if self.preface {
+			let n = self.buffer.len().min(PREFACE.len());
+			if self.buffer[..n] != PREFACE[..n] {
+				return Err(PROTOCOL_ERROR);
+			}
+			if n < PREFACE.len() {
+				return Ok(Vec::new());
+			}
+			self.buffer.drain(..PREFACE.len());
+			self.preface = false;
+		}
+		let mut events = Vec::new();
+		while self.buffer.len() >= FRAME_HEADER_LEN {
+			let length = (self.buffer[0] as usize) << 16
+				| (self.buffer[1] as usize) << 8
+				| self.buffer[2] as usize;
+			if length > DEFAULT_MAX_FRAME {
+				return Err(FRAME_SIZE_ERROR);
+			}
+			if self.buffer.len() < FRAME_HEADER_LEN + length {
+				break;
+			}
+			let kind = self.buffer[3];
+			let flags = self.buffer[4];
+			let stream = u32_at(&self.buffer[5..]) & 0x7FFF_FFFF;
+			let payload = self.buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + length].to_vec();
+			self.buffer.drain(..FRAME_HEADER_LEN + length);
+			self.handle_frame(kind, flags, stream, payload, &mut events)?;
+		}
+		Ok(events)
+	}
+
+	/// Queue a response head. Returns false if the stream has gone.
+	pub fn send_headers(
+		&mut self,
+		stream: u32,
+		block: &[u8],
+		end_stream: bool,
+		tag: Option<Context>,
+	) -> bool {
+		if !self.streams.contains_key(&stream) {
+			return false;
+		}
+		let mut pieces = block.chunks(self.peer_max_frame).peekable();
+		let mut kind = HEADERS;
+		let mut first = true;
+		// An empty block still needs its HEADERS frame
+		while first || pieces.peek().is_some() {
+			let piece = pieces.next().unwrap_or(&[]);
+			let mut flags = 0;
+			if first && end_stream {
+				flags |= FLAG_END_STREAM;
+			}
+			let last = pieces.peek().is_none();
+			if last {
+				flags |= FLAG_END_HEADERS;
+			}
+			let data = frame(kind, flags, stream, piece);
+			self.output.push_back((data, if last { tag } else { None }));
+			kind = CONTINUATION;
+			first = false;
+		}
+		if end_stream {
+			self.finish_stream(stream);
+		}
+		true
+	}
+
+	/// Queue some response body. It goes as the windows allow. Returns
+	/// false if the stream has gone.
+	pub fn send_data(
+		&mut self,
+		stream: u32,
+		data: Vec<u8>,
+		end_stream: bool,
+		tag: Option<Context>,
+	) -> bool {
+		match self.streams.get_mut(&stream) {
+			Some(s) => {
+				s.queue.push_back((data, end_stream, tag));
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// `length` bytes of request body on a stream have been dealt with,
+	/// so the client may send that much more. The windows are opened once
+	/// half of one has been consumed, so the updates don't go one by one.
+	pub fn consumed(&mut self, stream: u32, length: usize) {
+		let half = i64::from(self.settings.initial_window_size) / 2;
+		let length = length as i64;
+		self.released += length;
+		if self.released >= half {
+			let increment = self.released;
+			self.queue(frame(WINDOW_UPDATE, 0, 0, &be32(increment as u32)));
+			self.recv_window += increment;
+			self.released = 0;
+		}
+		let update = match self.streams.get_mut(&stream) {
+			// Once the request is over, its window doesn't matter
+			Some(ref mut s) if !s.remote_closed => {
+				s.released += length;
+				if s.released >= half {
+					let increment = s.released;
+					s.recv_window += increment;
+					s.released = 0;
+					Some(increment)
+				} else {
+					None
+				}
+			}
+			_ => None,
+		};
+		if let Some(increment) = update {
+			self.queue(frame(WINDOW_UPDATE, 0, stream, &be32(increment as u32)));
+		}
+	}
+
+	/// Give up on a stream, e.g. because the response can't be finished.
+	pub fn reset(&mut self, stream: u32, code: u32) {
+		if self.streams.remove(&stream).is_some() {
+			self.queue(frame(RST_STREAM, 0, stream, &be32(code)));
+		}
+	}
+
+	/// Tell the client we're done with the connection. Streams already
+	/// open are finished, but no new ones are accepted.
+	pub fn go_away(&mut self, code: u32) {
+		if !self.going_away {
+			self.going_away = true;
+			let mut payload = be32(self.last_stream).to_vec();
+			payload.extend_from_slice(&be32(code));
+			self.queue(frame(GOAWAY, 0, 0, &payload));
+		}
+	}
+
+	/// Whether the connection can be closed - someone has said GOAWAY and
+	/// everything has been sent.
+	pub fn is_finished(&self) -> bool {
+		self.going_away && self.streams.is_empty() && self.output.is_empty()
+	}
+
+	/// Everything which can be sent now. The bytes are split after each
+	/// tagged piece, and the piece's tag given with them.
+	pub fn flush(&mut self) -> Vec<(Vec<u8>, Option<Context>)> {
+		self.schedule_data();
+		let mut segments = Vec::new();
+		let mut current = Vec::new();
+		while let Some((data, tag)) = self.output.pop_front() {
+			current.extend_from_slice(&data);
+			if tag.is_some() {
+				segments.push((::std::mem::replace(&mut current, Vec::new()), tag));
+			}
+		}
+		if !current.is_empty() {
+			segments.push((current, None));
+		}
+		segments
+	}
+}
+
+/// Encode a response head for `send_headers`. Names are lower cased, and
+/// headers which only mean something to HTTP/1 are dropped.
+pub fn encode_head(status: u16, fields: &[(String, HeaderValue)]) -> Vec<u8> {
+	let status = status.to_string();
+	let names: Vec<String> = fields.iter().map(|f| f.0.to_ascii_lowercase()).collect();
+	let mut list: Vec<(&str, &[u8])> = vec![(":status", status.as_bytes())];
+	for (name, &(_, ref value)) in names.iter().zip(fields) {
+		if !CONNECTION_HEADERS.contains(&name.as_str()) {
+			list.push((name, value.as_bytes()));
+		}
+	}
+	hpack::encode(&list)
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl Session {
+	fn queue(&mut self, data: Vec<u8>) {
+		self.output.push_back((data, None));
+	}
+
+	fn new_stream(&self) -> Stream {
+		Stream {
+			send_window: i64::from(self.peer_window),
+			recv_window: i64::from(self.settings.initial_window_size),
+			released: 0,
+			remote_closed: false,
+			queue: VecDeque::new(),
+			expected: None,
+			received: 0,
+		}
+	}
+
+	fn handle_frame(
+		&mut self,
+		kind: u8,
+		flags: u8,
+		stream: u32,
+		payload: Vec<u8>,
+		events: &mut Vec<Event>,
+	) -> Result<(), u32> {
+		if !self.settled {
+			if kind != SETTINGS || flags & FLAG_ACK != 0 {
+				return Err(PROTOCOL_ERROR);
+			}
+			self.settled = true;
+		}
+		// Nothing may come between a header block's frames
+		if self.continuation.is_some() {
+			let ours = self.continuation.as_ref().map(|c| c.0);
+			if kind != CONTINUATION || ours != Some(stream) {
+				return Err(PROTOCOL_ERROR);
+			}
+		}
+		match kind {
+			DATA => self.handle_data(flags, stream, payload, events),
+			HEADERS => self.handle_headers(flags, stream, payload, events),
+			PRIORITY => match (stream, payload.len()) {
+				(0, _) => Err(PROTOCOL_ERROR),
+				(_, 5) => Ok(()),
+				_ => {
+					self.reset_stream(stream, FRAME_SIZE_ERROR, events);
+					Ok(())
+				}
+			},
+			RST_STREAM => {
+				if payload.len() != 4 {
+					return Err(FRAME_SIZE_ERROR);
+				}
+				if stream == 0 || stream > self.last_stream {
+					return Err(PROTOCOL_ERROR);
+				}
+				if self.streams.remove(&stream).is_some() {
+					events.push(Event::Reset { stream });
+				}
+				Ok(())
+			}
+			SETTINGS => {
+				if stream != 0 {
+					return Err(PROTOCOL_ERROR);
+				}
+				if flags & FLAG_ACK != 0 {
+					return if payload.is_empty() {
+						Ok(())
+					} else {
+						Err(FRAME_SIZE_ERROR)
+					};
+				}
+				self.apply_settings(&payload)?;
+				self.queue(frame(SETTINGS, FLAG_ACK, 0, &[]));
+				Ok(())
+			}
+			PUSH_PROMISE => Err(PROTOCOL_ERROR),
+			PING => {
+				if payload.len() != 8 {
+					return Err(FRAME_SIZE_ERROR);
+				}
+				if stream != 0 {
+					return Err(PROTOCOL_ERROR);
+				}
+				if flags & FLAG_ACK == 0 {
+					self.queue(frame(PING, FLAG_ACK, 0, &payload));
+				}
+				Ok(())
+			}
+			GOAWAY => {
+				if stream != 0 {
+					return Err(PROTOCOL_ERROR);
+				}
+				// Finish what's open, then close
+				self.going_away = true;
+				Ok(())
+			}
+			WINDOW_UPDATE => self.handle_window_update(stream, &payload, events),
+			CONTINUATION => {
+				let (id, end_stream, mut block) = match self.continuation.take() {
+					Some(c) => c,
+					None => return Err(PROTOCOL_ERROR),
+				};
+				block.extend_from_slice(&payload);
+				self.header_block(id, flags, end_stream, block, events)
+			}
+			// Unknown frames are ignored
+			_ => Ok(()),
+		}
+	}
+
+	fn handle_data(
+		&mut self,
+		flags: u8,
+		stream: u32,
+		payload: Vec<u8>,
+		events: &mut Vec<Event>,
+	) -> Result<(), u32> {
+		if stream == 0 {
+			return Err(PROTOCOL_ERROR);
+		}
+		// Padding counts against the windows too
+		let length = payload.len();
+		self.recv_window -= length as i64;
+		if self.recv_window < 0 {
+			return Err(FLOW_CONTROL_ERROR);
+		}
+		let data = unpad(flags, payload)?;
+		let end_stream = flags & FLAG_END_STREAM != 0;
+		let code = match self.streams.get_mut(&stream) {
+			None if stream > self.last_stream => return Err(PROTOCOL_ERROR),
+			// We've finished with it, and the client may not know yet
+			None => {
+				self.consumed(stream, length);
+				return Ok(());
+			}
+			Some(ref s) if s.remote_closed => Some(STREAM_CLOSED),
+			Some(s) => {
+				s.recv_window -= length as i64;
+				s.received += data.len() as u64;
+				if s.recv_window < 0 {
+					Some(FLOW_CONTROL_ERROR)
+				} else if !content_length_ok(s, end_stream) {
+					Some(PROTOCOL_ERROR)
+				} else {
+					s.remote_closed = end_stream;
+					None
+				}
+			}
+		};
+		match code {
+			Some(code) => {
+				self.reset_stream(stream, code, events);
+				self.consumed(stream, length);
+			}
+			None => {
+				// The caller only sees the data, so the padding is done with
+				self.consumed(stream, length - data.len());
+				events.push(Event::Data {
+					stream,
+					data,
+					end_stream,
+				});
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_headers(
+		&mut self,
+		flags: u8,
+		stream: u32,
+		payload: Vec<u8>,
+		events: &mut Vec<Event>,
+	) -> Result<(), u32> {
+		if stream == 0 || stream % 2 == 0 {
+			return Err(PROTOCOL_ERROR);
+		}
+		let mut block = unpad(flags, payload)?;
+		if flags & FLAG_PRIORITY != 0 {
+			if block.len() < 5 {
+				return Err(FRAME_SIZE_ERROR);
+			}
+			block.drain(..5);
+		}
+		let end_stream = flags & FLAG_END_STREAM != 0;
+		self.header_block(stream, flags, end_stream, block, events)
+	}
+
+	/// Deal with a header block once all of it is here
+	fn header_block(
+		&mut self,
+		stream: u32,
+		flags: u8,
+		end_stream: bool,
+		block: Vec<u8>,
+		events: &mut Vec<Event>,
+	) -> Result<(), u32> {
+		// Don't buffer any more than we'd be prepared to decode
+		if block.len() > self.settings.max_header_list_size as usize * 2 {
+			return Err(ENHANCE_YOUR_CALM);
+		}
+		if flags & FLAG_END_HEADERS == 0 {
+			self.continuation = Some((stream, end_stream, block));
+			return Ok(());
+		}
+		// Every block goes through the decoder, to keep its table in step
+		let fields = self.decoder.decode(&block).map_err(|e| match e {
+			hpack::DecodeError::ListTooLarge => ENHANCE_YOUR_CALM,
+			_ => COMPRESSION_ERROR,
+		})?;
+		if let Some(remote_closed) = self.streams.get(&stream).map(|s| s.remote_closed) {
+			// Trailers, which must end the stream
+			let ok = !remote_closed
+				&& end_stream
+				&& self.streams
+					.get(&stream)
+					.map_or(false, |s| content_length_ok(s, true));
+			if ok {
+				self.streams.get_mut(&stream).unwrap().remote_closed = true;
+				events.push(Event::Data {
+					stream,
+					data: Vec::new(),
+					end_stream,
+				});
+			} else {
+				let code = if remote_closed {
+					STREAM_CLOSED
+				} else {
+					PROTOCOL_ERROR
+				};
+				self.reset_stream(stream, code, events);
+			}
+			return Ok(());
+		}
+		if stream <= self.last_stream {
+			return Err(STREAM_CLOSED);
+		}
+		self.last_stream = stream;
+		if self.going_away {
+			return Ok(());
+		}
+		if self.streams.len() >= self.settings.max_concurrent_streams as usize {
+			self.queue(frame(RST_STREAM, 0, stream, &be32(REFUSED_STREAM)));
+			return Ok(());
+		}
+		match request(fields) {
+			Ok((method, uri, headers, expected)) => {
+				let mut s = self.new_stream();
+				s.expected = expected;
+				s.remote_closed = end_stream;
+				if content_length_ok(&s, end_stream) {
+					self.streams.insert(stream, s);
+					events.push(Event::Request {
+						stream,
+						method,
+						uri,
+						headers,
+						end_stream,
+					});
+				} else {
+					self.queue(frame(RST_STREAM, 0, stream, &be32(PROTOCOL_ERROR)));
+				}
+			}
+			Err(()) => {
+				debug!("Malformed request on stream {}", stream);
+				self.queue(frame(RST_STREAM, 0, stream, &be32(PROTOCOL_ERROR)));
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_window_update(
+		&mut self,
+		stream: u32,
+		payload: &[u8],
+		events: &mut Vec<Event>,
+	) -> Result<(), u32> {
+		if payload.len() != 4 {
+			return Err(FRAME_SIZE_ERROR);
+		}
+		let increment = i64::from(u32_at(payload) & 0x7FFF_FFFF);
+		if stream == 0 {
+			if increment == 0 {
+				return Err(PROTOCOL_ERROR);
+			}
+			self.send_window += increment;
+			if self.send_window > MAX_WINDOW {
+				return Err(FLOW_CONTROL_ERROR);
+			}
+			return Ok(());
+		}
+		let code = match self.streams.get_mut(&stream) {
+			None if stream > self.last_stream => return Err(PROTOCOL_ERROR),
+			None => return Ok(()),
+			Some(_) if increment == 0 => PROTOCOL_ERROR,
+			Some(s) => {
+				s.send_window += increment;
+				if s.send_window <= MAX_WINDOW {
+					return Ok(());
+				}
+				FLOW_CONTROL_ERROR
+			}
+		};
+		self.reset_stream(stream, code, events);
+		Ok(())
+	}
+
+	/// Apply the client's SETTINGS
+	fn apply_settings(&mut self, payload: &[u8]) -> Result<(), u32> {
+		if payload.len() % 6 != 0 {
+			return Err(FRAME_SIZE_ERROR);
+		}
+		for setting in payload.chunks(6) {
+			let id = u16::from(setting[0]) << 8 | u16::from(setting[1]);
+			let value = u32_at(&setting[2..]);
+			match id {
+				SETTINGS_ENABLE_PUSH if value > 1 => return Err(PROTOCOL_ERROR),
+				SETTINGS_INITIAL_WINDOW_SIZE => {
+					if i64::from(value) > MAX_WINDOW {
+						return Err(FLOW_CONTROL_ERROR);
+					}
+					let delta = i64::from(value) - i64::from(self.peer_window);
+					self.peer_window = value;
+					for s in self.streams.values_mut() {
+						s.send_window += delta;
+						if s.send_window > MAX_WINDOW {
+							return Err(FLOW_CONTROL_ERROR);
+						}
+					}
+				}
+				SETTINGS_MAX_FRAME_SIZE => {
+					let value = value as usize;
+					if value < DEFAULT_MAX_FRAME || value > MAX_MAX_FRAME {
+						return Err(PROTOCOL_ERROR);
+					}
+					self.peer_max_frame = value;
+				}
+				// We don't use the dynamic table or push, and the rest is
+				// advice
+				SETTINGS_HEADER_TABLE_SIZE
+				| SETTINGS_ENABLE_PUSH
+				| SETTINGS_MAX_CONCURRENT_STREAMS
+				| SETTINGS_MAX_HEADER_LIST_SIZE => {}
+				_ => {}
+			}
+		}
+		Ok(())
+	}
+
+	/// Reset a stream which has broken the rules
+	fn reset_stream(&mut self, stream: u32, code: u32, events: &mut Vec<Event>) {
+		if self.streams.remove(&stream).is_some() {
+			events.push(Event::Reset { stream });
+		}
+		self.queue(frame(RST_STREAM, 0, stream, &be32(code)));
+	}
+
+	/// We've sent the end of a response. If the client is still sending
+	/// the request, tell it not to bother.
+	fn finish_stream(&mut self, stream: u32) {
+		if let Some(s) = self.streams.remove(&stream) {
+			if !s.remote_closed {
+				self.queue(frame(RST_STREAM, 0, stream, &be32(NO_ERROR)));
+			}
+		}
+	}
+
+	/// Move as much queued DATA to the output as the windows allow, a
+	/// frame from each stream in turn.
+	fn schedule_data(&mut self) {
+		loop {
+			let mut progress = false;
+			let ids: Vec<u32> = self.streams.keys().cloned().collect();
+			for id in ids {
+				if let Some((data, tag, end_stream)) = self.next_frame(id) {
+					self.output.push_back((data, tag));
+					if end_stream {
+						self.finish_stream(id);
+					}
+					progress = true;
+				}
+			}
+			if !progress {
+				break;
+			}
+		}
+	}
+
+	/// The next DATA frame a stream can send, if any, with the tag of the
+	/// piece it finishes and whether it ends the stream.
+	fn next_frame(&mut self, id: u32) -> Option<(Vec<u8>, Option<Context>, bool)> {
+		let connection_window = self.send_window;
+		let max_frame = self.peer_max_frame as i64;
+		let s = self.streams.get_mut(&id)?;
+		let (allowed, done, end_stream, tag) = {
+			let &mut (ref data, end_stream, tag) = s.queue.front_mut()?;
+			let allowed = (data.len() as i64)
+				.min(s.send_window)
+				.min(connection_window)
+				.min(max_frame)
+				.max(0) as usize;
+			if allowed == 0 && !data.is_empty() {
+				return None;
+			}
+			(allowed, allowed == data.len(), end_stream, tag)
+		};
+		let piece: Vec<u8> = s.queue.front_mut().unwrap().0.drain(..allowed).collect();
+		s.send_window -= allowed as i64;
+		self.send_window -= allowed as i64;
+		if !done {
+			return Some((frame(DATA, 0, id, &piece), None, false));
+		}
+		s.queue.pop_front();
+		let flags = if end_stream { FLAG_END_STREAM } else { 0 };
+		Some((frame(DATA, flags, id, &piece), tag, end_stream))
+	}
+}
+
+/// Turn a request's header fields into its method, URI, headers and any
+/// `Content-Length`. Anything RFC 7540 section 8.1.2 calls malformed is
+/// refused.
+fn request(
+	fields: Vec<hpack::Field>,
+) -> Result<(Method, Uri, HeaderMap, Option<u64>), Malformed> {
+	let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+	let mut headers = HeaderMap::new();
+	let mut cookies: Vec<Vec<u8>> = Vec::new();
+	for (name, value) in fields {
+		if name.starts_with(b":") {
+			// Pseudo-headers come first, once each
+			let slot = match &name[..] {
+				b":method" => &mut method,
+				b":scheme" => &mut scheme,
+				b":path" => &mut path,
+				b":authority" => &mut authority,
+				_ => return Err(()),
+			};
+			if slot.is_some() || !headers.is_empty() || !cookies.is_empty() {
+				return Err(());
+			}
+			*slot = Some(value);
+			continue;
+		}
+		if name.iter().any(|b| b.is_ascii_uppercase()) {
+			return Err(());
+		}
+		let name = HeaderName::from_bytes(&name).map_err(|_| ())?;
+		if CONNECTION_HEADERS.contains(&name.as_str()) {
+			return Err(());
+		}
+		if name == "te" && &value[..] != b"trailers" {
+			return Err(());
+		}
+		if name == "cookie" {
+			cookies.push(value);
+			continue;
+		}
+		headers.append(name, HeaderValue::from_bytes(&value).map_err(|_| ())?);
+	}
+	if !cookies.is_empty() {
+		let cookie = cookies.join(&b"; "[..]);
+		headers.insert("cookie", HeaderValue::from_bytes(&cookie).map_err(|_| ())?);
+	}
+	let method = Method::from_bytes(&method.ok_or(())?).map_err(|_| ())?;
+	let target = if method == Method::CONNECT {
+		if scheme.is_some() || path.is_some() {
+			return Err(());
+		}
+		authority.clone().ok_or(())?
+	} else {
+		match (scheme, path) {
+			(Some(_), Some(ref path)) if !path.is_empty() => path.clone(),
+			_ => return Err(()),
+		}
+	};
+	let uri = String::from_utf8(target)
+		.ok()
+		.and_then(|t| t.parse::<Uri>().ok())
+		.ok_or(())?;
+	if let Some(authority) = authority {
+		if !headers.contains_key("host") {
+			headers.insert("host", HeaderValue::from_bytes(&authority).map_err(|_| ())?);
+		}
+	}
+	let mut lengths = headers.get_all("content-length").iter();
+	let expected = match (lengths.next(), lengths.next()) {
+		(None, _) => None,
+		(Some(value), None) => Some(
+			value
+				.to_str()
+				.ok()
+				.and_then(|v| v.parse().ok())
+				.ok_or(())?,
+		),
+		_ => return Err(()),
+	};
+	Ok((method, uri, headers, expected))
+}
+
+/// Does the body so far agree with the `Content-Length`?
+fn content_length_ok(s: &Stream, end_stream: bool) -> bool {
+	match s.expected {
+		Some(expected) if end_stream => s.received == expected,
+		Some(expected) => s.received <= expected,
+		None => true,
+	}
+}
+
+/// Strip the padding from a DATA or HEADERS frame
+fn unpad(flags: u8, mut payload: Vec<u8>) -> Result<Vec<u8>, u32> {
+	if flags & FLAG_PADDED == 0 {
+		return Ok(payload);
+	}
+	let padding = *payload.first().ok_or(FRAME_SIZE_ERROR)? as usize;
+	if padding >= payload.len() {
+		return Err(PROTOCOL_ERROR);
+	}
+	let end = payload.len() - padding;
+	payload.truncate(end);
+	payload.remove(0);
+	Ok(payload)
+}
+
+fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+	let length = payload.len();
+	let mut data = Vec::with_capacity(FRAME_HEADER_LEN + length);
+	data.extend_from_slice(&[(length >> 16) as u8, (length >> 8) as u8, length as u8]);
+	data.push(kind);
+	data.push(flags);
+	data.extend_from_slice(&be32(stream));
+	data.extend_from_slice(payload);
+	data
+}
+
+fn be32(value: u32) -> [u8; 4] {
+	[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn u32_at(data: &[u8]) -> u32 {
+	u32::from(data[0]) << 24 | u32::from(data[1]) << 16 | u32::from(data[2]) << 8
+		| u32::from(data[3])
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn settings() -> Settings {
+		Settings {
+			max_concurrent_streams: 2,
+			initial_window_size: 65_535,
+			max_header_list_size: 16 * 1024,
+		}
+	}
+
+	/// Split some output into (type, flags, stream, payload) frames
+	fn frames(data: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+		let mut frames = Vec::new();
+		let mut rest = data;
+		while !rest.is_empty() {
+			let length = (rest[0] as usize) << 16 | (rest[1] as usize) << 8 | rest[2] as usize;
+			let payload = rest[9..9 + length].to_vec();
+			frames.push((rest[3], rest[4], u32_at(&rest[5..]), payload));
+			rest = &rest[9 + length..];
+		}
+		frames
+	}
+
+	fn flushed(session: &mut Session) -> Vec<(u8, u8, u32, Vec<u8>)> {
+		let data: Vec<u8> = session.flush().into_iter().flat_map(|s| s.0).collect();
+		frames(&data)
+	}
+
+	/// A session which has had the preface and an empty SETTINGS
+	fn started() -> Session {
+		let mut session = Session::new(settings());
+		let mut data = PREFACE.to_vec();
+		data.extend(frame(SETTINGS, 0, 0, &[]));
+		assert!(session.receive(&data).unwrap().is_empty());
+		let out = flushed(&mut session);
+		assert_eq!(out.len(), 2);
+		assert_eq!(out[0].0, SETTINGS);
+		assert_eq!(out[0].3.len(), 18);
+		assert_eq!(out[1], (SETTINGS, FLAG_ACK, 0, Vec::new()));
+		session
+	}
+
+	fn get(stream: u32, end_stream: bool) -> Vec<u8> {
+		let block = hpack::encode(&[
+			(":method", b"GET"),
+			(":scheme", b"http"),
+			(":path", b"/index.html"),
+			(":authority", b"example.com"),
+			("cookie", b"a=1"),
+			("cookie", b"b=2"),
+		]);
+		let flags = FLAG_END_HEADERS | if end_stream { FLAG_END_STREAM } else { 0 };
+		frame(HEADERS, flags, stream, &block)
+	}
+
+	#[test]
+	fn preface() {
+		let mut session = Session::new(settings());
+		assert!(session.receive(&PREFACE[..10]).unwrap().is_empty());
+		assert!(session.receive(&PREFACE[10..]).unwrap().is_empty());
+		// The first frame must be a SETTINGS
+		assert_eq!(
+			session.receive(&frame(PING, 0, 0, &[0; 8])).unwrap_err(),
+			PROTOCOL_ERROR
+		);
+		let mut session = Session::new(settings());
+		assert_eq!(
+			session.receive(b"GET / HTTP/1.1\r\n").unwrap_err(),
+			PROTOCOL_ERROR
+		);
+	}
+
+	#[test]
+	fn requests() {
+		let mut session = started();
+		let events = session.receive(&get(1, true)).unwrap();
+		match events[..] {
+			[Event::Request {
+				stream: 1,
+				ref method,
+				ref uri,
+				ref headers,
+				end_stream: true,
+				..
+			}] => {
+				assert_eq!(*method, Method::GET);
+				assert_eq!(*uri, "/index.html");
+				assert_eq!(headers["host"], "example.com");
+				assert_eq!(headers["cookie"], "a=1; b=2");
+			}
+			ref other => panic!("{:?}", other),
+		}
+		// More headers on a finished request reset it
+		match session.receive(&get(1, true)).unwrap()[..] {
+			[Event::Reset { stream: 1 }] => {}
+			ref other => panic!("{:?}", other),
+		}
+		// A stream can't be reused
+		assert_eq!(session.receive(&get(1, true)).unwrap_err(), STREAM_CLOSED);
+
+		// Malformed requests are reset
+		let mut session = started();
+		let block = hpack::encode(&[(":method", b"GET"), (":path", b"/")]);
+		let events = session
+			.receive(&frame(HEADERS, FLAG_END_HEADERS, 3, &block))
+			.unwrap();
+		assert!(events.is_empty());
+		let out = flushed(&mut session);
+		assert_eq!(out, vec![(RST_STREAM, 0, 3, be32(PROTOCOL_ERROR).to_vec())]);
+		let block = hpack::encode(&[
+			(":method", b"GET"),
+			(":scheme", b"http"),
+			(":path", b"/"),
+			("connection", b"close"),
+		]);
+		session
+			.receive(&frame(HEADERS, FLAG_END_HEADERS, 5, &block))
+			.unwrap();
+		assert_eq!(flushed(&mut session)[0].0, RST_STREAM);
+
+		// As are those over the limit
+		session.receive(&get(7, false)).unwrap();
+		session.receive(&get(9, false)).unwrap();
+		assert!(session.receive(&get(11, false)).unwrap().is_empty());
+		let out = flushed(&mut session);
+		assert_eq!(out, vec![(RST_STREAM, 0, 11, be32(REFUSED_STREAM).to_vec())]);
+
+		// Even streams are ours
+		assert_eq!(session.receive(&get(12, true)).unwrap_err(), PROTOCOL_ERROR);
+	}
+
+	#[test]
+	fn continuation() {
+		let mut session = started();
+		let data = get(1, false);
+		let block = &data[9..];
+		let mut input = frame(HEADERS, 0, 1, &block[..4]);
+		input.extend(frame(CONTINUATION, 0, 1, &block[4..8]));
+		assert!(session.receive(&input).unwrap().is_empty());
+		let input = frame(CONTINUATION, FLAG_END_HEADERS, 1, &block[8..]);
+		let events = session.receive(&input).unwrap();
+		assert_eq!(events.len(), 1);
+		// A body, then trailers
+		let events = session.receive(&frame(DATA, 0, 1, b"hello")).unwrap();
+		match events[..] {
+			[Event::Data {
+				stream: 1,
+				ref data,
+				end_stream: false,
+			}] => assert_eq!(data, b"hello"),
+			ref other => panic!("{:?}", other),
+		}
+		let trailers = hpack::encode(&[("x-checksum", b"1234")]);
+		let input = frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &trailers);
+		match session.receive(&input).unwrap()[..] {
+			[Event::Data {
+				stream: 1,
+				ref data,
+				end_stream: true,
+			}] => assert!(data.is_empty()),
+			ref other => panic!("{:?}", other),
+		}
+
+		// Nothing may interrupt a header block
+		let mut session = started();
+		let mut input = frame(HEADERS, 0, 1, &block[..4]);
+		input.extend(frame(PING, 0, 0, &[0; 8]));
+		assert_eq!(session.receive(&input).unwrap_err(), PROTOCOL_ERROR);
+	}
+
+	#[test]
+	fn responses() {
+		let mut session = started();
+		session.receive(&get(1, true)).unwrap();
+		let length = HeaderValue::from_static("5");
+		let block = encode_head(200, &[("Content-Length".to_owned(), length)]);
+		let tag = Context::new(1);
+		assert!(session.send_headers(1, &block, false, Some(tag)));
+		assert!(session.send_data(1, b"hello".to_vec(), true, Some(Context::new(2))));
+		let segments = session.flush();
+		assert_eq!(segments.len(), 2);
+		assert_eq!(segments[0].1, Some(tag));
+		assert_eq!(segments[1].1, Some(Context::new(2)));
+		let out = frames(&segments[0].0);
+		assert_eq!(out, vec![(HEADERS, FLAG_END_HEADERS, 1, block.clone())]);
+		let out = frames(&segments[1].0);
+		assert_eq!(out, vec![(DATA, FLAG_END_STREAM, 1, b"hello".to_vec())]);
+		// The stream has gone
+		assert!(!session.send_data(1, b"more".to_vec(), true, None));
+
+		// Finishing a response before the request tells the client to stop
+		session.receive(&get(3, false)).unwrap();
+		assert!(session.send_headers(3, &block, true, None));
+		let out = flushed(&mut session);
+		assert_eq!(out[1], (RST_STREAM, 0, 3, be32(NO_ERROR).to_vec()));
+		assert_eq!(session.receive(&frame(DATA, 0, 3, b"late")).unwrap().len(), 0);
+
+		// Done once we've said so and everything's gone
+		session.receive(&get(5, true)).unwrap();
+		session.go_away(NO_ERROR);
+		assert!(!session.is_finished());
+		session.send_headers(5, &block, true, None);
+		let out = flushed(&mut session);
+		assert_eq!(out[0], (GOAWAY, 0, 0, [be32(5), be32(NO_ERROR)].concat()));
+		assert!(session.is_finished());
+	}
+
+	#[test]
+	fn flow_control() {
+		let mut session = started();
+		// Only a little window on each stream
+		let mut setting = vec![0, SETTINGS_INITIAL_WINDOW_SIZE as u8];
+		setting.extend_from_slice(&be32(10));
+		session.receive(&frame(SETTINGS, 0, 0, &setting)).unwrap();
+		session.receive(&get(1, true)).unwrap();
+		session.receive(&get(3, true)).unwrap();
+		flushed(&mut session);
+		session.send_data(1, vec![b'a'; 25], true, Some(Context::new(1)));
+		session.send_data(3, vec![b'b'; 5], false, Some(Context::new(3)));
+		let segments = session.flush();
+		// Stream 3's piece is all sent, stream 1's isn't
+		assert_eq!(segments.len(), 1);
+		assert_eq!(segments[0].1, Some(Context::new(3)));
+		let out = frames(&segments[0].0);
+		assert_eq!(out.len(), 2);
+		assert_eq!(out[0], (DATA, 0, 1, vec![b'a'; 10]));
+		assert_eq!(out[1], (DATA, 0, 3, vec![b'b'; 5]));
+		assert!(session.flush().is_empty());
+
+		// More window lets more out
+		session.receive(&frame(WINDOW_UPDATE, 0, 1, &be32(100))).unwrap();
+		let segments = session.flush();
+		assert_eq!(segments[0].1, Some(Context::new(1)));
+		let out = frames(&segments[0].0);
+		assert_eq!(out, vec![(DATA, FLAG_END_STREAM, 1, vec![b'a'; 15])]);
+
+		// A smaller window can go negative
+		let mut setting = vec![0, SETTINGS_INITIAL_WINDOW_SIZE as u8];
+		setting.extend_from_slice(&be32(0));
+		session.receive(&frame(SETTINGS, 0, 0, &setting)).unwrap();
+		assert_eq!(session.streams[&3].send_window, -5);
+		assert_eq!(
+			session.receive(&frame(WINDOW_UPDATE, 0, 0, &be32(0x7FFF_FFFF))).unwrap_err(),
+			FLOW_CONTROL_ERROR
+		);
+	}
+
+	#[test]
+	fn receive_window() {
+		let mut session = started();
+		session.receive(&get(1, false)).unwrap();
+		// Nothing opens the windows until the body has been consumed
+		for _ in 0..3 {
+			session.receive(&frame(DATA, 0, 1, &[0; 16_000])).unwrap();
+			assert!(flushed(&mut session).is_empty());
+		}
+		assert_eq!(session.recv_window, 65_535 - 48_000);
+		session.consumed(1, 16_000);
+		assert!(flushed(&mut session).is_empty());
+		session.consumed(1, 32_000);
+		let out = flushed(&mut session);
+		assert_eq!(
+			out,
+			vec![
+				(WINDOW_UPDATE, 0, 0, be32(48_000).to_vec()),
+				(WINDOW_UPDATE, 0, 1, be32(48_000).to_vec()),
+			]
+		);
+		assert_eq!(session.recv_window, 65_535);
+		// Padding counts too, but isn't passed on
+		let events = session
+			.receive(&frame(DATA, FLAG_PADDED, 1, &[3, b'x', 0, 0, 0]))
+			.unwrap();
+		match events[..] {
+			[Event::Data { ref data, .. }] => assert_eq!(data, b"x"),
+			ref other => panic!("{:?}", other),
+		}
+		assert_eq!(session.released, 4);
+		assert_eq!(session.streams[&1].released, 4);
+		// Data which isn't passed on is done with straight away
+		session.receive(&get(3, true)).unwrap();
+		session.receive(&frame(DATA, 0, 3, &[0; 100])).unwrap();
+		assert_eq!(session.released, 104);
+		// Sending past the window resets the stream
+		session.streams.get_mut(&1).unwrap().recv_window = 2;
+		match session.receive(&frame(DATA, 0, 1, b"hello")).unwrap()[..] {
+			[Event::Reset { stream: 1 }] => {}
+			ref other => panic!("{:?}", other),
+		}
+		let out = flushed(&mut session);
+		assert_eq!(out.last().unwrap().3, be32(FLOW_CONTROL_ERROR).to_vec());
+	}
+
+	#[test]
+	fn header_list_size() {
+		// A small block which refers to a big table entry over and over
+		let mut session = started();
+		let mut block = vec![0x40, 0x01, b'x', 0x7F, 0xE9, 0x06];
+		block.extend_from_slice(&[b'v'; 1000]);
+		block.extend_from_slice(&[0xBE; 16]);
+		assert!(block.len() < 16 * 1024 / 2);
+		assert_eq!(
+			session
+				.receive(&frame(HEADERS, FLAG_END_HEADERS, 1, &block))
+				.unwrap_err(),
+			ENHANCE_YOUR_CALM
+		);
+	}
+
+	#[test]
+	fn upgrade() {
+		// SETTINGS_MAX_FRAME_SIZE of 32768, as curl might send it
+		let mut session = Session::upgraded(settings(), b"AAUAAIAA").unwrap();
+		assert_eq!(session.peer_max_frame, 32_768);
+		let mut data = PREFACE.to_vec();
+		data.extend(frame(SETTINGS, 0, 0, &[]));
+		session.receive(&data).unwrap();
+		let block = encode_head(204, &[]);
+		assert!(session.send_headers(1, &block, true, None));
+		assert!(session.streams.is_empty());
+		assert!(Session::upgraded(settings(), b"AAUAAIA").is_none());
+		assert!(Session::upgraded(settings(), b"!!").is_none());
+	}
+
+	#[test]
+	fn head_encoding() {
+		let fields = vec![
+			("Server".to_owned(), HeaderValue::from_static("grease/http")),
+			("Connection".to_owned(), HeaderValue::from_static("close")),
+			("x-magic".to_owned(), HeaderValue::from_static("frobbins")),
+		];
+		let block = encode_head(404, &fields);
+		let decoded = hpack::Decoder::new(4096, 16 * 1024).decode(&block).unwrap();
+		let names: Vec<&[u8]> = decoded.iter().map(|f| &f.0[..]).collect();
+		assert_eq!(names, vec![&b":status"[..], b"server", b"x-magic"]);
+		assert_eq!(block[0], 0x8D);
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,578 @@
+//! # head - rendering HTTP response heads
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! A `ResponseHead` collects the status, content type, length and any extra
+//! headers for a response, then renders them as bytes ready for the socket.
+//! Rendering checks the headers make sense - nothing which would break the
+//! framing, and no body related headers on a status which can't have a
+//! body - and fills in `Server`, `Date`, `Content-Length` and
+//! `Content-Type` where the user hasn't.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::header;
+
+use super::conditional::parse_content_range;
+use super::{Error, HeaderMap, HeaderValue, HttpResponseStatus, StatusCode};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The first part of a response
+#[derive(Debug)]
+pub struct ResponseHead {
+	/// The status line, e.g. "200 OK"
+	status: String,
+	/// The numeric status code
+	code: u16,
+	content_type: Option<String>,
+	length: Option<usize>,
+	headers: HeaderMap,
+	/// Whether this answers a HEAD request
+	head: bool,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Month names, as used in HTTP dates
+pub const MONTHS: [&str; 12] = [
+	"Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// What we call ourselves, unless the user says otherwise
+const SERVER: &str = "grease/http";
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl ResponseHead {
+	pub fn new(status: HttpResponseStatus) -> ResponseHead {
+		ResponseHead {
+			status: status.to_string(),
+			code: status.code().as_u16(),
+			content_type: None,
+			length: None,
+			headers: HeaderMap::new(),
+			head: false,
+		}
+	}
+
+	/// The head of a response we refuse a request with. These may have
+	/// statuses `HttpResponseStatus` doesn't (e.g. 431).
+	pub fn refusal(code: StatusCode) -> ResponseHead {
+		ResponseHead {
+			status: format!("{} {}", code.as_u16(), code.canonical_reason().unwrap_or("Error")),
+			code: code.as_u16(),
+			content_type: None,
+			length: None,
+			headers: HeaderMap::new(),
+			head: false,
+		}
+	}
+
+	/// The `Content-Type`, sent if the status allows a body.
+	pub fn content_type(mut self, content_type: &str) -> ResponseHead {
+		self.content_type = Some(content_type.to_owned());
+		self
+	}
+
+	/// The body length, sent as `Content-Length` if the status allows it.
+	/// None if we don't know.
+	pub fn length(mut self, length: Option<usize>) -> ResponseHead {
+		self.length = length;
+		self
+	}
+
+	/// Add some headers. Headers given here win over any we'd generate.
+	pub fn headers(mut self, headers: HeaderMap) -> ResponseHead {
+		for (name, value) in headers.iter() {
+			self.headers.append(name, value.clone());
+		}
+		self
+	}
+
+	/// Add a single header, replacing any of the same name.
+	pub fn header(mut self, name: &'static str, value: HeaderValue) -> ResponseHead {
+		self.headers.insert(name, value);
+		self
+	}
+
+	/// Say whether this answers a HEAD request. Those never have a body,
+	/// but any `Content-Length` header given is passed on as it is, as it
+	/// describes what a GET would have returned.
+	pub fn for_head(mut self, head: bool) -> ResponseHead {
+		self.head = head;
+		self
+	}
+
+	/// The numeric status code
+	pub fn code(&self) -> u16 {
+		self.code
+	}
+
+	/// Can this response have a body?
+	pub fn has_body(&self) -> bool {
+		!self.head && !no_body(self.code)
+	}
+
+	/// Render the head, ready to send. Header values are written as raw
+	/// bytes, so anything a `HeaderValue` can hold is fine, but we refuse
+	/// line breaks and NULs, a `Content-Length` which can't be right, body
+	/// lengths on statuses which don't have a body, and a 206 whose
+	/// `Content-Range` doesn't match the length being sent.
+	pub fn render(&self) -> Result<Vec<u8>, Error> {
+		let mut out = Vec::new();
+		out.extend_from_slice(format!("HTTP/1.1 {}\r\n", self.status).as_bytes());
+		for (name, value) in self.fields()? {
+			out.extend_from_slice(name.as_bytes());
+			out.extend_from_slice(b": ");
+			out.extend_from_slice(value.as_bytes());
+			out.extend_from_slice(b"\r\n");
+		}
+		out.extend_from_slice(b"\r\n");
+		Ok(out)
+	}
+
+	/// The header fields `render` would send, in order, after the same
+	/// checks. For HTTP/2, which carries the status separately.
+	pub fn fields(&self) -> Result<Vec<(String, HeaderValue)>, Error> {
+		let headers = &self.headers;
+		let bodiless = no_body(self.code);
+		if bodiless && self.length.map_or(false, |l| l != 0) {
+			return Err(Error::BadHeader);
+		}
+		if headers
+			.values()
+			.any(|v| v.as_bytes().iter().any(|&b| b == b'\r' || b == b'\n' || b == 0))
+		{
+			return Err(Error::BadHeader);
+		}
+		let has_te = headers.contains_key(header::TRANSFER_ENCODING);
+		if let Some(value) = headers.get(header::CONTENT_LENGTH) {
+			let given = value.to_str().ok().and_then(|v| v.trim().parse::<usize>().ok());
+			let ok = match (given, self.length) {
+				(None, _) => false,
+				// 1xx and 204 must never have one
+				_ if self.code < 200 || self.code == 204 => false,
+				_ if has_te => false,
+				// 304 and HEAD give the length of what a GET would have had
+				_ if self.code == 304 || self.head => true,
+				(Some(given), Some(length)) => given == length,
+				(Some(_), None) => true,
+			};
+			if !ok {
+				return Err(Error::BadHeader);
+			}
+		}
+		if has_te && (self.code < 200 || self.code == 204) {
+			return Err(Error::BadHeader);
+		}
+		self.check_range()?;
+		let content_type = match self.content_type {
+			Some(ref ct)
+				if !bodiless && !ct.is_empty() && !headers.contains_key(header::CONTENT_TYPE) =>
+			{
+				Some(HeaderValue::from_str(ct).map_err(|_| Error::BadHeader)?)
+			}
+			_ => None,
+		};
+
+		let mut fields = Vec::new();
+		if !headers.contains_key(header::SERVER) {
+			fields.push(("Server".to_owned(), HeaderValue::from_static(SERVER)));
+		}
+		if !headers.contains_key(header::DATE) {
+			let date = http_date(SystemTime::now());
+			fields.push(("Date".to_owned(), HeaderValue::from_str(&date).unwrap()));
+		}
+		let auto_length = !bodiless && !self.head && !has_te;
+		if auto_length && !headers.contains_key(header::CONTENT_LENGTH) {
+			if let Some(length) = self.length {
+				fields.push(("Content-Length".to_owned(), HeaderValue::from(length)));
+			}
+		}
+		if let Some(ct) = content_type {
+			fields.push(("Content-Type".to_owned(), ct));
+		}
+		for (name, value) in headers.iter() {
+			fields.push((name.as_str().to_owned(), value.clone()));
+		}
+		Ok(fields)
+	}
+}
+
+impl ResponseHead {
+	/// A 206 carries either one range, with a `Content-Range` saying which,
+	/// or several, as `multipart/byteranges`. A 416 says how long the
+	/// representation is.
+	fn check_range(&self) -> Result<(), Error> {
+		let range = self.headers.get(header::CONTENT_RANGE);
+		let value = match range.map(|v| v.to_str()) {
+			Some(Ok(value)) => Some(value),
+			Some(Err(_)) => return Err(Error::BadHeader),
+			None => None,
+		};
+		let multipart = self.headers
+			.get(header::CONTENT_TYPE)
+			.and_then(|v| v.to_str().ok())
+			.or_else(|| self.content_type.as_ref().map(|ct| &ct[..]))
+			.map_or(false, |ct| {
+				ct.trim_start().to_ascii_lowercase().starts_with("multipart/byteranges")
+			});
+		let ok = match (self.code, value) {
+			(206, Some(value)) if !multipart => match parse_content_range(value) {
+				// With HEAD, nothing is sent, so there's nothing to check
+				Some((first, last, _)) => {
+					self.head || self.length.map_or(true, |l| l as u64 == last - first + 1)
+				}
+				None => false,
+			},
+			(206, None) => multipart,
+			(206, Some(_)) => false,
+			(416, Some(value)) => {
+				value.starts_with("bytes */") && value[8..].bytes().all(|b| b.is_ascii_digit())
+					&& value.len() > 8
+			}
+			_ => true,
+		};
+		if ok {
+			Ok(())
+		} else {
+			Err(Error::BadHeader)
+		}
+	}
+}
+
+/// Format a time as an HTTP date (RFC 7231 IMF-fixdate), e.g.
+/// "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn http_date(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+	let days = secs / 86_400;
+	let secs_of_day = secs % 86_400;
+	let (year, month, day) = civil_date(days);
+	format!(
+		"{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+		DAYS[(days % 7) as usize],
+		day,
+		MONTHS[(month - 1) as usize],
+		year,
+		secs_of_day / 3_600,
+		(secs_of_day / 60) % 60,
+		secs_of_day % 60
+	)
+}
+
+/// Convert days since the epoch to a civil (year, month, day). Months
+/// count from 1.
+pub fn civil_date(days: u64) -> (u64, u64, u64) {
+	let z = days + 719_468;
+	let era = z / 146_097;
+	let doe = z - era * 146_097;
+	let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+/// Parse an HTTP date. We take the IMF-fixdate we send, plus the obsolete
+/// RFC 850 and asctime forms, as RFC 7231 says we must.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+	let fields: Vec<&str> = date.split_whitespace().collect();
+	let (day, month, year, time) = match fields.len() {
+		// Sun, 06 Nov 1994 08:49:37 GMT
+		6 if fields[0].ends_with(',') && fields[5] == "GMT" => {
+			(fields[1], fields[2], fields[3].parse().ok()?, fields[4])
+		}
+		// Sunday, 06-Nov-94 08:49:37 GMT
+		4 if fields[0].ends_with(',') && fields[3] == "GMT" => {
+			let mut parts = fields[1].split('-');
+			let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+			if parts.next().is_some() || year.len() != 2 {
+				return None;
+			}
+			let year: u64 = year.parse().ok()?;
+			// Close enough to RFC 7231's "more than 50 years in the future"
+			let year = if year < 70 { 2000 + year } else { 1900 + year };
+			(day, month, year, fields[2])
+		}
+		// Sun Nov  6 08:49:37 1994
+		5 => (fields[2], fields[1], fields[4].parse().ok()?, fields[3]),
+		_ => return None,
+	};
+	if day.is_empty() || day.len() > 2 || year < 1970 {
+		return None;
+	}
+	let day: u64 = day.parse().ok()?;
+	let month = MONTHS.iter().position(|&m| m == month)? as u64 + 1;
+	let mut hms = time.split(':').map(|t| if t.len() == 2 { t.parse().ok() } else { None });
+	let (hour, min, sec): (u64, u64, u64) = (hms.next()??, hms.next()??, hms.next()??);
+	if hms.next().is_some() || day < 1 || day > 31 || hour > 23 || min > 59 || sec > 60 {
+		return None;
+	}
+	// Convert a civil date to days since the epoch
+	let y = if month <= 2 { year - 1 } else { year };
+	let era = y / 400;
+	let yoe = y - era * 400;
+	let mp = if month > 2 { month - 3 } else { month + 9 };
+	let doy = (153 * mp + 2) / 5 + day - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	let days = era * 146_097 + doe - 719_468;
+	let secs = days * 86_400 + hour * 3_600 + min * 60 + sec;
+	Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Informational responses, 204 and 304 never have a body.
+fn no_body(code: u16) -> bool {
+	code < 200 || code == 204 || code == 304
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Render, check there's a sensible Date header, and take it out.
+	fn render(head: ResponseHead) -> Result<String, Error> {
+		let s = String::from_utf8(head.render()?).unwrap();
+		let start = s.find("Date: ").unwrap();
+		let end = start + s[start..].find("\r\n").unwrap() + 2;
+		assert_eq!(end - start, "Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n".len());
+		assert!(s[start..end].ends_with(" GMT\r\n"));
+		Ok(format!("{}{}", &s[..start], &s[end..]))
+	}
+
+	#[test]
+	fn dates() {
+		let at = |secs| http_date(UNIX_EPOCH + Duration::from_secs(secs));
+		assert_eq!(at(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+		assert_eq!(at(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+		assert_eq!(at(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+		assert_eq!(at(4_102_444_799), "Thu, 31 Dec 2099 23:59:59 GMT");
+	}
+
+	#[test]
+	fn parse_dates() {
+		let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+		assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), at(784_111_777));
+		assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), at(784_111_777));
+		assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), at(784_111_777));
+		assert_eq!(parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT"), at(951_782_400));
+		assert_eq!(parse_http_date("Thu, 31 Dec 2099 23:59:59 GMT"), at(4_102_444_799));
+		for secs in &[0, 68_169_600, 1_234_567_890, 1_700_000_000] {
+			let date = http_date(UNIX_EPOCH + Duration::from_secs(*secs));
+			assert_eq!(parse_http_date(&date), at(*secs), "{}", date);
+		}
+		for bad in &[
+			"",
+			"yesterday",
+			"Sun, 06 Nov 1994 08:49:37 UTC",
+			"Sun, 06 Foo 1994 08:49:37 GMT",
+			"Sun, 06 Nov 1994 8:49:37 GMT",
+			"Sun, 06 Nov 1994 24:00:00 GMT",
+			"Sun, 32 Nov 1994 08:49:37 GMT",
+			"Sun, 06 Nov 1969 08:49:37 GMT",
+			"Sunday, 06-Nov-1994 08:49:37 GMT",
+		] {
+			assert_eq!(parse_http_date(bad), None, "{}", bad);
+		}
+	}
+
+	#[test]
+	fn normal_heads() {
+		let mut headers = HeaderMap::new();
+		headers.insert("x-magic", "frobbins".parse().unwrap());
+		let head = ResponseHead::new(HttpResponseStatus::OK)
+			.content_type("text/plain")
+			.length(Some(5))
+			.headers(headers);
+		assert_eq!(
+			render(head).unwrap(),
+			"HTTP/1.1 200 OK\r\nServer: grease/http\r\nContent-Length: 5\r\n\
+			 Content-Type: text/plain\r\nx-magic: frobbins\r\n\r\n"
+		);
+
+		// User headers win
+		let mut headers = HeaderMap::new();
+		headers.insert("server", "mine".parse().unwrap());
+		headers.insert("content-type", "text/html".parse().unwrap());
+		headers.insert("date", "whenever".parse().unwrap());
+		let head = ResponseHead::new(HttpResponseStatus::OK)
+			.content_type("text/plain")
+			.headers(headers);
+		assert_eq!(
+			String::from_utf8(head.render().unwrap()).unwrap(),
+			"HTTP/1.1 200 OK\r\nserver: mine\r\ncontent-type: text/html\r\ndate: whenever\r\n\r\n"
+		);
+
+		// Values are written as bytes, not text
+		let mut headers = HeaderMap::new();
+		headers.insert("x-name", HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap());
+		let head = ResponseHead::new(HttpResponseStatus::OK).headers(headers);
+		let data = head.render().unwrap();
+		let expected = b"x-name: caf\xc3\xa9\r\n";
+		assert!(data.windows(expected.len()).any(|w| w == expected));
+	}
+
+	#[test]
+	fn bodiless_heads() {
+		let head = ResponseHead::new(HttpResponseStatus::NoContent)
+			.content_type("text/plain")
+			.length(Some(0));
+		assert_eq!(
+			render(head).unwrap(),
+			"HTTP/1.1 204 No Content\r\nServer: grease/http\r\n\r\n"
+		);
+		let head = ResponseHead::new(HttpResponseStatus::NotModified)
+			.content_type("text/plain")
+			.length(None);
+		assert_eq!(
+			render(head).unwrap(),
+			"HTTP/1.1 304 Not Modified\r\nServer: grease/http\r\n\r\n"
+		);
+		// A 304 may say how long the full response would have been
+		let mut headers = HeaderMap::new();
+		headers.insert("content-length", "1234".parse().unwrap());
+		let head = ResponseHead::new(HttpResponseStatus::NotModified)
+			.length(Some(0))
+			.headers(headers);
+		assert!(render(head).unwrap().contains("content-length: 1234\r\n"));
+		// So may a response to HEAD, but we don't make one up
+		let mut headers = HeaderMap::new();
+		headers.insert("content-length", "1234".parse().unwrap());
+		let head = ResponseHead::new(HttpResponseStatus::OK)
+			.for_head(true)
+			.content_type("text/plain")
+			.length(Some(0))
+			.headers(headers);
+		assert!(!head.has_body());
+		assert_eq!(
+			render(head).unwrap(),
+			"HTTP/1.1 200 OK\r\nServer: grease/http\r\nContent-Type: text/plain\r\n\
+			 content-length: 1234\r\n\r\n"
+		);
+		let head = ResponseHead::new(HttpResponseStatus::OK)
+			.for_head(true)
+			.length(Some(0));
+		assert!(!render(head).unwrap().contains("Content-Length"));
+		assert!(!ResponseHead::new(HttpResponseStatus::NoContent).has_body());
+		assert!(ResponseHead::new(HttpResponseStatus::NotFound).has_body());
+	}
+
+	#[test]
+	fn refusals() {
+		let head = ResponseHead::refusal(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+			.content_type("text/plain")
+			.length(Some(2));
+		assert_eq!(head.code(), 431);
+		assert!(render(head)
+			.unwrap()
+			.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+	}
+
+	#[test]
+	fn bad_heads() {
+		let bad = |head: ResponseHead| match head.render() {
+			Err(Error::BadHeader) => true,
+			_ => false,
+		};
+		// Body on a bodiless status
+		assert!(bad(ResponseHead::new(HttpResponseStatus::NoContent).length(Some(3))));
+		// Content-Length which disagrees
+		let mut headers = HeaderMap::new();
+		headers.insert("content-length", "4".parse().unwrap());
+		assert!(bad(ResponseHead::new(HttpResponseStatus::OK)
+			.length(Some(3))
+			.headers(headers.clone())));
+		assert!(bad(ResponseHead::new(HttpResponseStatus::NoContent).headers(headers)));
+		// Content-Length which isn't a number
+		let mut headers = HeaderMap::new();
+		headers.insert("content-length", "lots".parse().unwrap());
+		assert!(bad(ResponseHead::new(HttpResponseStatus::OK).headers(headers)));
+		// Content-Length and Transfer-Encoding
+		let mut headers = HeaderMap::new();
+		headers.insert("content-length", "4".parse().unwrap());
+		headers.insert("transfer-encoding", "chunked".parse().unwrap());
+		assert!(bad(ResponseHead::new(HttpResponseStatus::OK).headers(headers)));
+		// A content type which would break the head
+		assert!(bad(ResponseHead::new(HttpResponseStatus::OK).content_type("text/plain\r\nX: y")));
+	}
+
+	#[test]
+	fn range_heads() {
+		let partial = |range: Option<&str>, ct: &str, length: usize| {
+			let mut headers = HeaderMap::new();
+			if let Some(range) = range {
+				headers.insert("content-range", range.parse().unwrap());
+			}
+			ResponseHead::new(HttpResponseStatus::PartialContent)
+				.content_type(ct)
+				.length(Some(length))
+				.headers(headers)
+				.render()
+				.is_ok()
+		};
+		assert!(partial(Some("bytes 0-499/1234"), "text/plain", 500));
+		assert!(partial(Some("bytes 10-10/*"), "text/plain", 1));
+		// The bytes sent must match the range
+		assert!(!partial(Some("bytes 0-499/1234"), "text/plain", 499));
+		assert!(!partial(Some("bytes 0-499/1234"), "text/plain", 501));
+		assert!(!partial(Some("bytes 0-1234/1234"), "text/plain", 1235));
+		assert!(!partial(Some("bytes 0-499"), "text/plain", 500));
+		assert!(!partial(None, "text/plain", 500));
+		// Several ranges are multipart, and the parts say which is which
+		assert!(partial(None, "multipart/byteranges; boundary=x", 500));
+		assert!(!partial(Some("bytes 0-499/1234"), "multipart/byteranges; boundary=x", 500));
+
+		let unsatisfied = |range: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert("content-range", range.parse().unwrap());
+			ResponseHead::new(HttpResponseStatus::RequestedRangeNotSatisfiable)
+				.headers(headers)
+				.render()
+				.is_ok()
+		};
+		assert!(unsatisfied("bytes */1234"));
+		assert!(!unsatisfied("bytes */"));
+		assert!(!unsatisfied("bytes 0-1/1234"));
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
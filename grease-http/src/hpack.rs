@@ -0,0 +1,618 @@
+//! # hpack - RFC 7541 header compression for HTTP/2
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! The `Decoder` turns the header blocks a client sends into lists of
+//! fields. It keeps the dynamic table the client's encoder is building, so
+//! each connection needs its own, and every block on the connection must go
+//! through it in order - even those we're going to refuse.
+//!
+//! Our own blocks are simple. `encode` uses the static table where it can
+//! and otherwise sends literals which aren't added to the dynamic table, so
+//! the client never has to keep one for us. Nothing is Huffman coded.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::collections::VecDeque;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A header field, as a name and value
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Decodes the header blocks from one client
+pub struct Decoder {
+	/// The dynamic table, newest entry first
+	table: VecDeque<Field>,
+	/// The size of the table, as RFC 7541 counts it
+	size: usize,
+	/// The largest the client may make the table, as our SETTINGS say
+	max_size: usize,
+	/// The largest the client has said it will make the table
+	limit: usize,
+	/// The largest header list we'll decode, as RFC 7540 counts it
+	max_list_size: usize,
+	huffman: Huffman,
+}
+
+/// Why a header block couldn't be decoded. Any of these is fatal to the
+/// connection, as we can no longer follow the client's dynamic table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+	/// The block ends in the middle of a field
+	Truncated,
+	/// An index which isn't in either table
+	BadIndex,
+	/// A Huffman coded string with bad padding or an EOS in it
+	BadHuffman,
+	/// A table size update which is too big or in the wrong place
+	BadTableSize,
+	/// An integer too large for us to bother with
+	TooLong,
+	/// The fields add up to more than the decoder's `max_list_size`
+	ListTooLarge,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// The static table, from RFC 7541 appendix A. Index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+	(":authority", ""),
+	(":method", "GET"),
+	(":method", "POST"),
+	(":path", "/"),
+	(":path", "/index.html"),
+	(":scheme", "http"),
+	(":scheme", "https"),
+	(":status", "200"),
+	(":status", "204"),
+	(":status", "206"),
+	(":status", "304"),
+	(":status", "400"),
+	(":status", "404"),
+	(":status", "500"),
+	("accept-charset", ""),
+	("accept-encoding", "gzip, deflate"),
+	("accept-language", ""),
+	("accept-ranges", ""),
+	("accept", ""),
+	("access-control-allow-origin", ""),
+	("age", ""),
+	("allow", ""),
+	("authorization", ""),
+	("cache-control", ""),
+	("content-disposition", ""),
+	("content-encoding", ""),
+	("content-language", ""),
+	("content-length", ""),
+	("content-location", ""),
+	("content-range", ""),
+	("content-type", ""),
+	("cookie", ""),
+	("date", ""),
+	("etag", ""),
+	("expect", ""),
+	("expires", ""),
+	("from", ""),
+	("host", ""),
+	("if-match", ""),
+	("if-modified-since", ""),
+	("if-none-match", ""),
+	("if-range", ""),
+	("if-unmodified-since", ""),
+	("last-modified", ""),
+	("link", ""),
+	("location", ""),
+	("max-forwards", ""),
+	("proxy-authenticate", ""),
+	("proxy-authorization", ""),
+	("range", ""),
+	("referer", ""),
+	("refresh", ""),
+	("retry-after", ""),
+	("server", ""),
+	("set-cookie", ""),
+	("strict-transport-security", ""),
+	("transfer-encoding", ""),
+	("user-agent", ""),
+	("vary", ""),
+	("via", ""),
+	("www-authenticate", ""),
+];
+
+/// The length of each symbol's Huffman code, from RFC 7541 appendix B. The
+/// code is canonical, so the lengths are all we need. Symbol 256 is EOS.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+	13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+	28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+	6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+	5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+	13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+	7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+	15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+	6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+	20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+	24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+	22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+	21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+	26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+	19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+	20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+	26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+	30,
+];
+
+/// The longest Huffman code
+const MAX_CODE_LEN: usize = 30;
+
+/// The end of string symbol, which must never appear in a string
+const EOS: u16 = 256;
+
+/// Each entry costs this much on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Decodes canonical Huffman codes
+struct Huffman {
+	/// How many symbols have codes of each length
+	counts: [u16; MAX_CODE_LEN + 1],
+	/// The symbols in code order
+	symbols: Vec<u16>,
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Decoder {
+	/// A decoder for a client which may use a dynamic table of up to
+	/// `max_size`, the `SETTINGS_HEADER_TABLE_SIZE` we've given it, and
+	/// send header lists of up to `max_list_size`, its
+	/// `SETTINGS_MAX_HEADER_LIST_SIZE`.
+	pub fn new(max_size: usize, max_list_size: usize) -> Decoder {
+		Decoder {
+			table: VecDeque::new(),
+			size: 0,
+			max_size,
+			limit: max_size,
+			max_list_size,
+			huffman: Huffman::new(),
+		}
+	}
+
+	/// Decode one complete header block. A small block can refer to the
+	/// same big table entry over and over, so we stop once the fields add
+	/// up to more than `max_list_size`.
+	pub fn decode(&mut self, block: &[u8]) -> Result<Vec<Field>, DecodeError> {
+		let mut fields = Vec::new();
+		let mut list_size = 0;
+		let mut pos = 0;
+		while pos < block.len() {
+			let first = block[pos];
+			let field = if first & 0x80 != 0 {
+				// Indexed field
+				let index = integer(block, &mut pos, 7)?;
+				self.entry(index)?
+			} else if first & 0x40 != 0 {
+				// Literal, added to the table
+				let field = self.literal(block, &mut pos, 6)?;
+				self.insert(field.clone());
+				field
+			} else if first & 0x20 != 0 {
+				// Table size update, which must come before any fields
+				let size = integer(block, &mut pos, 5)?;
+				if size > self.max_size || !fields.is_empty() {
+					return Err(DecodeError::BadTableSize);
+				}
+				self.limit = size;
+				self.evict();
+				continue;
+			} else {
+				// Literal, not added to the table (perhaps never)
+				self.literal(block, &mut pos, 4)?
+			};
+			list_size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+			if list_size > self.max_list_size {
+				return Err(DecodeError::ListTooLarge);
+			}
+			fields.push(field);
+		}
+		Ok(fields)
+	}
+}
+
+/// Encode a list of fields as a header block. Names must be lower case.
+pub fn encode(fields: &[(&str, &[u8])]) -> Vec<u8> {
+	let mut out = Vec::new();
+	for &(name, value) in fields {
+		let exact = STATIC_TABLE
+			.iter()
+			.position(|&(n, v)| n == name && v.as_bytes() == value);
+		if let Some(i) = exact {
+			put_integer(&mut out, 0x80, 7, i + 1);
+			continue;
+		}
+		match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+			Some(i) => put_integer(&mut out, 0x00, 4, i + 1),
+			None => {
+				out.push(0x00);
+				put_string(&mut out, name.as_bytes());
+			}
+		}
+		put_string(&mut out, value);
+	}
+	out
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl Decoder {
+	/// A field from either table
+	fn entry(&self, index: usize) -> Result<Field, DecodeError> {
+		match index {
+			0 => Err(DecodeError::BadIndex),
+			i if i <= STATIC_TABLE.len() => {
+				let (name, value) = STATIC_TABLE[i - 1];
+				Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+			}
+			i => self.table
+				.get(i - STATIC_TABLE.len() - 1)
+				.cloned()
+				.ok_or(DecodeError::BadIndex),
+		}
+	}
+
+	/// A literal field, whose name may be indexed
+	fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<Field, DecodeError> {
+		let name = match integer(block, pos, prefix)? {
+			0 => self.string(block, pos)?,
+			index => self.entry(index)?.0,
+		};
+		Ok((name, self.string(block, pos)?))
+	}
+
+	/// A string literal, which may be Huffman coded
+	fn string(&self, block: &[u8], pos: &mut usize) -> Result<Vec<u8>, DecodeError> {
+		let huffman = block.get(*pos).ok_or(DecodeError::Truncated)? & 0x80 != 0;
+		let len = integer(block, pos, 7)?;
+		if block.len() - *pos < len {
+			return Err(DecodeError::Truncated);
+		}
+		let raw = &block[*pos..*pos + len];
+		*pos += len;
+		if huffman {
+			self.huffman.decode(raw)
+		} else {
+			Ok(raw.to_vec())
+		}
+	}
+
+	/// Add a field to the dynamic table, making room for it
+	fn insert(&mut self, field: Field) {
+		let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+		self.size += size;
+		self.table.push_front(field);
+		self.evict();
+	}
+
+	/// Throw out the oldest entries until the table fits its limit. An
+	/// entry which is too big on its own empties the table.
+	fn evict(&mut self) {
+		while self.size > self.limit {
+			match self.table.pop_back() {
+				Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+				None => break,
+			}
+		}
+	}
+}
+
+impl Huffman {
+	fn new() -> Huffman {
+		let mut counts = [0; MAX_CODE_LEN + 1];
+		for &len in HUFFMAN_LENGTHS.iter() {
+			counts[len as usize] += 1;
+		}
+		// The sort is stable, so symbols with the same length stay in order
+		let mut symbols: Vec<u16> = (0..=EOS).collect();
+		symbols.sort_by_key(|&s| HUFFMAN_LENGTHS[s as usize]);
+		Huffman { counts, symbols }
+	}
+
+	/// Decode a string a bit at a time. Codes of each length follow on
+	/// from those of the length before, so we only need to know where each
+	/// length starts. The string must be padded with (up to seven) ones.
+	fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+		let mut out = Vec::with_capacity(data.len() * 8 / 5);
+		let (mut code, mut first, mut index, mut len) = (0u32, 0u32, 0usize, 0usize);
+		// Whether the bits since the last symbol are all ones
+		let mut ones = true;
+		for byte in data {
+			for shift in (0..8).rev() {
+				let bit = u32::from(byte >> shift) & 1;
+				code |= bit;
+				len += 1;
+				ones &= bit == 1;
+				let count = u32::from(self.counts[len]);
+				if code < first + count {
+					let symbol = self.symbols[index + (code - first) as usize];
+					if symbol == EOS {
+						return Err(DecodeError::BadHuffman);
+					}
+					out.push(symbol as u8);
+					code = 0;
+					first = 0;
+					index = 0;
+					len = 0;
+					ones = true;
+				} else if len == MAX_CODE_LEN {
+					return Err(DecodeError::BadHuffman);
+				} else {
+					index += count as usize;
+					first = (first + count) << 1;
+					code <<= 1;
+				}
+			}
+		}
+		if len > 7 || !ones {
+			return Err(DecodeError::BadHuffman);
+		}
+		Ok(out)
+	}
+}
+
+/// Decode an integer with an N bit prefix, starting at `pos`
+fn integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, DecodeError> {
+	let max = (1usize << prefix) - 1;
+	let mut value = usize::from(*block.get(*pos).ok_or(DecodeError::Truncated)?) & max;
+	*pos += 1;
+	if value < max {
+		return Ok(value);
+	}
+	let mut shift = 0;
+	loop {
+		let byte = *block.get(*pos).ok_or(DecodeError::Truncated)?;
+		*pos += 1;
+		// Nothing we accept needs more than 28 bits
+		if shift > 21 {
+			return Err(DecodeError::TooLong);
+		}
+		value += usize::from(byte & 0x7F) << shift;
+		shift += 7;
+		if byte & 0x80 == 0 {
+			return Ok(value);
+		}
+	}
+}
+
+/// Encode an integer with an N bit prefix, after the bits in `flags`
+fn put_integer(out: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+	let max = (1usize << prefix) - 1;
+	if value < max {
+		out.push(flags | value as u8);
+		return;
+	}
+	out.push(flags | max as u8);
+	let mut value = value - max;
+	while value >= 0x80 {
+		out.push(0x80 | (value & 0x7F) as u8);
+		value >>= 7;
+	}
+	out.push(value as u8);
+}
+
+/// Encode a string literal, without Huffman coding
+fn put_string(out: &mut Vec<u8>, value: &[u8]) {
+	put_integer(out, 0x00, 7, value.len());
+	out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn hex(s: &str) -> Vec<u8> {
+		let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+		digits
+			.chunks(2)
+			.map(|pair| u8::from_str_radix(::std::str::from_utf8(pair).unwrap(), 16).unwrap())
+			.collect()
+	}
+
+	fn fields(list: &[(&str, &str)]) -> Vec<Field> {
+		list.iter()
+			.map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+			.collect()
+	}
+
+	#[test]
+	fn integers() {
+		// The examples from RFC 7541 appendix C.1
+		let mut out = Vec::new();
+		put_integer(&mut out, 0xE0, 5, 10);
+		put_integer(&mut out, 0xE0, 5, 1337);
+		put_integer(&mut out, 0x00, 8, 42);
+		assert_eq!(out, vec![0xEA, 0xFF, 0x9A, 0x0A, 0x2A]);
+		let mut pos = 0;
+		assert_eq!(integer(&out, &mut pos, 5), Ok(10));
+		assert_eq!(integer(&out, &mut pos, 5), Ok(1337));
+		assert_eq!(integer(&out, &mut pos, 8), Ok(42));
+		assert_eq!(pos, out.len());
+		let mut pos = 0;
+		assert_eq!(integer(&[0x1F, 0x9A], &mut pos, 5), Err(DecodeError::Truncated));
+		let mut pos = 0;
+		assert_eq!(
+			integer(&[0x1F, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F], &mut pos, 5),
+			Err(DecodeError::TooLong)
+		);
+	}
+
+	#[test]
+	fn requests() {
+		// RFC 7541 appendix C.3, then the same requests Huffman coded (C.4)
+		for &(first, second, third) in &[
+			(
+				"8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+				"8286 84be 5808 6e6f 2d63 6163 6865",
+				"8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+			),
+			(
+				"8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+				"8286 84be 5886 a8eb 1064 9cbf",
+				"8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+			),
+		] {
+			let mut d = Decoder::new(4096, 16 * 1024);
+			assert_eq!(
+				d.decode(&hex(first)),
+				Ok(fields(&[
+					(":method", "GET"),
+					(":scheme", "http"),
+					(":path", "/"),
+					(":authority", "www.example.com"),
+				]))
+			);
+			assert_eq!(d.size, 57);
+			assert_eq!(
+				d.decode(&hex(second)),
+				Ok(fields(&[
+					(":method", "GET"),
+					(":scheme", "http"),
+					(":path", "/"),
+					(":authority", "www.example.com"),
+					("cache-control", "no-cache"),
+				]))
+			);
+			assert_eq!(d.size, 110);
+			assert_eq!(
+				d.decode(&hex(third)),
+				Ok(fields(&[
+					(":method", "GET"),
+					(":scheme", "https"),
+					(":path", "/index.html"),
+					(":authority", "www.example.com"),
+					("custom-key", "custom-value"),
+				]))
+			);
+			assert_eq!(d.size, 164);
+			assert_eq!(d.table.len(), 3);
+		}
+	}
+
+	#[test]
+	fn eviction() {
+		// RFC 7541 appendix C.5, with a 256 byte table
+		let mut d = Decoder::new(256, 16 * 1024);
+		let first = "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 \
+		             7420 3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 \
+		             3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d";
+		assert_eq!(
+			d.decode(&hex(first)),
+			Ok(fields(&[
+				(":status", "302"),
+				("cache-control", "private"),
+				("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+				("location", "https://www.example.com"),
+			]))
+		);
+		assert_eq!(d.size, 222);
+		assert_eq!(
+			d.decode(&hex("4803 3330 37c1 c0bf")),
+			Ok(fields(&[
+				(":status", "307"),
+				("cache-control", "private"),
+				("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+				("location", "https://www.example.com"),
+			]))
+		);
+		assert_eq!(d.size, 222);
+		assert_eq!(d.table.len(), 4);
+		// Shrinking the table throws out what no longer fits
+		assert_eq!(d.decode(&hex("3f61")), Ok(Vec::new()));
+		assert_eq!(d.limit, 128);
+		assert_eq!(d.size, 105);
+		assert_eq!(d.decode(&hex("3fe1 1f")), Err(DecodeError::BadTableSize));
+		assert_eq!(d.decode(&hex("82 3f61")), Err(DecodeError::BadTableSize));
+	}
+
+	#[test]
+	fn bad_blocks() {
+		let mut d = Decoder::new(4096, 16 * 1024);
+		// Index 0, and one past the end of the tables
+		assert_eq!(d.decode(&hex("80")), Err(DecodeError::BadIndex));
+		assert_eq!(d.decode(&hex("be")), Err(DecodeError::BadIndex));
+		// A string longer than the block
+		assert_eq!(d.decode(&hex("0085 f2b2")), Err(DecodeError::Truncated));
+		// "a" padded with zeros rather than ones
+		assert_eq!(d.decode(&hex("0081 1881 18")), Err(DecodeError::BadHuffman));
+		// A whole byte of padding
+		assert_eq!(d.decode(&hex("0082 1fff 811f")), Err(DecodeError::BadHuffman));
+		// EOS
+		assert_eq!(d.decode(&hex("0084 ffff ffff 811f")), Err(DecodeError::BadHuffman));
+		assert_eq!(
+			d.decode(&hex("0081 1f81 1f")),
+			Ok(vec![(b"a".to_vec(), b"a".to_vec())])
+		);
+	}
+
+	#[test]
+	fn list_size() {
+		// A 200 byte value goes in the table once, then is referred to over
+		// and over with one byte each
+		let mut block = vec![0x40, 0x01, b'x', 0x7F, 0x49];
+		block.extend_from_slice(&[b'v'; 200]);
+		block.extend_from_slice(&[0xBE; 4]);
+		let mut d = Decoder::new(4096, 1024);
+		assert_eq!(d.decode(&block), Err(DecodeError::ListTooLarge));
+		let mut d = Decoder::new(4096, 2 * 1024);
+		assert_eq!(d.decode(&block).unwrap().len(), 5);
+		// The same entry is still there for the next block
+		assert_eq!(d.decode(&[0xBE; 8]).unwrap().len(), 8);
+		assert_eq!(d.decode(&[0xBE; 9]), Err(DecodeError::ListTooLarge));
+	}
+
+	#[test]
+	fn encoding() {
+		let long = vec![b'x'; 200];
+		let block = encode(&[
+			(":status", b"200"),
+			(":status", b"418"),
+			("content-length", b"5"),
+			("x-magic", b"frobbins"),
+			("x-long", &long),
+		]);
+		assert_eq!(&block[..6], b"\x88\x08\x03418");
+		assert_eq!(&block[6..9], b"\x0f\x0d\x01");
+		let mut d = Decoder::new(4096, 16 * 1024);
+		let decoded = d.decode(&block).unwrap();
+		assert_eq!(decoded[1], (b":status".to_vec(), b"418".to_vec()));
+		assert_eq!(decoded[3], (b"x-magic".to_vec(), b"frobbins".to_vec()));
+		assert_eq!(decoded[4], (b"x-long".to_vec(), long));
+		assert_eq!(decoded.len(), 5);
+		assert!(d.table.is_empty());
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -34,8 +34,107 @@
 //! the data anyway, using flow control properly saves memory - especially
 //! when sending large bodies.
 //!
-//! TODO: We need to support an `IndBody` / `RspBody` at some point, so that
-//! POST and PUT will actually work.
+//! If the request is a WebSocket upgrade (see
+//! `IndRxRequest::is_websocket_upgrade`), the user can send a `ReqWsAccept`
+//! instead of a `ReqResponseStart`. We then complete the RFC 6455 handshake
+//! and from then on the connection carries WebSocket messages rather than
+//! HTTP - `IndWsMessage` for messages from the client, and `ReqWsSend` for
+//! messages to it. The connection keeps its `ConnHandle`. Pings are answered
+//! automatically, fragmented messages are reassembled before they're
+//! indicated, and protocol errors from the client get the appropriate close
+//! code. Sending a `WsMessage::Close` starts the closing handshake; the
+//! socket is closed (and `IndClosed` sent) once the client replies.
+//!
+//! Responses can be compressed. If `ServerConfig::compress` is set (or
+//! `ReqResponseStart::compress` overrides it), and the client sent an
+//! `Accept-Encoding` we can satisfy, the body is passed through a gzip or
+//! deflate compressor as each `ReqResponseBody` arrives. The compressed
+//! length isn't known up front, so the `Content-Length` is dropped and the
+//! body is sent with chunked transfer encoding (or, for HTTP/1.0 clients,
+//! delimited by closing the connection). The `length` the user gives is
+//! still the uncompressed length.
+//!
+//! For Server-Sent Events, reply to the `IndRxRequest` with a
+//! `ReqSseStart` rather than a `ReqResponseStart`. That sends the
+//! `text/event-stream` headers, and each `ReqSseEvent` is then formatted
+//! and sent as one event. If a heartbeat interval is given, we send a
+//! comment line whenever the stream has been idle for that long, so that
+//! proxies don't time the connection out. When the client goes away you'll
+//! get an `IndClosed`. To end the stream from this side, send an empty
+//! `ReqResponseBody`.
+//!
+//! Some requests can be dealt with here if `ServerConfig::auto` asks for
+//! it. HEAD requests can be indicated as GET, with the body you send being
+//! thrown away. `OPTIONS *` can be answered from a fixed list of methods.
+//! Requests with `Expect: 100-continue` can be held until you send a
+//! `ReqContinue`, which either lets the client send the body or refuses it
+//! with a 417.
+//!
+//! Cross-origin requests can be handled too, by giving a `CorsConfig` in
+//! the `ServerConfig`. Preflights are then answered for you - with a 204
+//! and the `Access-Control-Allow-*` headers if the config allows them, or a
+//! 403 if not - and the `Access-Control-*` headers for anything else from
+//! an allowed origin are added to your response.
+//!
+//! For conditional and range requests, `IndRxRequest` has typed accessors
+//! for `If-Match`, `If-None-Match`, `If-Modified-Since`,
+//! `If-Unmodified-Since`, `If-Range` and `Range`. Given the resource's
+//! `ETag` and modification time, `IndRxRequest::preconditions` says whether
+//! to answer with a 304 or 412, and `IndRxRequest::select_ranges` says
+//! which ranges to send. A 206 is checked before it goes out - its
+//! `Content-Range` must match the length given in the `ReqResponseStart`
+//! (or it must be `multipart/byteranges`, see `ByteRanges`), and it is
+//! never compressed.
+//!
+//! Set `ServerConfig::access_log` to have every answered request logged,
+//! in Common Log Format, Combined Log Format or as JSON, to the `log`
+//! crate, a file or a channel. A record is written once the response has
+//! been sent (or the connection has gone), with the status, the number of
+//! body bytes sent and how long it took. Requests we refuse are logged too;
+//! requests which never get a response are not.
+//!
+//! Requests we refuse (because they're malformed, break the `Limits` and so
+//! on) get a short plain text page. `ServerConfig::errors` can give pages
+//! of your own for each status, or ask for an `IndError` so that you can
+//! send the response yourself. Either way the error goes out - if you don't
+//! answer the `IndError` in time, or answer it with something we can't
+//! send, we fall back to the page.
+//!
+//! If a response can't be finished - say the backend fails half way
+//! through a body whose length we've already sent - send a `ReqAbort`. The
+//! connection is reset rather than closed, so the client knows the
+//! transfer was broken.
+//!
+//! Request heads are parsed by our own `RequestParser`, which is strict
+//! about framing (conflicting or malformed `Content-Length` and
+//! `Transfer-Encoding` headers are refused) and maps each problem to a
+//! status via `ParseError::status`. It, the `ResponseParser`, the
+//! `ChunkedDecoder` and `encode_chunk` are public, for clients and proxies
+//! which want the same rules.
+//!
+//! A request body follows its `IndRxRequest` in `IndRxBody` indications,
+//! with any chunked framing stripped. If it's a form, a `FormDecoder` will
+//! split it into fields and file uploads as it arrives. If you pass bodies
+//! on to something slower than the client, set the server's
+//! `body_flow_control` and send a `RspRxBody` for each piece you've dealt
+//! with; we stop reading from the client until you do. On an HTTP/2 stream
+//! the client's flow control windows stay shut instead, so it can still
+//! send up to `Http2Config::initial_window_size` before it has to wait.
+//!
+//! Give a `Http2Config` in the `ServerConfig` and the server speaks HTTP/2
+//! over cleartext too, to clients which start with the HTTP/2 preface or
+//! ask for it with `Upgrade: h2c`. Each stream on the connection is a
+//! request with a `ConnHandle` of its own, so the messages are the same as
+//! for HTTP/1 - only `IndRxRequest::version` tells them apart. WebSockets,
+//! Server-Sent Events and `ReqContinue` aren't available on streams, and
+//! requests refused there get the error page straight away rather than an
+//! `IndError`.
+//!
+//! To take a server out of service, send a `ReqDrain`. The listening socket
+//! and any idle connections are closed, the requests in progress are left
+//! to finish, and the
+//! `CfmDrain` comes once the last connection has gone - or, if some are
+//! still open at the deadline, once those have been reset.
 
 #![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
 #![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
@@ -50,20 +149,54 @@
 
 #[macro_use]
 extern crate grease;
+extern crate base64;
+extern crate flate2;
 extern crate grease_socket as socket;
+extern crate http;
 #[macro_use]
 extern crate log;
 extern crate multi_map;
-extern crate rushttp;
+extern crate sha1;
 
-use std::collections::HashMap;
+mod access_log;
+mod chunked;
+mod conditional;
+mod cors;
+mod form;
+mod h2;
+mod head;
+mod hpack;
+mod parser;
+mod status;
+mod uri;
+mod vhost;
+mod websocket;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
 use std::net;
+use std::time::{Duration, Instant, SystemTime};
 
 use multi_map::MultiMap;
 use std::sync::mpsc;
 
-pub use rushttp::response::HttpResponseStatus;
-pub use rushttp::{HeaderMap, Method, Uri};
+pub use access_log::{AccessLog, AccessRecord, LogFormat, LogSink};
+pub use chunked::{encode_chunk, ChunkedDecoder};
+pub use conditional::{content_range, parse_content_range, preconditions, select_ranges,
+                      unsatisfied_range, ByteRange, ByteRanges, EntityTag, EntityTagMatch,
+                      IfRange, Precondition, RangeOutcome, MAX_RANGES};
+pub use cors::{CorsConfig, CorsOrigins};
+pub use form::{FormDecoder, FormError, FormEvent, FormLimits, FormPart};
+pub use head::{http_date, parse_http_date};
+pub use parser::{BodyLength, Header, ParseError, ParseLimits, Parsed, RequestHead, RequestParser,
+                 ResponseParser, StatusHead};
+pub use status::HttpResponseStatus;
+pub use uri::{decode_path, decode_query, QueryParams, UriError};
+pub use vhost::{request_host, HostPattern};
+pub use http::header::HeaderValue;
+pub use http::{HeaderMap, Method, StatusCode, Uri, Version};
+pub use websocket::{WS_CLOSE_GOING_AWAY, WS_CLOSE_INVALID_DATA, WS_CLOSE_NORMAL,
+                    WS_CLOSE_PROTOCOL_ERROR, WS_CLOSE_TOO_BIG};
 
 use grease::Context;
 
@@ -80,7 +213,7 @@ impl grease::Service for Service {
 	type Request = Request;
 	type Confirm = Confirm;
 	type Indication = Indication;
-	type Response = ();
+	type Response = Response;
 }
 
 /// Requests that can be sent to the http task.
@@ -92,11 +225,38 @@ pub enum Request {
 	ResponseStart(ReqResponseStart),
 	/// Send some body content for an HTTP response
 	ResponseBody(ReqResponseBody),
+	/// Accept a WebSocket upgrade request
+	WsAccept(ReqWsAccept),
+	/// Send a message on a WebSocket
+	WsSend(ReqWsSend),
+	/// Start a Server-Sent Events stream
+	SseStart(ReqSseStart),
+	/// Send an event on a Server-Sent Events stream
+	SseEvent(ReqSseEvent),
+	/// Serve a virtual host on a bound server
+	AddHost(ReqAddHost),
+	/// Stop serving a virtual host
+	RemoveHost(ReqRemoveHost),
+	/// Answer a request which is waiting for a `100 Continue`
+	Continue(ReqContinue),
+	/// Give up on a response and reset the connection
+	Abort(ReqAbort),
+	/// Stop a server taking new connections, and wait for the ones it has
+	Drain(ReqDrain),
 }
 
 make_wrapper!(ReqBind, Request, Request::Bind);
 make_wrapper!(ReqResponseStart, Request, Request::ResponseStart);
 make_wrapper!(ReqResponseBody, Request, Request::ResponseBody);
+make_wrapper!(ReqWsAccept, Request, Request::WsAccept);
+make_wrapper!(ReqWsSend, Request, Request::WsSend);
+make_wrapper!(ReqSseStart, Request, Request::SseStart);
+make_wrapper!(ReqSseEvent, Request, Request::SseEvent);
+make_wrapper!(ReqAddHost, Request, Request::AddHost);
+make_wrapper!(ReqRemoveHost, Request, Request::RemoveHost);
+make_wrapper!(ReqContinue, Request, Request::Continue);
+make_wrapper!(ReqAbort, Request, Request::Abort);
+make_wrapper!(ReqDrain, Request, Request::Drain);
 
 /// Confirms that must be sent back to the http task.
 #[derive(Debug)]
@@ -107,11 +267,38 @@ pub enum Confirm {
 	ResponseStart(CfmResponseStart),
 	/// Confirms a ReqResponseBody has been sent
 	ResponseBody(CfmResponseBody),
+	/// Whether the ReqWsAccept was successful
+	WsAccept(CfmWsAccept),
+	/// Confirms a ReqWsSend has been sent
+	WsSend(CfmWsSend),
+	/// Whether the ReqSseStart was successful
+	SseStart(CfmSseStart),
+	/// Confirms a ReqSseEvent has been sent
+	SseEvent(CfmSseEvent),
+	/// Whether the ReqAddHost was successful
+	AddHost(CfmAddHost),
+	/// Whether the ReqRemoveHost was successful
+	RemoveHost(CfmRemoveHost),
+	/// Whether the ReqContinue was successful
+	Continue(CfmContinue),
+	/// Whether the ReqAbort was successful
+	Abort(CfmAbort),
+	/// The server given in a ReqDrain has gone
+	Drain(CfmDrain),
 }
 
 make_wrapper!(CfmBind, Confirm, Confirm::Bind);
 make_wrapper!(CfmResponseStart, Confirm, Confirm::ResponseStart);
 make_wrapper!(CfmResponseBody, Confirm, Confirm::ResponseBody);
+make_wrapper!(CfmWsAccept, Confirm, Confirm::WsAccept);
+make_wrapper!(CfmWsSend, Confirm, Confirm::WsSend);
+make_wrapper!(CfmSseStart, Confirm, Confirm::SseStart);
+make_wrapper!(CfmSseEvent, Confirm, Confirm::SseEvent);
+make_wrapper!(CfmAddHost, Confirm, Confirm::AddHost);
+make_wrapper!(CfmRemoveHost, Confirm, Confirm::RemoveHost);
+make_wrapper!(CfmContinue, Confirm, Confirm::Continue);
+make_wrapper!(CfmAbort, Confirm, Confirm::Abort);
+make_wrapper!(CfmDrain, Confirm, Confirm::Drain);
 
 /// Indications that come out of the http task.
 #[derive(Debug)]
@@ -120,10 +307,28 @@ pub enum Indication {
 	RxRequest(IndRxRequest),
 	/// An HTTP connection has been dropped
 	Closed(IndClosed),
+	/// A message has arrived on a WebSocket
+	WsMessage(IndWsMessage),
+	/// We're refusing a request, and want you to say how
+	Error(IndError),
+	/// Some of a request's body has been received
+	RxBody(IndRxBody),
 }
 
 make_wrapper!(IndRxRequest, Indication, Indication::RxRequest);
 make_wrapper!(IndClosed, Indication, Indication::Closed);
+make_wrapper!(IndWsMessage, Indication, Indication::WsMessage);
+make_wrapper!(IndError, Indication, Indication::Error);
+make_wrapper!(IndRxBody, Indication, Indication::RxBody);
+
+/// Responses that can be sent to the http task.
+#[derive(Debug)]
+pub enum Response {
+	/// Unblocks a request body so more IndRxBody can be sent
+	RxBody(RspRxBody),
+}
+
+make_wrapper!(RspRxBody, Response, Response::RxBody);
 
 /// A bind request - start an HTTP server on a given port.
 #[derive(Debug)]
@@ -132,11 +337,17 @@ pub struct ReqBind {
 	pub addr: net::SocketAddr,
 	/// Reflected back in the cfm, and in subsequent IndRxRequest
 	pub context: Context,
+	/// How this server should behave. `ServerConfig::default()` is fine
+	/// for most uses.
+	pub config: ServerConfig,
 }
 
-/// Send the headers for an HTTP response. Host, Content-Length
+/// Send the headers for an HTTP response. Server, Date, Content-Length
 /// and Content-Type are automatically added from the relevant fields
-/// but you can add arbitrary other headers in the header vector.
+/// (Content-Length and Content-Type only if the status allows a body)
+/// but you can add arbitrary other headers in the header vector. If the
+/// headers don't make sense, e.g. a Content-Length which doesn't match
+/// `length`, the cfm will have `Error::BadHeader` and nothing is sent.
 ///
 /// Send zero or more `ReqResponseBody` to fulfill the length specified.
 /// (Wait for `CfmResponseBody` between each one as the link might be slow).
@@ -157,6 +368,10 @@ pub struct ReqResponseStart {
 	pub length: Option<usize>,
 	/// Any other headers required.
 	pub headers: HeaderMap,
+	/// Whether to compress this response, if the client allows it. None
+	/// means use the server's `ServerConfig::compress` setting. Responses
+	/// with a `Content-Encoding` header are never compressed.
+	pub compress: Option<bool>,
 }
 
 /// Send some body content for an HTTP response. Must be proceeded
@@ -171,6 +386,137 @@ pub struct ReqResponseBody {
 	pub data: Vec<u8>,
 }
 
+/// Accept a WebSocket upgrade. Send this instead of `ReqResponseStart` in
+/// reply to an `IndRxRequest` for which `is_websocket_upgrade()` is true.
+/// `IndWsMessage`s for this connection will be sent to whoever sends this.
+#[derive(Debug)]
+pub struct ReqWsAccept {
+	/// Which HTTP connection to upgrade
+	pub handle: ConnHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// The subprotocol (from the client's `Sec-WebSocket-Protocol` list)
+	/// to use, if any.
+	pub protocol: Option<String>,
+}
+
+/// Send a message on an upgraded connection. Sending a `WsMessage::Close`
+/// starts the closing handshake, after which nothing else can be sent.
+#[derive(Debug)]
+pub struct ReqWsSend {
+	/// Which WebSocket to send on
+	pub handle: ConnHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// The message to send
+	pub message: WsMessage,
+}
+
+/// Start a Server-Sent Events stream. Send this instead of
+/// `ReqResponseStart` in reply to an `IndRxRequest`.
+#[derive(Debug)]
+pub struct ReqSseStart {
+	/// Which HTTP connection to start the stream on
+	pub handle: ConnHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Any other headers required. We add `Content-Type` and
+	/// `Cache-Control`.
+	pub headers: HeaderMap,
+	/// If set, send a comment whenever nothing has been sent for this long
+	pub heartbeat: Option<Duration>,
+}
+
+/// Send one event on a stream started with `ReqSseStart`.
+#[derive(Debug)]
+pub struct ReqSseEvent {
+	/// Which stream to send the event on
+	pub handle: ConnHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Sets the client's last event ID. Must not contain line breaks.
+	pub id: Option<String>,
+	/// The event type. None means "message". Must not contain line breaks.
+	pub event: Option<String>,
+	/// The event data. Can span multiple lines.
+	pub data: String,
+	/// Tells the client how long to wait (in milliseconds) before
+	/// reconnecting
+	pub retry: Option<u32>,
+}
+
+/// Serve a virtual host on a server you have bound. Requests whose host
+/// matches `pattern` are indicated to whoever sent this, rather than to
+/// whoever bound the server. A pattern is a host name
+/// (`"www.example.com"`), any subdomain of a domain (`"*.example.com"`) or
+/// `"*"` for anything. Port numbers are ignored and the most specific
+/// pattern wins.
+#[derive(Debug)]
+pub struct ReqAddHost {
+	/// The server to add the host to
+	pub server: ServerHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Which hosts to serve
+	pub pattern: String,
+}
+
+/// Stop serving a virtual host. Connections already indicated are not
+/// affected.
+#[derive(Debug)]
+pub struct ReqRemoveHost {
+	/// The handle from the `CfmAddHost`
+	pub handle: HostHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+}
+
+/// Answer a request with `Expect: 100-continue`, if the server has
+/// `AutoResponses::expect_continue` set. Accepting sends `100 Continue` so
+/// the client sends the body. Refusing sends `417 Expectation Failed` and
+/// closes the connection. You can also skip this and go straight to a
+/// `ReqResponseStart`.
+#[derive(Debug)]
+pub struct ReqContinue {
+	/// The connection from the `IndRxRequest`
+	pub handle: ConnHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Whether we want the body
+	pub accept: bool,
+}
+
+/// Give up on a connection, whatever state it's in - e.g. because the
+/// backend failed half way through a response. Body data we haven't sent
+/// yet is thrown away, and the socket is reset, so the client sees a
+/// broken transfer rather than waiting for the rest. Any confirms still
+/// outstanding fail with `Error::Aborted`, and whoever has the connection
+/// gets an `IndClosed`.
+#[derive(Debug)]
+pub struct ReqAbort {
+	/// The connection from the `IndRxRequest`
+	pub handle: ConnHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+}
+
+/// Stop a server taking new connections, e.g. for a rolling deploy. HTTP/1
+/// connections which haven't sent a request yet are closed straight away.
+/// The rest carry on: HTTP/1 responses started from now on say
+/// `Connection: close`, and HTTP/2 clients are sent a GOAWAY so they
+/// finish the requests they have open and start no more. Once every
+/// connection has gone, the server goes too, and you get the `CfmDrain`.
+/// Any still open at the deadline are reset, with an `IndClosed` for each.
+#[derive(Debug)]
+pub struct ReqDrain {
+	/// The handle from the `CfmBind`
+	pub handle: ServerHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// How long to wait for the connections to finish
+	pub deadline: Duration,
+}
+
 /// Whether the `ReqBind` was successfull
 #[derive(Debug)]
 pub struct CfmBind {
@@ -194,6 +540,79 @@ pub struct CfmResponseBody {
 	pub result: Result<(), Error>,
 }
 
+/// Whether the `ReqWsAccept` was successful. If so, the connection is now
+/// a WebSocket.
+#[derive(Debug)]
+pub struct CfmWsAccept {
+	pub handle: ConnHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// Confirms a `ReqWsSend` has been sent
+#[derive(Debug)]
+pub struct CfmWsSend {
+	pub handle: ConnHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// Whether the `ReqSseStart` was successful
+#[derive(Debug)]
+pub struct CfmSseStart {
+	pub handle: ConnHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// Confirms a `ReqSseEvent` has been sent
+#[derive(Debug)]
+pub struct CfmSseEvent {
+	pub handle: ConnHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// Whether the `ReqAddHost` was successful
+#[derive(Debug)]
+pub struct CfmAddHost {
+	pub context: Context,
+	pub result: Result<HostHandle, Error>,
+}
+
+/// Whether the `ReqRemoveHost` was successful
+#[derive(Debug)]
+pub struct CfmRemoveHost {
+	pub handle: HostHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// Whether the `ReqContinue` was successful
+#[derive(Debug)]
+pub struct CfmContinue {
+	pub handle: ConnHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// Whether the `ReqAbort` was successful
+#[derive(Debug)]
+pub struct CfmAbort {
+	pub handle: ConnHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// The server given in a `ReqDrain` has gone, and its handle is no longer
+/// valid. `Error::DrainTimeout` if connections had to be reset.
+#[derive(Debug)]
+pub struct CfmDrain {
+	pub handle: ServerHandle,
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
 /// A new HTTP request has been received
 #[derive(Debug)]
 pub struct IndRxRequest {
@@ -201,7 +620,41 @@ pub struct IndRxRequest {
 	pub connection_handle: ConnHandle,
 	pub url: Uri,
 	pub method: Method,
+	pub version: Version,
 	pub headers: HeaderMap,
+	/// The address of the far end of the TCP connection
+	pub peer: net::SocketAddr,
+	/// Our end of the TCP connection
+	pub local: net::SocketAddr,
+	/// The address of the client that originated the request. This is the
+	/// IP address of `peer`, unless `peer` is one of the server's
+	/// `trusted_proxies`, in which case it comes from the `Forwarded` or
+	/// `X-Forwarded-For` header.
+	pub client: net::IpAddr,
+	/// The virtual host this request matched, or None if it went to
+	/// whoever bound the server
+	pub host: Option<HostHandle>,
+	/// Whether a body follows, in one or more `IndRxBody`
+	pub body: bool,
+}
+
+/// Some of the body of a request you've had an `IndRxRequest` for. The
+/// body arrives in order, with any chunked transfer coding removed, and
+/// `last` is set on the final piece. A request without a body (see
+/// `IndRxRequest::has_body`) gets no `IndRxBody` at all. If the server's
+/// `ServerConfig::body_flow_control` is set, send a `RspRxBody` after each
+/// one (unless `last` is set) to get the next.
+#[derive(Debug)]
+pub struct IndRxBody {
+	pub handle: ConnHandle,
+	pub data: Vec<u8>,
+	pub last: bool,
+}
+
+/// Tell the task that more of a request body can now be sent.
+#[derive(Debug)]
+pub struct RspRxBody {
+	pub handle: ConnHandle,
 }
 
 /// An HTTP connection has been dropped
@@ -210,6 +663,37 @@ pub struct IndClosed {
 	pub handle: ConnHandle,
 }
 
+/// We're refusing a request, and the server's `ErrorResponses::indicate`
+/// is set. Answer it with a `ReqResponseStart` on `connection_handle`,
+/// giving `status`, and then the body as usual; the connection is closed
+/// once the response has gone. If you don't start a response in time, or
+/// the one you start can't be sent, we send the error page ourselves. You
+/// may already have had an `IndRxRequest` for this connection. Statuses
+/// with no `HttpResponseStatus` (421 and 431) can only get our page.
+#[derive(Debug)]
+pub struct IndError {
+	pub server_handle: ServerHandle,
+	pub connection_handle: ConnHandle,
+	/// What we're refusing the request with
+	pub status: StatusCode,
+	/// The request line, if we got that far
+	pub method: Option<Method>,
+	pub url: Option<Uri>,
+	/// The address of the far end of the TCP connection
+	pub peer: net::SocketAddr,
+	/// The address of the client, as in `IndRxRequest::client`
+	pub client: net::IpAddr,
+}
+
+/// A message has arrived on a WebSocket. Pings are answered for you, but
+/// are still indicated. If this is a `WsMessage::Close`, the closing
+/// handshake has been completed for you and an `IndClosed` will follow.
+#[derive(Debug)]
+pub struct IndWsMessage {
+	pub handle: ConnHandle,
+	pub message: WsMessage,
+}
+
 // ****************************************************************************
 //
 // Public Types
@@ -223,6 +707,142 @@ pub struct Handle(mpsc::Sender<Incoming>);
 /// A new one of these is allocated for every new HTTP server
 pub type ServerHandle = Context;
 
+/// A new one of these is allocated for every virtual host
+pub type HostHandle = Context;
+
+/// Per-server settings, given in `ReqBind`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+	/// Compress response bodies with gzip or deflate when the client
+	/// accepts it. Media which is normally compressed already (images,
+	/// audio, video, archives) is left alone unless the response asks for
+	/// compression explicitly.
+	pub compress: bool,
+	/// Reverse proxies in front of this server. Requests arriving from
+	/// these addresses have their client address taken from the
+	/// `Forwarded` (or failing that, `X-Forwarded-For`) header. Empty by
+	/// default, in which case those headers are ignored.
+	pub trusted_proxies: Vec<net::IpAddr>,
+	/// Limits on what clients can send us
+	pub limits: Limits,
+	/// How to answer requests which match none of the server's virtual
+	/// hosts. None (the default) indicates them to whoever bound the
+	/// server; otherwise they are refused with this status, e.g.
+	/// `StatusCode::MISDIRECTED_REQUEST`.
+	pub unknown_host: Option<StatusCode>,
+	/// Requests we can deal with for you
+	pub auto: AutoResponses,
+	/// Where to record each request the server answers. None (the default)
+	/// for no access log.
+	pub access_log: Option<AccessLog>,
+	/// How to answer the requests we refuse
+	pub errors: ErrorResponses,
+	/// How to answer cross-origin requests. None (the default) leaves
+	/// them, and their preflights, to whoever bound the server.
+	pub cors: Option<CorsConfig>,
+	/// Read request bodies only as fast as they're used: after each
+	/// `IndRxBody`, wait for a `RspRxBody` before reading any more of the
+	/// body. Time spent waiting doesn't count towards the
+	/// `Limits::body_timeout`. False (the default) reads bodies as fast as
+	/// they arrive.
+	pub body_flow_control: bool,
+	/// Accept HTTP/2 over cleartext, from clients which start with it or
+	/// ask for it with `Upgrade: h2c`. None (the default) for HTTP/1 only.
+	pub http2: Option<Http2Config>,
+}
+
+/// How a server speaks HTTP/2
+#[derive(Debug, Clone)]
+pub struct Http2Config {
+	/// How many requests a client may have open at once on a connection.
+	/// Any more are refused, and the client can try them again later.
+	pub max_concurrent_streams: u32,
+	/// How much of each request body a client may send before it hears
+	/// from us. Values below the protocol's default of 65,535 are raised
+	/// to it.
+	pub initial_window_size: u32,
+}
+
+/// How to answer the requests we refuse, e.g. with a 400 because they
+/// couldn't be parsed, or a 413 because they broke the `Limits`. By default
+/// they get a short plain text page.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorResponses {
+	/// Pages to send instead, by status. These are checked when the server
+	/// is bound.
+	pub pages: HashMap<StatusCode, ErrorPage>,
+	/// If set, send an `IndError` to whoever bound the server (or had the
+	/// request), so they can send a page of their own. If they haven't
+	/// started one within this long, we send ours.
+	pub indicate: Option<Duration>,
+}
+
+/// A custom error page
+#[derive(Debug, Clone)]
+pub struct ErrorPage {
+	pub content_type: String,
+	pub body: Vec<u8>,
+	/// Any extra headers. We add `Content-Type`, `Content-Length` and
+	/// `Connection`, so those (and `Transfer-Encoding`) can't be given.
+	pub headers: HeaderMap,
+}
+
+/// Requests the http task can deal with itself. All off by default.
+#[derive(Debug, Clone, Default)]
+pub struct AutoResponses {
+	/// Indicate HEAD requests as GET. Respond as you would to a GET - the
+	/// head goes out with its `Content-Length`, and the body you send is
+	/// confirmed but thrown away.
+	pub head: bool,
+	/// Answer `OPTIONS *` with an `Allow` header listing `allow`, HEAD if
+	/// `head` is on, and OPTIONS.
+	pub options: bool,
+	/// The methods the server supports, for `options`
+	pub allow: Vec<Method>,
+	/// Hold on to requests with `Expect: 100-continue` until you send a
+	/// `ReqContinue`, and refuse any other expectation with a 417.
+	/// Otherwise, the `Expect` header is passed up and ignored.
+	pub expect_continue: bool,
+}
+
+/// Limits on the requests a server will accept. A request which breaks
+/// them gets an error response (431, 414, 413 or 408) and the connection
+/// is closed. If you've already had the `IndRxRequest`, you'll get an
+/// `IndClosed`.
+#[derive(Debug, Clone)]
+pub struct Limits {
+	/// Maximum size of the request line and headers, in bytes
+	pub max_header_bytes: usize,
+	/// Maximum number of header lines
+	pub max_headers: usize,
+	/// Maximum length of the request URI, in bytes
+	pub max_uri_len: usize,
+	/// Maximum size of the request body, in bytes. For chunked bodies this
+	/// includes the chunk framing.
+	pub max_body: usize,
+	/// How long a client has to send the request line and headers, from
+	/// when it connects
+	pub header_timeout: Option<Duration>,
+	/// How long a client has to send the request body, from when the
+	/// headers arrive. Stops once you start a response.
+	pub body_timeout: Option<Duration>,
+}
+
+/// A WebSocket message
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsMessage {
+	/// A UTF-8 text message
+	Text(String),
+	/// A binary message
+	Binary(Vec<u8>),
+	/// A ping, with up to 125 bytes of payload
+	Ping(Vec<u8>),
+	/// A pong, with up to 125 bytes of payload
+	Pong(Vec<u8>),
+	/// A close, with an optional code (e.g. `WS_CLOSE_NORMAL`) and reason
+	Close(Option<u16>, String),
+}
+
 /// A new one of these is allocated for every new HTTP request
 pub type ConnHandle = Context;
 
@@ -236,6 +856,181 @@ pub enum Error {
 	BadHandle,
 	/// Socket bind failed,
 	Socket(socket::SocketError),
+	/// A `ReqWsAccept` was sent for a request that wasn't a WebSocket
+	/// upgrade
+	NotWebSocket,
+	/// A `ReqWsSend` or `ReqSseEvent` had a message we can't send, e.g. a
+	/// ping with too much payload or an event ID with a line break in it
+	BadMessage,
+	/// A `ReqAddHost` had a pattern we don't understand
+	BadPattern,
+	/// A `ReqAddHost` had a pattern which is already in use on that server
+	DuplicateHost,
+	/// A response had a header we can't send, or one which contradicts
+	/// the status or length (e.g. a body on a 204)
+	BadHeader,
+	/// A `ReqContinue` was sent for a request which isn't waiting for one
+	NotExpecting,
+	/// The access log file given in the `ServerConfig` couldn't be opened
+	LogFile,
+	/// An `ErrorPage` in the `ServerConfig` isn't for an error status, or
+	/// has a header we can't send
+	BadErrorPage,
+	/// The `CorsConfig` in the `ServerConfig` is inconsistent (say it
+	/// allows any origin with credentials) or has a bad origin or header
+	/// name
+	BadCors,
+	/// The connection was given up on with a `ReqAbort` before this could
+	/// be done
+	Aborted,
+	/// A `ReqDrain`'s deadline passed with connections still open
+	DrainTimeout,
+}
+
+impl Default for Http2Config {
+	fn default() -> Http2Config {
+		Http2Config {
+			max_concurrent_streams: 100,
+			initial_window_size: 65_535,
+		}
+	}
+}
+
+impl Default for Limits {
+	fn default() -> Limits {
+		Limits {
+			max_header_bytes: 16 * 1024,
+			max_headers: 100,
+			max_uri_len: 8 * 1024,
+			max_body: 16 * 1024 * 1024,
+			header_timeout: Some(Duration::from_secs(30)),
+			body_timeout: Some(Duration::from_secs(60)),
+		}
+	}
+}
+
+impl IndRxRequest {
+	/// Is this a valid WebSocket upgrade request? If so, you can send a
+	/// `ReqWsAccept` to accept it.
+	pub fn is_websocket_upgrade(&self) -> bool {
+		websocket::upgrade_key(&self.method, &self.headers).is_some()
+	}
+
+	/// Is the client waiting for a `100 Continue` before it sends the body?
+	/// If the server has `AutoResponses::expect_continue`, answer with a
+	/// `ReqContinue`.
+	pub fn expects_continue(&self) -> bool {
+		expects_continue(self.version, &self.headers)
+	}
+
+	/// Does the request have a body? If so, it will follow in one or more
+	/// `IndRxBody`.
+	pub fn has_body(&self) -> bool {
+		self.body
+	}
+
+	/// The request path, percent-decoded and normalised (see
+	/// `decode_path`). Fails for paths with bad escapes, `..` segments or
+	/// encoded slashes, which are best refused with a 400.
+	pub fn path(&self) -> Result<String, UriError> {
+		decode_path(self.url.path())
+	}
+
+	/// The decoded query string parameters, if any
+	pub fn query(&self) -> Result<QueryParams, UriError> {
+		decode_query(self.url.query().unwrap_or(""))
+	}
+
+	/// Make a `FormDecoder` for the body, if it's a form
+	pub fn form_decoder(&self, limits: FormLimits) -> Result<FormDecoder, FormError> {
+		FormDecoder::new(&self.headers, limits)
+	}
+
+	/// The `If-Match` header, if there is a valid one
+	pub fn if_match(&self) -> Option<EntityTagMatch> {
+		conditional::tag_match(&self.headers, http::header::IF_MATCH)
+	}
+
+	/// The `If-None-Match` header, if there is a valid one
+	pub fn if_none_match(&self) -> Option<EntityTagMatch> {
+		conditional::tag_match(&self.headers, http::header::IF_NONE_MATCH)
+	}
+
+	/// The `If-Modified-Since` header, if there is a valid one
+	pub fn if_modified_since(&self) -> Option<SystemTime> {
+		self.header_str(http::header::IF_MODIFIED_SINCE)
+			.and_then(parse_http_date)
+	}
+
+	/// The `If-Unmodified-Since` header, if there is a valid one
+	pub fn if_unmodified_since(&self) -> Option<SystemTime> {
+		self.header_str(http::header::IF_UNMODIFIED_SINCE)
+			.and_then(parse_http_date)
+	}
+
+	/// The `If-Range` header, if there is a valid one
+	pub fn if_range(&self) -> Option<IfRange> {
+		self.header_str(http::header::IF_RANGE)
+			.and_then(IfRange::parse)
+	}
+
+	/// The ranges in the `Range` header, if there is a valid one
+	pub fn range(&self) -> Option<Vec<ByteRange>> {
+		self.header_str(http::header::RANGE)
+			.and_then(ByteRange::parse)
+	}
+
+	/// Check the request's preconditions against the resource's current
+	/// `ETag` and `Last-Modified`.
+	pub fn preconditions(
+		&self,
+		etag: Option<&EntityTag>,
+		modified: Option<SystemTime>,
+	) -> Precondition {
+		preconditions(&self.method, &self.headers, etag, modified)
+	}
+
+	/// Which parts of a resource `length` bytes long to send. See
+	/// `select_ranges`.
+	pub fn select_ranges(
+		&self,
+		length: u64,
+		etag: Option<&EntityTag>,
+		modified: Option<SystemTime>,
+	) -> RangeOutcome {
+		select_ranges(&self.method, &self.headers, length, etag, modified)
+	}
+
+	/// A header, as long as it's there once and is valid text
+	fn header_str(&self, name: http::header::HeaderName) -> Option<&str> {
+		let mut values = self.headers.get_all(name).iter();
+		match (values.next(), values.next()) {
+			(Some(value), None) => value.to_str().ok(),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(feature = "test-util")]
+impl IndRxRequest {
+	/// A request as a server would pass it up, for testing the tasks which
+	/// handle them. Both handles are the default, and it came over
+	/// HTTP/1.1 from 127.0.0.1.
+	pub fn for_test(method: Method, url: &str, headers: HeaderMap) -> IndRxRequest {
+		IndRxRequest {
+			server_handle: ServerHandle::default(),
+			connection_handle: ConnHandle::default(),
+			url: url.parse().unwrap(),
+			method,
+			version: Version::HTTP_11,
+			headers,
+			peer: "127.0.0.1:56789".parse().unwrap(),
+			local: "127.0.0.1:8000".parse().unwrap(),
+			client: "127.0.0.1".parse().unwrap(),
+			host: None,
+			body: false,
+		}
+	}
 }
 
 // ****************************************************************************
@@ -265,6 +1060,45 @@ enum CfmType {
 	Start,
 	Body,
 	Close,
+	WsAccept,
+	WsSend,
+	SseStart,
+	SseEvent,
+	Continue,
+}
+
+/// Things we need to do at some point in the future
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Timer {
+	/// Send a comment on an idle event stream
+	SseHeartbeat,
+	/// The client has taken too long to send the request head
+	Header,
+	/// The client has taken too long to send the request body
+	Body,
+	/// The user has taken too long to answer an `IndError`
+	ErrorPage,
+	/// A draining server's connections have had long enough. Keyed by the
+	/// `ServerHandle`.
+	Drain,
+}
+
+/// How far through receiving a request we are
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RxState {
+	/// Reading the request line and headers
+	Head,
+	/// Reading the request body
+	Body {
+		/// Bytes received so far, including any chunked framing
+		received: usize,
+		/// None if the body is chunked
+		expected: Option<usize>,
+		/// Strips the framing from a chunked body
+		chunked: ChunkedDecoder,
+	},
+	/// Anything else the client sends is ignored
+	Done,
 }
 
 /// If we get a Request from above and consequently need to wait
@@ -283,12 +1117,29 @@ struct PendingCfm {
 struct Server {
 	/// Supplied in a `socket::CfmBind`
 	listen_handle: Option<socket::ListenHandle>,
+	/// As given in the `ReqBind`
+	config: ServerConfig,
 	/// To whom we need to acknowledge the bind/unbind
 	reply_ctx: Option<ReplyContext>,
 	/// The handle by which the upper layer refers to us
 	our_handle: ServerHandle,
 	/// Who to tell about the new connections we get
 	ind_to: grease::ServiceUserHandle<Service>,
+	/// Virtual hosts, which take requests for particular hosts instead of
+	/// `ind_to`
+	hosts: Vec<VirtualHost>,
+	/// Writes the access log, if the config asks for one
+	logger: Option<access_log::Logger>,
+	/// A `ReqDrain` has closed the listener, and we're waiting for the
+	/// connections to go. Who to confirm to is in `reply_ctx`.
+	draining: bool,
+}
+
+struct VirtualHost {
+	handle: HostHandle,
+	pattern: vhost::HostPattern,
+	/// Who to tell about requests for this host
+	ind_to: grease::ServiceUserHandle<Service>,
 }
 
 struct Connection {
@@ -298,13 +1149,104 @@ struct Connection {
 	server_handle: ServerHandle,
 	/// The socket handle for this specific connection
 	socket_handle: socket::ConnHandle,
+	/// The far end of the connection
+	peer: net::SocketAddr,
+	/// Our end of the connection
+	local: net::SocketAddr,
 	/// The parser object we feed data through
-	parser: rushttp::request::Parser,
+	parser: parser::RequestParser,
+	/// The request head so far
+	head: Vec<u8>,
+	/// How much of the request we've had
+	rx: RxState,
 	/// The length of the response body we're sending
 	/// When enough has been sent, we close the connection automatically.
 	/// If the length is None, close when an empty body request is sent
 	/// If the length is Some(0), close after the headers
 	body_length: Option<usize>,
+	/// If the request was a WebSocket upgrade, the client's key
+	ws_key: Option<String>,
+	/// Whether the request was a HEAD, so the response has no body
+	head_request: bool,
+	/// Whether we're throwing the response body away (for automatic HEAD)
+	discard_body: bool,
+	/// Whether the client is waiting for a `100 Continue`
+	awaiting_continue: bool,
+	/// Set while we wait for a `RspRxBody` before reading any more of the
+	/// request body
+	rx_blocked: bool,
+	/// Set if we're holding on to the socket's `RspReceived` until then
+	socket_blocked: bool,
+	/// What was left of the body timeout when we stopped reading
+	body_time_left: Option<Duration>,
+	/// For a request on an HTTP/2 stream, the size of each piece of body
+	/// we're waiting for a `RspRxBody` for. The client can't send any more
+	/// than its windows allow until we've had them.
+	rx_owed: VecDeque<usize>,
+	/// The best encoding the client's `Accept-Encoding` allows
+	encoding: Option<Encoding>,
+	/// Whether the client can cope with chunked transfer encoding
+	chunked_ok: bool,
+	/// Set if we're compressing the response body
+	encoder: Option<BodyEncoder>,
+	/// Who to send the `IndClosed` to if the connection drops. This is
+	/// whoever got the `IndRxRequest` until someone starts responding.
+	closed_to: Option<grease::ServiceUserHandle<Service>>,
+	/// Who gets the request body - whoever got the `IndRxRequest`
+	body_to: Option<grease::ServiceUserHandle<Service>>,
+	/// For event streams, the heartbeat interval (if any). None if this
+	/// isn't an event stream.
+	sse: Option<Option<Duration>>,
+	/// Set once the connection has been upgraded to a WebSocket
+	ws: Option<WebSocket>,
+	/// What we'll write to the access log. Started when the request starts
+	/// to arrive, along with the time it did.
+	access: Option<(Instant, AccessRecord)>,
+	/// Set while we wait for the user to answer an `IndError`, to the
+	/// status they must answer with
+	refused: Option<http::StatusCode>,
+	/// `Access-Control-*` headers to add to the response
+	cors: HeaderMap,
+	/// For a request on an HTTP/2 connection, its stream. These live in
+	/// `TaskContext::streams`, and share their connection's socket.
+	stream: Option<u32>,
+	/// Set once the connection has switched to HTTP/2
+	h2: Option<Http2>,
+}
+
+/// The state of an HTTP/2 connection
+struct Http2 {
+	session: h2::Session,
+	/// The request on each open stream
+	streams: HashMap<u32, ConnHandle>,
+}
+
+/// Content codings we can apply to a response body
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Encoding {
+	Gzip,
+	Deflate,
+}
+
+enum Compressor {
+	Gzip(flate2::write::GzEncoder<Vec<u8>>),
+	Deflate(flate2::write::ZlibEncoder<Vec<u8>>),
+}
+
+/// Compresses a response body a piece at a time
+struct BodyEncoder {
+	compressor: Compressor,
+	/// Wrap the output in chunked transfer encoding
+	chunked: bool,
+}
+
+/// The state of an upgraded connection
+struct WebSocket {
+	decoder: websocket::Decoder,
+	/// Who accepted the upgrade, and so gets the messages
+	ind_to: grease::ServiceUserHandle<Service>,
+	/// Set once we've sent a close frame
+	close_sent: bool,
 }
 
 struct TaskContext {
@@ -316,11 +1258,21 @@ struct TaskContext {
 	servers: MultiMap<ServerHandle, Option<socket::ListenHandle>, Server>,
 	/// Our list of connections, indexed by the handle given in IndRxRequest
 	connections: MultiMap<ConnHandle, socket::ConnHandle, Connection>,
+	/// Requests on HTTP/2 connections, indexed by the handle given in
+	/// IndRxRequest
+	streams: HashMap<ConnHandle, Connection>,
+	/// Which server each virtual host belongs to
+	hosts: HashMap<HostHandle, ServerHandle>,
 	/// The next context we use for downward messages
 	next_ctx: Context,
 	/// Cfms we haven't sent yet, indexed by the unique context ID we
 	/// send to the socket task.
 	pending: HashMap<Context, PendingCfm>,
+	/// When each connection's timers go off
+	timers: HashMap<(ConnHandle, Timer), Instant>,
+	/// Draining servers which have lost a connection since we last looked
+	/// at whether they're done
+	drain_checks: Vec<ServerHandle>,
 }
 
 type ReplyContext = grease::ReplyContext<Service>;
@@ -338,14 +1290,64 @@ pub fn make_task(socket: grease::ServiceProviderHandle<socket::Service>) -> Hand
 	let handle = Handle(tx.clone());
 	std::thread::spawn(move || {
 		let mut t = TaskContext::new(socket, handle);
-		for msg in rx.iter() {
-			t.handle(msg);
+		loop {
+			let msg = match t.next_timeout() {
+				Some(timeout) => match rx.recv_timeout(timeout) {
+					Ok(msg) => Some(msg),
+					Err(mpsc::RecvTimeoutError::Timeout) => None,
+					Err(mpsc::RecvTimeoutError::Disconnected) => break,
+				},
+				None => match rx.recv() {
+					Ok(msg) => Some(msg),
+					Err(_) => break,
+				},
+			};
+			if let Some(msg) = msg {
+				t.handle(msg);
+			}
+			t.check_timers();
+			if !t.drain_checks.is_empty() {
+				t.check_drains();
+			}
 		}
 		panic!("This task should never die!");
 	});
 	Handle(tx)
 }
 
+/// Answer a request with just a status, as plain text. Tasks which sit
+/// above this one use this for the errors they generate themselves.
+pub fn send_error(
+	http: &grease::ServiceProvider<Service>,
+	reply_to: &grease::ServiceUser<Service>,
+	handle: ConnHandle,
+	context: Context,
+	status: HttpResponseStatus,
+	headers: HeaderMap,
+) {
+	let body = format!("{}\r\n", status).into_bytes();
+	http.send_request(
+		ReqResponseStart {
+			handle,
+			context,
+			status,
+			content_type: String::from("text/plain"),
+			length: Some(body.len()),
+			headers,
+			compress: None,
+		}.into(),
+		reply_to,
+	);
+	http.send_request(
+		ReqResponseBody {
+			handle,
+			context,
+			data: body,
+		}.into(),
+		reply_to,
+	);
+}
+
 // ****************************************************************************
 //
 // Private Functions
@@ -360,10 +1362,14 @@ impl TaskContext {
 			socket,
 			servers: MultiMap::new(),
 			connections: MultiMap::new(),
+			streams: HashMap::new(),
+			hosts: HashMap::new(),
 			reply_to: us,
 			// This number is arbitrary
 			next_ctx: grease::Context::new(2_000),
 			pending: HashMap::new(),
+			timers: HashMap::new(),
+			drain_checks: Vec::new(),
 		}
 	}
 
@@ -386,13 +1392,21 @@ impl TaskContext {
 			}
 			Incoming::Response(x) => {
 				debug!("Rx: {:?}", x);
+				self.handle_http_rsp(x);
 			}
 		}
 	}
 
+	fn handle_http_rsp(&mut self, rsp: Response) {
+		match rsp {
+			Response::RxBody(x) => self.handle_rx_body(x),
+		}
+	}
+
 	fn handle_socket_cfm(&mut self, cfm: socket::Confirm) {
 		match cfm {
 			socket::Confirm::Bind(x) => self.handle_socket_cfm_bind(x),
+			socket::Confirm::Connect(x) => warn!("Unexpected {:?}", x),
 			socket::Confirm::Close(x) => self.handle_socket_cfm_close(x),
 			socket::Confirm::Send(x) => self.handle_socket_cfm_send(x),
 		}
@@ -411,19 +1425,64 @@ impl TaskContext {
 			Request::Bind(x) => self.handle_bind(x, reply_to),
 			Request::ResponseStart(x) => self.handle_responsestart(x, reply_to),
 			Request::ResponseBody(x) => self.handle_responsebody(x, reply_to),
+			Request::WsAccept(x) => self.handle_ws_accept(x, reply_to),
+			Request::WsSend(x) => self.handle_ws_send(x, reply_to),
+			Request::SseStart(x) => self.handle_sse_start(x, reply_to),
+			Request::SseEvent(x) => self.handle_sse_event(x, reply_to),
+			Request::AddHost(x) => self.handle_add_host(x, reply_to),
+			Request::RemoveHost(x) => self.handle_remove_host(x, reply_to),
+			Request::Continue(x) => self.handle_continue(x, reply_to),
+			Request::Abort(x) => self.handle_abort(x, reply_to),
+			Request::Drain(x) => self.handle_drain(x, reply_to),
 		}
 	}
 
 	fn handle_bind(&mut self, req_bind: ReqBind, reply_to: grease::ServiceUserHandle<Service>) {
+		let logger = match req_bind.config.access_log.as_ref().map(access_log::Logger::open) {
+			Some(Ok(logger)) => Some(logger),
+			Some(Err(e)) => {
+				warn!("Failed to open access log: {}", e);
+				reply_to.send_confirm(
+					CfmBind {
+						context: req_bind.context,
+						result: Err(Error::LogFile),
+					}.into(),
+				);
+				return;
+			}
+			None => None,
+		};
+		if !req_bind.config.errors.pages.iter().all(valid_error_page) {
+			reply_to.send_confirm(
+				CfmBind {
+					context: req_bind.context,
+					result: Err(Error::BadErrorPage),
+				}.into(),
+			);
+			return;
+		}
+		if !req_bind.config.cors.as_ref().map_or(true, CorsConfig::is_valid) {
+			reply_to.send_confirm(
+				CfmBind {
+					context: req_bind.context,
+					result: Err(Error::BadCors),
+				}.into(),
+			);
+			return;
+		}
 		let reply_ctx = ReplyContext {
 			context: req_bind.context,
 			reply_to: reply_to.clone(),
 		};
 		let server = Server {
 			listen_handle: None,
+			config: req_bind.config,
 			reply_ctx: Some(reply_ctx),
 			our_handle: self.next_ctx.take(),
 			ind_to: reply_to.clone(),
+			hosts: Vec::new(),
+			logger,
+			draining: false,
 		};
 		self.socket.send_request(
 			socket::ReqBind {
@@ -436,18 +1495,221 @@ impl TaskContext {
 		self.servers.insert(server.our_handle, None, server);
 	}
 
-	/// Get the connection from a connection handle
-	fn get_conn_by_http_handle(&mut self, handle: &ConnHandle) -> Option<&mut Connection> {
-		self.connections.get_mut(handle)
+	fn handle_add_host(&mut self, req: ReqAddHost, reply_to: grease::ServiceUserHandle<Service>) {
+		let result = match (
+			self.servers.get_mut(&req.server),
+			vhost::HostPattern::parse(&req.pattern),
+		) {
+			(None, _) => Err(Error::BadHandle),
+			(_, None) => Err(Error::BadPattern),
+			(Some(ref server), Some(ref pattern))
+				if server.hosts.iter().any(|h| h.pattern == *pattern) =>
+			{
+				Err(Error::DuplicateHost)
+			}
+			(Some(server), Some(pattern)) => {
+				let handle = self.next_ctx.take();
+				server.hosts.push(VirtualHost {
+					handle,
+					pattern,
+					ind_to: reply_to.clone(),
+				});
+				self.hosts.insert(handle, req.server);
+				Ok(handle)
+			}
+		};
+		reply_to.send_confirm(
+			CfmAddHost {
+				context: req.context,
+				result,
+			}.into(),
+		);
 	}
 
-	/// Get the Server for a given socket ListenHandle.
-	fn get_server_by_socket_handle(
+	fn handle_remove_host(
 		&mut self,
-		handle: &socket::ListenHandle,
-	) -> Option<&mut Server> {
-		// The key here is Option<socket::ListenHandle> because
-		// it takes time to bind the socket.
+		req: ReqRemoveHost,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let result = match self.hosts
+			.remove(&req.handle)
+			.and_then(|sh| self.servers.get_mut(&sh))
+		{
+			Some(server) => {
+				server.hosts.retain(|h| h.handle != req.handle);
+				Ok(())
+			}
+			None => Err(Error::BadHandle),
+		};
+		reply_to.send_confirm(
+			CfmRemoveHost {
+				handle: req.handle,
+				context: req.context,
+				result,
+			}.into(),
+		);
+	}
+
+	fn handle_drain(&mut self, req: ReqDrain, reply_to: grease::ServiceUserHandle<Service>) {
+		let listen_handle = match self.servers.get_mut(&req.handle) {
+			// Not while it's still binding, or already draining
+			Some(ref mut server) if server.reply_ctx.is_none() => {
+				server.draining = true;
+				server.reply_ctx = Some(ReplyContext {
+					context: req.context,
+					reply_to: reply_to.clone(),
+				});
+				server.listen_handle
+			}
+			_ => {
+				reply_to.send_confirm(
+					CfmDrain {
+						handle: req.handle,
+						context: req.context,
+						result: Err(Error::BadHandle),
+					}.into(),
+				);
+				return;
+			}
+		};
+		info!("Draining server {:?}", req.handle);
+		if let Some(listen_handle) = listen_handle {
+			self.socket.send_request(
+				socket::ReqClose {
+					handle: listen_handle,
+					context: Context::default(),
+					reset: false,
+				}.into(),
+				&self.reply_to,
+			);
+		}
+		self.timers
+			.insert((req.handle, Timer::Drain), Instant::now() + req.deadline);
+		// HTTP/2 clients are told to start no more requests
+		let sessions: Vec<socket::ConnHandle> = self.connections
+			.iter()
+			.filter(|&(_, &(_, ref conn))| conn.server_handle == req.handle && conn.h2.is_some())
+			.map(|(_, &(skt, _))| skt)
+			.collect();
+		for skt in sessions {
+			if let Some(h2) = self.connections
+				.get_mut_alt(&skt)
+				.and_then(|c| c.h2.as_mut())
+			{
+				h2.session.go_away(h2::NO_ERROR);
+			}
+			self.flush_h2(&skt);
+		}
+		// HTTP/1 connections with no request on them yet can go now
+		let idle: Vec<socket::ConnHandle> = self.connections
+			.iter()
+			.filter(|&(_, &(_, ref conn))| conn.server_handle == req.handle && conn.idle())
+			.map(|(_, &(skt, _))| skt)
+			.collect();
+		for skt in idle {
+			debug!("Closing idle {:?}, server is draining", skt);
+			self.remove_connection_alt(&skt);
+			self.socket.send_request(
+				socket::ReqClose {
+					handle: skt,
+					context: Context::default(),
+					reset: false,
+				}.into(),
+				&self.reply_to,
+			);
+		}
+		// There may be nothing left to wait for
+		self.drain_checks.push(req.handle);
+	}
+
+	fn handle_continue(&mut self, req: ReqContinue, reply_to: grease::ServiceUserHandle<Service>) {
+		let (skt, body_timeout) = match self.connections.get_mut(&req.handle) {
+			Some(ref mut conn) if conn.awaiting_continue => {
+				conn.awaiting_continue = false;
+				let body_timeout = match conn.rx {
+					RxState::Body { .. } => self.servers
+						.get(&conn.server_handle)
+						.and_then(|s| s.config.limits.body_timeout),
+					_ => None,
+				};
+				(conn.socket_handle, body_timeout)
+			}
+			Some(_) => {
+				reply_to.send_confirm(
+					CfmContinue {
+						handle: req.handle,
+						context: req.context,
+						result: Err(Error::NotExpecting),
+					}.into(),
+				);
+				return;
+			}
+			None => {
+				reply_to.send_confirm(
+					CfmContinue {
+						handle: req.handle,
+						context: req.context,
+						result: Err(Error::BadHandle),
+					}.into(),
+				);
+				return;
+			}
+		};
+		if req.accept {
+			// Now they can have as long as they're allowed to send the body
+			if let Some(timeout) = body_timeout {
+				self.timers
+					.insert((req.handle, Timer::Body), Instant::now() + timeout);
+			}
+			let send = socket::ReqSend {
+				handle: skt,
+				context: self.next_ctx.take(),
+				data: b"HTTP/1.1 100 Continue\r\n\r\n".to_vec(),
+			};
+			let pend = PendingCfm {
+				handle: req.handle,
+				context: req.context,
+				reply_to,
+				cfm_type: CfmType::Continue,
+				close_after: false,
+			};
+			self.pending.insert(send.context, pend);
+			self.socket.send_request(send.into(), &self.reply_to);
+		} else {
+			let (_, output) = self.remove_refused(&skt, http::StatusCode::EXPECTATION_FAILED);
+			self.send_and_close(&skt, output);
+			reply_to.send_confirm(
+				CfmContinue {
+					handle: req.handle,
+					context: req.context,
+					result: Ok(()),
+				}.into(),
+			);
+		}
+	}
+
+	/// Whether the server a request came to compresses responses unless
+	/// told otherwise
+	fn compress_default(&self, handle: &ConnHandle) -> bool {
+		self.connections
+			.get(handle)
+			.or_else(|| self.streams.get(handle))
+			.and_then(|c| self.servers.get(&c.server_handle))
+			.map_or(false, |s| s.config.compress)
+	}
+
+	/// Get the connection from a connection handle
+	fn get_conn_by_http_handle(&mut self, handle: &ConnHandle) -> Option<&mut Connection> {
+		self.connections.get_mut(handle)
+	}
+
+	/// Get the Server for a given socket ListenHandle.
+	fn get_server_by_socket_handle(
+		&mut self,
+		handle: &socket::ListenHandle,
+	) -> Option<&mut Server> {
+		// The key here is Option<socket::ListenHandle> because
+		// it takes time to bind the socket.
 		self.servers.get_mut_alt(&Some(*handle))
 	}
 
@@ -477,34 +1739,49 @@ impl TaskContext {
 		None
 	}
 
-	fn delete_connection_by_socket_handle(&mut self, handle: &socket::ConnHandle) {
-		self.connections.remove_alt(handle);
-	}
-
-	fn render_response(
-		status: HttpResponseStatus,
-		content_type: &str,
-		length: Option<usize>,
-		headers: &HeaderMap,
-	) -> String {
-		let mut s = String::new();
-		s.push_str(&format!("HTTP/1.1 {}\r\n", status));
-		if !headers.contains_key("Server") {
-			s.push_str("Server: grease/http\r\n");
+	fn handle_abort(&mut self, req: ReqAbort, reply_to: grease::ServiceUserHandle<Service>) {
+		if self.streams.contains_key(&req.handle) {
+			self.handle_h2_abort(req, reply_to);
+			return;
 		}
-		if !headers.contains_key("Content-Length") {
-			if let Some(l) = length {
-				s.push_str(&format!("Content-Length: {}\r\n", l));
+		let conn = match self.remove_connection(&req.handle) {
+			Some(conn) => conn,
+			None => {
+				reply_to.send_confirm(
+					CfmAbort {
+						handle: req.handle,
+						context: req.context,
+						result: Err(Error::BadHandle),
+					}.into(),
+				);
+				return;
 			}
+		};
+		debug!("Aborting connection {:?}", req.handle);
+		self.fail_pending(&req.handle, Error::Aborted);
+		self.timers.retain(|&(handle, _), _| handle != req.handle);
+		self.socket.send_request(
+			socket::ReqClose {
+				handle: conn.socket_handle,
+				context: Context::default(),
+				reset: true,
+			}.into(),
+			&self.reply_to,
+		);
+		reply_to.send_confirm(
+			CfmAbort {
+				handle: req.handle,
+				context: req.context,
+				result: Ok(()),
+			}.into(),
+		);
+		if let Some(closed_to) = conn.closed_to {
+			closed_to.send_indication(
+				IndClosed {
+					handle: conn.our_handle,
+				}.into(),
+			);
 		}
-		if !headers.contains_key("Content-Type") {
-			s.push_str(&format!("Content-Type: {}\r\n", content_type));
-		}
-		for (k, v) in headers.iter() {
-			s.push_str(&format!("{}: {}\r\n", k.as_str(), v.to_str().unwrap()));
-		}
-		s.push_str("\r\n");
-		s
 	}
 
 	/// @todo We should we check they call this once and only once.
@@ -513,33 +1790,86 @@ impl TaskContext {
 		req_start: ReqResponseStart,
 		reply_to: grease::ServiceUserHandle<Service>,
 	) {
-		if self.get_conn_by_http_handle(&req_start.handle).is_some() {
-			let skt = {
+		if self.streams.contains_key(&req_start.handle) {
+			self.handle_h2_responsestart(req_start, reply_to);
+			return;
+		}
+		if self.get_conn_by_http_handle(&req_start.handle)
+			.map_or(false, |c| c.ws.is_none() && c.sse.is_none())
+		{
+			if let Some(conn) = self.connections.get_mut(&req_start.handle) {
+				// Too late for a 100 now
+				conn.awaiting_continue = false;
+			}
+			let mut req_start = req_start;
+			let compress_default = self.compress_default(&req_start.handle);
+			let (skt, server_handle, head) = {
 				let conn = self.get_conn_by_http_handle(&req_start.handle).unwrap();
-				conn.body_length = req_start.length;
-				conn.socket_handle
+				let head = conn.start_response(&mut req_start, compress_default, &reply_to);
+				(conn.socket_handle, conn.server_handle, head)
 			};
+			let head = if self.draining(&server_handle) {
+				head.header("Connection", HeaderValue::from_static("close"))
+			} else {
+				head
+			};
+			// Any more of the request body is thrown away, so don't hold it up
+			self.resume_rx(&req_start.handle);
 
-			// Render the headers as a String
-			// Send to the socket server
-			// Send the cfm when the socket server has sent this data
-			let s = Self::render_response(
-				req_start.status,
-				&req_start.content_type,
-				req_start.length,
-				&req_start.headers,
-			);
+			// Render the head, send it to the socket server, and send the
+			// cfm when the socket server has sent it
+			let close_after = req_start.length == Some(0) || !head.has_body();
+			let refused = self.connections
+				.get(&req_start.handle)
+				.and_then(|c| c.refused);
+			let data = match refused {
+				// An answer to an `IndError` must have the status we gave
+				Some(status) if head.code() != status.as_u16() => Err(Error::BadHeader),
+				_ => head.render(),
+			};
+			let data = match data {
+				Ok(data) => data,
+				Err(e) => {
+					reply_to.send_confirm(
+						CfmResponseStart {
+							context: req_start.context,
+							handle: req_start.handle,
+							result: Err(e),
+						}.into(),
+					);
+					if let Some(status) = refused {
+						// No second chances - the error has to go out
+						self.send_error(&skt, status);
+					} else {
+						// Nothing has gone out, so they can try again
+						let conn = self.get_conn_by_http_handle(&req_start.handle).unwrap();
+						conn.encoder = None;
+						conn.body_length = None;
+					}
+					return;
+				}
+			};
+			if refused.is_some() {
+				self.timers.remove(&(req_start.handle, Timer::ErrorPage));
+			}
+			let conn = self.get_conn_by_http_handle(&req_start.handle).unwrap();
+			conn.refused = None;
+			conn.responded(head.code());
+			if close_after {
+				conn.encoder = None;
+				conn.body_length = Some(0);
+			}
 			let req = socket::ReqSend {
 				handle: skt,
 				context: self.next_ctx.take(),
-				data: s.into_bytes(),
+				data,
 			};
 			let pend = PendingCfm {
 				handle: req_start.handle,
 				context: req_start.context,
 				reply_to: reply_to.clone(),
 				cfm_type: CfmType::Start,
-				close_after: req_start.length == Some(0),
+				close_after,
 			};
 			self.pending.insert(req.context, pend);
 			self.socket.send_request(req.into(), &self.reply_to);
@@ -559,54 +1889,82 @@ impl TaskContext {
 		req_body: ReqResponseBody,
 		reply_to: grease::ServiceUserHandle<Service>,
 	) {
-		if self.get_conn_by_http_handle(&req_body.handle).is_some() {
-			let mut close_after = false;
-			let skt = {
+		if self.streams.contains_key(&req_body.handle) {
+			self.handle_h2_responsebody(req_body, reply_to);
+			return;
+		}
+		if self.get_conn_by_http_handle(&req_body.handle)
+			.map_or(false, |c| c.ws.is_none())
+		{
+			let (skt, close_after) = {
 				let conn = self.get_conn_by_http_handle(&req_body.handle).unwrap();
-				match conn.body_length {
-					Some(0) => {
-						// This response has no body
-						reply_to.send_confirm(
-							CfmResponseBody {
-								context: req_body.context,
-								handle: req_body.handle,
-								result: Err(Error::BadHandle),
-							}.into(),
-						);
-						return;
-					}
-					Some(len) if req_body.data.len() > len => {
-						// This body is too long
+				match conn.body_sent(req_body.data.len()) {
+					Ok(close_after) => (conn.socket_handle, close_after),
+					Err(e) => {
+						// This response has no body, or this one is too long
 						reply_to.send_confirm(
 							CfmResponseBody {
 								context: req_body.context,
 								handle: req_body.handle,
-								result: Err(Error::BadHandle),
+								result: Err(e),
 							}.into(),
 						);
 						return;
 					}
-					Some(len) if req_body.data.len() == len => {
-						conn.body_length = Some(0);
-						close_after = true;
-						conn.socket_handle
-					}
-					Some(len) => {
-						conn.body_length = Some(len - req_body.data.len());
-						conn.socket_handle
-					}
-					None => conn.socket_handle,
 				}
 			};
 
+			// An empty body ends an unbounded response.
+			let last = close_after || req_body.data.is_empty();
+			if self.connections
+				.get(&req_body.handle)
+				.map_or(false, |c| c.discard_body)
+			{
+				// Answering a HEAD as if it were a GET, so the body goes nowhere
+				reply_to.send_confirm(
+					CfmResponseBody {
+						context: req_body.context,
+						handle: req_body.handle,
+						result: Ok(()),
+					}.into(),
+				);
+				if last {
+					let req = socket::ReqClose {
+						handle: skt,
+						context: self.next_ctx.take(),
+						reset: false,
+					};
+					let pend = PendingCfm {
+						handle: req_body.handle,
+						context: req_body.context,
+						reply_to: reply_to.clone(),
+						cfm_type: CfmType::Close,
+						close_after: false,
+					};
+					self.pending.insert(req.context, pend);
+					self.socket.send_request(req.into(), &self.reply_to);
+					let _ = self.remove_connection(&req_body.handle);
+				}
+				return;
+			}
+
+			// Run the body through the compressor, if there is one.
+			let encoded = self.connections
+				.get_mut(&req_body.handle)
+				.and_then(|c| c.encoder.as_mut())
+				.map(|e| e.encode(&req_body.data, last));
+
 			if !req_body.data.is_empty() {
 				// Send to the socket server
 				// Send the cfm when the socket server has sent this data
 				let req = socket::ReqSend {
 					handle: skt,
 					context: self.next_ctx.take(),
-					data: req_body.data.clone(),
+					data: encoded.unwrap_or(req_body.data),
 				};
+				if let Some(conn) = self.connections.get_mut(&req_body.handle) {
+					conn.sent(req.data.len());
+				}
 				let pend = PendingCfm {
 					handle: req_body.handle,
 					context: req_body.context,
@@ -617,10 +1975,25 @@ impl TaskContext {
 				self.pending.insert(req.context, pend);
 				self.socket.send_request(req.into(), &self.reply_to);
 			} else {
+				if let Some(data) = encoded {
+					if let Some(conn) = self.connections.get_mut(&req_body.handle) {
+						conn.sent(data.len());
+					}
+					// Flush out the end of the compressed body first
+					self.socket.send_request(
+						socket::ReqSend {
+							handle: skt,
+							context: Context::default(),
+							data,
+						}.into(),
+						&self.reply_to,
+					);
+				}
 				// Close connection now!
 				let req = socket::ReqClose {
 					handle: skt,
 					context: self.next_ctx.take(),
+					reset: false,
 				};
 				let pend = PendingCfm {
 					handle: req_body.handle,
@@ -631,7 +2004,7 @@ impl TaskContext {
 				};
 				self.pending.insert(req.context, pend);
 				self.socket.send_request(req.into(), &self.reply_to);
-				let _ = self.connections.remove(&req_body.handle);
+				let _ = self.remove_connection(&req_body.handle);
 			}
 		} else {
 			reply_to.send_confirm(
@@ -644,364 +2017,4605 @@ impl TaskContext {
 		}
 	}
 
-	fn send_response(&self, handle: &socket::ConnHandle, code: HttpResponseStatus, message: &str) {
-		// An error occured which we must tell them about
-		let mut r = rushttp::response::HttpResponse::new_with_body(code, "HTTP/1.0", message);
-		r.add_header("Content-Type", "text/plain");
-		let mut output = Vec::new();
-		if r.write(&mut output).is_ok() {
-			self.socket.send_request(
-				socket::ReqSend {
-					handle: *handle,
-					context: Context::default(),
-					data: output,
-				}.into(),
-				&self.reply_to,
-			);
-		} else {
-			warn!("Failed to render error");
-		}
-
-		self.socket.send_request(
-			socket::ReqClose {
-				handle: *handle,
-				context: Context::default(),
-			}.into(),
-			&self.reply_to,
-		);
-	}
-
-	fn map_result<T>(result: Result<T, socket::SocketError>) -> Result<(), Error> {
-		match result {
-			Ok(_) => Ok(()),
-			Err(e) => Err(Error::Socket(e)),
-		}
-	}
-
-	/// Handle a response to a socket task bind request. It's either
-	/// bound our socket, or it failed, but either way we must let
-	/// our user know.
-	fn handle_socket_cfm_bind(&mut self, cfm_bind: socket::CfmBind) {
-		if let Some(mut server) = self.servers.remove(&cfm_bind.context) {
-			let reply_ctx = server.reply_ctx.unwrap();
-			server.reply_ctx = None;
-			match cfm_bind.result {
-				Ok(ref handle) => {
-					server.listen_handle = Some(*handle);
-					// Re-insert, but with socket handle as second key
-					reply_ctx.reply_to.send_confirm(
-						CfmBind {
-							context: reply_ctx.context,
-							result: Ok(server.our_handle),
-						}.into(),
-					);
-					self.servers.insert(cfm_bind.context, Some(*handle), server);
-				}
-				Err(ref err) => {
-					reply_ctx.reply_to.send_confirm(
-						CfmBind {
-							context: reply_ctx.context,
-							result: Err(Error::Socket(*err)),
-						}.into(),
-					);
-				}
+	fn handle_ws_accept(
+		&mut self,
+		req_accept: ReqWsAccept,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let protocol = match req_accept.protocol.as_ref().map(|p| HeaderValue::from_str(p)) {
+			Some(Ok(protocol)) => Some(protocol),
+			None => None,
+			Some(Err(_)) => {
+				reply_to.send_confirm(
+					CfmWsAccept {
+						context: req_accept.context,
+						handle: req_accept.handle,
+						result: Err(Error::BadHeader),
+					}.into(),
+				);
+				return;
 			}
-		} else {
-			warn!("Context {} not found", cfm_bind.context);
-		}
-	}
-
-	fn handle_socket_cfm_close(&mut self, cfm: socket::CfmClose) {
-		debug!("Got {:?}", cfm);
-		if let Some(pend) = self.pending.remove(&cfm.context) {
-			// Close out whatever triggered this close
-			match pend.cfm_type {
-				CfmType::Start => {
-					pend.reply_to.send_confirm(
-						CfmResponseStart {
-							context: pend.context,
-							handle: pend.handle,
-							result: Self::map_result(cfm.result),
-						}.into(),
-					);
+		};
+		let result = match self.connections.get_mut(&req_accept.handle) {
+			Some(ref mut conn) if conn.ws.is_none() => match conn.ws_key.take() {
+				Some(key) => {
+					conn.ws = Some(WebSocket {
+						decoder: websocket::Decoder::new(),
+						ind_to: reply_to.clone(),
+						close_sent: false,
+					});
+					conn.closed_to = Some(reply_to.clone());
+					conn.rx = RxState::Done;
+					conn.responded(101);
+					Ok((conn.socket_handle, key))
 				}
-				CfmType::Body => {
-					pend.reply_to.send_confirm(
-						CfmResponseBody {
-							context: pend.context,
-							handle: pend.handle,
-							result: Self::map_result(cfm.result),
-						}.into(),
+				None => Err(Error::NotWebSocket),
+			},
+			_ => Err(Error::BadHandle),
+		};
+		match result {
+			Ok((skt, key)) => {
+				let mut head = head::ResponseHead::new(HttpResponseStatus::SwitchingProtocols)
+					.header("Upgrade", HeaderValue::from_static("websocket"))
+					.header("Connection", HeaderValue::from_static("Upgrade"))
+					.header(
+						"Sec-WebSocket-Accept",
+						// Base64, so always a valid value
+						HeaderValue::from_str(&websocket::accept_key(&key)).unwrap(),
 					);
+				if let Some(protocol) = protocol {
+					head = head.header("Sec-WebSocket-Protocol", protocol);
 				}
-				CfmType::Close => {
-					// Nothing to send - internally generated
-				}
+				let req = socket::ReqSend {
+					handle: skt,
+					context: self.next_ctx.take(),
+					// Nothing here can fail to render
+					data: head.render().unwrap(),
+				};
+				let pend = PendingCfm {
+					handle: req_accept.handle,
+					context: req_accept.context,
+					reply_to,
+					cfm_type: CfmType::WsAccept,
+					close_after: false,
+				};
+				self.pending.insert(req.context, pend);
+				self.socket.send_request(req.into(), &self.reply_to);
+			}
+			Err(e) => {
+				reply_to.send_confirm(
+					CfmWsAccept {
+						context: req_accept.context,
+						handle: req_accept.handle,
+						result: Err(e),
+					}.into(),
+				);
 			}
-			let ind = IndClosed {
-				handle: pend.handle,
-			};
-			pend.reply_to.send_indication(ind.into());
 		}
 	}
 
-	fn handle_socket_cfm_send(&mut self, cfm: socket::CfmSend) {
-		debug!("Got {:?}", cfm);
-		if let Some(pend) = self.pending.remove(&cfm.context) {
-			match pend.cfm_type {
-				CfmType::Start => {
-					pend.reply_to.send_confirm(
-						CfmResponseStart {
-							context: pend.context,
-							handle: pend.handle,
-							result: Self::map_result(cfm.result),
-						}.into(),
-					);
-				}
-				CfmType::Body => {
-					pend.reply_to.send_confirm(
-						CfmResponseBody {
-							context: pend.context,
-							handle: pend.handle,
-							result: Self::map_result(cfm.result),
-						}.into(),
-					);
-				}
-				CfmType::Close => {
-					// Should never happen
-					panic!("Pend stored with CfmType::Close against ReqSend")
-				}
+	fn handle_ws_send(
+		&mut self,
+		req_send: ReqWsSend,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let result = if !websocket::valid_to_send(&req_send.message) {
+			Err(Error::BadMessage)
+		} else {
+			match self.connections.get_mut(&req_send.handle) {
+				Some(conn) => match conn.ws {
+					Some(ref mut ws) if !ws.close_sent => {
+						if let WsMessage::Close(_, _) = req_send.message {
+							ws.close_sent = true;
+						}
+						Ok(conn.socket_handle)
+					}
+					_ => Err(Error::BadHandle),
+				},
+				None => Err(Error::BadHandle),
 			}
-			if pend.close_after {
-				// Close connection now!
-				let req = socket::ReqClose {
-					handle: cfm.handle,
+		};
+		match result {
+			Ok(skt) => {
+				let req = socket::ReqSend {
+					handle: skt,
 					context: self.next_ctx.take(),
+					data: websocket::encode(&req_send.message),
 				};
 				let pend = PendingCfm {
-					handle: pend.handle,
-					context: pend.context,
-					reply_to: pend.reply_to,
-					cfm_type: CfmType::Close,
+					handle: req_send.handle,
+					context: req_send.context,
+					reply_to,
+					cfm_type: CfmType::WsSend,
 					close_after: false,
 				};
-				let _ = self.connections.remove(&pend.handle);
 				self.pending.insert(req.context, pend);
 				self.socket.send_request(req.into(), &self.reply_to);
 			}
+			Err(e) => {
+				reply_to.send_confirm(
+					CfmWsSend {
+						context: req_send.context,
+						handle: req_send.handle,
+						result: Err(e),
+					}.into(),
+				);
+			}
 		}
 	}
 
-	/// Someone has connected to our bound socket, so create a Connection
-	/// and wait for data. We don't tell the layer above until we've parsed
-	/// the HTTP Request header.
-	fn handle_socket_ind_connected(&mut self, ind: socket::IndConnected) {
-		debug!("Got {:?}", ind);
-		if let Some(server_handle) = self.get_server_handle_by_socket_handle(&ind.listen_handle) {
-			let conn = Connection {
-				our_handle: self.next_ctx.take(),
-				server_handle,
-				socket_handle: ind.conn_handle,
-				parser: rushttp::request::Parser::new(),
-				body_length: None,
-			};
-			debug!(
-				"New connection {:?}, socket={:?}",
-				conn.our_handle, conn.socket_handle
-			);
-			self.connections
-				.insert(conn.our_handle, conn.socket_handle, conn);
+	fn handle_sse_start(
+		&mut self,
+		req_start: ReqSseStart,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let skt = match self.connections.get_mut(&req_start.handle) {
+			Some(ref mut conn)
+				if conn.ws.is_none() && conn.sse.is_none() && conn.refused.is_none() =>
+			{
+				conn.sse = Some(req_start.heartbeat);
+				conn.body_length = None;
+				conn.closed_to = Some(reply_to.clone());
+				conn.rx = RxState::Done;
+				Some((conn.socket_handle, conn.cors.clone()))
+			}
+			_ => None,
+		};
+		if let Some((skt, cors)) = skt {
+			let mut headers = req_start.headers;
+			cors::add_headers(&mut headers, &cors);
+			let data = head::ResponseHead::new(HttpResponseStatus::OK)
+				.content_type("text/event-stream")
+				.headers(headers)
+				.header("Cache-Control", HeaderValue::from_static("no-cache"))
+				.render();
+			let data = match data {
+				Ok(data) => {
+					if let Some(conn) = self.connections.get_mut(&req_start.handle) {
+						conn.responded(200);
+					}
+					data
+				}
+				Err(e) => {
+					if let Some(conn) = self.connections.get_mut(&req_start.handle) {
+						conn.sse = None;
+					}
+					reply_to.send_confirm(
+						CfmSseStart {
+							context: req_start.context,
+							handle: req_start.handle,
+							result: Err(e),
+						}.into(),
+					);
+					return;
+				}
+			};
+			let req = socket::ReqSend {
+				handle: skt,
+				context: self.next_ctx.take(),
+				data,
+			};
+			let pend = PendingCfm {
+				handle: req_start.handle,
+				context: req_start.context,
+				reply_to,
+				cfm_type: CfmType::SseStart,
+				close_after: false,
+			};
+			self.pending.insert(req.context, pend);
+			self.socket.send_request(req.into(), &self.reply_to);
+			self.start_sse_heartbeat(req_start.handle);
 		} else {
-			warn!("Connection on non-existant socket handle");
+			reply_to.send_confirm(
+				CfmSseStart {
+					context: req_start.context,
+					handle: req_start.handle,
+					result: Err(Error::BadHandle),
+				}.into(),
+			);
 		}
 	}
 
-	fn handle_socket_ind_dropped(&mut self, ind: socket::IndDropped) {
-		self.connections.remove_alt(&ind.handle);
+	fn handle_sse_event(
+		&mut self,
+		req_event: ReqSseEvent,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let result = match self.connections.get_mut(&req_event.handle) {
+			Some(ref mut conn) if conn.sse.is_some() => format_sse_event(&req_event)
+				.map(|data| {
+					conn.sent(data.len());
+					(conn.socket_handle, data)
+				})
+				.ok_or(Error::BadMessage),
+			_ => Err(Error::BadHandle),
+		};
+		match result {
+			Ok((skt, data)) => {
+				let req = socket::ReqSend {
+					handle: skt,
+					context: self.next_ctx.take(),
+					data: data.into_bytes(),
+				};
+				let pend = PendingCfm {
+					handle: req_event.handle,
+					context: req_event.context,
+					reply_to,
+					cfm_type: CfmType::SseEvent,
+					close_after: false,
+				};
+				self.pending.insert(req.context, pend);
+				self.socket.send_request(req.into(), &self.reply_to);
+				self.start_sse_heartbeat(req_event.handle);
+			}
+			Err(e) => {
+				reply_to.send_confirm(
+					CfmSseEvent {
+						context: req_event.context,
+						handle: req_event.handle,
+						result: Err(e),
+					}.into(),
+				);
+			}
+		}
 	}
 
-	fn handle_socket_ind_received(&mut self, ind: socket::IndReceived) {
-		debug!("Got {:?}", ind);
-		let r = if let Some((conn, serv)) = self.get_conn_by_socket_handle(&ind.handle) {
-			debug!("Got data for conn {:?}!", conn.our_handle);
-			// Extract the fields from connection
-			// As we can't keep a reference to it
-			Some((
-				conn.parser.parse(&ind.data),
-				conn.our_handle,
-				conn.server_handle,
-				serv.ind_to.clone(),
-			))
+	/// (Re)start the heartbeat timer on an event stream, if it has one.
+	fn start_sse_heartbeat(&mut self, handle: ConnHandle) {
+		if let Some(&Some(Some(interval))) = self.connections.get(&handle).map(|c| &c.sse) {
+			self.timers
+				.insert((handle, Timer::SseHeartbeat), Instant::now() + interval);
+		}
+	}
+
+	/// How long until the next timer goes off.
+	fn next_timeout(&self) -> Option<Duration> {
+		let now = Instant::now();
+		self.timers
+			.values()
+			.min()
+			.map(|t| if *t > now { *t - now } else { Duration::from_secs(0) })
+	}
+
+	/// Deal with any timers which have gone off.
+	fn check_timers(&mut self) {
+		let now = Instant::now();
+		let expired: Vec<(ConnHandle, Timer)> = self.timers
+			.iter()
+			.filter(|&(_, t)| *t <= now)
+			.map(|(k, _)| *k)
+			.collect();
+		for key in expired {
+			self.timers.remove(&key);
+			let (handle, timer) = key;
+			if let Timer::Drain = timer {
+				self.drain_timeout(handle);
+				continue;
+			}
+			let (skt, rx) = match self.connections.get(&handle) {
+				Some(conn) => (conn.socket_handle, conn.rx),
+				// Connection has gone
+				None => continue,
+			};
+			match timer {
+				Timer::Header | Timer::Body => {
+					// Only if we're still waiting for that part of the request
+					match (timer, rx) {
+						(Timer::Header, RxState::Head)
+						| (Timer::Body, RxState::Body { .. }) => {
+							self.reject_request(&skt, http::StatusCode::REQUEST_TIMEOUT);
+						}
+						_ => {}
+					}
+				}
+				Timer::ErrorPage => {
+					let refused = self.connections.get(&handle).and_then(|c| c.refused);
+					if let Some(status) = refused {
+						debug!("No answer to IndError on {:?}", handle);
+						self.send_error(&skt, status);
+					}
+				}
+				Timer::SseHeartbeat => {
+					self.socket.send_request(
+						socket::ReqSend {
+							handle: skt,
+							context: Context::default(),
+							data: b":\n\n".to_vec(),
+						}.into(),
+						&self.reply_to,
+					);
+					self.start_sse_heartbeat(handle);
+				}
+				Timer::Drain => {}
+			}
+		}
+	}
+
+	/// A draining server's deadline has passed. Reset whatever it has left.
+	fn drain_timeout(&mut self, server_handle: ServerHandle) {
+		let remaining: Vec<socket::ConnHandle> = self.connections
+			.iter()
+			.filter(|&(_, &(_, ref conn))| conn.server_handle == server_handle)
+			.map(|(_, &(skt, _))| skt)
+			.collect();
+		// An HTTP/1 connection has one request in progress, as idle ones
+		// were closed when the drain started
+		let in_flight: usize = remaining
+			.iter()
+			.filter_map(|skt| self.connections.get_alt(skt))
+			.map(|conn| conn.h2.as_ref().map_or(1, |h2| h2.streams.len()))
+			.sum();
+		if in_flight > 0 {
+			warn!(
+				"Server {:?} still had {} requests in progress at its deadline",
+				server_handle,
+				in_flight
+			);
+		}
+		for skt in &remaining {
+			let conn = self.remove_connection_alt(skt).unwrap();
+			if let Some(ref h2) = conn.h2 {
+				self.close_h2_streams(h2);
+			}
+			self.fail_pending(&conn.our_handle, Error::BadHandle);
+			self.socket.send_request(
+				socket::ReqClose {
+					handle: *skt,
+					context: Context::default(),
+					reset: true,
+				}.into(),
+				&self.reply_to,
+			);
+			if let Some(closed_to) = conn.closed_to {
+				closed_to.send_indication(
+					IndClosed {
+						handle: conn.our_handle,
+					}.into(),
+				);
+			}
+		}
+		let result = if in_flight == 0 {
+			Ok(())
 		} else {
-			None
+			Err(Error::DrainTimeout)
 		};
+		self.finish_drain(server_handle, result);
+	}
 
-		match r {
-			Some((rushttp::request::ParseResult::Complete(req, _), ch, sh, ind_to)) => {
-				// All done!
-				ind_to.send_indication(
-					IndRxRequest {
-						server_handle: sh,
-						connection_handle: ch,
-						url: req.uri().clone(),
-						method: req.method().clone(),
-						headers: req.headers().clone(),
+	/// Confirm the drains of any servers which have lost their last
+	/// connection since we last looked.
+	fn check_drains(&mut self) {
+		while let Some(handle) = self.drain_checks.pop() {
+			let done = !self.connections
+				.iter()
+				.any(|(_, &(_, ref conn))| conn.server_handle == handle);
+			if done {
+				self.finish_drain(handle, Ok(()));
+			}
+		}
+	}
+
+	/// Forget a drained server, and tell whoever asked.
+	fn finish_drain(&mut self, handle: ServerHandle, result: Result<(), Error>) {
+		self.timers.remove(&(handle, Timer::Drain));
+		if let Some(server) = self.servers.remove(&handle) {
+			info!("Server {:?} drained", handle);
+			for host in &server.hosts {
+				self.hosts.remove(&host.handle);
+			}
+			if let Some(reply_ctx) = server.reply_ctx {
+				reply_ctx.reply_to.send_confirm(
+					CfmDrain {
+						handle,
+						context: reply_ctx.context,
+						result,
 					}.into(),
 				);
 			}
-			Some((rushttp::request::ParseResult::InProgress, _, _, _)) => {
-				// Need more data
+		}
+	}
+
+	/// Is the server waiting for its connections to go?
+	fn draining(&self, handle: &ServerHandle) -> bool {
+		self.servers.get(handle).map_or(false, |s| s.draining)
+	}
+
+	/// Data has arrived on an upgraded connection. Decode as many messages
+	/// as we can and pass them up.
+	fn handle_ws_received(&mut self, mut conn: Connection, data: &[u8]) {
+		let mut close = None;
+		{
+			let ws = conn.ws.as_mut().unwrap();
+			ws.decoder.push(data);
+			loop {
+				match ws.decoder.next_message() {
+					Ok(Some(message)) => {
+						match message {
+							WsMessage::Ping(ref payload) => {
+								if !ws.close_sent {
+									self.send_ws_frame(
+										conn.socket_handle,
+										&WsMessage::Pong(payload.clone()),
+									);
+								}
+							}
+							WsMessage::Close(code, _) => {
+								// Echo the close back, unless this is the
+								// reply to ours
+								close = Some(if ws.close_sent { None } else { Some(code) });
+							}
+							_ => {}
+						}
+						ws.ind_to.send_indication(
+							IndWsMessage {
+								handle: conn.our_handle,
+								message,
+							}.into(),
+						);
+						if close.is_some() {
+							break;
+						}
+					}
+					Ok(None) => break,
+					Err(code) => {
+						warn!("WebSocket {} protocol error {}", conn.our_handle, code);
+						close = Some(if ws.close_sent { None } else { Some(Some(code)) });
+						break;
+					}
+				}
 			}
-			Some(_) => {
-				self.delete_connection_by_socket_handle(&ind.handle);
-				self.send_response(
-					&ind.handle,
-					rushttp::response::HttpResponseStatus::BadRequest,
-					"Bad Request",
-				);
+		}
+		match close {
+			Some(echo) => {
+				if let Some(code) = echo {
+					self.send_ws_frame(conn.socket_handle, &WsMessage::Close(code, String::new()));
+				}
+				self.log_access(&conn);
+				let ws = conn.ws.unwrap();
+				let req = socket::ReqClose {
+					handle: conn.socket_handle,
+					context: self.next_ctx.take(),
+					reset: false,
+				};
+				let pend = PendingCfm {
+					handle: conn.our_handle,
+					context: Context::default(),
+					reply_to: ws.ind_to,
+					cfm_type: CfmType::Close,
+					close_after: false,
+				};
+				self.pending.insert(req.context, pend);
+				self.socket.send_request(req.into(), &self.reply_to);
 			}
 			None => {
-				warn!("Data on non-existant socket handle");
+				self.connections
+					.insert(conn.our_handle, conn.socket_handle, conn);
 			}
 		}
-
-		self.socket
-			.send_response(socket::Response::Received(socket::RspReceived {
-				handle: ind.handle,
-			}));
 	}
-}
-
-#[cfg(test)]
-mod test {
-	use super::*;
-	use std::net;
-	use std::sync::atomic;
-	use std::sync::mpsc;
-
-	use grease::prelude::*;
 
-	enum TestIncoming {
-		HttpCfm(Confirm),
-		HttpInd(Indication),
-		SocketReq(socket::Request, grease::ServiceUserHandle<socket::Service>),
-		SocketRsp(socket::Response),
+	/// Send a frame we've generated ourselves (so no cfm is required).
+	fn send_ws_frame(&self, handle: socket::ConnHandle, message: &WsMessage) {
+		self.socket.send_request(
+			socket::ReqSend {
+				handle,
+				context: Context::default(),
+				data: websocket::encode(message),
+			}.into(),
+			&self.reply_to,
+		);
 	}
 
-	struct TestHandle(mpsc::Sender<TestIncoming>);
-
-	static PORT_NUMBER: atomic::AtomicUsize = atomic::AtomicUsize::new(8000);
-
-	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+	/// Render the response we refuse a request with - the server's page
+	/// for that status if it has one, otherwise a short one of our own.
+	/// Also gives the length of the body.
+	fn error_response(
+		&self,
+		handle: &socket::ConnHandle,
+		status: http::StatusCode,
+	) -> (Vec<u8>, usize) {
+		let page = self.connections
+			.get_alt(handle)
+			.and_then(|c| self.servers.get(&c.server_handle))
+			.and_then(|s| s.config.errors.pages.get(&status));
+		let (content_type, body) = match page {
+			Some(page) => (page.content_type.as_bytes(), &page.body[..]),
+			None => (&b"text/plain"[..], error_body(status).as_bytes()),
+		};
+		let mut output = format!(
+			"HTTP/1.0 {} {}\r\n",
+			status.as_u16(),
+			status.canonical_reason().unwrap_or("Error")
+		).into_bytes();
+		if let Some(page) = page {
+			for (name, value) in page.headers.iter() {
+				output.extend_from_slice(name.as_str().as_bytes());
+				output.extend_from_slice(b": ");
+				output.extend_from_slice(value.as_bytes());
+				output.extend_from_slice(b"\r\n");
+			}
+		}
+		output.extend_from_slice(b"Content-Type: ");
+		output.extend_from_slice(content_type);
+		output.extend_from_slice(
+			format!(
+				"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+				body.len()
+			).as_bytes(),
+		);
+		output.extend_from_slice(body);
+		(output, body.len())
+	}
 
-	fn allocate_test_port() -> net::SocketAddr {
-		let port = PORT_NUMBER.fetch_add(1, atomic::Ordering::SeqCst);
-		net::SocketAddr::new(
-			net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 1, 1)),
-			port as u16,
-		)
+	/// Forget a connection, writing its access log record if it answered a
+	/// request.
+	fn remove_connection(&mut self, handle: &ConnHandle) -> Option<Connection> {
+		let conn = self.connections.remove(handle);
+		if let Some(ref conn) = conn {
+			self.connection_gone(conn);
+		}
+		conn
 	}
 
-	impl grease::ServiceUser<Service> for TestHandle {
-		fn send_confirm(&self, cfm: Confirm) {
-			self.0.send(TestIncoming::HttpCfm(cfm)).unwrap();
+	fn remove_connection_alt(&mut self, handle: &socket::ConnHandle) -> Option<Connection> {
+		let conn = self.connections.remove_alt(handle);
+		if let Some(ref conn) = conn {
+			self.connection_gone(conn);
 		}
-		fn send_indication(&self, ind: Indication) {
-			self.0.send(TestIncoming::HttpInd(ind)).unwrap();
+		conn
+	}
+
+	/// Write the access log record for a connection we've forgotten, and
+	/// note if its server might now have drained.
+	fn connection_gone(&mut self, conn: &Connection) {
+		self.log_access(conn);
+		if self.draining(&conn.server_handle) {
+			self.drain_checks.push(conn.server_handle);
 		}
-		fn clone(&self) -> grease::ServiceUserHandle<Service> {
-			Box::new(TestHandle(self.0.clone()))
+	}
+
+	/// Forget a connection we're about to refuse, giving the response to
+	/// send_and_close.
+	fn remove_refused(
+		&mut self,
+		handle: &socket::ConnHandle,
+		status: http::StatusCode,
+	) -> (Option<Connection>, Vec<u8>) {
+		let (output, length) = self.error_response(handle, status);
+		if let Some(conn) = self.connections.get_mut_alt(handle) {
+			conn.responded(status.as_u16());
+			conn.sent(length);
 		}
+		(self.remove_connection_alt(handle), output)
 	}
 
-	impl grease::ServiceProvider<socket::Service> for TestHandle {
-		fn send_request(
-			&self,
-			req: socket::Request,
-			reply_to: &grease::ServiceUser<socket::Service>,
-		) {
-			self.0
-				.send(TestIncoming::SocketReq(req, reply_to.clone()))
-				.unwrap();
+	/// Fail the confirms a connection is waiting for. Whatever the socket
+	/// task says about them now, it's too late.
+	fn fail_pending(&mut self, handle: &ConnHandle, error: Error) {
+		let outstanding: Vec<Context> = self.pending
+			.iter()
+			.filter(|&(_, pend)| pend.handle == *handle)
+			.map(|(ctx, _)| *ctx)
+			.collect();
+		for ctx in outstanding {
+			let pend = self.pending.remove(&ctx).unwrap();
+			Self::send_cfm(&pend, Err(error));
 		}
-		fn send_response(&self, rsp: socket::Response) {
-			self.0.send(TestIncoming::SocketRsp(rsp)).unwrap();
+	}
+
+	/// Forget a request on an HTTP/2 stream, writing its access log record
+	/// if it was answered.
+	fn remove_stream(&mut self, handle: &ConnHandle) -> Option<Connection> {
+		let conn = self.streams.remove(handle)?;
+		self.log_access(&conn);
+		let h2 = self.connections
+			.get_mut_alt(&conn.socket_handle)
+			.and_then(|c| c.h2.as_mut());
+		if let (Some(h2), Some(stream)) = (h2, conn.stream) {
+			h2.streams.remove(&stream);
+			// No one will deal with what's left of the body now
+			h2.session.consumed(stream, conn.rx_owed.iter().sum());
 		}
-		fn clone(&self) -> grease::ServiceProviderHandle<socket::Service> {
-			Box::new(TestHandle(self.0.clone()))
+		Some(conn)
+	}
+
+	/// Write the access log record for a connection, if its server keeps
+	/// a log and it got as far as a response.
+	fn log_access(&self, conn: &Connection) {
+		let logger = self.servers
+			.get(&conn.server_handle)
+			.and_then(|s| s.logger.as_ref());
+		if let (Some(logger), Some(&(started, ref record))) = (logger, conn.access.as_ref()) {
+			if record.status != 0 {
+				let mut record = record.clone();
+				record.duration = started.elapsed();
+				logger.write(&record);
+			}
 		}
 	}
 
-	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
-		let (test_tx, rx) = mpsc::channel();
-		(TestHandle(test_tx), rx)
+	/// Send something we've generated ourselves and close the socket.
+	fn send_and_close(&self, handle: &socket::ConnHandle, data: Vec<u8>) {
+		self.socket.send_request(
+			socket::ReqSend {
+				handle: *handle,
+				context: Context::default(),
+				data,
+			}.into(),
+			&self.reply_to,
+		);
+		self.socket.send_request(
+			socket::ReqClose {
+				handle: *handle,
+				context: Context::default(),
+				reset: false,
+			}.into(),
+			&self.reply_to,
+		);
 	}
 
-	fn bind_port<T>(
-		this_thread: &TestHandle,
-		test_rx: &mpsc::Receiver<TestIncoming>,
-		http_north: &T,
-		addr: &net::SocketAddr,
-		ctx: Context,
-		socket_handle: socket::ListenHandle,
-	) -> (ServerHandle, grease::ServiceUserHandle<socket::Service>)
-	where
-		T: grease::ServiceProvider<Service>,
-	{
-		let bind_req = ReqBind {
-			addr: addr.clone(),
-			context: ctx,
-		};
-		http_north.send_request(bind_req.into(), this_thread);
-		let cfm = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		let reply_to_copy: grease::ServiceUserHandle<socket::Service>;
-		match cfm {
-			TestIncoming::SocketReq(socket::Request::Bind(ref x), ref reply_to) => {
-				assert_eq!(x.addr, *addr);
-				let bind_cfm = socket::CfmBind {
-					result: Ok(socket_handle),
-					context: x.context,
-				};
-				reply_to.send_confirm(bind_cfm.into());
-				reply_to_copy = (*reply_to).clone();
+	/// Send the confirm the user is waiting for, now that the socket task
+	/// has dealt with our request.
+	fn send_cfm(pend: &PendingCfm, result: Result<(), Error>) {
+		let cfm = match pend.cfm_type {
+			CfmType::Start => CfmResponseStart {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::Body => CfmResponseBody {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::WsAccept => CfmWsAccept {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::WsSend => CfmWsSend {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::SseStart => CfmSseStart {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::SseEvent => CfmSseEvent {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::Continue => CfmContinue {
+				context: pend.context,
+				handle: pend.handle,
+				result,
+			}.into(),
+			CfmType::Close => {
+				// Nothing to send - internally generated
+				return;
 			}
-			_ => panic!("Unexpected message."),
+		};
+		pend.reply_to.send_confirm(cfm);
+	}
+
+	fn map_result<T>(result: Result<T, socket::SocketError>) -> Result<(), Error> {
+		match result {
+			Ok(_) => Ok(()),
+			Err(e) => Err(Error::Socket(e)),
 		}
-		let cfm = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match cfm {
-			TestIncoming::HttpCfm(Confirm::Bind(ref x)) => {
-				assert_eq!(x.context, ctx);
-				if let Ok(h) = x.result {
-					(h, reply_to_copy.clone())
+	}
+
+	/// Handle a response to a socket task bind request. It's either
+	/// bound our socket, or it failed, but either way we must let
+	/// our user know.
+	fn handle_socket_cfm_bind(&mut self, cfm_bind: socket::CfmBind) {
+		if let Some(mut server) = self.servers.remove(&cfm_bind.context) {
+			let reply_ctx = server.reply_ctx.unwrap();
+			server.reply_ctx = None;
+			match cfm_bind.result {
+				Ok(ref handle) => {
+					server.listen_handle = Some(*handle);
+					// Re-insert, but with socket handle as second key
+					reply_ctx.reply_to.send_confirm(
+						CfmBind {
+							context: reply_ctx.context,
+							result: Ok(server.our_handle),
+						}.into(),
+					);
+					self.servers.insert(cfm_bind.context, Some(*handle), server);
+				}
+				Err(ref err) => {
+					reply_ctx.reply_to.send_confirm(
+						CfmBind {
+							context: reply_ctx.context,
+							result: Err(Error::Socket(*err)),
+						}.into(),
+					);
+				}
+			}
+		} else {
+			warn!("Context {} not found", cfm_bind.context);
+		}
+	}
+
+	fn handle_socket_cfm_close(&mut self, cfm: socket::CfmClose) {
+		debug!("Got {:?}", cfm);
+		if let Some(pend) = self.pending.remove(&cfm.context) {
+			// Close out whatever triggered this close
+			Self::send_cfm(&pend, Self::map_result(cfm.result));
+			let ind = IndClosed {
+				handle: pend.handle,
+			};
+			pend.reply_to.send_indication(ind.into());
+		}
+	}
+
+	fn handle_socket_cfm_send(&mut self, cfm: socket::CfmSend) {
+		debug!("Got {:?}", cfm);
+		if let Some(pend) = self.pending.remove(&cfm.context) {
+			if let CfmType::Close = pend.cfm_type {
+				// Should never happen
+				panic!("Pend stored with CfmType::Close against ReqSend")
+			}
+			Self::send_cfm(&pend, Self::map_result(cfm.result));
+			if pend.close_after && self.streams.contains_key(&pend.handle) {
+				// That's the end of the response on this stream
+				let conn = self.remove_stream(&pend.handle).unwrap();
+				pend.reply_to.send_indication(
+					IndClosed {
+						handle: pend.handle,
+					}.into(),
+				);
+				// Which may have been the last on a connection going away
+				self.flush_h2(&conn.socket_handle);
+			} else if pend.close_after {
+				// Close connection now!
+				let req = socket::ReqClose {
+					handle: cfm.handle,
+					context: self.next_ctx.take(),
+					reset: false,
+				};
+				let pend = PendingCfm {
+					handle: pend.handle,
+					context: pend.context,
+					reply_to: pend.reply_to,
+					cfm_type: CfmType::Close,
+					close_after: false,
+				};
+				let _ = self.remove_connection(&pend.handle);
+				self.pending.insert(req.context, pend);
+				self.socket.send_request(req.into(), &self.reply_to);
+			}
+		}
+	}
+
+	/// Someone has connected to our bound socket, so create a Connection
+	/// and wait for data. We don't tell the layer above until we've parsed
+	/// the HTTP Request header.
+	fn handle_socket_ind_connected(&mut self, ind: socket::IndConnected) {
+		debug!("Got {:?}", ind);
+		if let Some(server_handle) = self.get_server_handle_by_socket_handle(&ind.listen_handle) {
+			if self.draining(&server_handle) {
+				// Accepted before the listener closed
+				debug!("Closing {:?}, server is draining", ind.conn_handle);
+				self.socket.send_request(
+					socket::ReqClose {
+						handle: ind.conn_handle,
+						context: Context::default(),
+						reset: false,
+					}.into(),
+					&self.reply_to,
+				);
+				return;
+			}
+			let limits = self.servers
+				.get(&server_handle)
+				.map(|s| s.config.limits.clone())
+				.unwrap_or_default();
+			let conn = Connection::new(
+				self.next_ctx.take(),
+				server_handle,
+				ind.conn_handle,
+				(ind.peer, ind.local),
+				&limits,
+			);
+			debug!(
+				"New connection {:?}, socket={:?}",
+				conn.our_handle, conn.socket_handle
+			);
+			if let Some(timeout) = self.servers
+				.get(&server_handle)
+				.and_then(|s| s.config.limits.header_timeout)
+			{
+				self.timers
+					.insert((conn.our_handle, Timer::Header), Instant::now() + timeout);
+			}
+			self.connections
+				.insert(conn.our_handle, conn.socket_handle, conn);
+		} else {
+			warn!("Connection on non-existant socket handle");
+		}
+	}
+
+	fn handle_socket_ind_dropped(&mut self, ind: socket::IndDropped) {
+		if let Some(conn) = self.remove_connection_alt(&ind.handle) {
+			if let Some(ref h2) = conn.h2 {
+				self.close_h2_streams(h2);
+			}
+			if let Some(closed_to) = conn.closed_to {
+				closed_to.send_indication(
+					IndClosed {
+						handle: conn.our_handle,
+					}.into(),
+				);
+			}
+		}
+	}
+
+	/// Feed data from the client through the parser, and tell our user
+	/// about the request once the head has arrived. If the client breaks
+	/// the server's limits, returns the status to reject it with.
+	fn handle_request_data(
+		&mut self,
+		handle: &socket::ConnHandle,
+		data: &[u8],
+	) -> Result<(), http::StatusCode> {
+		let conn_serv = self.get_conn_by_socket_handle(handle);
+		let (limits, trusted_proxies, auto, cors, http2) = match conn_serv {
+			Some((_, serv)) => (
+				serv.config.limits.clone(),
+				serv.config.trusted_proxies.clone(),
+				serv.config.auto.clone(),
+				serv.config.cors.clone(),
+				serv.config.http2.clone(),
+			),
+			None => {
+				warn!("Data on non-existant socket handle");
+				return Ok(());
+			}
+		};
+		let conn = self.connections.get_mut_alt(handle).unwrap();
+		debug!("Got data for conn {:?}!", conn.our_handle);
+		if conn.access.is_none() {
+			conn.access = Some((
+				Instant::now(),
+				AccessRecord {
+					peer: conn.peer,
+					client: conn.peer.ip(),
+					method: None,
+					uri: None,
+					version: None,
+					status: 0,
+					bytes_sent: 0,
+					referer: None,
+					user_agent: None,
+					time: SystemTime::now(),
+					duration: Duration::from_secs(0),
+				},
+			));
+		}
+
+		match conn.rx {
+			RxState::Head => conn.head.extend_from_slice(data),
+			RxState::Body { .. } => return self.handle_request_body(handle, data),
+			RxState::Done => return Ok(()),
+		}
+
+		// A client with prior knowledge of HTTP/2 starts with its preface
+		if let Some(ref config) = http2 {
+			let n = conn.head.len().min(h2::PREFACE.len());
+			if conn.head[..n] == h2::PREFACE[..n] {
+				if n < h2::PREFACE.len() {
+					return Ok(());
+				}
+				let data = ::std::mem::replace(&mut conn.head, Vec::new());
+				conn.start_h2(h2::Session::new(h2_settings(config, &limits)));
+				self.timers.remove(&(conn.our_handle, Timer::Header));
+				self.handle_h2_received(handle, &data);
+				return Ok(());
+			}
+		}
+
+		// The head stays where it is until it's all here
+		let (method, uri, version, headers, expected, body) = {
+			let (head, rest) = match conn.parser.parse(&conn.head) {
+				Ok(Parsed::Complete { head, rest }) => (head, rest),
+				Ok(Parsed::Partial) => return Ok(()),
+				Err(e) => {
+					debug!("Bad request on {:?}: {}", conn.our_handle, e);
+					return Err(e.status());
+				}
+			};
+			let parts = (head.http_method(), head.uri(), head.header_map());
+			let (method, uri, headers) = match parts {
+				(Ok(method), Ok(uri), Ok(headers)) => (method, uri, headers),
+				_ => return Err(http::StatusCode::BAD_REQUEST),
+			};
+			if let Some((_, ref mut record)) = conn.access {
+				let header = |name| {
+					head.header(name)
+						.map(|v| String::from_utf8_lossy(v).into_owned())
+				};
+				record.client = client_address(conn.peer.ip(), &trusted_proxies, &headers);
+				record.method = Some(method.clone());
+				record.uri = Some(uri.clone());
+				record.version = Some(head.version);
+				record.referer = header("referer");
+				record.user_agent = header("user-agent");
+			}
+			let expected = match head.body_length().map_err(|e| e.status())? {
+				BodyLength::Fixed(length) => Some(length),
+				_ => None,
+			};
+			(method, uri, head.version, headers, expected, rest.to_vec())
+		};
+		conn.head = Vec::new();
+
+		// All done! Check it's something we're prepared to deal with.
+		let received = body.len() as u64;
+		if expected.map_or(received, |e| e.max(received)) > limits.max_body as u64 {
+			return Err(http::StatusCode::PAYLOAD_TOO_LARGE);
+		}
+		let expected = expected.map(|e| e as usize);
+		let upgrade = http2
+			.as_ref()
+			.and_then(|config| h2c_upgrade(config, &limits, version, &headers, expected));
+		if let Some(session) = upgrade {
+			// The 101 goes first, then our SETTINGS, then the response to
+			// this request on stream 1
+			conn.start_h2(session);
+			self.timers.remove(&(conn.our_handle, Timer::Header));
+			self.socket.send_request(
+				socket::ReqSend {
+					handle: *handle,
+					context: Context::default(),
+					data: b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+					        Upgrade: h2c\r\n\r\n"
+						.to_vec(),
+				}.into(),
+				&self.reply_to,
+			);
+			let mut headers = headers;
+			for name in &["connection", "upgrade", "http2-settings"] {
+				headers.remove(*name);
+			}
+			self.handle_h2_request(handle, 1, method, uri, headers, true);
+			self.handle_h2_received(handle, &body);
+			return Ok(());
+		}
+		let awaiting_continue = auto.expect_continue
+			&& match &headers.get(http::header::EXPECT) {
+				// HTTP/1.0 clients can't ask, so we ignore it
+				Some(_) if version < Version::HTTP_11 => false,
+				Some(_) if expects_continue(version, &headers) => received == 0,
+				Some(_) => return Err(http::StatusCode::EXPECTATION_FAILED),
+				None => false,
+			};
+		if auto.options && method == Method::OPTIONS && uri == "*" {
+			return match options_head(&auto).and_then(|h| h.render()) {
+				Ok(data) => {
+					if let Some(conn) = self.connections.get_mut_alt(handle) {
+						conn.responded(200);
+					}
+					self.remove_connection_alt(handle);
+					self.send_and_close(handle, data);
+					Ok(())
+				}
+				Err(_) => Err(http::StatusCode::INTERNAL_SERVER_ERROR),
+			};
+		}
+		conn.cors = match cors.map(|c| c.check(&method, &headers)) {
+			Some(cors::Cors::Pass(cors)) => cors,
+			Some(cors::Cors::Allow(cors)) => return self.answer_preflight(handle, true, cors),
+			Some(cors::Cors::Refuse(cors)) => return self.answer_preflight(handle, false, cors),
+			None => HeaderMap::new(),
+		};
+		let (ind_to, host) = match self.servers.get(&conn.server_handle) {
+			Some(serv) => serv.route(&uri, &headers)?,
+			None => return Ok(()),
+		};
+		conn.rx = if expected == Some(0) {
+			RxState::Done
+		} else {
+			RxState::Body {
+				received: 0,
+				expected,
+				chunked: ChunkedDecoder::new(),
+			}
+		};
+
+		let (ch, sh, peer) = (conn.our_handle, conn.server_handle, conn.peer);
+		conn.ws_key = websocket::upgrade_key(&method, &headers);
+		conn.head_request = method == Method::HEAD;
+		conn.discard_body = conn.head_request && auto.head;
+		conn.awaiting_continue = awaiting_continue;
+		conn.encoding = choose_encoding(&headers);
+		conn.chunked_ok = version == http::Version::HTTP_11;
+		conn.closed_to = Some(ind_to.clone());
+		conn.body_to = Some(ind_to.clone());
+		let ind = IndRxRequest {
+			server_handle: sh,
+			connection_handle: ch,
+			url: uri.clone(),
+			method: if conn.discard_body {
+				Method::GET
+			} else {
+				method.clone()
+			},
+			version,
+			headers: headers.clone(),
+			peer,
+			local: conn.local,
+			client: client_address(peer.ip(), &trusted_proxies, &headers),
+			host,
+			body: expected != Some(0),
+		};
+
+		self.timers.remove(&(ch, Timer::Header));
+		if let (RxState::Body { .. }, Some(timeout), false) =
+			(conn.rx, limits.body_timeout, awaiting_continue)
+		{
+			self.timers
+				.insert((ch, Timer::Body), Instant::now() + timeout);
+		}
+		ind_to.send_indication(ind.into());
+		if body.is_empty() {
+			Ok(())
+		} else {
+			self.handle_request_body(handle, &body)
+		}
+	}
+
+	/// Pass some of a request body up to whoever had the `IndRxRequest`.
+	fn handle_request_body(
+		&mut self,
+		handle: &socket::ConnHandle,
+		data: &[u8],
+	) -> Result<(), http::StatusCode> {
+		let (max_body, flow_control) = match self.get_conn_by_socket_handle(handle) {
+			Some((_, serv)) => (serv.config.limits.max_body, serv.config.body_flow_control),
+			None => return Ok(()),
+		};
+		let conn = self.connections.get_mut_alt(handle).unwrap();
+		let (piece, last) = match conn.rx {
+			RxState::Body {
+				ref mut received,
+				expected,
+				ref mut chunked,
+			} => {
+				*received += data.len();
+				if *received > max_body {
+					return Err(http::StatusCode::PAYLOAD_TOO_LARGE);
+				}
+				match expected {
+					Some(e) => {
+						// Anything past the end isn't ours to pass on
+						let extra = received.saturating_sub(e);
+						(data[..data.len() - extra].to_vec(), *received >= e)
+					}
+					None => {
+						let mut piece = Vec::new();
+						chunked
+							.decode(data, &mut piece)
+							.map_err(|e| e.status())?;
+						(piece, chunked.is_done())
+					}
+				}
+			}
+			_ => return Ok(()),
+		};
+		if last {
+			conn.rx = RxState::Done;
+			self.timers.remove(&(conn.our_handle, Timer::Body));
+		}
+		if let Some(ref body_to) = conn.body_to {
+			if !piece.is_empty() || last {
+				body_to.send_indication(
+					IndRxBody {
+						handle: conn.our_handle,
+						data: piece,
+						last,
+					}.into(),
+				);
+				if flow_control && !last {
+					// Read no more until the user has dealt with this
+					let now = Instant::now();
+					conn.rx_blocked = true;
+					conn.body_time_left = self.timers
+						.remove(&(conn.our_handle, Timer::Body))
+						.map(|t| t.saturating_duration_since(now));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// The user wants more of a request body.
+	fn handle_rx_body(&mut self, rsp: RspRxBody) {
+		if self.streams.contains_key(&rsp.handle) {
+			self.h2_release(&rsp.handle, false);
+		} else {
+			self.resume_rx(&rsp.handle);
+		}
+	}
+
+	/// Start reading a request body again, if we'd stopped.
+	fn resume_rx(&mut self, handle: &ConnHandle) {
+		let skt = match self.connections.get_mut(handle) {
+			Some(conn) if conn.rx_blocked => {
+				conn.rx_blocked = false;
+				if let (RxState::Body { .. }, Some(left)) = (conn.rx, conn.body_time_left.take()) {
+					self.timers
+						.insert((conn.our_handle, Timer::Body), Instant::now() + left);
+				}
+				if conn.socket_blocked {
+					conn.socket_blocked = false;
+					Some(conn.socket_handle)
 				} else {
-					panic!("HTTP Bind failed");
+					None
+				}
+			}
+			_ => None,
+		};
+		if let Some(skt) = skt {
+			self.socket
+				.send_response(socket::Response::Received(socket::RspReceived {
+					handle: skt,
+				}));
+		}
+	}
+
+	/// Refuse a request with an error response and drop the connection.
+	/// If the server wants to know, ask the user for the response instead.
+	fn reject_request(&mut self, handle: &socket::ConnHandle, status: http::StatusCode) {
+		debug!("Rejecting request on {:?} with {}", handle, status);
+		let indicate = self.connections.get_alt(handle).and_then(|c| {
+			self.servers
+				.get(&c.server_handle)
+				.and_then(|s| s.config.errors.indicate.map(|t| (t, s.ind_to.clone())))
+		});
+		match (indicate, self.connections.get_mut_alt(handle)) {
+			(Some((timeout, ind_to)), Some(conn)) => {
+				let ind_to = conn.closed_to.take().unwrap_or(ind_to);
+				conn.closed_to = Some(ind_to.clone());
+				conn.rx = RxState::Done;
+				conn.refused = Some(status);
+				conn.awaiting_continue = false;
+				conn.ws_key = None;
+				let (method, url, client) = match conn.access {
+					Some((_, ref record)) => {
+						(record.method.clone(), record.uri.clone(), record.client)
+					}
+					None => (None, None, conn.peer.ip()),
+				};
+				let ch = conn.our_handle;
+				self.timers.remove(&(ch, Timer::Header));
+				self.timers.remove(&(ch, Timer::Body));
+				self.timers
+					.insert((ch, Timer::ErrorPage), Instant::now() + timeout);
+				ind_to.send_indication(
+					IndError {
+						server_handle: conn.server_handle,
+						connection_handle: ch,
+						status,
+						method,
+						url,
+						peer: conn.peer,
+						client,
+					}.into(),
+				);
+			}
+			_ => self.send_error(handle, status),
+		}
+	}
+
+	/// Refuse a request with an error response now, and drop the
+	/// connection.
+	fn send_error(&mut self, handle: &socket::ConnHandle, status: http::StatusCode) {
+		let (conn, output) = self.remove_refused(handle, status);
+		if let Some(conn) = conn {
+			// They'll never hear from the connection again
+			if let Some(closed_to) = conn.closed_to {
+				closed_to.send_indication(
+					IndClosed {
+						handle: conn.our_handle,
+					}.into(),
+				);
+			}
+		}
+		self.send_and_close(handle, output);
+	}
+
+	/// Answer a CORS preflight with a 204 if we allow it, or a 403 if not
+	fn answer_preflight(
+		&mut self,
+		handle: &socket::ConnHandle,
+		allow: bool,
+		headers: HeaderMap,
+	) -> Result<(), http::StatusCode> {
+		let head = preflight_head(allow, headers);
+		let data = head.render()
+			.map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+		if let Some(conn) = self.connections.get_mut_alt(handle) {
+			conn.responded(head.code());
+		}
+		self.remove_connection_alt(handle);
+		self.send_and_close(handle, data);
+		Ok(())
+	}
+
+	fn handle_socket_ind_received(&mut self, ind: socket::IndReceived) {
+		debug!("Got {:?}", ind);
+		if self.connections
+			.get_alt(&ind.handle)
+			.map_or(false, |c| c.h2.is_some())
+		{
+			self.handle_h2_received(&ind.handle, &ind.data);
+			self.socket
+				.send_response(socket::Response::Received(socket::RspReceived {
+					handle: ind.handle,
+				}));
+			return;
+		}
+		if self.connections
+			.get_alt(&ind.handle)
+			.map_or(false, |c| c.ws.is_some())
+		{
+			let conn = self.connections.remove_alt(&ind.handle).unwrap();
+			self.handle_ws_received(conn, &ind.data);
+			self.socket
+				.send_response(socket::Response::Received(socket::RspReceived {
+					handle: ind.handle,
+				}));
+			return;
+		}
+		if let Err(status) = self.handle_request_data(&ind.handle, &ind.data) {
+			self.reject_request(&ind.handle, status);
+		}
+
+		let blocked = match self.connections.get_mut_alt(&ind.handle) {
+			Some(conn) if conn.rx_blocked => {
+				conn.socket_blocked = true;
+				true
+			}
+			_ => false,
+		};
+		if !blocked {
+			self.socket
+				.send_response(socket::Response::Received(socket::RspReceived {
+					handle: ind.handle,
+				}));
+		}
+	}
+
+	/// Data has arrived on an HTTP/2 connection. Pass it through the
+	/// session and deal with what comes out.
+	fn handle_h2_received(&mut self, handle: &socket::ConnHandle, data: &[u8]) {
+		let result = match self.connections
+			.get_mut_alt(handle)
+			.and_then(|c| c.h2.as_mut())
+		{
+			Some(h2) => h2.session.receive(data),
+			None => return,
+		};
+		match result {
+			Ok(events) => for event in events {
+				match event {
+					h2::Event::Request {
+						stream,
+						method,
+						uri,
+						headers,
+						end_stream,
+					} => self.handle_h2_request(handle, stream, method, uri, headers, end_stream),
+					h2::Event::Data {
+						stream,
+						data,
+						end_stream,
+					} => self.handle_h2_data(handle, stream, data, end_stream),
+					h2::Event::Reset { stream } => self.handle_h2_reset(handle, stream),
 				}
+			},
+			Err(code) => {
+				debug!("HTTP/2 error {} on {:?}", code, handle);
+				if let Some(h2) = self.connections
+					.get_mut_alt(handle)
+					.and_then(|c| c.h2.as_mut())
+				{
+					h2.session.go_away(code);
+				}
+				self.flush_h2(handle);
+				self.close_h2(handle);
+				return;
+			}
+		}
+		let draining = self.connections
+			.get_alt(handle)
+			.map_or(false, |c| self.draining(&c.server_handle));
+		if draining {
+			if let Some(h2) = self.connections
+				.get_mut_alt(handle)
+				.and_then(|c| c.h2.as_mut())
+			{
+				h2.session.go_away(h2::NO_ERROR);
+			}
+		}
+		self.flush_h2(handle);
+	}
+
+	/// A request has arrived on an HTTP/2 stream. Check it as we would an
+	/// HTTP/1 request, then pass it up with a `ConnHandle` of its own.
+	fn handle_h2_request(
+		&mut self,
+		handle: &socket::ConnHandle,
+		stream: u32,
+		method: Method,
+		uri: Uri,
+		headers: HeaderMap,
+		end_stream: bool,
+	) {
+		let (server_handle, addrs) = match self.connections.get_alt(handle) {
+			Some(conn) => (conn.server_handle, (conn.peer, conn.local)),
+			None => return,
+		};
+		let (limits, trusted_proxies, auto, cors) = match self.servers.get(&server_handle) {
+			Some(serv) => (
+				serv.config.limits.clone(),
+				serv.config.trusted_proxies.clone(),
+				serv.config.auto.clone(),
+				serv.config.cors.clone(),
+			),
+			None => return,
+		};
+		let expected = headers
+			.get(http::header::CONTENT_LENGTH)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.parse::<u64>().ok());
+		let client = client_address(addrs.0.ip(), &trusted_proxies, &headers);
+		let ch = self.next_ctx.take();
+		let mut conn = Connection::new(ch, server_handle, *handle, addrs, &limits);
+		conn.stream = Some(stream);
+		conn.rx = match expected {
+			_ if end_stream => RxState::Done,
+			Some(0) => RxState::Done,
+			_ => RxState::Body {
+				received: 0,
+				expected: expected.map(|e| e as usize),
+				chunked: ChunkedDecoder::new(),
+			},
+		};
+		let header = |name| {
+			headers
+				.get(name)
+				.map(|v: &HeaderValue| String::from_utf8_lossy(v.as_bytes()).into_owned())
+		};
+		conn.access = Some((
+			Instant::now(),
+			AccessRecord {
+				peer: addrs.0,
+				client,
+				method: Some(method.clone()),
+				uri: Some(uri.clone()),
+				version: Some(Version::HTTP_2),
+				status: 0,
+				bytes_sent: 0,
+				referer: header(http::header::REFERER),
+				user_agent: header(http::header::USER_AGENT),
+				time: SystemTime::now(),
+				duration: Duration::from_secs(0),
+			},
+		));
+		if let Some(h2) = self.connections
+			.get_mut_alt(handle)
+			.and_then(|c| c.h2.as_mut())
+		{
+			h2.streams.insert(stream, ch);
+		}
+		self.streams.insert(ch, conn);
+
+		// The same limits as for HTTP/1, with the head as it would have been
+		let target = uri.to_string();
+		let head_size = method.as_str().len() + target.len()
+			+ headers
+				.iter()
+				.map(|(n, v)| n.as_str().len() + v.len() + 4)
+				.sum::<usize>();
+		let refusal = if head_size > limits.max_header_bytes || headers.len() > limits.max_headers
+		{
+			Some(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+		} else if target.len() > limits.max_uri_len {
+			Some(http::StatusCode::URI_TOO_LONG)
+		} else if expected.map_or(false, |e| e > limits.max_body as u64) {
+			Some(http::StatusCode::PAYLOAD_TOO_LARGE)
+		} else {
+			None
+		};
+		if let Some(status) = refusal {
+			self.h2_send_error(ch, status);
+			return;
+		}
+		if auto.options && method == Method::OPTIONS && uri == "*" {
+			match options_head(&auto) {
+				Ok(head) => self.h2_respond(ch, &head, &[]),
+				Err(_) => self.h2_send_error(ch, http::StatusCode::INTERNAL_SERVER_ERROR),
+			}
+			return;
+		}
+		let cors = match cors.map(|c| c.check(&method, &headers)) {
+			Some(cors::Cors::Pass(cors)) => cors,
+			Some(cors::Cors::Allow(cors)) => {
+				self.h2_respond(ch, &preflight_head(true, cors), &[]);
+				return;
 			}
+			Some(cors::Cors::Refuse(cors)) => {
+				self.h2_respond(ch, &preflight_head(false, cors), &[]);
+				return;
+			}
+			None => HeaderMap::new(),
+		};
+		let route = self.servers
+			.get(&server_handle)
+			.map(|s| s.route(&uri, &headers));
+		let (ind_to, host) = match route {
+			Some(Ok(route)) => route,
+			Some(Err(status)) => {
+				self.h2_send_error(ch, status);
+				return;
+			}
+			None => return,
+		};
+		let conn = self.streams.get_mut(&ch).unwrap();
+		conn.cors = cors;
+		conn.head_request = method == Method::HEAD;
+		conn.discard_body = conn.head_request && auto.head;
+		conn.encoding = choose_encoding(&headers);
+		conn.closed_to = Some(ind_to.clone());
+		conn.body_to = Some(ind_to.clone());
+		let ind = IndRxRequest {
+			server_handle,
+			connection_handle: ch,
+			url: uri,
+			method: if conn.discard_body { Method::GET } else { method },
+			version: Version::HTTP_2,
+			headers,
+			peer: addrs.0,
+			local: addrs.1,
+			client,
+			host,
+			body: !end_stream && expected != Some(0),
+		};
+		ind_to.send_indication(ind.into());
+	}
+
+	/// Pass some of a request body on an HTTP/2 stream up to whoever had
+	/// the `IndRxRequest`.
+	fn handle_h2_data(
+		&mut self,
+		handle: &socket::ConnHandle,
+		stream: u32,
+		data: Vec<u8>,
+		end_stream: bool,
+	) {
+		let length = data.len();
+		let ch = match self.stream_handle(handle, stream) {
+			Some(ch) => ch,
+			None => return self.h2_consumed(handle, stream, length),
+		};
+		let (max_body, flow_control) = match self.streams
+			.get(&ch)
+			.and_then(|c| self.servers.get(&c.server_handle))
+		{
+			Some(serv) => (serv.config.limits.max_body, serv.config.body_flow_control),
+			None => return self.h2_consumed(handle, stream, length),
+		};
+		let too_large = match self.streams.get_mut(&ch).map(|c| &mut c.rx) {
+			Some(&mut RxState::Body {
+				ref mut received, ..
+			}) => {
+				*received += length;
+				*received > max_body
+			}
+			// Once a response has started, the rest of the body is ignored
+			_ => return self.h2_consumed(handle, stream, length),
+		};
+		if too_large {
+			self.h2_send_error(ch, http::StatusCode::PAYLOAD_TOO_LARGE);
+			self.h2_consumed(handle, stream, length);
+			return;
+		}
+		let conn = self.streams.get_mut(&ch).unwrap();
+		if end_stream {
+			conn.rx = RxState::Done;
+		}
+		let owed = match conn.body_to {
+			Some(ref body_to) if !data.is_empty() || end_stream => {
+				body_to.send_indication(
+					IndRxBody {
+						handle: ch,
+						data,
+						last: end_stream,
+					}.into(),
+				);
+				flow_control && !end_stream
+			}
+			_ => false,
+		};
+		if owed {
+			// The client can send more once the user has dealt with this
+			conn.rx_owed.push_back(length);
+		} else {
+			self.h2_consumed(handle, stream, length);
+		}
+	}
+
+	/// The client has given up on a stream, or it broke the rules.
+	fn handle_h2_reset(&mut self, handle: &socket::ConnHandle, stream: u32) {
+		let ch = match self.stream_handle(handle, stream) {
+			Some(ch) => ch,
+			None => return,
+		};
+		debug!("Stream {} reset on {:?}", stream, handle);
+		if let Some(conn) = self.remove_stream(&ch) {
+			self.fail_pending(&ch, Error::BadHandle);
+			if let Some(closed_to) = conn.closed_to {
+				closed_to.send_indication(IndClosed { handle: ch }.into());
+			}
+		}
+	}
+
+	fn handle_h2_responsestart(
+		&mut self,
+		mut req_start: ReqResponseStart,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let compress_default = self.compress_default(&req_start.handle);
+		let (stream, head) = {
+			let conn = self.streams.get_mut(&req_start.handle).unwrap();
+			let head = conn.start_response(&mut req_start, compress_default, &reply_to);
+			(conn.stream.unwrap_or(0), head)
+		};
+		// Any more of the request body is thrown away, so don't hold it up
+		self.h2_release(&req_start.handle, true);
+		let fields = match head.fields() {
+			Ok(fields) => fields,
+			Err(e) => {
+				reply_to.send_confirm(
+					CfmResponseStart {
+						context: req_start.context,
+						handle: req_start.handle,
+						result: Err(e),
+					}.into(),
+				);
+				// Nothing has gone out, so they can try again
+				let conn = self.streams.get_mut(&req_start.handle).unwrap();
+				conn.encoder = None;
+				conn.body_length = None;
+				return;
+			}
+		};
+		let end_stream = req_start.length == Some(0) || !head.has_body();
+		let conn = self.streams.get_mut(&req_start.handle).unwrap();
+		conn.responded(head.code());
+		if end_stream {
+			conn.encoder = None;
+			conn.body_length = Some(0);
+		}
+		let block = h2::encode_head(head.code(), &fields);
+		let pend = PendingCfm {
+			handle: req_start.handle,
+			context: req_start.context,
+			reply_to,
+			cfm_type: CfmType::Start,
+			close_after: end_stream,
+		};
+		self.h2_queue(pend, |session, tag| {
+			session.send_headers(stream, &block, end_stream, Some(tag))
+		});
+	}
+
+	fn handle_h2_responsebody(
+		&mut self,
+		req_body: ReqResponseBody,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let (stream, result, discard) = {
+			let conn = self.streams.get_mut(&req_body.handle).unwrap();
+			let result = conn.body_sent(req_body.data.len());
+			(conn.stream.unwrap_or(0), result, conn.discard_body)
+		};
+		let close_after = match result {
+			Ok(close_after) => close_after,
+			Err(e) => {
+				reply_to.send_confirm(
+					CfmResponseBody {
+						context: req_body.context,
+						handle: req_body.handle,
+						result: Err(e),
+					}.into(),
+				);
+				return;
+			}
+		};
+		// An empty body ends an unbounded response
+		let last = close_after || req_body.data.is_empty();
+		let pend = PendingCfm {
+			handle: req_body.handle,
+			context: req_body.context,
+			reply_to,
+			cfm_type: CfmType::Body,
+			close_after: last,
+		};
+		if discard {
+			// Answering a HEAD as if it were a GET, so only the end of the
+			// stream goes out
+			if last {
+				self.h2_queue(pend, |session, tag| {
+					session.send_data(stream, Vec::new(), true, Some(tag))
+				});
+			} else {
+				Self::send_cfm(&pend, Ok(()));
+			}
+			return;
+		}
+		let conn = self.streams.get_mut(&req_body.handle).unwrap();
+		let data = match conn.encoder {
+			Some(ref mut encoder) => encoder.encode(&req_body.data, last),
+			None => req_body.data,
+		};
+		conn.sent(data.len());
+		self.h2_queue(pend, |session, tag| {
+			session.send_data(stream, data, last, Some(tag))
+		});
+	}
+
+	fn handle_h2_abort(&mut self, req: ReqAbort, reply_to: grease::ServiceUserHandle<Service>) {
+		let conn = self.remove_stream(&req.handle).unwrap();
+		debug!("Aborting stream {:?} on {:?}", conn.stream, conn.socket_handle);
+		self.fail_pending(&req.handle, Error::Aborted);
+		if let (Some(h2), Some(stream)) = (
+			self.connections
+				.get_mut_alt(&conn.socket_handle)
+				.and_then(|c| c.h2.as_mut()),
+			conn.stream,
+		) {
+			h2.session.reset(stream, h2::INTERNAL_ERROR);
+		}
+		reply_to.send_confirm(
+			CfmAbort {
+				handle: req.handle,
+				context: req.context,
+				result: Ok(()),
+			}.into(),
+		);
+		if let Some(closed_to) = conn.closed_to {
+			closed_to.send_indication(
+				IndClosed {
+					handle: conn.our_handle,
+				}.into(),
+			);
+		}
+		self.flush_h2(&conn.socket_handle);
+	}
+
+	/// The request on a stream of an HTTP/2 connection
+	fn stream_handle(&self, handle: &socket::ConnHandle, stream: u32) -> Option<ConnHandle> {
+		self.connections
+			.get_alt(handle)
+			.and_then(|c| c.h2.as_ref())
+			.and_then(|h2| h2.streams.get(&stream).cloned())
+	}
+
+	/// Some of the request body on a stream has been dealt with, so the
+	/// client may send more.
+	fn h2_consumed(&mut self, handle: &socket::ConnHandle, stream: u32, length: usize) {
+		if let Some(h2) = self.connections
+			.get_mut_alt(handle)
+			.and_then(|c| c.h2.as_mut())
+		{
+			h2.session.consumed(stream, length);
+		}
+	}
+
+	/// The user has dealt with the oldest piece of request body on a
+	/// stream, or with all of them, so the client may send more.
+	fn h2_release(&mut self, handle: &ConnHandle, all: bool) {
+		let (skt, stream, length) = match self.streams.get_mut(handle) {
+			Some(conn) => {
+				let length = if all {
+					conn.rx_owed.drain(..).sum()
+				} else {
+					conn.rx_owed.pop_front().unwrap_or(0)
+				};
+				(conn.socket_handle, conn.stream.unwrap_or(0), length)
+			}
+			None => return,
+		};
+		if length > 0 {
+			self.h2_consumed(&skt, stream, length);
+			self.flush_h2(&skt);
+		}
+	}
+
+	/// Queue something on a stream's session, to be confirmed once it has
+	/// gone to the socket task.
+	fn h2_queue<F>(&mut self, pend: PendingCfm, queue: F)
+	where
+		F: FnOnce(&mut h2::Session, Context) -> bool,
+	{
+		let skt = match self.streams.get(&pend.handle) {
+			Some(conn) => conn.socket_handle,
+			None => return,
+		};
+		let tag = self.next_ctx.take();
+		let queued = self.connections
+			.get_mut_alt(&skt)
+			.and_then(|c| c.h2.as_mut())
+			.map_or(false, |h2| queue(&mut h2.session, tag));
+		if queued {
+			self.pending.insert(tag, pend);
+			self.flush_h2(&skt);
+		} else {
+			Self::send_cfm(&pend, Err(Error::BadHandle));
+		}
+	}
+
+	/// Answer a request on an HTTP/2 stream ourselves, and forget it.
+	fn h2_respond(&mut self, handle: ConnHandle, head: &head::ResponseHead, body: &[u8]) {
+		match head.fields() {
+			Ok(fields) => self.h2_send(handle, head.code(), &fields, body),
+			Err(_) => self.h2_send_error(handle, http::StatusCode::INTERNAL_SERVER_ERROR),
+		}
+	}
+
+	/// Refuse a request on an HTTP/2 stream with the server's page for
+	/// that status, or a short one of our own.
+	fn h2_send_error(&mut self, handle: ConnHandle, status: http::StatusCode) {
+		debug!("Rejecting request on {:?} with {}", handle, status);
+		let page = self.streams
+			.get(&handle)
+			.and_then(|c| self.servers.get(&c.server_handle))
+			.and_then(|s| s.config.errors.pages.get(&status))
+			.cloned();
+		let page = page.unwrap_or_else(|| ErrorPage {
+			content_type: "text/plain".to_owned(),
+			body: error_body(status).as_bytes().to_vec(),
+			headers: HeaderMap::new(),
+		});
+		let head = head::ResponseHead::refusal(status)
+			.content_type(&page.content_type)
+			.length(Some(page.body.len()))
+			.headers(page.headers);
+		// The pages were checked when the server was bound
+		let fields = head.fields().unwrap_or_default();
+		self.h2_send(handle, status.as_u16(), &fields, &page.body);
+	}
+
+	fn h2_send(
+		&mut self,
+		handle: ConnHandle,
+		code: u16,
+		fields: &[(String, HeaderValue)],
+		body: &[u8],
+	) {
+		if let Some(conn) = self.streams.get_mut(&handle) {
+			conn.responded(code);
+			conn.sent(body.len());
+		}
+		let conn = match self.remove_stream(&handle) {
+			Some(conn) => conn,
+			None => return,
+		};
+		let block = h2::encode_head(code, fields);
+		if let (Some(h2), Some(stream)) = (
+			self.connections
+				.get_mut_alt(&conn.socket_handle)
+				.and_then(|c| c.h2.as_mut()),
+			conn.stream,
+		) {
+			h2.session.send_headers(stream, &block, body.is_empty(), None);
+			if !body.is_empty() {
+				h2.session.send_data(stream, body.to_vec(), true, None);
+			}
+		}
+		if let Some(closed_to) = conn.closed_to {
+			closed_to.send_indication(IndClosed { handle }.into());
+		}
+		self.flush_h2(&conn.socket_handle);
+	}
+
+	/// Send whatever an HTTP/2 connection has ready, and close it once the
+	/// session is over.
+	fn flush_h2(&mut self, handle: &socket::ConnHandle) {
+		let (segments, finished) = match self.connections
+			.get_mut_alt(handle)
+			.and_then(|c| c.h2.as_mut())
+		{
+			// Wait for the last response's cfm before closing
+			Some(h2) => (
+				h2.session.flush(),
+				h2.session.is_finished() && h2.streams.is_empty(),
+			),
+			None => return,
+		};
+		for (data, tag) in segments {
+			self.socket.send_request(
+				socket::ReqSend {
+					handle: *handle,
+					context: tag.unwrap_or_default(),
+					data,
+				}.into(),
+				&self.reply_to,
+			);
+		}
+		if finished {
+			self.close_h2(handle);
+		}
+	}
+
+	/// Close an HTTP/2 connection, and with it any streams still open.
+	fn close_h2(&mut self, handle: &socket::ConnHandle) {
+		if let Some(conn) = self.remove_connection_alt(handle) {
+			if let Some(ref h2) = conn.h2 {
+				self.close_h2_streams(h2);
+			}
+			self.socket.send_request(
+				socket::ReqClose {
+					handle: *handle,
+					context: Context::default(),
+					reset: false,
+				}.into(),
+				&self.reply_to,
+			);
+		}
+	}
+
+	/// Forget the streams on an HTTP/2 connection which has gone.
+	fn close_h2_streams(&mut self, h2: &Http2) {
+		for handle in h2.streams.values() {
+			if let Some(conn) = self.streams.remove(handle) {
+				self.log_access(&conn);
+				self.fail_pending(handle, Error::BadHandle);
+				if let Some(closed_to) = conn.closed_to {
+					closed_to.send_indication(IndClosed { handle: *handle }.into());
+				}
+			}
+		}
+	}
+}
+
+/// The settings for an HTTP/2 session on a server
+fn h2_settings(config: &Http2Config, limits: &Limits) -> h2::Settings {
+	h2::Settings {
+		max_concurrent_streams: config.max_concurrent_streams,
+		initial_window_size: config.initial_window_size,
+		// Each field costs another 32 bytes, as RFC 7540 counts them
+		max_header_list_size: (limits.max_header_bytes + 32 * limits.max_headers) as u32,
+	}
+}
+
+/// If a request asks to switch to HTTP/2 with `Upgrade: h2c`, the session
+/// to switch to. Requests with a body stay on HTTP/1.1.
+fn h2c_upgrade(
+	config: &Http2Config,
+	limits: &Limits,
+	version: Version,
+	headers: &HeaderMap,
+	expected: Option<usize>,
+) -> Option<h2::Session> {
+	let tokens = |name| -> Vec<String> {
+		headers
+			.get_all(name)
+			.iter()
+			.flat_map(|v| v.to_str().unwrap_or("").split(','))
+			.map(|t| t.trim().to_ascii_lowercase())
+			.collect()
+	};
+	let connection = tokens(http::header::CONNECTION);
+	let mut settings = headers.get_all("http2-settings").iter();
+	let settings = match (settings.next(), settings.next()) {
+		(Some(value), None) => value,
+		_ => return None,
+	};
+	if version != Version::HTTP_11
+		|| expected != Some(0)
+		|| !tokens(http::header::UPGRADE).contains(&"h2c".to_owned())
+		|| !connection.contains(&"upgrade".to_owned())
+		|| !connection.contains(&"http2-settings".to_owned())
+	{
+		return None;
+	}
+	h2::Session::upgraded(h2_settings(config, limits), settings.as_bytes())
+}
+
+/// The answer to `OPTIONS *`, listing the methods the server supports
+fn options_head(auto: &AutoResponses) -> Result<head::ResponseHead, Error> {
+	let allow = HeaderValue::from_str(&allow_list(auto)).map_err(|_| Error::BadHeader)?;
+	Ok(head::ResponseHead::new(HttpResponseStatus::OK)
+		.length(Some(0))
+		.header("Allow", allow))
+}
+
+/// The answer to a CORS preflight - a 204 if we allow it, or a 403 if not
+fn preflight_head(allow: bool, headers: HeaderMap) -> head::ResponseHead {
+	let status = if allow {
+		HttpResponseStatus::NoContent
+	} else {
+		HttpResponseStatus::Forbidden
+	};
+	head::ResponseHead::new(status)
+		.length(Some(0))
+		.headers(headers)
+}
+
+/// Is the request `Expect: 100-continue`? Only HTTP/1.1 clients can ask.
+fn expects_continue(version: Version, headers: &HeaderMap) -> bool {
+	version >= Version::HTTP_11
+		&& headers
+			.get(http::header::EXPECT)
+			.and_then(|v| v.to_str().ok())
+			.map_or(false, |v| v.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// The `Allow` header for `OPTIONS *`. HEAD only works if it's answered
+/// for the server, and OPTIONS always is, so we add those.
+fn allow_list(auto: &AutoResponses) -> String {
+	let mut methods: Vec<&Method> = Vec::new();
+	let extra = [Method::HEAD, Method::OPTIONS];
+	let extra = if auto.head { &extra[..] } else { &extra[1..] };
+	for method in auto.allow.iter().chain(extra.iter()) {
+		if !methods.contains(&method) {
+			methods.push(method);
+		}
+	}
+	methods
+		.iter()
+		.map(|m| m.as_str())
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+/// Work out who originated a request. If it came from a trusted proxy, walk
+/// back along the chain of addresses the proxies have recorded until we
+/// find one we don't trust - that's the client. Anything we can't parse
+/// stops the walk, as we can't know who sent it.
+fn client_address(
+	peer: net::IpAddr,
+	trusted: &[net::IpAddr],
+	headers: &HeaderMap,
+) -> net::IpAddr {
+	let mut client = peer;
+	if !trusted.contains(&peer) {
+		return client;
+	}
+	let forwarded = headers.get_all(http::header::FORWARDED);
+	let chain: Vec<Option<net::IpAddr>> = if forwarded.iter().next().is_some() {
+		forwarded
+			.iter()
+			.flat_map(|v| v.to_str().unwrap_or("").split(','))
+			.map(|element| {
+				element
+					.split(';')
+					.filter_map(|pair| {
+						let mut kv = pair.trim().splitn(2, '=');
+						match (kv.next(), kv.next()) {
+							(Some(k), Some(v)) if k.eq_ignore_ascii_case("for") => Some(v),
+							_ => None,
+						}
+					})
+					.next()
+					.and_then(parse_node)
+			})
+			.collect()
+	} else {
+		headers
+			.get_all("x-forwarded-for")
+			.iter()
+			.flat_map(|v| v.to_str().unwrap_or("").split(','))
+			.map(parse_node)
+			.collect()
+	};
+	for node in chain.iter().rev() {
+		match *node {
+			Some(addr) => {
+				client = addr;
+				if !trusted.contains(&addr) {
+					break;
+				}
+			}
+			None => break,
+		}
+	}
+	client
+}
+
+/// Parse a node from a `Forwarded` or `X-Forwarded-For` header. These can
+/// be quoted, bracketed (for IPv6) and can carry a port. Obfuscated
+/// identifiers and "unknown" give None.
+fn parse_node(node: &str) -> Option<net::IpAddr> {
+	let node = node.trim().trim_matches('"');
+	if let Ok(addr) = node.parse::<net::IpAddr>() {
+		Some(addr)
+	} else if let Ok(addr) = node.parse::<net::SocketAddr>() {
+		Some(addr.ip())
+	} else if node.starts_with('[') {
+		node[1..].split(']').next().and_then(|a| a.parse().ok())
+	} else {
+		None
+	}
+}
+
+/// Render an event in the `text/event-stream` format. Returns None if the
+/// id or event type would break the format.
+fn format_sse_event(req: &ReqSseEvent) -> Option<String> {
+	let single_line = |s: &str| !s.contains('\r') && !s.contains('\n');
+	let mut s = String::new();
+	if let Some(ref id) = req.id {
+		if !single_line(id) || id.contains('\0') {
+			return None;
+		}
+		s.push_str(&format!("id: {}\n", id));
+	}
+	if let Some(ref event) = req.event {
+		if !single_line(event) {
+			return None;
+		}
+		s.push_str(&format!("event: {}\n", event));
+	}
+	if let Some(retry) = req.retry {
+		s.push_str(&format!("retry: {}\n", retry));
+	}
+	// Any of CRLF, CR or LF ends a line
+	for line in req.data.replace("\r\n", "\n").split(|c| c == '\r' || c == '\n') {
+		s.push_str(&format!("data: {}\n", line));
+	}
+	s.push('\n');
+	Some(s)
+}
+
+/// The body of the responses we generate when we refuse a request
+fn error_body(status: http::StatusCode) -> &'static str {
+	status.canonical_reason().unwrap_or("Error")
+}
+
+/// Can we send this page when we refuse a request?
+fn valid_error_page((status, page): (&http::StatusCode, &ErrorPage)) -> bool {
+	let ours = [
+		http::header::CONTENT_TYPE,
+		http::header::CONTENT_LENGTH,
+		http::header::CONNECTION,
+		http::header::TRANSFER_ENCODING,
+	];
+	(status.is_client_error() || status.is_server_error())
+		&& HeaderValue::from_str(&page.content_type).is_ok()
+		&& !ours.iter().any(|h| page.headers.contains_key(h))
+		&& page.headers
+			.values()
+			.all(|v| !v.as_bytes().iter().any(|&b| b == b'\r' || b == b'\n' || b == 0))
+}
+
+impl Server {
+	/// Work out who gets a request - the virtual host it's for, or whoever
+	/// bound the server. If no one does, gives the status to refuse it with.
+	fn route(
+		&self,
+		uri: &Uri,
+		headers: &HeaderMap,
+	) -> Result<(grease::ServiceUserHandle<Service>, Option<HostHandle>), http::StatusCode> {
+		let name = vhost::request_host(uri, headers);
+		let best = self.hosts
+			.iter()
+			.filter_map(|h| h.pattern.score(name.as_ref().map(|n| &n[..])).map(|s| (s, h)))
+			.max_by_key(|&(s, _)| s);
+		match (best, self.config.unknown_host) {
+			(Some((_, h)), _) => Ok((h.ind_to.clone(), Some(h.handle))),
+			(None, None) => Ok((self.ind_to.clone(), None)),
+			(None, Some(status)) => Err(status),
+		}
+	}
+}
+
+impl Connection {
+	fn new(
+		our_handle: ConnHandle,
+		server_handle: ServerHandle,
+		socket_handle: socket::ConnHandle,
+		(peer, local): (net::SocketAddr, net::SocketAddr),
+		limits: &Limits,
+	) -> Connection {
+		Connection {
+			our_handle,
+			server_handle,
+			socket_handle,
+			peer,
+			local,
+			parser: parser::RequestParser::new(ParseLimits {
+				max_head_bytes: limits.max_header_bytes,
+				max_headers: limits.max_headers,
+				max_target_len: limits.max_uri_len,
+			}),
+			head: Vec::new(),
+			rx: RxState::Head,
+			body_length: None,
+			ws_key: None,
+			head_request: false,
+			discard_body: false,
+			awaiting_continue: false,
+			rx_blocked: false,
+			socket_blocked: false,
+			body_time_left: None,
+			rx_owed: VecDeque::new(),
+			encoding: None,
+			chunked_ok: false,
+			encoder: None,
+			closed_to: None,
+			body_to: None,
+			sse: None,
+			ws: None,
+			access: None,
+			refused: None,
+			cors: HeaderMap::new(),
+			stream: None,
+			h2: None,
+		}
+	}
+
+	/// Whether this is an HTTP/1 connection which hasn't had any of a
+	/// request yet, so it can be closed without losing one.
+	fn idle(&self) -> bool {
+		match self.rx {
+			RxState::Head => self.h2.is_none() && self.head.is_empty(),
+			_ => false,
+		}
+	}
+
+	/// Get ready to send a response: note who's sending it, pick the
+	/// encoding for the body and build the head.
+	fn start_response(
+		&mut self,
+		req_start: &mut ReqResponseStart,
+		compress_default: bool,
+		reply_to: &grease::ServiceUserHandle<Service>,
+	) -> head::ResponseHead {
+		cors::add_headers(&mut req_start.headers, &self.cors);
+		self.body_length = req_start.length;
+		self.closed_to = Some((*reply_to).clone());
+		self.rx = RxState::Done;
+		let encoding = match req_start.compress {
+			// The ranges are of the uncompressed body
+			_ if req_start.status == HttpResponseStatus::PartialContent => None,
+			Some(false) => None,
+			Some(true) => self.encoding,
+			None if compress_default && compressible(&req_start.content_type) => self.encoding,
+			None => None,
+		};
+		let length = match encoding {
+			Some(encoding)
+				if req_start.length != Some(0)
+					&& !req_start.headers.contains_key("Content-Encoding") =>
+			{
+				req_start.headers.insert(
+					"Content-Encoding",
+					HeaderValue::from_static(encoding.name()),
+				);
+				req_start
+					.headers
+					.append("Vary", HeaderValue::from_static("Accept-Encoding"));
+				if self.chunked_ok {
+					req_start
+						.headers
+						.insert("Transfer-Encoding", HeaderValue::from_static("chunked"));
+				}
+				self.encoder = Some(BodyEncoder::new(encoding, self.chunked_ok));
+				// We don't know how long it will be once compressed
+				None
+			}
+			_ => req_start.length,
+		};
+		// For automatic HEAD, the head is as it would be for the GET
+		head::ResponseHead::new(req_start.status)
+			.for_head(self.head_request && !self.discard_body)
+			.content_type(&req_start.content_type)
+			.length(length)
+			.headers(::std::mem::replace(&mut req_start.headers, HeaderMap::new()))
+	}
+
+	/// Switch the connection to HTTP/2. The requests on it will each have
+	/// their own `Connection`.
+	fn start_h2(&mut self, session: h2::Session) {
+		self.rx = RxState::Done;
+		self.head = Vec::new();
+		self.access = None;
+		self.h2 = Some(Http2 {
+			session,
+			streams: HashMap::new(),
+		});
+	}
+
+	/// Account for some response body going out. Gives whether it
+	/// finishes the response, or an error if there's no room for it.
+	fn body_sent(&mut self, length: usize) -> Result<bool, Error> {
+		match self.body_length {
+			Some(0) => Err(Error::BadHandle),
+			Some(remaining) if length > remaining => Err(Error::BadHandle),
+			Some(remaining) => {
+				self.body_length = Some(remaining - length);
+				Ok(length == remaining)
+			}
+			None => Ok(false),
+		}
+	}
+
+	/// Note the status of the response, for the access log.
+	fn responded(&mut self, status: u16) {
+		if let Some((_, ref mut record)) = self.access {
+			record.status = status;
+		}
+	}
+
+	/// Note some response body going out, for the access log.
+	fn sent(&mut self, bytes: usize) {
+		if let Some((_, ref mut record)) = self.access {
+			record.bytes_sent += bytes as u64;
+		}
+	}
+}
+
+impl Encoding {
+	/// The name used in `Accept-Encoding` and `Content-Encoding`
+	fn name(&self) -> &'static str {
+		match *self {
+			Encoding::Gzip => "gzip",
+			Encoding::Deflate => "deflate",
+		}
+	}
+}
+
+impl BodyEncoder {
+	fn new(encoding: Encoding, chunked: bool) -> BodyEncoder {
+		let level = flate2::Compression::default();
+		BodyEncoder {
+			compressor: match encoding {
+				Encoding::Gzip => {
+					Compressor::Gzip(flate2::write::GzEncoder::new(Vec::new(), level))
+				}
+				Encoding::Deflate => {
+					Compressor::Deflate(flate2::write::ZlibEncoder::new(Vec::new(), level))
+				}
+			},
+			chunked,
+		}
+	}
+
+	/// Compress some more of the body and return whatever is ready to
+	/// send. Everything given so far is flushed out, so the client sees
+	/// each piece as it's sent. If `last` is set, the compressed stream
+	/// (and any chunked encoding) is finished off.
+	fn encode(&mut self, data: &[u8], last: bool) -> Vec<u8> {
+		// Writing to a Vec can't fail
+		let output = match self.compressor {
+			Compressor::Gzip(ref mut e) => {
+				e.write_all(data).unwrap();
+				if last {
+					e.try_finish().unwrap();
+				} else {
+					e.flush().unwrap();
+				}
+				e.get_mut().split_off(0)
+			}
+			Compressor::Deflate(ref mut e) => {
+				e.write_all(data).unwrap();
+				if last {
+					e.try_finish().unwrap();
+				} else {
+					e.flush().unwrap();
+				}
+				e.get_mut().split_off(0)
+			}
+		};
+		if self.chunked {
+			encode_chunk(&output, last)
+		} else {
+			output
+		}
+	}
+}
+
+/// Pick the best encoding from an `Accept-Encoding` header. We prefer gzip
+/// when the client likes both equally.
+fn choose_encoding(headers: &HeaderMap) -> Option<Encoding> {
+	let mut gzip = None;
+	let mut deflate = None;
+	let mut star = None;
+	for value in headers.get_all("Accept-Encoding").iter() {
+		let value = match value.to_str() {
+			Ok(v) => v,
+			Err(_) => continue,
+		};
+		for item in value.split(',') {
+			let mut parts = item.split(';');
+			let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+			let mut q = 1.0f32;
+			for param in parts {
+				let param = param.trim();
+				if param.starts_with("q=") || param.starts_with("Q=") {
+					q = param[2..].trim().parse().unwrap_or(0.0);
+				}
+			}
+			match coding.as_str() {
+				"gzip" | "x-gzip" => gzip = Some(q),
+				"deflate" => deflate = Some(q),
+				"*" => star = Some(q),
+				_ => {}
+			}
+		}
+	}
+	let gzip = gzip.or(star).unwrap_or(0.0);
+	let deflate = deflate.or(star).unwrap_or(0.0);
+	if gzip > 0.0 && gzip >= deflate {
+		Some(Encoding::Gzip)
+	} else if deflate > 0.0 {
+		Some(Encoding::Deflate)
+	} else {
+		None
+	}
+}
+
+/// Is this type of content worth compressing?
+fn compressible(content_type: &str) -> bool {
+	let content_type = content_type.trim().to_ascii_lowercase();
+	if content_type.starts_with("image/svg") {
+		return true;
+	}
+	let skip = [
+		"image/",
+		"audio/",
+		"video/",
+		"font/woff",
+		"application/zip",
+		"application/gzip",
+		"application/x-gzip",
+		"application/octet-stream",
+	];
+	!skip.iter().any(|s| content_type.starts_with(s))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::net;
+	use std::sync::atomic;
+	use std::sync::mpsc;
+
+	use grease::prelude::*;
+
+	enum TestIncoming {
+		HttpCfm(Confirm),
+		HttpInd(Indication),
+		SocketReq(socket::Request, grease::ServiceUserHandle<socket::Service>),
+		SocketRsp(socket::Response),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	static PORT_NUMBER: atomic::AtomicUsize = atomic::AtomicUsize::new(8000);
+
+	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+	fn allocate_test_port() -> net::SocketAddr {
+		let port = PORT_NUMBER.fetch_add(1, atomic::Ordering::SeqCst);
+		net::SocketAddr::new(
+			net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 1, 1)),
+			port as u16,
+		)
+	}
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::HttpCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: Indication) {
+			self.0.send(TestIncoming::HttpInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<socket::Service> for TestHandle {
+		fn send_request(
+			&self,
+			req: socket::Request,
+			reply_to: &grease::ServiceUser<socket::Service>,
+		) {
+			self.0
+				.send(TestIncoming::SocketReq(req, reply_to.clone()))
+				.unwrap();
+		}
+		fn send_response(&self, rsp: socket::Response) {
+			self.0.send(TestIncoming::SocketRsp(rsp)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceProviderHandle<socket::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
+		let (test_tx, rx) = mpsc::channel();
+		(TestHandle(test_tx), rx)
+	}
+
+	/// Remove the Date header from a response head, checking it's there.
+	fn strip_date(data: &[u8]) -> Vec<u8> {
+		let s = String::from_utf8(data.to_vec()).unwrap();
+		let start = s.find("\r\nDate: ").expect("No Date header") + 2;
+		let end = start + s[start..].find("\r\n").unwrap() + 2;
+		assert!(s[start..end].ends_with(" GMT\r\n"));
+		format!("{}{}", &s[..start], &s[end..]).into_bytes()
+	}
+
+	fn bind_port<T>(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		http_north: &T,
+		addr: &net::SocketAddr,
+		ctx: Context,
+		socket_handle: socket::ListenHandle,
+	) -> (ServerHandle, grease::ServiceUserHandle<socket::Service>)
+	where
+		T: grease::ServiceProvider<Service>,
+	{
+		bind_port_with_config(
+			this_thread,
+			test_rx,
+			http_north,
+			addr,
+			ctx,
+			socket_handle,
+			ServerConfig::default(),
+		)
+	}
+
+	fn bind_port_with_config<T>(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		http_north: &T,
+		addr: &net::SocketAddr,
+		ctx: Context,
+		socket_handle: socket::ListenHandle,
+		config: ServerConfig,
+	) -> (ServerHandle, grease::ServiceUserHandle<socket::Service>)
+	where
+		T: grease::ServiceProvider<Service>,
+	{
+		let bind_req = ReqBind {
+			addr: addr.clone(),
+			context: ctx,
+			config,
+		};
+		http_north.send_request(bind_req.into(), this_thread);
+		let cfm = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		let reply_to_copy: grease::ServiceUserHandle<socket::Service>;
+		match cfm {
+			TestIncoming::SocketReq(socket::Request::Bind(ref x), ref reply_to) => {
+				assert_eq!(x.addr, *addr);
+				let bind_cfm = socket::CfmBind {
+					result: Ok(socket_handle),
+					context: x.context,
+				};
+				reply_to.send_confirm(bind_cfm.into());
+				reply_to_copy = (*reply_to).clone();
+			}
+			_ => panic!("Unexpected message."),
+		}
+		let cfm = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match cfm {
+			TestIncoming::HttpCfm(Confirm::Bind(ref x)) => {
+				assert_eq!(x.context, ctx);
+				if let Ok(h) = x.result {
+					(h, reply_to_copy.clone())
+				} else {
+					panic!("HTTP Bind failed");
+				}
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	fn bind_port_ok() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let _ = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(1),
+			Context::new(2),
+		);
+	}
+
+	#[test]
+	fn basic_get_vary_len() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (sh, http_south) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+		);
+
+		// ******************** Connection opens ********************
+
+		let msg = socket::IndConnected {
+			listen_handle: Context::new(4),
+			conn_handle: Context::new(5),
+			peer: "127.0.0.1:56789".parse().unwrap(),
+			local: "127.0.0.1:8000".parse().unwrap(),
+		};
+		http_south.send_indication(msg.into());
+
+		// ******************** Request arrives ********************
+
+		let msg = socket::IndReceived {
+			handle: Context::new(5),
+			data: String::from("GET /foo/bar HTTP/1.1\r\nHost: localhost\r\n\r\n").into_bytes(),
+		};
+		http_south.send_indication(msg.into());
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		let ch = match msg {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.server_handle, sh);
+				assert_eq!(x.url, "/foo/bar");
+				assert_eq!(x.method, Method::GET);
+				let mut expected_headers = HeaderMap::new();
+				expected_headers.insert("HOST", "localhost".parse().unwrap());
+				assert_eq!(x.headers, expected_headers);
+				assert_eq!(x.version, Version::HTTP_11);
+				assert_eq!(x.peer, "127.0.0.1:56789".parse().unwrap());
+				assert_eq!(x.local, "127.0.0.1:8000".parse().unwrap());
+				assert_eq!(x.client, x.peer.ip());
+				assert_eq!(x.host, None);
+				x.connection_handle
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(5));
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Send the headers ********************
+
+		let mut msg = ReqResponseStart {
+			handle: ch,
+			context: Context::new(1234),
+			status: HttpResponseStatus::OK,
+			content_type: String::from("text/plain"),
+			length: None,
+			headers: HeaderMap::new(),
+			compress: None,
+		};
+		msg.headers.insert("x-magic", "frobbins".parse().unwrap());
+		http_north.send_request(msg.into(), &reply_to);
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				let headers = "HTTP/1.1 200 OK\r\nServer: grease/http\r\nContent-Type: \
+				               text/plain\r\nx-magic: frobbins\r\n\r\n"
+					.as_bytes();
+				assert_eq!(strip_date(&x.data), headers);
+				let send_cfm = socket::CfmSend {
+					handle: x.handle,
+					context: x.context,
+					result: Ok(x.data.len()),
+				};
+				msg_reply_to.send_confirm(send_cfm.into());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(1234));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Send the body ********************
+
+		let test_body = Vec::from("One two, Rust on my shoe");
+		let msg = ReqResponseBody {
+			handle: ch,
+			context: Context::new(5678),
+			data: test_body.clone(),
+		};
+		http_north.send_request(msg.into(), &reply_to);
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				assert_eq!(x.data, test_body);
+				let send_cfm = socket::CfmSend {
+					handle: x.handle,
+					context: x.context,
+					result: Ok(x.data.len()),
+				};
+				msg_reply_to.send_confirm(send_cfm.into());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(5678));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Send an empty body ********************
+
+		let msg = ReqResponseBody {
+			handle: ch,
+			context: Context::new(5678),
+			data: Vec::new(),
+		};
+		http_north.send_request(msg.into(), &reply_to);
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				let send_cfm = socket::CfmClose {
+					handle: x.handle,
+					context: x.context,
+					result: Ok(()),
+				};
+				msg_reply_to.send_confirm(send_cfm.into());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(5678));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Get a close indication ********************
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, ch);
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** All done ********************
+	}
+
+	#[test]
+	fn basic_get_fixed_len() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (sh, http_south) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+		);
+
+		// ******************** Connection opens ********************
+
+		let msg = socket::IndConnected {
+			listen_handle: Context::new(4),
+			conn_handle: Context::new(5),
+			peer: "127.0.0.1:56789".parse().unwrap(),
+			local: "127.0.0.1:8000".parse().unwrap(),
+		};
+		http_south.send_indication(msg.into());
+
+		// ******************** Request arrives ********************
+
+		let msg = socket::IndReceived {
+			handle: Context::new(5),
+			data: String::from("GET /foo/bar HTTP/1.1\r\nHost: localhost\r\n\r\n").into_bytes(),
+		};
+		http_south.send_indication(msg.into());
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		let ch = match msg {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.server_handle, sh);
+				assert_eq!(x.url, "/foo/bar");
+				assert_eq!(x.method, Method::GET);
+				let mut expected_headers = HeaderMap::new();
+				expected_headers.insert("Host", "localhost".parse().unwrap());
+				assert_eq!(x.headers, expected_headers);
+				x.connection_handle
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(5));
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Send the headers ********************
+
+		let mut msg = ReqResponseStart {
+			handle: ch,
+			context: Context::new(1234),
+			status: HttpResponseStatus::OK,
+			content_type: String::from("text/plain"),
+			length: Some(24),
+			headers: HeaderMap::new(),
+			compress: None,
+		};
+		msg.headers.insert("x-magic", "frobbins".parse().unwrap());
+		http_north.send_request(msg.into(), &reply_to);
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				let headers = "HTTP/1.1 200 OK\r\nServer: grease/http\r\nContent-Length: \
+				               24\r\nContent-Type: text/plain\r\nx-magic: frobbins\r\n\r\n"
+					.as_bytes();
+				println!("Headers: {:?}", String::from_utf8(x.data.clone()));
+				assert_eq!(strip_date(&x.data), headers);
+				let send_cfm = socket::CfmSend {
+					handle: x.handle,
+					context: x.context,
+					result: Ok(x.data.len()),
+				};
+				msg_reply_to.send_confirm(send_cfm.into());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(1234));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Send the body ********************
+
+		let test_body = Vec::from("One two, Rust on my shoe");
+		let msg = ReqResponseBody {
+			handle: ch,
+			context: Context::new(5678),
+			data: test_body.clone(),
+		};
+		http_north.send_request(msg.into(), &reply_to);
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				assert_eq!(x.data, test_body);
+				let send_cfm = socket::CfmSend {
+					handle: x.handle,
+					context: x.context,
+					result: Ok(x.data.len()),
+				};
+				msg_reply_to.send_confirm(send_cfm.into());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(5678));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Auto close ********************
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				let send_cfm = socket::CfmClose {
+					handle: x.handle,
+					context: x.context,
+					result: Ok(()),
+				};
+				msg_reply_to.send_confirm(send_cfm.into());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Get a close indication ********************
+
+		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		match msg {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, ch);
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** All done ********************
+	}
+
+	/// Mask a frame's payload as a client would
+	fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+		let mask = [1u8, 2, 3, 4];
+		let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+		frame.extend_from_slice(&mask);
+		frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+		frame
+	}
+
+	/// Expect a socket ReqSend, confirm it, and return the data.
+	fn expect_send(test_rx: &mpsc::Receiver<TestIncoming>) -> Vec<u8> {
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Send(x), msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				msg_reply_to.send_confirm(
+					socket::CfmSend {
+						handle: x.handle,
+						context: x.context,
+						result: Ok(x.data.len()),
+					}.into(),
+				);
+				x.data
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn expect_received_rsp(test_rx: &mpsc::Receiver<TestIncoming>) {
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(5));
+			}
+			_ => panic!("Unexpected message"),
+		};
+	}
+
+	#[test]
+	fn websocket_upgrade() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+		);
+
+		// ******************** Upgrade request arrives ********************
+
+		let msg = socket::IndConnected {
+			listen_handle: Context::new(4),
+			conn_handle: Context::new(5),
+			peer: "127.0.0.1:56789".parse().unwrap(),
+			local: "127.0.0.1:8000".parse().unwrap(),
+		};
+		http_south.send_indication(msg.into());
+		let msg = socket::IndReceived {
+			handle: Context::new(5),
+			data: String::from(
+				"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+				 Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+				 Sec-WebSocket-Version: 13\r\n\r\n",
+			).into_bytes(),
+		};
+		http_south.send_indication(msg.into());
+
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => {
+				assert!(x.is_websocket_upgrade());
+				x.connection_handle
+			}
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+
+		// ******************** Accept it ********************
+
+		http_north.send_request(
+			ReqWsAccept {
+				handle: ch,
+				context: Context::new(10),
+				protocol: None,
+			}.into(),
+			&reply_to,
+		);
+		assert_eq!(
+			strip_date(&expect_send(&test_rx)),
+			&b"HTTP/1.1 101 Switching Protocols\r\nServer: grease/http\r\n\
+			   upgrade: websocket\r\nconnection: Upgrade\r\n\
+			   sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"[..]
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::WsAccept(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(10));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// A second accept is rejected
+		http_north.send_request(
+			ReqWsAccept {
+				handle: ch,
+				context: Context::new(11),
+				protocol: None,
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::WsAccept(ref x)) => {
+				assert!(x.result.is_err());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Messages in ********************
+
+		let mut data = client_frame(0x1, b"Hello");
+		data.extend(client_frame(0x9, b"ping"));
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::WsMessage(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.message, WsMessage::Text(String::from("Hello")));
+			}
+			_ => panic!("Unexpected message"),
+		};
+		// The ping is answered for us
+		assert_eq!(expect_send(&test_rx), b"\x8a\x04ping");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::WsMessage(ref x)) => {
+				assert_eq!(x.message, WsMessage::Ping(b"ping".to_vec()));
+			}
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+
+		// ******************** Message out ********************
+
+		http_north.send_request(
+			ReqWsSend {
+				handle: ch,
+				context: Context::new(12),
+				message: WsMessage::Binary(vec![1, 2, 3]),
+			}.into(),
+			&reply_to,
+		);
+		assert_eq!(expect_send(&test_rx), b"\x82\x03\x01\x02\x03");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::WsSend(ref x)) => {
+				assert_eq!(x.handle, ch);
+				assert_eq!(x.context, Context::new(12));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Client closes ********************
+
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data: client_frame(0x8, b"\x03\xe8"),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::WsMessage(ref x)) => {
+				assert_eq!(x.message, WsMessage::Close(Some(WS_CLOSE_NORMAL), String::new()));
+			}
+			_ => panic!("Unexpected message"),
+		};
+		// Close is echoed, then the socket closed
+		assert_eq!(expect_send(&test_rx), b"\x88\x02\x03\xe8");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), ref msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(5));
+				msg_reply_to.send_confirm(
+					socket::CfmClose {
+						handle: x.handle,
+						context: x.context,
+						result: Ok(()),
+					}.into(),
+				);
+			}
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, ch);
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** All done ********************
+	}
+
+	#[test]
+	fn accept_encoding() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(choose_encoding(&headers), None);
+		headers.insert("Accept-Encoding", "gzip, deflate, br".parse().unwrap());
+		assert_eq!(choose_encoding(&headers), Some(Encoding::Gzip));
+		headers.insert("Accept-Encoding", "gzip;q=0.5, deflate".parse().unwrap());
+		assert_eq!(choose_encoding(&headers), Some(Encoding::Deflate));
+		headers.insert("Accept-Encoding", "*;q=0.1, gzip;q=0".parse().unwrap());
+		assert_eq!(choose_encoding(&headers), Some(Encoding::Deflate));
+		headers.insert("Accept-Encoding", "identity".parse().unwrap());
+		assert_eq!(choose_encoding(&headers), None);
+		assert!(compressible("text/html; charset=utf-8"));
+		assert!(compressible("image/svg+xml"));
+		assert!(!compressible("image/png"));
+		assert!(!compressible("application/octet-stream"));
+	}
+
+	#[test]
+	fn body_encoder() {
+		use std::io::Read;
+		let text = "All work and no play makes Jack a dull boy. ".repeat(100);
+		for &encoding in &[Encoding::Gzip, Encoding::Deflate] {
+			let mut e = BodyEncoder::new(encoding, false);
+			let mut compressed = e.encode(&text.as_bytes()[0..1000], false);
+			// Each piece is flushed so the client can start on it
+			assert!(!compressed.is_empty());
+			compressed.extend(e.encode(&text.as_bytes()[1000..], true));
+			assert!(compressed.len() < text.len());
+			let mut decoded = String::new();
+			match encoding {
+				Encoding::Gzip => {
+					flate2::read::GzDecoder::new(&compressed[..])
+						.read_to_string(&mut decoded)
+						.unwrap();
+				}
+				Encoding::Deflate => {
+					flate2::read::ZlibDecoder::new(&compressed[..])
+						.read_to_string(&mut decoded)
+						.unwrap();
+				}
+			}
+			assert_eq!(decoded, text);
+		}
+		let mut e = BodyEncoder::new(Encoding::Gzip, true);
+		let chunk = e.encode(b"hello", false);
+		let crlf = chunk.iter().position(|&b| b == b'\r').unwrap();
+		let len = usize::from_str_radix(::std::str::from_utf8(&chunk[..crlf]).unwrap(), 16)
+			.unwrap();
+		assert_eq!(chunk.len(), crlf + 2 + len + 2);
+		assert!(e.encode(b"", true).ends_with(b"\r\n0\r\n\r\n"));
+	}
+
+	#[test]
+	fn compressed_response() {
+		use std::io::Read;
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				compress: true,
+				..Default::default()
+			},
+		);
+
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data: String::from(
+					"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+				).into_bytes(),
+			}.into(),
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+
+		let text = "Compress me, compress me, compress me. ".repeat(50);
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(10),
+				status: HttpResponseStatus::OK,
+				content_type: String::from("text/plain"),
+				length: Some(text.len()),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		let head = String::from_utf8(expect_send(&test_rx)).unwrap();
+		assert!(head.contains("content-encoding: gzip\r\n"));
+		assert!(head.contains("vary: Accept-Encoding\r\n"));
+		assert!(head.contains("transfer-encoding: chunked\r\n"));
+		assert!(!head.contains("Content-Length"));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+
+		// Send the body in two halves, and de-chunk what comes out
+		let mut compressed = Vec::new();
+		for (idx, half) in text.as_bytes().chunks(text.len() / 2 + 1).enumerate() {
+			http_north.send_request(
+				ReqResponseBody {
+					handle: ch,
+					context: Context::new(11 + idx),
+					data: half.to_vec(),
+				}.into(),
+				&reply_to,
+			);
+			let mut data = &expect_send(&test_rx)[..];
+			while !data.is_empty() {
+				let crlf = data.iter().position(|&b| b == b'\r').unwrap();
+				let len = usize::from_str_radix(::std::str::from_utf8(&data[..crlf]).unwrap(), 16)
+					.unwrap();
+				compressed.extend_from_slice(&data[crlf + 2..crlf + 2 + len]);
+				data = &data[crlf + 2 + len + 2..];
+			}
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => assert!(x.result.is_ok()),
+				_ => panic!("Unexpected message"),
+			};
+		}
+		let mut decoded = String::new();
+		flate2::read::GzDecoder::new(&compressed[..])
+			.read_to_string(&mut decoded)
+			.unwrap();
+		assert_eq!(decoded, text);
+
+		// Then the connection is closed
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), _) => {
+				assert_eq!(x.handle, Context::new(5));
+			}
+			_ => panic!("Unexpected message"),
+		};
+	}
+
+	fn sse_event(
+		id: Option<&str>,
+		event: Option<&str>,
+		data: &str,
+		retry: Option<u32>,
+	) -> ReqSseEvent {
+		ReqSseEvent {
+			handle: Context::new(1),
+			context: Context::new(2),
+			id: id.map(String::from),
+			event: event.map(String::from),
+			data: String::from(data),
+			retry,
+		}
+	}
+
+	#[test]
+	fn sse_format() {
+		assert_eq!(
+			format_sse_event(&sse_event(None, None, "hello", None)).unwrap(),
+			"data: hello\n\n"
+		);
+		assert_eq!(
+			format_sse_event(&sse_event(Some("42"), Some("update"), "a\r\nb\rc\nd", Some(500)))
+				.unwrap(),
+			"id: 42\nevent: update\nretry: 500\ndata: a\ndata: b\ndata: c\ndata: d\n\n"
+		);
+		assert_eq!(format_sse_event(&sse_event(None, None, "", None)).unwrap(), "data: \n\n");
+		assert!(format_sse_event(&sse_event(Some("4\n2"), None, "x", None)).is_none());
+		assert!(format_sse_event(&sse_event(None, Some("a\rb"), "x", None)).is_none());
+	}
+
+	#[test]
+	fn sse_stream() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+		);
+
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data: String::from(
+					"GET /events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n",
+				).into_bytes(),
+			}.into(),
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+
+		// ******************** Start the stream ********************
+
+		http_north.send_request(
+			ReqSseStart {
+				handle: ch,
+				context: Context::new(10),
+				headers: HeaderMap::new(),
+				heartbeat: Some(Duration::from_millis(50)),
+			}.into(),
+			&reply_to,
+		);
+		let head = String::from_utf8(expect_send(&test_rx)).unwrap();
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(head.contains("Content-Type: text/event-stream\r\n"));
+		assert!(head.contains("cache-control: no-cache\r\n"));
+		assert!(!head.contains("Content-Length"));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::SseStart(ref x)) => {
+				assert_eq!(x.context, Context::new(10));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Send an event ********************
+
+		let mut event = sse_event(Some("1"), None, "first", None);
+		event.handle = ch;
+		http_north.send_request(event.into(), &reply_to);
+		assert_eq!(expect_send(&test_rx), b"id: 1\ndata: first\n\n");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::SseEvent(ref x)) => {
+				assert_eq!(x.context, Context::new(2));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// A bad event is rejected
+		let mut event = sse_event(None, Some("bad\nevent"), "x", None);
+		event.handle = ch;
+		http_north.send_request(event.into(), &reply_to);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::SseEvent(ref x)) => assert!(x.result.is_err()),
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** Heartbeats when idle ********************
+
+		for _ in 0..2 {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::SocketReq(socket::Request::Send(ref x), _) => {
+					assert_eq!(x.handle, Context::new(5));
+					assert_eq!(x.data, b":\n\n");
+				}
+				_ => panic!("Unexpected message"),
+			}
+		}
+
+		// ******************** Client goes away ********************
+
+		http_south.send_indication(
+			socket::IndDropped {
+				handle: Context::new(5),
+			}.into(),
+		);
+		loop {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpInd(Indication::Closed(ref x)) => {
+					assert_eq!(x.handle, ch);
+					break;
+				}
+				// A heartbeat might have crossed with the drop
+				TestIncoming::SocketReq(socket::Request::Send(_), _) => {}
+				_ => panic!("Unexpected message"),
+			}
+		}
+
+		// No more heartbeats
+		assert!(test_rx.recv_timeout(Duration::from_millis(200)).is_err());
+	}
+
+	#[test]
+	fn forwarded_client() {
+		let proxy: net::IpAddr = "10.0.0.1".parse().unwrap();
+		let inner: net::IpAddr = "10.0.0.2".parse().unwrap();
+		let trusted = [proxy, inner];
+		let client = |peer: net::IpAddr, headers: &[(&str, &str)]| {
+			let mut map = HeaderMap::new();
+			for &(k, v) in headers {
+				map.append(
+					http::header::HeaderName::from_bytes(k.as_bytes()).unwrap(),
+					v.parse().unwrap(),
+				);
+			}
+			client_address(peer, &trusted, &map).to_string()
+		};
+
+		// Untrusted peers can't tell us anything
+		let stranger = "192.0.2.1".parse().unwrap();
+		assert_eq!(client(stranger, &[("x-forwarded-for", "1.2.3.4")]), "192.0.2.1");
+		// Trusted peer, but no headers
+		assert_eq!(client(proxy, &[]), "10.0.0.1");
+		// X-Forwarded-For, with and without a spoofed entry from the client
+		assert_eq!(client(proxy, &[("x-forwarded-for", "1.2.3.4")]), "1.2.3.4");
+		assert_eq!(
+			client(proxy, &[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]),
+			"1.2.3.4"
+		);
+		assert_eq!(
+			client(proxy, &[("x-forwarded-for", "6.6.6.6"), ("x-forwarded-for", "1.2.3.4")]),
+			"1.2.3.4"
+		);
+		// Forwarded wins over X-Forwarded-For
+		assert_eq!(
+			client(
+				proxy,
+				&[
+					("x-forwarded-for", "6.6.6.6"),
+					("forwarded", "for=192.0.2.60;proto=http;by=10.0.0.1"),
+				]
+			),
+			"192.0.2.60"
+		);
+		assert_eq!(
+			client(proxy, &[("forwarded", "for=1.2.3.4, For=\"[2001:db8:cafe::17]:4711\"")]),
+			"2001:db8:cafe::17"
+		);
+		assert_eq!(client(proxy, &[("forwarded", "for=\"1.2.3.4:80\"")]), "1.2.3.4");
+		// Unknown nodes stop the walk at the last proxy we know about
+		assert_eq!(client(proxy, &[("forwarded", "for=1.2.3.4, for=unknown")]), "10.0.0.1");
+		assert_eq!(
+			client(proxy, &[("forwarded", "for=_hidden, for=10.0.0.2")]),
+			"10.0.0.2"
+		);
+	}
+
+	/// Expect a socket ReqSend then a ReqClose on the given socket. Returns
+	/// what was sent.
+	fn expect_rejection(
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		skt: socket::ConnHandle,
+	) -> String {
+		let data = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Send(x), _) => {
+				assert_eq!(x.handle, skt);
+				String::from_utf8(x.data).unwrap()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), _) => {
+				assert_eq!(x.handle, skt);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		data
+	}
+
+	#[test]
+	fn request_limits() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				limits: Limits {
+					max_header_bytes: 100,
+					max_headers: 3,
+					max_uri_len: 20,
+					max_body: 10,
+					header_timeout: Some(Duration::from_millis(200)),
+					body_timeout: Some(Duration::from_millis(200)),
+				},
+				..Default::default()
+			},
+		);
+		let connect = |skt: usize| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+		};
+		let receive = |skt: usize, data: &str| {
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: data.as_bytes().to_vec(),
+				}.into(),
+			);
+		};
+		let expect_received = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(skt));
+			}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_body = |data: &str, last: bool| match test_rx
+			.recv_timeout(DEFAULT_TIMEOUT)
+			.unwrap()
+		{
+			TestIncoming::HttpInd(Indication::RxBody(ref x)) => {
+				assert_eq!(x.data, data.as_bytes());
+				assert_eq!(x.last, last);
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// Too many header bytes, spread over two packets
+		connect(10);
+		receive(10, "GET / HTTP/1.1\r\nX-Padding: ");
+		expect_received(10);
+		receive(10, &"x".repeat(100));
+		let rsp = expect_rejection(&test_rx, Context::new(10));
+		assert!(rsp.starts_with("HTTP/1.0 431 Request Header Fields Too Large\r\n"));
+		assert!(rsp.contains("Connection: close\r\n"));
+		expect_received(10);
+
+		// Too many headers
+		connect(11);
+		receive(11, "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n");
+		let rsp = expect_rejection(&test_rx, Context::new(11));
+		assert!(rsp.starts_with("HTTP/1.0 431 "));
+		expect_received(11);
+
+		// A URI which is too long, but fits in the header limit
+		connect(12);
+		receive(12, "GET /0123456789/0123456789 HTTP/1.1\r\n\r\n");
+		let rsp = expect_rejection(&test_rx, Context::new(12));
+		assert!(rsp.starts_with("HTTP/1.0 414 URI Too Long\r\n"));
+		expect_received(12);
+
+		// A URI which blows the header limit
+		connect(13);
+		receive(13, &format!("GET /{}", "x".repeat(100)));
+		let rsp = expect_rejection(&test_rx, Context::new(13));
+		assert!(rsp.starts_with("HTTP/1.0 414 "));
+		expect_received(13);
+
+		// A body which is declared too big
+		connect(14);
+		receive(14, "POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n");
+		let rsp = expect_rejection(&test_rx, Context::new(14));
+		assert!(rsp.starts_with("HTTP/1.0 413 Payload Too Large\r\n"));
+		expect_received(14);
+
+		// A chunked body which turns out to be too big. We've been told about
+		// this one, so we get told it has closed.
+		connect(15);
+		receive(15, "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n");
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received(15);
+		receive(15, "hello\r\n0\r\n\r\n");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
+			_ => panic!("Unexpected message"),
+		}
+		let rsp = expect_rejection(&test_rx, Context::new(15));
+		assert!(rsp.starts_with("HTTP/1.0 413 "));
+		expect_received(15);
+
+		// A body which arrives in full is fine
+		connect(16);
+		receive(16, "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n01234");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.method, Method::POST);
+			}
+			_ => panic!("Unexpected message"),
+		};
+		expect_body("01234", false);
+		expect_received(16);
+		receive(16, "56789");
+		expect_body("56789", true);
+		expect_received(16);
+
+		// Slow headers
+		connect(17);
+		receive(17, "GET / HTTP/1.1\r\n");
+		expect_received(17);
+		let rsp = expect_rejection(&test_rx, Context::new(17));
+		assert!(rsp.starts_with("HTTP/1.0 408 Request Timeout\r\n"));
+
+		// Slow body
+		connect(18);
+		receive(18, "PUT / HTTP/1.1\r\nContent-Length: 10\r\n\r\n");
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received(18);
+		receive(18, "012");
+		expect_body("012", false);
+		expect_received(18);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
+			_ => panic!("Unexpected message"),
+		}
+		let rsp = expect_rejection(&test_rx, Context::new(18));
+		assert!(rsp.starts_with("HTTP/1.0 408 "));
+
+		// Connection 16 got its body in time, so nothing else happens
+		assert!(test_rx.recv_timeout(Duration::from_millis(300)).is_err());
+	}
+
+	#[test]
+	fn request_body() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig::default(),
+		);
+		let connect = |skt: usize| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+		};
+		let receive = |skt: usize, data: &str| {
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: data.as_bytes().to_vec(),
+				}.into(),
+			);
+		};
+		let expect_received = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(_)) => {}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_request = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x,
+			_ => panic!("Unexpected message"),
+		};
+		let expect_body = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxBody(x)) => x,
+			_ => panic!("Unexpected message"),
+		};
+
+		// No body, so no IndRxBody
+		connect(10);
+		receive(10, "GET /a/./b%20c?x=1&x=2 HTTP/1.1\r\n\r\n");
+		let ind = expect_request();
+		assert!(!ind.has_body());
+		assert_eq!(ind.path(), Ok(String::from("/a/b c")));
+		let query = ind.query().unwrap();
+		assert_eq!(query.get_all("x").collect::<Vec<_>>(), vec!["1", "2"]);
+		expect_received();
+
+		// A chunked form, split across packets, with the framing removed
+		connect(11);
+		receive(
+			11,
+			"POST /form HTTP/1.1\r\n\
+			 Content-Type: application/x-www-form-urlencoded\r\n\
+			 Transfer-Encoding: chunked\r\n\r\n4\r\na=1&",
+		);
+		let ind = expect_request();
+		assert!(ind.has_body());
+		let mut form = ind.form_decoder(FormLimits::default()).unwrap();
+		let body = expect_body();
+		assert_eq!((&body.data[..], body.last), (&b"a=1&"[..], false));
+		let mut events = form.decode(&body.data, body.last).unwrap();
+		expect_received();
+		receive(11, "\r\n3;ext=1\r\nb=2\r\n0\r\n");
+		assert_eq!(expect_body().data, b"b=2");
+		events.extend(form.decode(b"b=2", false).unwrap());
+		expect_received();
+		receive(11, "\r\n");
+		let body = expect_body();
+		assert_eq!((&body.data[..], body.last), (&b""[..], true));
+		events.extend(form.decode(&body.data, body.last).unwrap());
+		let field = |name: &str, value: &[u8]| {
+			vec![
+				FormEvent::Part(FormPart {
+					name: name.to_string(),
+					filename: None,
+					content_type: None,
+				}),
+				FormEvent::Data(value.to_vec()),
+				FormEvent::PartEnd,
+			]
+		};
+		assert_eq!(events, [field("a", b"1"), field("b", b"2")].concat());
+
+		// Anything after a fixed length body isn't passed up
+		expect_received();
+		connect(12);
+		receive(12, "PUT / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef");
+		assert_eq!(expect_request().method, Method::PUT);
+		let body = expect_body();
+		assert_eq!((&body.data[..], body.last), (&b"abc"[..], true));
+		expect_received();
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
+	}
+
+	#[test]
+	fn request_body_flow_control() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				body_flow_control: true,
+				limits: Limits {
+					body_timeout: Some(Duration::from_millis(200)),
+					..Default::default()
+				},
+				..Default::default()
+			},
+		);
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(10),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		let receive = |data: &str| {
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(10),
+					data: data.as_bytes().to_vec(),
+				}.into(),
+			);
+		};
+		let expect_body = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxBody(x)) => x,
+			_ => panic!("Unexpected message"),
+		};
+		let expect_received = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(10));
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		receive("POST / HTTP/1.1\r\nContent-Length: 6\r\n\r\nabc");
+		let handle = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		let body = expect_body();
+		assert_eq!((&body.data[..], body.last), (&b"abc"[..], false));
+		// Nothing more is read until we ask, and the wait doesn't count
+		// towards the body timeout
+		assert!(test_rx.recv_timeout(Duration::from_millis(300)).is_err());
+		http_north.send_response(RspRxBody { handle }.into());
+		expect_received();
+		receive("def");
+		let body = expect_body();
+		assert_eq!((&body.data[..], body.last), (&b"def"[..], true));
+		// No need to ask after the last piece
+		expect_received();
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
+	}
+
+	#[test]
+	fn virtual_hosts() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (sh, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				unknown_host: Some(StatusCode::MISDIRECTED_REQUEST),
+				..Default::default()
+			},
+		);
+		let add_host = |pattern: &str| -> Result<HostHandle, Error> {
+			http_north.send_request(
+				ReqAddHost {
+					server: sh,
+					context: Context::new(20),
+					pattern: String::from(pattern),
+				}.into(),
+				&reply_to,
+			);
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpCfm(Confirm::AddHost(x)) => {
+					assert_eq!(x.context, Context::new(20));
+					x.result
+				}
+				_ => panic!("Unexpected message"),
+			}
+		};
+		let request = |skt: usize, host: &str| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host).into_bytes(),
+				}.into(),
+			);
+		};
+		let expect_host = |skt: usize| {
+			let host = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpInd(Indication::RxRequest(x)) => x.host,
+				_ => panic!("Unexpected message"),
+			};
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+					assert_eq!(x.handle, Context::new(skt));
+				}
+				_ => panic!("Unexpected message"),
+			};
+			host
+		};
+
+		let www = add_host("www.example.com").unwrap();
+		let wildcard = add_host("*.Example.com").unwrap();
+		assert!(match add_host("*.example.com.") {
+			Err(Error::DuplicateHost) => true,
+			_ => false,
+		});
+		assert!(match add_host("bad host") {
+			Err(Error::BadPattern) => true,
+			_ => false,
+		});
+
+		request(10, "www.example.com:8000");
+		assert_eq!(expect_host(10), Some(www));
+		request(11, "mail.EXAMPLE.com");
+		assert_eq!(expect_host(11), Some(wildcard));
+
+		// Nobody wants this one
+		request(12, "example.org");
+		let rsp = expect_rejection(&test_rx, Context::new(12));
+		assert!(rsp.starts_with("HTTP/1.0 421 Misdirected Request\r\n"));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(12));
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// Take away the specific host and the wildcard gets it
+		http_north.send_request(
+			ReqRemoveHost {
+				handle: www,
+				context: Context::new(21),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::RemoveHost(ref x)) => {
+				assert_eq!(x.handle, www);
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		}
+		request(13, "www.example.com");
+		assert_eq!(expect_host(13), Some(wildcard));
+
+		// A catch-all host takes everything else
+		let any = add_host("*").unwrap();
+		request(14, "example.org");
+		assert_eq!(expect_host(14), Some(any));
+	}
+
+	#[test]
+	fn bad_response_head() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+		);
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data: b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
+			}.into(),
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+
+		// A body on a 204 is refused, and nothing is sent
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(10),
+				status: HttpResponseStatus::NoContent,
+				content_type: String::from("text/plain"),
+				length: Some(10),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => {
+				assert_eq!(x.context, Context::new(10));
+				match x.result {
+					Err(Error::BadHeader) => {}
+					_ => panic!("Expected BadHeader"),
+				}
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// Try again properly, with a header which isn't ASCII
+		let mut headers = HeaderMap::new();
+		headers.insert("x-name", HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap());
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(11),
+				status: HttpResponseStatus::NoContent,
+				content_type: String::from("text/plain"),
+				length: None,
+				headers,
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		assert_eq!(
+			strip_date(&expect_send(&test_rx)),
+			&b"HTTP/1.1 204 No Content\r\nServer: grease/http\r\nx-name: caf\xc3\xa9\r\n\r\n"[..]
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => {
+				assert_eq!(x.context, Context::new(11));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+		// No body, so the connection is closed straight away
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), _) => {
+				assert_eq!(x.handle, Context::new(5));
+			}
+			_ => panic!("Unexpected message"),
+		};
+	}
+
+	#[test]
+	fn allow_lists() {
+		let mut auto = AutoResponses {
+			options: true,
+			allow: vec![Method::GET, Method::POST],
+			..Default::default()
+		};
+		assert_eq!(allow_list(&auto), "GET, POST, OPTIONS");
+		auto.head = true;
+		assert_eq!(allow_list(&auto), "GET, POST, HEAD, OPTIONS");
+		auto.allow = vec![Method::OPTIONS, Method::HEAD, Method::GET];
+		assert_eq!(allow_list(&auto), "OPTIONS, HEAD, GET");
+	}
+
+	#[test]
+	fn auto_responses() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				auto: AutoResponses {
+					head: true,
+					options: true,
+					allow: vec![Method::GET, Method::POST],
+					expect_continue: true,
+				},
+				..Default::default()
+			},
+		);
+		let request = |skt: usize, data: &str| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: data.as_bytes().to_vec(),
+				}.into(),
+			);
+		};
+		let expect_received = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(skt));
+			}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_request = |skt: usize| {
+			let ind = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpInd(Indication::RxRequest(x)) => x,
+				_ => panic!("Unexpected message"),
+			};
+			expect_received(skt);
+			ind
+		};
+		let expect_send = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Send(x), msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(skt));
+				msg_reply_to.send_confirm(
+					socket::CfmSend {
+						handle: x.handle,
+						context: x.context,
+						result: Ok(x.data.len()),
+					}.into(),
+				);
+				String::from_utf8(x.data).unwrap()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_close = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), _) => {
+				assert_eq!(x.handle, Context::new(skt));
+			}
+			_ => panic!("Unexpected message"),
+		};
+
+		// ******************** HEAD looks like GET ********************
+
+		request(10, "HEAD /x HTTP/1.1\r\nHost: localhost\r\n\r\n");
+		let ind = expect_request(10);
+		assert_eq!(ind.method, Method::GET);
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ind.connection_handle,
+				context: Context::new(11),
+				status: HttpResponseStatus::OK,
+				content_type: String::from("text/plain"),
+				length: Some(5),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		let head = expect_send(10);
+		assert!(head.contains("Content-Length: 5\r\n"));
+		assert!(head.contains("Content-Type: text/plain\r\n"));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		http_north.send_request(
+			ReqResponseBody {
+				handle: ind.connection_handle,
+				context: Context::new(12),
+				data: b"hello".to_vec(),
+			}.into(),
+			&reply_to,
+		);
+		// Confirmed, but not sent
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
+				assert_eq!(x.context, Context::new(12));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+		expect_close(10);
+
+		// ******************** OPTIONS * ********************
+
+		request(20, "OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n");
+		let head = expect_send(20);
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(head.contains("allow: GET, POST, HEAD, OPTIONS\r\n"));
+		assert!(head.contains("Content-Length: 0\r\n"));
+		expect_close(20);
+		expect_received(20);
+
+		// But OPTIONS on a path is passed up
+		request(21, "OPTIONS /x HTTP/1.1\r\nHost: localhost\r\n\r\n");
+		assert_eq!(expect_request(21).method, Method::OPTIONS);
+
+		// ******************** 100-continue ********************
+
+		request(
+			30,
+			"PUT /x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+			 Expect: 100-continue\r\n\r\n",
+		);
+		let ind = expect_request(30);
+		assert!(ind.expects_continue());
+		// Nothing goes out until we say so
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
+		http_north.send_request(
+			ReqContinue {
+				handle: ind.connection_handle,
+				context: Context::new(31),
+				accept: true,
+			}.into(),
+			&reply_to,
+		);
+		assert_eq!(expect_send(30), "HTTP/1.1 100 Continue\r\n\r\n");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Continue(ref x)) => {
+				assert_eq!(x.context, Context::new(31));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		};
+		// Only once
+		http_north.send_request(
+			ReqContinue {
+				handle: ind.connection_handle,
+				context: Context::new(32),
+				accept: true,
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Continue(ref x)) => match x.result {
+				Err(Error::NotExpecting) => {}
+				_ => panic!("Expected NotExpecting"),
+			},
+			_ => panic!("Unexpected message"),
+		};
+
+		// Refusing the body
+		request(
+			33,
+			"PUT /x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+			 Expect: 100-continue\r\n\r\n",
+		);
+		let ind = expect_request(33);
+		http_north.send_request(
+			ReqContinue {
+				handle: ind.connection_handle,
+				context: Context::new(34),
+				accept: false,
+			}.into(),
+			&reply_to,
+		);
+		assert!(expect_send(33).starts_with("HTTP/1.0 417 Expectation Failed\r\n"));
+		expect_close(33);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Continue(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+
+		// Expectations we don't know about
+		request(35, "GET /x HTTP/1.1\r\nHost: localhost\r\nExpect: magic\r\n\r\n");
+		assert!(expect_send(35).starts_with("HTTP/1.0 417 "));
+		expect_close(35);
+		expect_received(35);
+	}
+
+	#[test]
+	fn access_logging() {
+		let (reply_to, test_rx) = make_test_channel();
+		let (log_tx, log_rx) = mpsc::channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				access_log: Some(AccessLog {
+					format: LogFormat::Combined,
+					sink: LogSink::Channel(log_tx),
+				}),
+				..Default::default()
+			},
+		);
+		let request = |skt: usize, data: &str| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "192.0.2.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: data.as_bytes().to_vec(),
+				}.into(),
+			);
+		};
+
+		let expect_received = |test_rx: &mpsc::Receiver<TestIncoming>| {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::SocketRsp(socket::Response::Received(_)) => {}
+				_ => panic!("Unexpected message"),
+			}
+		};
+
+		// A request we answer
+		request(
+			5,
+			"GET /page?x=1 HTTP/1.1\r\nHost: localhost\r\nReferer: http://a/\r\n\
+			 User-Agent: \"test\"\r\n\r\n",
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(10),
+				status: HttpResponseStatus::NotFound,
+				content_type: String::from("text/plain"),
+				length: Some(5),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		expect_send(&test_rx);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		// Nothing is logged until the response is done
+		assert!(log_rx.try_recv().is_err());
+		http_north.send_request(
+			ReqResponseBody {
+				handle: ch,
+				context: Context::new(11),
+				data: b"hello".to_vec(),
+			}.into(),
+			&reply_to,
+		);
+		expect_send(&test_rx);
+		let line = log_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		assert!(line.starts_with("192.0.2.1 - - ["), "{}", line);
+		assert!(line.contains(" +0000] "), "{}", line);
+		assert!(
+			line.ends_with("] \"GET /page?x=1 HTTP/1.1\" 404 5 \"http://a/\" \"\\\"test\\\"\""),
+			"{}",
+			line
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => assert!(x.result.is_ok()),
 			_ => panic!("Unexpected message"),
-		}
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(_), _) => {}
+			_ => panic!("Unexpected message"),
+		};
+
+		// A request we refuse
+		request(6, "POST /up HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n");
+		expect_rejection(&test_rx, Context::new(6));
+		expect_received(&test_rx);
+		let line = log_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
+		assert!(
+			line.ends_with("] \"POST /up HTTP/1.1\" 413 17 \"-\" \"-\""),
+			"{}",
+			line
+		);
+
+		// A request which is never answered isn't logged
+		request(7, "GET / HTTP/1.1\r\n\r\n");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(_)) => {}
+			_ => panic!("Unexpected message"),
+		};
+		expect_received(&test_rx);
+		http_south.send_indication(
+			socket::IndDropped {
+				handle: Context::new(7),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(_)) => {}
+			_ => panic!("Unexpected message"),
+		};
+		assert!(log_rx.try_recv().is_err());
+
+		// A log file we can't open stops the bind
+		http_north.send_request(
+			ReqBind {
+				addr: allocate_test_port(),
+				context: Context::new(20),
+				config: ServerConfig {
+					access_log: Some(AccessLog {
+						format: LogFormat::Common,
+						sink: LogSink::File(::std::path::PathBuf::from("/no/such/dir/access.log")),
+					}),
+					..Default::default()
+				},
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Bind(ref x)) => match x.result {
+				Err(Error::LogFile) => {}
+				_ => panic!("Expected LogFile"),
+			},
+			_ => panic!("Unexpected message"),
+		};
 	}
 
 	#[test]
-	fn bind_port_ok() {
+	fn error_responses() {
 		let (reply_to, test_rx) = make_test_channel();
 		// Use ourselves as the 'socket' task
 		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
-		let _ = bind_port(
+		let mut headers = HeaderMap::new();
+		headers.insert("X-Brand", HeaderValue::from_static("grease"));
+		let page = ErrorPage {
+			content_type: String::from("text/html"),
+			body: b"<h1>Too big</h1>".to_vec(),
+			headers,
+		};
+		let mut pages = HashMap::new();
+		pages.insert(StatusCode::PAYLOAD_TOO_LARGE, page.clone());
+		let limits = Limits {
+			max_body: 10,
+			..Default::default()
+		};
+		let (_, http_south) = bind_port_with_config(
 			&reply_to,
 			&test_rx,
 			&http_north,
 			&allocate_test_port(),
-			Context::new(1),
-			Context::new(2),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				limits: limits.clone(),
+				errors: ErrorResponses {
+					pages: pages.clone(),
+					indicate: None,
+				},
+				..Default::default()
+			},
+		);
+		let _ = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(13),
+			Context::new(14),
+			ServerConfig {
+				limits,
+				errors: ErrorResponses {
+					pages,
+					indicate: Some(Duration::from_millis(200)),
+				},
+				..Default::default()
+			},
+		);
+		let request = |listen: usize, skt: usize| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(listen),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: b"POST /up HTTP/1.1\r\nContent-Length: 11\r\n\r\n".to_vec(),
+				}.into(),
+			);
+		};
+		let expect_received = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(skt));
+			}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_error = |skt: usize| {
+			let ind = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpInd(Indication::Error(x)) => x,
+				_ => panic!("Unexpected message"),
+			};
+			expect_received(skt);
+			assert_eq!(ind.status, StatusCode::PAYLOAD_TOO_LARGE);
+			assert_eq!(ind.method, Some(Method::POST));
+			assert_eq!(ind.url.as_ref().map(|u| u.path()), Some("/up"));
+			ind.connection_handle
+		};
+		let expect_send = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Send(x), msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(skt));
+				msg_reply_to.send_confirm(
+					socket::CfmSend {
+						handle: x.handle,
+						context: x.context,
+						result: Ok(x.data.len()),
+					}.into(),
+				);
+				String::from_utf8(x.data).unwrap()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_page = |skt: usize| {
+			let rsp = expect_rejection(&test_rx, Context::new(skt));
+			assert!(rsp.starts_with("HTTP/1.0 413 Payload Too Large\r\n"));
+			assert!(rsp.contains("\r\nx-brand: grease\r\n"));
+			assert!(rsp.contains("\r\nContent-Type: text/html\r\n"));
+			assert!(rsp.contains("\r\nContent-Length: 16\r\n"));
+			assert!(rsp.ends_with("\r\n\r\n<h1>Too big</h1>"));
+		};
+		let start = |handle: ConnHandle, status: HttpResponseStatus| {
+			http_north.send_request(
+				ReqResponseStart {
+					handle,
+					context: Context::new(100),
+					status,
+					content_type: String::from("text/plain"),
+					length: Some(4),
+					headers: HeaderMap::new(),
+					compress: None,
+				}.into(),
+				&reply_to,
+			);
+		};
+
+		// Our page instead of the default
+		request(4, 5);
+		expect_page(5);
+		expect_received(5);
+
+		// The user answers the error
+		request(14, 6);
+		let ch = expect_error(6);
+		start(ch, HttpResponseStatus::RequestEntityTooLarge);
+		assert!(expect_send(6).starts_with("HTTP/1.1 413 "));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		http_north.send_request(
+			ReqResponseBody {
+				handle: ch,
+				context: Context::new(101),
+				data: b"nope".to_vec(),
+			}.into(),
+			&reply_to,
 		);
+		assert_eq!(expect_send(6), "nope");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(_), _) => {}
+			_ => panic!("Unexpected message"),
+		};
+		// Even after the timeout, nothing else goes out
+		assert!(test_rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+		// The user answers with the wrong status, so we send ours
+		request(14, 7);
+		let ch = expect_error(7);
+		start(ch, HttpResponseStatus::OK);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => match x.result {
+				Err(Error::BadHeader) => {}
+				_ => panic!("Expected BadHeader"),
+			},
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
+			_ => panic!("Unexpected message"),
+		};
+		expect_page(7);
+
+		// The user doesn't answer, so we send ours
+		request(14, 8);
+		let ch = expect_error(8);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
+			_ => panic!("Unexpected message"),
+		};
+		expect_page(8);
+		// And it's too late to try
+		start(ch, HttpResponseStatus::RequestEntityTooLarge);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => match x.result {
+				Err(Error::BadHandle) => {}
+				_ => panic!("Expected BadHandle"),
+			},
+			_ => panic!("Unexpected message"),
+		};
+
+		// Pages we can't send are refused at bind time
+		let bad_pages = vec![
+			(StatusCode::OK, page.clone()),
+			(
+				StatusCode::NOT_FOUND,
+				ErrorPage {
+					content_type: String::from("text/html\r\nX-Evil: yes"),
+					..page.clone()
+				},
+			),
+			(StatusCode::NOT_FOUND, {
+				let mut page = page.clone();
+				page.headers
+					.insert("Content-Length", HeaderValue::from_static("3"));
+				page
+			}),
+		];
+		for (status, page) in bad_pages {
+			let mut pages = HashMap::new();
+			pages.insert(status, page);
+			http_north.send_request(
+				ReqBind {
+					addr: allocate_test_port(),
+					context: Context::new(20),
+					config: ServerConfig {
+						errors: ErrorResponses {
+							pages,
+							indicate: None,
+						},
+						..Default::default()
+					},
+				}.into(),
+				&reply_to,
+			);
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpCfm(Confirm::Bind(ref x)) => match x.result {
+					Err(Error::BadErrorPage) => {}
+					_ => panic!("Expected BadErrorPage"),
+				},
+				_ => panic!("Unexpected message"),
+			};
+		}
 	}
 
 	#[test]
-	fn basic_get_vary_len() {
+	fn abort_response() {
 		let (reply_to, test_rx) = make_test_channel();
 		// Use ourselves as the 'socket' task
 		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
-		let (sh, http_south) = bind_port(
+		let (_, http_south) = bind_port(
 			&reply_to,
 			&test_rx,
 			&http_north,
@@ -1009,325 +6623,929 @@ mod test {
 			Context::new(3),
 			Context::new(4),
 		);
-
-		// ******************** Connection opens ********************
-
-		let msg = socket::IndConnected {
-			listen_handle: Context::new(4),
-			conn_handle: Context::new(5),
-			peer: "127.0.0.1:56789".parse().unwrap(),
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data: b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
+			}.into(),
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received_rsp(&test_rx);
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(10),
+				status: HttpResponseStatus::OK,
+				content_type: String::from("text/plain"),
+				length: Some(10),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		expect_send(&test_rx);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
 		};
-		http_south.send_indication(msg.into());
-
-		// ******************** Request arrives ********************
 
-		let msg = socket::IndReceived {
-			handle: Context::new(5),
-			data: String::from("GET /foo/bar HTTP/1.1\r\nHost: localhost\r\n\r\n").into_bytes(),
+		// Half the body goes to the socket task, which sits on it
+		http_north.send_request(
+			ReqResponseBody {
+				handle: ch,
+				context: Context::new(11),
+				data: b"hello".to_vec(),
+			}.into(),
+			&reply_to,
+		);
+		let (send, socket_reply_to) = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Send(x), msg_reply_to) => (x, msg_reply_to),
+			_ => panic!("Unexpected message"),
 		};
-		http_south.send_indication(msg.into());
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		let ch = match msg {
-			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => {
-				assert_eq!(x.server_handle, sh);
-				assert_eq!(x.url, "/foo/bar");
-				assert_eq!(x.method, Method::GET);
-				let mut expected_headers = HeaderMap::new();
-				expected_headers.insert("HOST", "localhost".parse().unwrap());
-				assert_eq!(x.headers, expected_headers);
-				x.connection_handle
+		// Then the backend fails
+		http_north.send_request(
+			ReqAbort {
+				handle: ch,
+				context: Context::new(12),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
+				assert_eq!(x.context, Context::new(11));
+				match x.result {
+					Err(Error::Aborted) => {}
+					_ => panic!("Expected Aborted"),
+				}
 			}
 			_ => panic!("Unexpected message"),
 		};
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), _) => {
 				assert_eq!(x.handle, Context::new(5));
+				assert!(x.reset);
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Abort(ref x)) => {
+				assert_eq!(x.context, Context::new(12));
+				assert!(x.result.is_ok());
 			}
 			_ => panic!("Unexpected message"),
 		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
+			_ => panic!("Unexpected message"),
+		};
 
-		// ******************** Send the headers ********************
+		// The late confirm from the socket task goes nowhere
+		socket_reply_to.send_confirm(
+			socket::CfmSend {
+				handle: send.handle,
+				context: send.context,
+				result: Err(socket::SocketError::Dropped),
+			}.into(),
+		);
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
 
-		let mut msg = ReqResponseStart {
-			handle: ch,
-			context: Context::new(1234),
-			status: HttpResponseStatus::OK,
-			content_type: String::from("text/plain"),
-			length: None,
-			headers: HeaderMap::new(),
+		// And the connection has gone
+		http_north.send_request(
+			ReqAbort {
+				handle: ch,
+				context: Context::new(13),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Abort(ref x)) => match x.result {
+				Err(Error::BadHandle) => {}
+				_ => panic!("Expected BadHandle"),
+			},
+			_ => panic!("Unexpected message"),
 		};
-		msg.headers.insert("x-magic", "frobbins".parse().unwrap());
-		http_north.send_request(msg.into(), &reply_to);
+	}
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
-				assert_eq!(x.handle, Context::new(5));
-				let headers = "HTTP/1.1 200 OK\r\nServer: grease/http\r\nContent-Type: \
-				               text/plain\r\nx-magic: frobbins\r\n\r\n"
-					.as_bytes();
-				assert_eq!(x.data, headers);
-				let send_cfm = socket::CfmSend {
-					handle: x.handle,
-					context: x.context,
-					result: Ok(x.data.len()),
-				};
-				msg_reply_to.send_confirm(send_cfm.into());
+	#[test]
+	fn cors_requests() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				cors: Some(CorsConfig {
+					origins: CorsOrigins::List(vec![String::from("https://app.test")]),
+					methods: vec![Method::GET, Method::PUT],
+					headers: vec![String::from("X-Token")],
+					credentials: true,
+					max_age: Some(Duration::from_secs(60)),
+					..Default::default()
+				}),
+				..Default::default()
+			},
+		);
+		let request = |skt: usize, data: &str| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(skt),
+					data: data.as_bytes().to_vec(),
+				}.into(),
+			);
+		};
+		let expect_received = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(skt));
 			}
 			_ => panic!("Unexpected message"),
 		};
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => {
-				assert_eq!(x.handle, ch);
-				assert_eq!(x.context, Context::new(1234));
-				assert!(x.result.is_ok());
+		let expect_send = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Send(x), msg_reply_to) => {
+				assert_eq!(x.handle, Context::new(skt));
+				msg_reply_to.send_confirm(
+					socket::CfmSend {
+						handle: x.handle,
+						context: x.context,
+						result: Ok(x.data.len()),
+					}.into(),
+				);
+				String::from_utf8(x.data).unwrap()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		let expect_close = |skt: usize| match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(ref x), _) => {
+				assert_eq!(x.handle, Context::new(skt));
 			}
 			_ => panic!("Unexpected message"),
 		};
 
-		// ******************** Send the body ********************
+		// A preflight we allow is answered for us
+		request(
+			10,
+			"OPTIONS /api HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.test\r\n\
+			 Access-Control-Request-Method: PUT\r\n\
+			 Access-Control-Request-Headers: x-token\r\n\r\n",
+		);
+		let head = expect_send(10);
+		assert!(head.starts_with("HTTP/1.1 204 No Content\r\n"));
+		assert!(head.contains("access-control-allow-origin: https://app.test\r\n"));
+		assert!(head.contains("access-control-allow-credentials: true\r\n"));
+		assert!(head.contains("access-control-allow-methods: GET, PUT\r\n"));
+		assert!(head.contains("access-control-allow-headers: X-Token\r\n"));
+		assert!(head.contains("access-control-max-age: 60\r\n"));
+		assert!(head.contains("vary: Origin\r\n"));
+		expect_close(10);
+		expect_received(10);
 
-		let test_body = Vec::from("One two, Rust on my shoe");
-		let msg = ReqResponseBody {
-			handle: ch,
-			context: Context::new(5678),
-			data: test_body.clone(),
-		};
-		http_north.send_request(msg.into(), &reply_to);
+		// One we don't is refused
+		request(
+			11,
+			"OPTIONS /api HTTP/1.1\r\nHost: localhost\r\nOrigin: https://evil.test\r\n\
+			 Access-Control-Request-Method: PUT\r\n\r\n",
+		);
+		let head = expect_send(11);
+		assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(!head.contains("access-control-allow-origin"));
+		expect_close(11);
+		expect_received(11);
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
-				assert_eq!(x.handle, Context::new(5));
-				assert_eq!(x.data, test_body);
-				let send_cfm = socket::CfmSend {
-					handle: x.handle,
-					context: x.context,
-					result: Ok(x.data.len()),
-				};
-				msg_reply_to.send_confirm(send_cfm.into());
-			}
+		// Other requests get the headers added to the response
+		request(
+			20,
+			"GET /api HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.test\r\n\r\n",
+		);
+		let ind = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x,
+			_ => panic!("Unexpected message"),
+		};
+		expect_received(20);
+		let mut headers = HeaderMap::new();
+		headers.insert("Vary", HeaderValue::from_static("Cookie"));
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ind.connection_handle,
+				context: Context::new(21),
+				status: HttpResponseStatus::OK,
+				content_type: String::from("text/plain"),
+				length: Some(0),
+				headers,
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		let head = expect_send(20);
+		assert!(head.contains("access-control-allow-origin: https://app.test\r\n"));
+		assert!(head.contains("access-control-allow-credentials: true\r\n"));
+		assert!(head.contains("vary: Cookie\r\nvary: Origin\r\n"));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
 			_ => panic!("Unexpected message"),
 		};
+		expect_close(20);
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
-				assert_eq!(x.handle, ch);
-				assert_eq!(x.context, Context::new(5678));
-				assert!(x.result.is_ok());
-			}
+		// Configs which don't make sense are refused
+		http_north.send_request(
+			ReqBind {
+				addr: allocate_test_port(),
+				context: Context::new(30),
+				config: ServerConfig {
+					cors: Some(CorsConfig {
+						credentials: true,
+						..Default::default()
+					}),
+					..Default::default()
+				},
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Bind(ref x)) => match x.result {
+				Err(Error::BadCors) => {}
+				_ => panic!("Expected BadCors"),
+			},
 			_ => panic!("Unexpected message"),
+		}
+	}
+
+	/// Build an HTTP/2 frame as a client would
+	fn h2_frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+		let mut frame = vec![
+			(payload.len() >> 16) as u8,
+			(payload.len() >> 8) as u8,
+			payload.len() as u8,
+			kind,
+			flags,
+		];
+		frame.extend_from_slice(&[
+			(stream >> 24) as u8,
+			(stream >> 16) as u8,
+			(stream >> 8) as u8,
+			stream as u8,
+		]);
+		frame.extend_from_slice(payload);
+		frame
+	}
+
+	/// Split what we sent into (type, flags, stream, payload) frames
+	fn h2_frames(data: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+		let mut frames = Vec::new();
+		let mut rest = data;
+		while !rest.is_empty() {
+			let length = (rest[0] as usize) << 16 | (rest[1] as usize) << 8 | rest[2] as usize;
+			let stream = (rest[5] as u32) << 24 | (rest[6] as u32) << 16
+				| (rest[7] as u32) << 8 | rest[8] as u32;
+			frames.push((rest[3], rest[4], stream, rest[9..9 + length].to_vec()));
+			rest = &rest[9 + length..];
+		}
+		frames
+	}
+
+	#[test]
+	fn http2_prior_knowledge() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				http2: Some(Http2Config::default()),
+				..Default::default()
+			},
+		);
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		let receive = |data: Vec<u8>| {
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(5),
+					data,
+				}.into(),
+			);
 		};
-
-		// ******************** Send an empty body ********************
-
-		let msg = ReqResponseBody {
-			handle: ch,
-			context: Context::new(5678),
-			data: Vec::new(),
+		let expect_request = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x,
+			_ => panic!("Unexpected message"),
 		};
-		http_north.send_request(msg.into(), &reply_to);
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketReq(socket::Request::Close(ref x), ref msg_reply_to) => {
-				assert_eq!(x.handle, Context::new(5));
-				let send_cfm = socket::CfmClose {
-					handle: x.handle,
-					context: x.context,
-					result: Ok(()),
-				};
-				msg_reply_to.send_confirm(send_cfm.into());
+		// The preface arrives in pieces, then two requests, one with a body
+		receive(h2::PREFACE[..10].to_vec());
+		expect_received_rsp(&test_rx);
+		let mut data = h2::PREFACE[10..].to_vec();
+		data.extend(h2_frame(0x4, 0, 0, &[]));
+		let get = hpack::encode(&[
+			(":method", b"GET"),
+			(":scheme", b"http"),
+			(":path", b"/a"),
+			(":authority", b"example.com"),
+		]);
+		data.extend(h2_frame(0x1, 0x5, 1, &get));
+		let post = hpack::encode(&[
+			(":method", b"POST"),
+			(":scheme", b"http"),
+			(":path", b"/b"),
+			(":authority", b"example.com"),
+			("content-length", b"3"),
+		]);
+		data.extend(h2_frame(0x1, 0x4, 3, &post));
+		data.extend(h2_frame(0x0, 0x1, 3, b"abc"));
+		receive(data);
+		let get = expect_request();
+		assert_eq!((&get.method, get.version), (&Method::GET, Version::HTTP_2));
+		assert_eq!(get.url, "/a");
+		assert_eq!(get.headers["host"], "example.com");
+		assert!(!get.has_body());
+		let post = expect_request();
+		assert_eq!(post.method, Method::POST);
+		assert!(post.has_body());
+		assert_ne!(get.connection_handle, post.connection_handle);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxBody(x)) => {
+				assert_eq!(x.handle, post.connection_handle);
+				assert_eq!((&x.data[..], x.last), (&b"abc"[..], true));
 			}
 			_ => panic!("Unexpected message"),
 		};
+		// Our SETTINGS, and the ack for theirs
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!(frames.len(), 2);
+		assert_eq!((frames[0].0, frames[0].1), (0x4, 0));
+		assert_eq!(frames[1], (0x4, 0x1, 0, Vec::new()));
+		expect_received_rsp(&test_rx);
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
-				assert_eq!(x.handle, ch);
-				assert_eq!(x.context, Context::new(5678));
-				assert!(x.result.is_ok());
+		// Answer the second request first, each on its own stream
+		let respond = |handle: ConnHandle, ctx: usize, body: &[u8]| {
+			http_north.send_request(
+				ReqResponseStart {
+					handle,
+					context: Context::new(ctx),
+					status: HttpResponseStatus::OK,
+					content_type: String::from("text/plain"),
+					length: Some(body.len()),
+					headers: HeaderMap::new(),
+					compress: None,
+				}.into(),
+				&reply_to,
+			);
+			http_north.send_request(
+				ReqResponseBody {
+					handle,
+					context: Context::new(ctx),
+					data: body.to_vec(),
+				}.into(),
+				&reply_to,
+			);
+		};
+		let mut decoder = hpack::Decoder::new(4096, 16 * 1024);
+		for &(ind, stream, body) in &[(&post, 3, &b"done"[..]), (&get, 1, &b"hello"[..])] {
+			respond(ind.connection_handle, stream as usize, body);
+			let frames = h2_frames(&expect_send(&test_rx));
+			assert_eq!(frames.len(), 1);
+			assert_eq!((frames[0].0, frames[0].1, frames[0].2), (0x1, 0x4, stream));
+			let fields = decoder.decode(&frames[0].3).unwrap();
+			assert_eq!(fields[0], (b":status".to_vec(), b"200".to_vec()));
+			let length = body.len().to_string().into_bytes();
+			assert!(fields.contains(&(b"content-length".to_vec(), length)));
+			assert!(fields.contains(&(b"content-type".to_vec(), b"text/plain".to_vec())));
+			let frames = h2_frames(&expect_send(&test_rx));
+			assert_eq!(frames, vec![(0x0, 0x1, stream, body.to_vec())]);
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+				_ => panic!("Unexpected message"),
+			};
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => assert!(x.result.is_ok()),
+				_ => panic!("Unexpected message"),
+			};
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpInd(Indication::Closed(ref x)) => {
+					assert_eq!(x.handle, ind.connection_handle)
+				}
+				_ => panic!("Unexpected message"),
+			};
+		}
+
+		// A stream which breaks the limits is refused on its own
+		let mut data = Vec::new();
+		let big = hpack::encode(&[
+			(":method", b"PUT"),
+			(":scheme", b"http"),
+			(":path", b"/c"),
+			(":authority", b"example.com"),
+			("content-length", b"99999999999"),
+		]);
+		data.extend(h2_frame(0x1, 0x4, 5, &big));
+		receive(data);
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!((frames[0].0, frames[0].2), (0x1, 5));
+		let fields = decoder.decode(&frames[0].3).unwrap();
+		assert_eq!(fields[0], (b":status".to_vec(), b"413".to_vec()));
+		// The client hasn't finished, so the stream is reset once answered
+		assert_eq!(frames[frames.len() - 1].0, 0x3);
+		expect_received_rsp(&test_rx);
+
+		// A connection error ends the lot
+		receive(h2_frame(0x0, 0, 7, b"x"));
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!(frames.len(), 1);
+		assert_eq!((frames[0].0, &frames[0].3[4..]), (0x7, &[0u8, 0, 0, 1][..]));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketReq(socket::Request::Close(x), _) => {
+				assert_eq!(x.handle, Context::new(5))
 			}
 			_ => panic!("Unexpected message"),
 		};
+		expect_received_rsp(&test_rx);
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
+	}
 
-		// ******************** Get a close indication ********************
+	#[test]
+	fn http2_body_flow_control() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (_, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+			ServerConfig {
+				http2: Some(Http2Config::default()),
+				body_flow_control: true,
+				..Default::default()
+			},
+		);
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpInd(Indication::Closed(ref x)) => {
-				assert_eq!(x.handle, ch);
+		// A body with no length, in three pieces
+		let mut data = h2::PREFACE.to_vec();
+		data.extend(h2_frame(0x4, 0, 0, &[]));
+		let post = hpack::encode(&[
+			(":method", b"POST"),
+			(":scheme", b"http"),
+			(":path", b"/upload"),
+			(":authority", b"example.com"),
+		]);
+		data.extend(h2_frame(0x1, 0x4, 1, &post));
+		for _ in 0..3 {
+			data.extend(h2_frame(0x0, 0, 1, &[b'x'; 16_000]));
+		}
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data,
+			}.into(),
+		);
+		let handle = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => {
+				assert!(x.has_body());
+				assert!(!x.headers.contains_key("transfer-encoding"));
+				x.connection_handle
 			}
 			_ => panic!("Unexpected message"),
 		};
+		for _ in 0..3 {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpInd(Indication::RxBody(x)) => {
+					assert_eq!((x.data.len(), x.last), (16_000, false))
+				}
+				_ => panic!("Unexpected message"),
+			};
+		}
+		// Only our SETTINGS and the ack - the windows stay shut
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!(frames.len(), 2);
+		assert!(frames.iter().all(|f| f.0 == 0x4));
+		expect_received_rsp(&test_rx);
 
-		// ******************** All done ********************
+		// They open once we've dealt with half a window's worth
+		http_north.send_response(RspRxBody { handle }.into());
+		http_north.send_response(RspRxBody { handle }.into());
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
+		http_north.send_response(RspRxBody { handle }.into());
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!(
+			frames,
+			vec![
+				(0x8, 0, 0, vec![0, 0, 0xBB, 0x80]),
+				(0x8, 0, 1, vec![0, 0, 0xBB, 0x80]),
+			]
+		);
+		assert!(test_rx.recv_timeout(Duration::from_millis(100)).is_err());
 	}
 
 	#[test]
-	fn basic_get_fixed_len() {
+	fn http2_upgrade() {
 		let (reply_to, test_rx) = make_test_channel();
 		// Use ourselves as the 'socket' task
 		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
-		let (sh, http_south) = bind_port(
+		let (_, http_south) = bind_port_with_config(
 			&reply_to,
 			&test_rx,
 			&http_north,
 			&allocate_test_port(),
 			Context::new(3),
 			Context::new(4),
+			ServerConfig {
+				http2: Some(Http2Config::default()),
+				..Default::default()
+			},
 		);
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		let receive = |data: &[u8]| {
+			http_south.send_indication(
+				socket::IndReceived {
+					handle: Context::new(5),
+					data: data.to_vec(),
+				}.into(),
+			);
+		};
 
-		// ******************** Connection opens ********************
-
-		let msg = socket::IndConnected {
-			listen_handle: Context::new(4),
-			conn_handle: Context::new(5),
-			peer: "127.0.0.1:56789".parse().unwrap(),
+		receive(
+			b"GET /up HTTP/1.1\r\nHost: example.com\r\n\
+			  Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+			  HTTP2-Settings: AAMAAABk\r\n\r\n",
+		);
+		assert_eq!(
+			expect_send(&test_rx),
+			b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n"
+				.to_vec()
+		);
+		// The request becomes stream 1, without the upgrade headers
+		let ind = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x,
+			_ => panic!("Unexpected message"),
 		};
-		http_south.send_indication(msg.into());
+		assert_eq!((ind.version, ind.url.path()), (Version::HTTP_2, "/up"));
+		assert!(!ind.headers.contains_key("upgrade"));
+		assert!(!ind.headers.contains_key("connection"));
+		assert!(!ind.headers.contains_key("http2-settings"));
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!((frames[0].0, frames[0].1), (0x4, 0));
+		expect_received_rsp(&test_rx);
 
-		// ******************** Request arrives ********************
+		let mut data = h2::PREFACE.to_vec();
+		data.extend(h2_frame(0x4, 0, 0, &[]));
+		receive(&data);
+		assert_eq!(h2_frames(&expect_send(&test_rx)), vec![(0x4, 0x1, 0, Vec::new())]);
+		expect_received_rsp(&test_rx);
 
-		let msg = socket::IndReceived {
-			handle: Context::new(5),
-			data: String::from("GET /foo/bar HTTP/1.1\r\nHost: localhost\r\n\r\n").into_bytes(),
+		// A response with no body ends the stream with its head
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ind.connection_handle,
+				context: Context::new(6),
+				status: HttpResponseStatus::NoContent,
+				content_type: String::new(),
+				length: Some(0),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!(frames.len(), 1);
+		assert_eq!((frames[0].0, frames[0].1, frames[0].2), (0x1, 0x5, 1));
+		let fields = hpack::Decoder::new(4096, 16 * 1024).decode(&frames[0].3).unwrap();
+		assert_eq!(fields[0], (b":status".to_vec(), b"204".to_vec()));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
 		};
-		http_south.send_indication(msg.into());
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		let ch = match msg {
-			TestIncoming::HttpInd(Indication::RxRequest(ref x)) => {
-				assert_eq!(x.server_handle, sh);
-				assert_eq!(x.url, "/foo/bar");
-				assert_eq!(x.method, Method::GET);
-				let mut expected_headers = HeaderMap::new();
-				expected_headers.insert("Host", "localhost".parse().unwrap());
-				assert_eq!(x.headers, expected_headers);
-				x.connection_handle
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, ind.connection_handle)
 			}
 			_ => panic!("Unexpected message"),
 		};
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
-				assert_eq!(x.handle, Context::new(5));
+		// Requests with a body aren't upgraded
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(4),
+				conn_handle: Context::new(7),
+				peer: "127.0.0.1:56790".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(7),
+				data: b"POST / HTTP/1.1\r\nHost: example.com\r\n\
+				        Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+				        HTTP2-Settings: AAMAAABk\r\nContent-Length: 1\r\n\r\nx"
+					.to_vec(),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => {
+				assert_eq!(x.version, Version::HTTP_11)
 			}
 			_ => panic!("Unexpected message"),
 		};
+	}
 
-		// ******************** Send the headers ********************
-
-		let mut msg = ReqResponseStart {
-			handle: ch,
-			context: Context::new(1234),
-			status: HttpResponseStatus::OK,
-			content_type: String::from("text/plain"),
-			length: Some(24),
-			headers: HeaderMap::new(),
+	#[test]
+	fn drain_server() {
+		let (reply_to, test_rx) = make_test_channel();
+		// Use ourselves as the 'socket' task
+		let http_north = make_task(grease::ServiceProvider::clone(&reply_to));
+		let (server, http_south) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(3),
+			Context::new(4),
+		);
+		let connect = |skt: usize| {
+			http_south.send_indication(
+				socket::IndConnected {
+					listen_handle: Context::new(4),
+					conn_handle: Context::new(skt),
+					peer: "127.0.0.1:56789".parse().unwrap(),
+					local: "127.0.0.1:8000".parse().unwrap(),
+				}.into(),
+			);
 		};
-		msg.headers.insert("x-magic", "frobbins".parse().unwrap());
-		http_north.send_request(msg.into(), &reply_to);
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
-				assert_eq!(x.handle, Context::new(5));
-				let headers = "HTTP/1.1 200 OK\r\nServer: grease/http\r\nContent-Length: \
-				               24\r\nContent-Type: text/plain\r\nx-magic: frobbins\r\n\r\n"
-					.as_bytes();
-				println!("Headers: {:?}", String::from_utf8(x.data.clone()));
-				assert_eq!(x.data, headers);
-				let send_cfm = socket::CfmSend {
-					handle: x.handle,
-					context: x.context,
-					result: Ok(x.data.len()),
-				};
-				msg_reply_to.send_confirm(send_cfm.into());
+		let expect_close = |skt: usize, reset: bool| match test_rx
+			.recv_timeout(DEFAULT_TIMEOUT)
+			.unwrap()
+		{
+			TestIncoming::SocketReq(socket::Request::Close(x), msg_reply_to) => {
+				assert_eq!((x.handle, x.reset), (Context::new(skt), reset));
+				msg_reply_to.send_confirm(
+					socket::CfmClose {
+						handle: x.handle,
+						context: x.context,
+						result: Ok(()),
+					}.into(),
+				);
 			}
 			_ => panic!("Unexpected message"),
 		};
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => {
-				assert_eq!(x.handle, ch);
-				assert_eq!(x.context, Context::new(1234));
-				assert!(x.result.is_ok());
+		let drain = |handle: ServerHandle, deadline: u64| {
+			http_north.send_request(
+				ReqDrain {
+					handle,
+					context: Context::new(7),
+					deadline: Duration::from_millis(deadline),
+				}.into(),
+				&reply_to,
+			);
+		};
+		let expect_drained = || match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Drain(x)) => {
+				assert_eq!((x.handle, x.context), (server, Context::new(7)));
+				x.result
 			}
 			_ => panic!("Unexpected message"),
 		};
 
-		// ******************** Send the body ********************
-
-		let test_body = Vec::from("One two, Rust on my shoe");
-		let msg = ReqResponseBody {
-			handle: ch,
-			context: Context::new(5678),
-			data: test_body.clone(),
+		// One request in progress, one which has stalled half way through
+		// its head, and one connection with nothing on it yet
+		connect(5);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+			}.into(),
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
 		};
-		http_north.send_request(msg.into(), &reply_to);
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketReq(socket::Request::Send(ref x), ref msg_reply_to) => {
-				assert_eq!(x.handle, Context::new(5));
-				assert_eq!(x.data, test_body);
-				let send_cfm = socket::CfmSend {
-					handle: x.handle,
-					context: x.context,
-					result: Ok(x.data.len()),
-				};
-				msg_reply_to.send_confirm(send_cfm.into());
+		expect_received_rsp(&test_rx);
+		connect(9);
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(9),
+				data: b"GET / HT".to_vec(),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketRsp(socket::Response::Received(ref x)) => {
+				assert_eq!(x.handle, Context::new(9));
 			}
 			_ => panic!("Unexpected message"),
 		};
+		connect(6);
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => {
-				assert_eq!(x.handle, ch);
-				assert_eq!(x.context, Context::new(5678));
-				assert!(x.result.is_ok());
-			}
+		// The listener goes, then the idle connection, and anything the
+		// listener had already accepted
+		drain(server, 300);
+		expect_close(4, false);
+		expect_close(6, false);
+		connect(8);
+		expect_close(8, false);
+
+		// The request in progress is answered, and says it's the last
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(9),
+				status: HttpResponseStatus::OK,
+				content_type: String::from("text/plain"),
+				length: Some(2),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		let head = String::from_utf8(expect_send(&test_rx)).unwrap();
+		assert!(head.contains("\r\nconnection: close\r\n"));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		http_north.send_request(
+			ReqResponseBody {
+				handle: ch,
+				context: Context::new(9),
+				data: b"ok".to_vec(),
+			}.into(),
+			&reply_to,
+		);
+		assert_eq!(expect_send(&test_rx), b"ok");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseBody(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		expect_close(5, false);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
 			_ => panic!("Unexpected message"),
 		};
 
-		// ******************** Auto close ********************
+		// The stalled request is still in progress at the deadline, so it's
+		// reset
+		expect_close(9, true);
+		match expect_drained() {
+			Err(Error::DrainTimeout) => {}
+			x => panic!("Expected DrainTimeout, got {:?}", x),
+		}
+		// After which the server has gone
+		drain(server, 300);
+		match expect_drained() {
+			Err(Error::BadHandle) => {}
+			x => panic!("Expected BadHandle, got {:?}", x),
+		}
 
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::SocketReq(socket::Request::Close(ref x), ref msg_reply_to) => {
-				assert_eq!(x.handle, Context::new(5));
-				let send_cfm = socket::CfmClose {
-					handle: x.handle,
-					context: x.context,
-					result: Ok(()),
-				};
-				msg_reply_to.send_confirm(send_cfm.into());
+		// With no connections, the drain finishes straight away
+		let (server, _) = bind_port(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(10),
+			Context::new(11),
+		);
+		drain(server, 60_000);
+		expect_close(11, false);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Drain(x)) => {
+				assert_eq!(x.handle, server);
+				assert!(x.result.is_ok());
 			}
 			_ => panic!("Unexpected message"),
 		};
 
-		// ******************** Get a close indication ********************
-
-		let msg = test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap();
-		match msg {
-			TestIncoming::HttpInd(Indication::Closed(ref x)) => {
-				assert_eq!(x.handle, ch);
+		// HTTP/2 clients are sent a GOAWAY, and the connection closes once
+		// its streams are done
+		let (server, http_south) = bind_port_with_config(
+			&reply_to,
+			&test_rx,
+			&http_north,
+			&allocate_test_port(),
+			Context::new(12),
+			Context::new(13),
+			ServerConfig {
+				http2: Some(Http2Config::default()),
+				..Default::default()
+			},
+		);
+		http_south.send_indication(
+			socket::IndConnected {
+				listen_handle: Context::new(13),
+				conn_handle: Context::new(5),
+				peer: "127.0.0.1:56789".parse().unwrap(),
+				local: "127.0.0.1:8000".parse().unwrap(),
+			}.into(),
+		);
+		let mut data = h2::PREFACE.to_vec();
+		data.extend(h2_frame(0x4, 0, 0, &[]));
+		let get = hpack::encode(&[
+			(":method", b"GET"),
+			(":scheme", b"http"),
+			(":path", b"/"),
+			(":authority", b"example.com"),
+		]);
+		data.extend(h2_frame(0x1, 0x5, 1, &get));
+		http_south.send_indication(
+			socket::IndReceived {
+				handle: Context::new(5),
+				data,
+			}.into(),
+		);
+		let ch = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::RxRequest(x)) => x.connection_handle,
+			_ => panic!("Unexpected message"),
+		};
+		expect_send(&test_rx);
+		expect_received_rsp(&test_rx);
+		http_north.send_request(
+			ReqDrain {
+				handle: server,
+				context: Context::new(14),
+				deadline: Duration::from_secs(60),
+			}.into(),
+			&reply_to,
+		);
+		expect_close(13, false);
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!(frames, vec![(0x7, 0, 0, vec![0, 0, 0, 1, 0, 0, 0, 0])]);
+		http_north.send_request(
+			ReqResponseStart {
+				handle: ch,
+				context: Context::new(15),
+				status: HttpResponseStatus::NoContent,
+				content_type: String::new(),
+				length: Some(0),
+				headers: HeaderMap::new(),
+				compress: None,
+			}.into(),
+			&reply_to,
+		);
+		let frames = h2_frames(&expect_send(&test_rx));
+		assert_eq!((frames[0].0, frames[0].1, frames[0].2), (0x1, 0x5, 1));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::ResponseStart(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpInd(Indication::Closed(ref x)) => assert_eq!(x.handle, ch),
+			_ => panic!("Unexpected message"),
+		};
+		expect_close(5, false);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpCfm(Confirm::Drain(x)) => {
+				assert_eq!((x.handle, x.context), (server, Context::new(14)));
+				assert!(x.result.is_ok());
 			}
 			_ => panic!("Unexpected message"),
 		};
-
-		// ******************** All done ********************
 	}
 }
 
@@ -0,0 +1,952 @@
+//! # parser - an incremental HTTP/1.x parser
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! Parses the head (start line and headers) of HTTP/1.0 and HTTP/1.1
+//! requests and responses. Nothing is copied - the caller keeps appending
+//! what arrives to one buffer and hands all of it over each time, and the
+//! parser carries on from the last line it checked. Once the head is
+//! complete you get a view of it which borrows from the buffer, along with
+//! whatever followed it (the start of the body, or a pipelined request).
+//!
+//! We follow RFC 7230, except that, like most servers, we accept a bare LF
+//! as a line ending. Empty lines before a request line are ignored.
+//! Obsolete line folding is refused.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+use std::str;
+
+use http::header::{HeaderName, HeaderValue};
+use http::{HeaderMap, Method, StatusCode, Uri, Version};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Limits on the size of a head. A head which breaks them is refused as
+/// soon as we can tell, rather than once it has all arrived.
+#[derive(Debug, Copy, Clone)]
+pub struct ParseLimits {
+	/// Maximum size of the start line and headers, in bytes
+	pub max_head_bytes: usize,
+	/// Maximum number of header lines
+	pub max_headers: usize,
+	/// Maximum length of a request target, in bytes
+	pub max_target_len: usize,
+}
+
+/// Parses a request head, a line at a time. Use a new one (or `reset` this
+/// one) for each request.
+#[derive(Debug, Clone)]
+pub struct RequestParser {
+	scanner: Scanner,
+	method: Span,
+	target: Span,
+	version: Version,
+}
+
+/// Parses a response head, a line at a time. Use a new one (or `reset`
+/// this one) for each response.
+#[derive(Debug, Clone)]
+pub struct ResponseParser {
+	scanner: Scanner,
+	version: Version,
+	status: u16,
+	reason: Span,
+}
+
+/// The result of giving a parser some more data
+#[derive(Debug, PartialEq)]
+pub enum Parsed<'a, T> {
+	/// The head is complete. `rest` is whatever followed it.
+	Complete { head: T, rest: &'a [u8] },
+	/// We need more data
+	Partial,
+}
+
+/// A request line and headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHead<'a> {
+	pub method: &'a str,
+	/// As sent, e.g. "/index.html?q=1" or "*"
+	pub target: &'a str,
+	pub version: Version,
+	pub headers: Vec<Header<'a>>,
+}
+
+/// A status line and headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusHead<'a> {
+	pub version: Version,
+	pub status: StatusCode,
+	/// The reason phrase, which may be empty. It's only there for humans.
+	pub reason: &'a [u8],
+	pub headers: Vec<Header<'a>>,
+}
+
+/// One header line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Header<'a> {
+	/// As sent - compare it without regard to case
+	pub name: &'a str,
+	/// Without any leading or trailing whitespace
+	pub value: &'a [u8],
+}
+
+/// How the body following a head is delimited
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BodyLength {
+	/// Exactly this many bytes, which may be none
+	Fixed(u64),
+	/// Chunked transfer encoding
+	Chunked,
+	/// Everything until the connection closes. Only responses do this.
+	UntilClose,
+}
+
+/// Why a head couldn't be parsed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+	/// The request line isn't `method SP request-target SP HTTP-version`
+	BadRequestLine,
+	/// The status line isn't `HTTP-version SP status-code SP reason-phrase`
+	BadStatusLine,
+	/// The method isn't a token
+	BadMethod,
+	/// The request target has characters which can't be in a URI, or isn't
+	/// in a form the method allows
+	BadTarget,
+	/// An HTTP version other than 1.0 or 1.1
+	BadVersion,
+	/// The status code isn't three digits
+	BadStatus,
+	/// A header line has no colon, or a name which isn't a token (which
+	/// includes having whitespace before the colon)
+	BadHeaderName,
+	/// A header value has control characters in it
+	BadHeaderValue,
+	/// A header line starts with whitespace (obsolete line folding)
+	FoldedHeader,
+	/// A CR which isn't followed by an LF
+	BadLineEnding,
+	/// The start line alone is more than `max_head_bytes`
+	StartLineTooLong,
+	/// The request target is more than `max_target_len`
+	TargetTooLong,
+	/// The head is more than `max_head_bytes`
+	HeadTooLarge,
+	/// There are more than `max_headers` header lines
+	TooManyHeaders,
+	/// A `Content-Length` which isn't a number, or several which disagree
+	BadContentLength,
+	/// Both `Content-Length` and `Transfer-Encoding`
+	ConflictingLength,
+	/// A request `Transfer-Encoding` which doesn't end with a single
+	/// `chunked`
+	BadTransferEncoding,
+	/// A request `Transfer-Encoding` with a coding other than `chunked`
+	UnknownEncoding,
+	/// A chunked body with a bad chunk size line, or a chunk which isn't
+	/// followed by a line ending
+	BadChunk,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// The start and end of something in the buffer
+type Span = (usize, usize);
+
+/// Finds and checks the lines of a head, remembering how far it has got.
+#[derive(Debug, Clone)]
+struct Scanner {
+	limits: ParseLimits,
+	/// Whether empty lines before the start line are ignored
+	skip_empty: bool,
+	/// How much of the buffer we've checked. Always the start of a line.
+	checked: usize,
+	/// Whether we've had the start line
+	started: bool,
+	/// The name and value of each header
+	headers: Vec<(Span, Span)>,
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Default for ParseLimits {
+	fn default() -> ParseLimits {
+		ParseLimits {
+			max_head_bytes: 16 * 1024,
+			max_headers: 100,
+			max_target_len: 8 * 1024,
+		}
+	}
+}
+
+impl RequestParser {
+	pub fn new(limits: ParseLimits) -> RequestParser {
+		RequestParser {
+			scanner: Scanner::new(limits, true),
+			method: (0, 0),
+			target: (0, 0),
+			version: Version::HTTP_11,
+		}
+	}
+
+	/// Get ready for the next request.
+	pub fn reset(&mut self) {
+		*self = RequestParser::new(self.scanner.limits);
+	}
+
+	/// Parse as much of `buffer` as we can. It must start with whatever was
+	/// given last time. Once this has failed, it will keep failing.
+	pub fn parse<'a>(
+		&mut self,
+		buffer: &'a [u8],
+	) -> Result<Parsed<'a, RequestHead<'a>>, ParseError> {
+		let max_target_len = self.scanner.limits.max_target_len;
+		let mut line = None;
+		let end = self.scanner.scan(buffer, &mut |at, l| {
+			line = Some(request_line(at, l, max_target_len)?);
+			Ok(())
+		})?;
+		if let Some((method, target, version)) = line {
+			self.method = method;
+			self.target = target;
+			self.version = version;
+		}
+		Ok(match end {
+			Some(end) => Parsed::Complete {
+				head: RequestHead {
+					method: ascii(buffer, self.method),
+					target: ascii(buffer, self.target),
+					version: self.version,
+					headers: self.scanner.headers(buffer),
+				},
+				rest: &buffer[end..],
+			},
+			None => Parsed::Partial,
+		})
+	}
+}
+
+impl ResponseParser {
+	pub fn new(limits: ParseLimits) -> ResponseParser {
+		ResponseParser {
+			scanner: Scanner::new(limits, false),
+			version: Version::HTTP_11,
+			status: 0,
+			reason: (0, 0),
+		}
+	}
+
+	/// Get ready for the next response.
+	pub fn reset(&mut self) {
+		*self = ResponseParser::new(self.scanner.limits);
+	}
+
+	/// Parse as much of `buffer` as we can. It must start with whatever was
+	/// given last time. Once this has failed, it will keep failing.
+	pub fn parse<'a>(
+		&mut self,
+		buffer: &'a [u8],
+	) -> Result<Parsed<'a, StatusHead<'a>>, ParseError> {
+		let mut line = None;
+		let end = self.scanner.scan(buffer, &mut |at, l| {
+			line = Some(status_line(at, l)?);
+			Ok(())
+		})?;
+		if let Some((version, status, reason)) = line {
+			self.version = version;
+			self.status = status;
+			self.reason = reason;
+		}
+		Ok(match end {
+			Some(end) => Parsed::Complete {
+				head: StatusHead {
+					version: self.version,
+					// We checked it was three digits
+					status: StatusCode::from_u16(self.status).map_err(|_| ParseError::BadStatus)?,
+					reason: &buffer[self.reason.0..self.reason.1],
+					headers: self.scanner.headers(buffer),
+				},
+				rest: &buffer[end..],
+			},
+			None => Parsed::Partial,
+		})
+	}
+}
+
+impl<'a> RequestHead<'a> {
+	/// The value of the first header with this name, if there is one
+	pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+		find_header(&self.headers, name)
+	}
+
+	/// The method, as an `http::Method`
+	pub fn http_method(&self) -> Result<Method, ParseError> {
+		Method::from_bytes(self.method.as_bytes()).map_err(|_| ParseError::BadMethod)
+	}
+
+	/// The target, as an `http::Uri`
+	pub fn uri(&self) -> Result<Uri, ParseError> {
+		self.target.parse().map_err(|_| ParseError::BadTarget)
+	}
+
+	/// The headers, as an `http::HeaderMap`
+	pub fn header_map(&self) -> Result<HeaderMap, ParseError> {
+		header_map(&self.headers)
+	}
+
+	/// How the request body is delimited. Requests without a
+	/// `Content-Length` or `Transfer-Encoding` have no body.
+	pub fn body_length(&self) -> Result<BodyLength, ParseError> {
+		let codings = transfer_codings(&self.headers);
+		if codings.is_empty() {
+			return Ok(BodyLength::Fixed(content_length(&self.headers)?.unwrap_or(0)));
+		}
+		if self.header("content-length").is_some() {
+			return Err(ParseError::ConflictingLength);
+		}
+		match codings.iter().rposition(|c| c == "chunked") {
+			Some(i) if i + 1 == codings.len() => {}
+			_ => return Err(ParseError::BadTransferEncoding),
+		}
+		if codings.iter().filter(|c| *c == "chunked").count() > 1 {
+			return Err(ParseError::BadTransferEncoding);
+		}
+		if codings.len() > 1 {
+			return Err(ParseError::UnknownEncoding);
+		}
+		Ok(BodyLength::Chunked)
+	}
+}
+
+impl<'a> StatusHead<'a> {
+	/// The value of the first header with this name, if there is one
+	pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+		find_header(&self.headers, name)
+	}
+
+	/// The headers, as an `http::HeaderMap`
+	pub fn header_map(&self) -> Result<HeaderMap, ParseError> {
+		header_map(&self.headers)
+	}
+
+	/// How the response body is delimited. This depends on whether it
+	/// answers a HEAD request, which never has a body.
+	pub fn body_length(&self, head_request: bool) -> Result<BodyLength, ParseError> {
+		let code = self.status.as_u16();
+		if head_request || code < 200 || code == 204 || code == 304 {
+			return Ok(BodyLength::Fixed(0));
+		}
+		let codings = transfer_codings(&self.headers);
+		if !codings.is_empty() {
+			// RFC 7230 would have the Transfer-Encoding win, but whoever
+			// passed the response on may not have read it that way
+			if self.header("content-length").is_some() {
+				return Err(ParseError::ConflictingLength);
+			}
+			return Ok(if codings.last().map_or(false, |c| c == "chunked") {
+				BodyLength::Chunked
+			} else {
+				BodyLength::UntilClose
+			});
+		}
+		Ok(match content_length(&self.headers)? {
+			Some(length) => BodyLength::Fixed(length),
+			None => BodyLength::UntilClose,
+		})
+	}
+}
+
+impl ParseError {
+	/// The status a server should refuse a request with, if it failed to
+	/// parse with this error
+	pub fn status(&self) -> StatusCode {
+		match *self {
+			ParseError::StartLineTooLong | ParseError::TargetTooLong => StatusCode::URI_TOO_LONG,
+			ParseError::HeadTooLarge | ParseError::TooManyHeaders => {
+				StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+			}
+			ParseError::BadVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+			ParseError::UnknownEncoding => StatusCode::NOT_IMPLEMENTED,
+			_ => StatusCode::BAD_REQUEST,
+		}
+	}
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match *self {
+			ParseError::BadRequestLine => "malformed request line",
+			ParseError::BadStatusLine => "malformed status line",
+			ParseError::BadMethod => "bad method",
+			ParseError::BadTarget => "bad request target",
+			ParseError::BadVersion => "unsupported HTTP version",
+			ParseError::BadStatus => "bad status code",
+			ParseError::BadHeaderName => "bad header name",
+			ParseError::BadHeaderValue => "bad header value",
+			ParseError::FoldedHeader => "folded header line",
+			ParseError::BadLineEnding => "bad line ending",
+			ParseError::StartLineTooLong => "start line too long",
+			ParseError::TargetTooLong => "request target too long",
+			ParseError::HeadTooLarge => "head too large",
+			ParseError::TooManyHeaders => "too many headers",
+			ParseError::BadContentLength => "bad Content-Length",
+			ParseError::ConflictingLength => "both Content-Length and Transfer-Encoding",
+			ParseError::BadTransferEncoding => "bad Transfer-Encoding",
+			ParseError::UnknownEncoding => "unknown transfer coding",
+			ParseError::BadChunk => "bad chunk",
+		};
+		f.write_str(s)
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl Scanner {
+	fn new(limits: ParseLimits, skip_empty: bool) -> Scanner {
+		Scanner {
+			limits,
+			skip_empty,
+			checked: 0,
+			started: false,
+			headers: Vec::new(),
+		}
+	}
+
+	/// Check the lines we haven't checked yet, passing the start line (and
+	/// where it is) to `start_line`. Returns the length of the head if it
+	/// is complete.
+	fn scan(
+		&mut self,
+		buffer: &[u8],
+		start_line: &mut FnMut(usize, &[u8]) -> Result<(), ParseError>,
+	) -> Result<Option<usize>, ParseError> {
+		let limit = buffer.len().min(self.limits.max_head_bytes);
+		loop {
+			let at = self.checked;
+			let newline = match buffer[at.min(limit)..limit].iter().position(|&b| b == b'\n') {
+				Some(i) => at + i,
+				None if buffer.len() > self.limits.max_head_bytes => {
+					return Err(if self.started {
+						ParseError::HeadTooLarge
+					} else {
+						ParseError::StartLineTooLong
+					});
+				}
+				None => return Ok(None),
+			};
+			let mut line = &buffer[at..newline];
+			if line.last() == Some(&b'\r') {
+				line = &line[..line.len() - 1];
+			}
+			if line.contains(&b'\r') {
+				return Err(ParseError::BadLineEnding);
+			}
+			if !self.started {
+				if !(line.is_empty() && self.skip_empty) {
+					start_line(at, line)?;
+					self.started = true;
+				}
+			} else if line.is_empty() {
+				self.checked = newline + 1;
+				return Ok(Some(self.checked));
+			} else {
+				self.headers.push(self.header_line(at, line)?);
+			}
+			self.checked = newline + 1;
+		}
+	}
+
+	/// Check a header line which starts at `at`.
+	fn header_line(&self, at: usize, line: &[u8]) -> Result<(Span, Span), ParseError> {
+		if line[0] == b' ' || line[0] == b'\t' {
+			return Err(ParseError::FoldedHeader);
+		}
+		if self.headers.len() == self.limits.max_headers {
+			return Err(ParseError::TooManyHeaders);
+		}
+		let colon = line.iter()
+			.position(|&b| b == b':')
+			.ok_or(ParseError::BadHeaderName)?;
+		if colon == 0 || !line[..colon].iter().all(|&b| is_tchar(b)) {
+			return Err(ParseError::BadHeaderName);
+		}
+		let mut start = colon + 1;
+		let mut end = line.len();
+		while start < end && is_space(line[start]) {
+			start += 1;
+		}
+		while end > start && is_space(line[end - 1]) {
+			end -= 1;
+		}
+		if line[start..end].iter().any(|&b| is_ctl(b) && b != b'\t') {
+			return Err(ParseError::BadHeaderValue);
+		}
+		Ok(((at, at + colon), (at + start, at + end)))
+	}
+
+	/// The headers we've found, in `buffer`
+	fn headers<'a>(&self, buffer: &'a [u8]) -> Vec<Header<'a>> {
+		self.headers
+			.iter()
+			.map(|&(name, value)| Header {
+				name: ascii(buffer, name),
+				value: &buffer[value.0..value.1],
+			})
+			.collect()
+	}
+}
+
+/// Check a request line which starts at `at`, and find its parts.
+fn request_line(
+	at: usize,
+	line: &[u8],
+	max_target_len: usize,
+) -> Result<(Span, Span, Version), ParseError> {
+	let parts: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+	if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+		return Err(ParseError::BadRequestLine);
+	}
+	let (method, target) = (parts[0], parts[1]);
+	if !method.iter().all(|&b| is_tchar(b)) {
+		return Err(ParseError::BadMethod);
+	}
+	if target.len() > max_target_len {
+		return Err(ParseError::TargetTooLong);
+	}
+	if !target.iter().all(|&b| b > b' ' && b < 0x7F) {
+		return Err(ParseError::BadTarget);
+	}
+	let form_ok = match (method, target) {
+		(b"OPTIONS", b"*") => true,
+		(_, b"*") => false,
+		// authority-form, e.g. "example.com:443"
+		(b"CONNECT", _) => !target.starts_with(b"/"),
+		(_, _) if target.starts_with(b"/") => true,
+		// absolute-form, e.g. "http://example.com/"
+		_ => target
+			.windows(3)
+			.position(|w| w == b"://")
+			.map_or(false, |i| i > 0),
+	};
+	if !form_ok {
+		return Err(ParseError::BadTarget);
+	}
+	let version = version(parts[2]).ok_or(ParseError::BadRequestLine)??;
+	let method_end = at + method.len();
+	let target_start = method_end + 1;
+	Ok((
+		(at, method_end),
+		(target_start, target_start + target.len()),
+		version,
+	))
+}
+
+/// Check a status line which starts at `at`, and find its parts.
+fn status_line(at: usize, line: &[u8]) -> Result<(Version, u16, Span), ParseError> {
+	let mut parts = line.splitn(3, |&b| b == b' ');
+	let version = parts
+		.next()
+		.and_then(version)
+		.ok_or(ParseError::BadStatusLine)??;
+	let code = parts.next().ok_or(ParseError::BadStatusLine)?;
+	if code.len() != 3 || !code.iter().all(|b| b.is_ascii_digit()) || code[0] == b'0' {
+		return Err(ParseError::BadStatus);
+	}
+	let status = code.iter().fold(0, |n, &b| n * 10 + u16::from(b - b'0'));
+	// Some servers leave out the space before an empty reason
+	let reason = parts.next().unwrap_or(b"");
+	if reason.iter().any(|&b| is_ctl(b) && b != b'\t') {
+		return Err(ParseError::BadStatusLine);
+	}
+	let reason_start = (at + "HTTP/1.1 200 ".len()).min(at + line.len());
+	Ok((
+		version,
+		status,
+		(reason_start, reason_start + reason.len()),
+	))
+}
+
+/// Parse an HTTP-version. None if it isn't one at all, Some(Err) if it's
+/// one we don't speak.
+fn version(s: &[u8]) -> Option<Result<Version, ParseError>> {
+	match s {
+		b"HTTP/1.1" => Some(Ok(Version::HTTP_11)),
+		b"HTTP/1.0" => Some(Ok(Version::HTTP_10)),
+		_ if s.len() == 8
+			&& s.starts_with(b"HTTP/")
+			&& s[5].is_ascii_digit()
+			&& s[6] == b'.'
+			&& s[7].is_ascii_digit() =>
+		{
+			Some(Err(ParseError::BadVersion))
+		}
+		_ => None,
+	}
+}
+
+fn find_header<'a>(headers: &[Header<'a>], name: &str) -> Option<&'a [u8]> {
+	headers
+		.iter()
+		.filter(|h| h.name.eq_ignore_ascii_case(name))
+		.map(|h| h.value)
+		.next()
+}
+
+fn header_map(headers: &[Header]) -> Result<HeaderMap, ParseError> {
+	let mut map = HeaderMap::with_capacity(headers.len());
+	for h in headers {
+		let name =
+			HeaderName::from_bytes(h.name.as_bytes()).map_err(|_| ParseError::BadHeaderName)?;
+		let value = HeaderValue::from_bytes(h.value).map_err(|_| ParseError::BadHeaderValue)?;
+		map.append(name, value);
+	}
+	Ok(map)
+}
+
+/// All the `Transfer-Encoding` codings, in order and in lower case
+fn transfer_codings(headers: &[Header]) -> Vec<String> {
+	headers
+		.iter()
+		.filter(|h| h.name.eq_ignore_ascii_case("transfer-encoding"))
+		.flat_map(|h| h.value.split(|&b| b == b','))
+		.map(|c| String::from_utf8_lossy(c).trim().to_lowercase())
+		.filter(|c| !c.is_empty())
+		.collect()
+}
+
+/// The `Content-Length`, if there is one. Repeats are fine as long as they
+/// agree.
+fn content_length(headers: &[Header]) -> Result<Option<u64>, ParseError> {
+	let mut length = None;
+	let values = headers
+		.iter()
+		.filter(|h| h.name.eq_ignore_ascii_case("content-length"))
+		.flat_map(|h| h.value.split(|&b| b == b','));
+	for value in values {
+		let value = str::from_utf8(value)
+			.map_err(|_| ParseError::BadContentLength)?
+			.trim();
+		if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+			return Err(ParseError::BadContentLength);
+		}
+		let value = value
+			.parse::<u64>()
+			.map_err(|_| ParseError::BadContentLength)?;
+		if length.map_or(false, |l| l != value) {
+			return Err(ParseError::BadContentLength);
+		}
+		length = Some(value);
+	}
+	Ok(length)
+}
+
+/// Something in the buffer we've already checked is ASCII
+fn ascii(buffer: &[u8], span: Span) -> &str {
+	str::from_utf8(&buffer[span.0..span.1]).unwrap_or("")
+}
+
+/// Can this be in a token (RFC 7230 section 3.2.6)?
+fn is_tchar(b: u8) -> bool {
+	match b {
+		b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_'
+		| b'`' | b'|' | b'~' => true,
+		_ => b.is_ascii_alphanumeric(),
+	}
+}
+
+fn is_space(b: u8) -> bool {
+	b == b' ' || b == b'\t'
+}
+
+fn is_ctl(b: u8) -> bool {
+	b < b' ' || b == 0x7F
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Our cases, one per paragraph. Lines starting with `#` are comments.
+	/// Each case is a line saying what should happen, then the input with
+	/// `\r`, `\n`, `\t`, `\\` and `\xNN` escaped. Unescaped line breaks
+	/// are ignored, so long inputs can be split.
+	const REQUESTS: &str = include_str!("corpus/requests.txt");
+	const RESPONSES: &str = include_str!("corpus/responses.txt");
+
+	struct Case {
+		name: String,
+		expect: Vec<String>,
+		input: Vec<u8>,
+	}
+
+	fn unescape(s: &str) -> Vec<u8> {
+		let mut out = Vec::new();
+		let mut bytes = s.bytes();
+		while let Some(b) = bytes.next() {
+			if b != b'\\' {
+				out.push(b);
+				continue;
+			}
+			match bytes.next() {
+				Some(b'r') => out.push(b'\r'),
+				Some(b'n') => out.push(b'\n'),
+				Some(b't') => out.push(b'\t'),
+				Some(b'\\') => out.push(b'\\'),
+				Some(b'x') => {
+					let hex: String = bytes.by_ref().take(2).map(|b| b as char).collect();
+					out.push(u8::from_str_radix(&hex, 16).unwrap());
+				}
+				x => panic!("Bad escape {:?}", x),
+			}
+		}
+		out
+	}
+
+	fn corpus(text: &str) -> Vec<Case> {
+		let mut cases = Vec::new();
+		for para in text.split("\n\n") {
+			let mut name = String::new();
+			let mut lines = para
+				.lines()
+				.filter(|l| {
+					if l.starts_with('#') {
+						name.push_str(l[1..].trim());
+						false
+					} else {
+						!l.trim().is_empty()
+					}
+				})
+				.collect::<Vec<_>>()
+				.into_iter();
+			let expect = match lines.next() {
+				Some(line) => line.split_whitespace().map(String::from).collect(),
+				None => continue,
+			};
+			let input = unescape(&lines.collect::<String>());
+			cases.push(Case {
+				name,
+				expect,
+				input,
+			});
+		}
+		cases
+	}
+
+	fn limits() -> ParseLimits {
+		ParseLimits {
+			max_head_bytes: 256,
+			max_headers: 8,
+			max_target_len: 64,
+		}
+	}
+
+	/// Describe how parsing went, in the corpus's terms
+	fn describe<T, F>(result: Result<Parsed<T>, ParseError>, describe_head: F) -> Vec<String>
+	where
+		F: Fn(&T) -> Vec<String>,
+	{
+		match result {
+			Ok(Parsed::Partial) => vec![String::from("partial")],
+			Ok(Parsed::Complete { head, rest }) => {
+				let mut out = vec![String::from("ok")];
+				out.extend(describe_head(&head));
+				out.push(format!("rest={}", rest.len()));
+				out
+			}
+			Err(e) => vec![String::from("error"), format!("{:?}", e)],
+		}
+	}
+
+	fn describe_request(head: &RequestHead) -> Vec<String> {
+		vec![
+			head.method.to_string(),
+			head.target.to_string(),
+			format!("{:?}", head.version),
+			format!("headers={}", head.headers.len()),
+			match head.body_length() {
+				Ok(length) => format!("body={:?}", length),
+				Err(e) => format!("body={:?}", e),
+			},
+		]
+	}
+
+	fn describe_response(head: &StatusHead) -> Vec<String> {
+		vec![
+			format!("{:?}", head.version),
+			head.status.as_str().to_string(),
+			format!("headers={}", head.headers.len()),
+			match head.body_length(false) {
+				Ok(length) => format!("body={:?}", length),
+				Err(e) => format!("body={:?}", e),
+			},
+		]
+	}
+
+	/// Feed the parser all at once, then a byte at a time, and check we get
+	/// the same answer both ways.
+	fn run<P, F>(text: &str, new: F, parse: &Fn(&mut P, &[u8]) -> Vec<String>)
+	where
+		F: Fn() -> P,
+	{
+		let cases = corpus(text);
+		assert!(cases.len() > 20);
+		for case in cases {
+			let whole = parse(&mut new(), &case.input);
+			assert_eq!(whole, case.expect, "{}", case.name);
+			let mut parser = new();
+			let mut result = vec![String::from("partial")];
+			for i in 1..case.input.len() + 1 {
+				result = parse(&mut parser, &case.input[..i]);
+				if result[0] != "partial" {
+					break;
+				}
+			}
+			// Byte by byte, a complete head has nothing after it
+			if result[0] == "ok" {
+				result.pop();
+				result.push(String::from("rest=0"));
+				let mut expect = case.expect.clone();
+				expect.pop();
+				expect.push(String::from("rest=0"));
+				assert_eq!(result, expect, "{} (byte by byte)", case.name);
+			} else {
+				assert_eq!(result, case.expect, "{} (byte by byte)", case.name);
+			}
+		}
+	}
+
+	#[test]
+	fn request_corpus() {
+		run(REQUESTS, || RequestParser::new(limits()), &|p: &mut RequestParser, input| {
+			describe(p.parse(input), describe_request)
+		});
+	}
+
+	#[test]
+	fn response_corpus() {
+		run(RESPONSES, || ResponseParser::new(limits()), &|p: &mut ResponseParser, input| {
+			describe(p.parse(input), describe_response)
+		});
+	}
+
+	#[test]
+	fn request_head() {
+		let mut parser = RequestParser::new(ParseLimits::default());
+		let input = b"POST /form?a=1 HTTP/1.1\r\nHost:  example.com \r\n\
+			content-length: 3\r\nX-Empty:\r\n\r\nabcGET";
+		assert_eq!(parser.parse(&input[..30]), Ok(Parsed::Partial));
+		let (head, rest) = match parser.parse(input) {
+			Ok(Parsed::Complete { head, rest }) => (head, rest),
+			x => panic!("Unexpected {:?}", x),
+		};
+		assert_eq!(rest, b"abcGET");
+		assert_eq!(head.method, "POST");
+		assert_eq!(head.http_method(), Ok(Method::POST));
+		assert_eq!(head.target, "/form?a=1");
+		assert_eq!(head.uri().unwrap().query(), Some("a=1"));
+		assert_eq!(head.version, Version::HTTP_11);
+		assert_eq!(
+			head.headers,
+			vec![
+				Header {
+					name: "Host",
+					value: b"example.com",
+				},
+				Header {
+					name: "content-length",
+					value: b"3",
+				},
+				Header {
+					name: "X-Empty",
+					value: b"",
+				},
+			]
+		);
+		assert_eq!(head.header("HOST"), Some(&b"example.com"[..]));
+		assert_eq!(head.body_length(), Ok(BodyLength::Fixed(3)));
+		let map = head.header_map().unwrap();
+		assert_eq!(map["host"], "example.com");
+		assert_eq!(map.len(), 3);
+
+		// And again, for the next one
+		parser.reset();
+		match parser.parse(b"GET / HTTP/1.0\n\n") {
+			Ok(Parsed::Complete { head, rest }) => {
+				assert_eq!(head.version, Version::HTTP_10);
+				assert!(head.headers.is_empty());
+				assert!(rest.is_empty());
+			}
+			x => panic!("Unexpected {:?}", x),
+		}
+	}
+
+	#[test]
+	fn status_head() {
+		let mut parser = ResponseParser::new(ParseLimits::default());
+		let input = b"HTTP/1.0 404 Not Found\r\nA: 1\r\nB:  2 \r\n\r\nbody";
+		match parser.parse(input) {
+			Ok(Parsed::Complete { head, rest }) => {
+				assert_eq!(head.version, Version::HTTP_10);
+				assert_eq!(head.status, StatusCode::NOT_FOUND);
+				assert_eq!(head.reason, b"Not Found");
+				assert_eq!(head.header("b"), Some(&b"2"[..]));
+				assert_eq!(head.body_length(false), Ok(BodyLength::UntilClose));
+				assert_eq!(head.body_length(true), Ok(BodyLength::Fixed(0)));
+				assert_eq!(rest, b"body");
+			}
+			x => panic!("Unexpected {:?}", x),
+		}
+		parser.reset();
+		match parser.parse(b"HTTP/1.1 200\r\n\r\n") {
+			Ok(Parsed::Complete { head, .. }) => assert_eq!(head.reason, b""),
+			x => panic!("Unexpected {:?}", x),
+		}
+	}
+
+	#[test]
+	fn error_statuses() {
+		assert_eq!(ParseError::BadHeaderName.status(), StatusCode::BAD_REQUEST);
+		assert_eq!(ParseError::StartLineTooLong.status(), StatusCode::URI_TOO_LONG);
+		assert_eq!(ParseError::TargetTooLong.status(), StatusCode::URI_TOO_LONG);
+		assert_eq!(
+			ParseError::TooManyHeaders.status(),
+			StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+		);
+		assert_eq!(
+			ParseError::BadVersion.status(),
+			StatusCode::HTTP_VERSION_NOT_SUPPORTED
+		);
+		assert_eq!(ParseError::UnknownEncoding.status(), StatusCode::NOT_IMPLEMENTED);
+		assert_eq!(ParseError::FoldedHeader.to_string(), "folded header line");
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,272 @@
+//! # status - the response statuses users can send
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! `HttpResponseStatus` names the statuses of RFC 2616 (and 422, which
+//! request bodies which don't make sense get), and displays as the code and
+//! reason phrase for the status line (e.g. "404 Not Found"). A
+//! `StatusCode` from elsewhere maps onto one with `from_code`, or onto the
+//! generic status of its class with `from_code_or_class`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+
+use super::StatusCode;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A response status
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HttpResponseStatus {
+	Continue,
+	SwitchingProtocols,
+	OK,
+	Created,
+	Accepted,
+	NonAuthoritativeInformation,
+	NoContent,
+	ResetContent,
+	PartialContent,
+	MultipleChoices,
+	MovedPermanently,
+	Found,
+	SeeOther,
+	NotModified,
+	UseProxy,
+	TemporaryRedirect,
+	BadRequest,
+	Unauthorized,
+	PaymentRequired,
+	Forbidden,
+	NotFound,
+	MethodNotAllowed,
+	NotAcceptable,
+	ProxyAuthenticationRequired,
+	RequestTimeout,
+	Conflict,
+	Gone,
+	LengthRequired,
+	PreconditionFailed,
+	RequestEntityTooLarge,
+	RequestURITooLong,
+	UnsupportedMediaType,
+	RequestedRangeNotSatisfiable,
+	ExpectationFailed,
+	UnprocessableEntity,
+	InternalServerError,
+	NotImplemented,
+	BadGateway,
+	ServiceUnavailable,
+	GatewayTimeout,
+	HTTPVersionNotSupported,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+const ALL: [HttpResponseStatus; 41] = [
+	HttpResponseStatus::Continue,
+	HttpResponseStatus::SwitchingProtocols,
+	HttpResponseStatus::OK,
+	HttpResponseStatus::Created,
+	HttpResponseStatus::Accepted,
+	HttpResponseStatus::NonAuthoritativeInformation,
+	HttpResponseStatus::NoContent,
+	HttpResponseStatus::ResetContent,
+	HttpResponseStatus::PartialContent,
+	HttpResponseStatus::MultipleChoices,
+	HttpResponseStatus::MovedPermanently,
+	HttpResponseStatus::Found,
+	HttpResponseStatus::SeeOther,
+	HttpResponseStatus::NotModified,
+	HttpResponseStatus::UseProxy,
+	HttpResponseStatus::TemporaryRedirect,
+	HttpResponseStatus::BadRequest,
+	HttpResponseStatus::Unauthorized,
+	HttpResponseStatus::PaymentRequired,
+	HttpResponseStatus::Forbidden,
+	HttpResponseStatus::NotFound,
+	HttpResponseStatus::MethodNotAllowed,
+	HttpResponseStatus::NotAcceptable,
+	HttpResponseStatus::ProxyAuthenticationRequired,
+	HttpResponseStatus::RequestTimeout,
+	HttpResponseStatus::Conflict,
+	HttpResponseStatus::Gone,
+	HttpResponseStatus::LengthRequired,
+	HttpResponseStatus::PreconditionFailed,
+	HttpResponseStatus::RequestEntityTooLarge,
+	HttpResponseStatus::RequestURITooLong,
+	HttpResponseStatus::UnsupportedMediaType,
+	HttpResponseStatus::RequestedRangeNotSatisfiable,
+	HttpResponseStatus::ExpectationFailed,
+	HttpResponseStatus::UnprocessableEntity,
+	HttpResponseStatus::InternalServerError,
+	HttpResponseStatus::NotImplemented,
+	HttpResponseStatus::BadGateway,
+	HttpResponseStatus::ServiceUnavailable,
+	HttpResponseStatus::GatewayTimeout,
+	HttpResponseStatus::HTTPVersionNotSupported,
+];
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl HttpResponseStatus {
+	/// The status with this code, if there is one
+	pub fn from_code(code: StatusCode) -> Option<HttpResponseStatus> {
+		ALL.iter().find(|s| s.parts().0 == code.as_u16()).cloned()
+	}
+
+	/// The status to send for this code: the one with the same code, or
+	/// else the generic status of its class (e.g. `400 Bad Request` for a
+	/// `421`). Informational codes can't end a response, so they become
+	/// `500 Internal Server Error`.
+	pub fn from_code_or_class(code: StatusCode) -> HttpResponseStatus {
+		if code.is_informational() {
+			return HttpResponseStatus::InternalServerError;
+		}
+		HttpResponseStatus::from_code(code).unwrap_or_else(|| match code.as_u16() / 100 {
+			2 => HttpResponseStatus::OK,
+			3 => HttpResponseStatus::MultipleChoices,
+			4 => HttpResponseStatus::BadRequest,
+			_ => HttpResponseStatus::InternalServerError,
+		})
+	}
+
+	/// The numeric status code
+	pub fn code(&self) -> StatusCode {
+		StatusCode::from_u16(self.parts().0).unwrap()
+	}
+}
+
+impl fmt::Display for HttpResponseStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (code, reason) = self.parts();
+		write!(f, "{} {}", code, reason)
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl HttpResponseStatus {
+	fn parts(&self) -> (u16, &'static str) {
+		match *self {
+			HttpResponseStatus::Continue => (100, "Continue"),
+			HttpResponseStatus::SwitchingProtocols => (101, "Switching Protocols"),
+			HttpResponseStatus::OK => (200, "OK"),
+			HttpResponseStatus::Created => (201, "Created"),
+			HttpResponseStatus::Accepted => (202, "Accepted"),
+			HttpResponseStatus::NonAuthoritativeInformation => {
+				(203, "Non-Authoritative Information")
+			}
+			HttpResponseStatus::NoContent => (204, "No Content"),
+			HttpResponseStatus::ResetContent => (205, "Reset Content"),
+			HttpResponseStatus::PartialContent => (206, "Partial Content"),
+			HttpResponseStatus::MultipleChoices => (300, "Multiple Choices"),
+			HttpResponseStatus::MovedPermanently => (301, "Moved Permanently"),
+			HttpResponseStatus::Found => (302, "Found"),
+			HttpResponseStatus::SeeOther => (303, "See Other"),
+			HttpResponseStatus::NotModified => (304, "Not Modified"),
+			HttpResponseStatus::UseProxy => (305, "Use Proxy"),
+			HttpResponseStatus::TemporaryRedirect => (307, "Temporary Redirect"),
+			HttpResponseStatus::BadRequest => (400, "Bad Request"),
+			HttpResponseStatus::Unauthorized => (401, "Unauthorized"),
+			HttpResponseStatus::PaymentRequired => (402, "Payment Required"),
+			HttpResponseStatus::Forbidden => (403, "Forbidden"),
+			HttpResponseStatus::NotFound => (404, "Not Found"),
+			HttpResponseStatus::MethodNotAllowed => (405, "Method Not Allowed"),
+			HttpResponseStatus::NotAcceptable => (406, "Not Acceptable"),
+			HttpResponseStatus::ProxyAuthenticationRequired => {
+				(407, "Proxy Authentication Required")
+			}
+			HttpResponseStatus::RequestTimeout => (408, "Request Timeout"),
+			HttpResponseStatus::Conflict => (409, "Conflict"),
+			HttpResponseStatus::Gone => (410, "Gone"),
+			HttpResponseStatus::LengthRequired => (411, "Length Required"),
+			HttpResponseStatus::PreconditionFailed => (412, "Precondition Failed"),
+			HttpResponseStatus::RequestEntityTooLarge => (413, "Request Entity Too Large"),
+			HttpResponseStatus::RequestURITooLong => (414, "Request-URI Too Long"),
+			HttpResponseStatus::UnsupportedMediaType => (415, "Unsupported Media Type"),
+			HttpResponseStatus::RequestedRangeNotSatisfiable => {
+				(416, "Requested Range Not Satisfiable")
+			}
+			HttpResponseStatus::ExpectationFailed => (417, "Expectation Failed"),
+			HttpResponseStatus::UnprocessableEntity => (422, "Unprocessable Entity"),
+			HttpResponseStatus::InternalServerError => (500, "Internal Server Error"),
+			HttpResponseStatus::NotImplemented => (501, "Not Implemented"),
+			HttpResponseStatus::BadGateway => (502, "Bad Gateway"),
+			HttpResponseStatus::ServiceUnavailable => (503, "Service Unavailable"),
+			HttpResponseStatus::GatewayTimeout => (504, "Gateway Timeout"),
+			HttpResponseStatus::HTTPVersionNotSupported => (505, "HTTP Version Not Supported"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn status_lines() {
+		assert_eq!(HttpResponseStatus::OK.to_string(), "200 OK");
+		assert_eq!(
+			HttpResponseStatus::RequestURITooLong.to_string(),
+			"414 Request-URI Too Long"
+		);
+		assert_eq!(
+			HttpResponseStatus::UnprocessableEntity.to_string(),
+			"422 Unprocessable Entity"
+		);
+		for status in ALL.iter() {
+			assert_eq!(HttpResponseStatus::from_code(status.code()), Some(*status));
+		}
+		assert_eq!(HttpResponseStatus::from_code(StatusCode::PERMANENT_REDIRECT), None);
+	}
+
+	#[test]
+	fn class_fallback() {
+		let status = |code: u16| {
+			HttpResponseStatus::from_code_or_class(StatusCode::from_u16(code).unwrap())
+		};
+		assert_eq!(status(404), HttpResponseStatus::NotFound);
+		assert_eq!(status(226), HttpResponseStatus::OK);
+		assert_eq!(status(308), HttpResponseStatus::MultipleChoices);
+		assert_eq!(status(421), HttpResponseStatus::BadRequest);
+		assert_eq!(status(511), HttpResponseStatus::InternalServerError);
+		assert_eq!(status(101), HttpResponseStatus::InternalServerError);
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,269 @@
+//! # uri - decoding request paths and query strings
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! A request target arrives percent-encoded. `decode_path` turns its path
+//! into the one you'd want to look up - decoded, with empty and `.`
+//! segments dropped - and refuses anything which could be used to climb out
+//! of a directory: `..` segments (however they're encoded), and encoded
+//! slashes, backslashes or NULs. `decode_query` splits the query string
+//! into its (decoded) name/value pairs. Both insist on valid escapes and
+//! UTF-8.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The decoded name/value pairs from a query string, in the order they
+/// were given. A name may appear any number of times.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryParams {
+	pairs: Vec<(String, String)>,
+}
+
+/// Why a path or query string couldn't be decoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UriError {
+	/// A `%` which isn't followed by two hex digits
+	BadEscape,
+	/// The decoded bytes aren't UTF-8
+	NotUtf8,
+	/// The path doesn't start with a `/`
+	NotAbsolute,
+	/// The path has a `..` segment
+	DotDotSegment,
+	/// A path segment decodes to something with a `/`, `\` or NUL in it
+	BadSegment,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Decode and normalise a request path. The result always starts with a
+/// `/`, and ends with one if `path` did.
+pub fn decode_path(path: &str) -> Result<String, UriError> {
+	if !path.starts_with('/') {
+		return Err(UriError::NotAbsolute);
+	}
+	let mut decoded = String::with_capacity(path.len());
+	for segment in path.split('/').filter(|s| !s.is_empty()) {
+		let segment = decode(segment, false)?;
+		match &segment[..] {
+			"." => continue,
+			".." => return Err(UriError::DotDotSegment),
+			_ if segment.contains(&['/', '\\', '\0'][..]) => return Err(UriError::BadSegment),
+			_ => {}
+		}
+		decoded.push('/');
+		decoded.push_str(&segment);
+	}
+	if decoded.is_empty() || path.ends_with('/') {
+		decoded.push('/');
+	}
+	Ok(decoded)
+}
+
+/// Split up and decode a query string (without the `?`). `+` is taken as a
+/// space, and a name with no `=` has an empty value.
+pub fn decode_query(query: &str) -> Result<QueryParams, UriError> {
+	let mut pairs = Vec::new();
+	for pair in query.split('&').filter(|p| !p.is_empty()) {
+		let mut parts = pair.splitn(2, '=');
+		let name = decode(parts.next().unwrap_or(""), true)?;
+		let value = decode(parts.next().unwrap_or(""), true)?;
+		pairs.push((name, value));
+	}
+	Ok(QueryParams { pairs })
+}
+
+/// Undo percent-encoding, and optionally `+` for space. None if there's a
+/// bad escape.
+pub fn percent_decode(data: &[u8], plus_is_space: bool) -> Option<Vec<u8>> {
+	let mut decoded = Vec::with_capacity(data.len());
+	let mut bytes = data.iter();
+	while let Some(&b) = bytes.next() {
+		decoded.push(match b {
+			b'%' => {
+				let hi = (*bytes.next()? as char).to_digit(16)?;
+				let lo = (*bytes.next()? as char).to_digit(16)?;
+				(hi * 16 + lo) as u8
+			}
+			b'+' if plus_is_space => b' ',
+			_ => b,
+		});
+	}
+	Some(decoded)
+}
+
+impl QueryParams {
+	/// The first value given for `name`
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.pairs
+			.iter()
+			.find(|pair| pair.0 == name)
+			.map(|pair| &pair.1[..])
+	}
+
+	/// Every value given for `name`
+	pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+		self.pairs
+			.iter()
+			.filter(move |pair| pair.0 == name)
+			.map(|pair| &pair.1[..])
+	}
+
+	/// Was `name` given at all?
+	pub fn contains(&self, name: &str) -> bool {
+		self.get(name).is_some()
+	}
+
+	/// All the pairs, in order
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.pairs.iter().map(|pair| (&pair.0[..], &pair.1[..]))
+	}
+
+	pub fn len(&self) -> usize {
+		self.pairs.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.pairs.is_empty()
+	}
+}
+
+impl fmt::Display for UriError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match *self {
+			UriError::BadEscape => "bad percent escape",
+			UriError::NotUtf8 => "not UTF-8",
+			UriError::NotAbsolute => "path is not absolute",
+			UriError::DotDotSegment => "'..' in path",
+			UriError::BadSegment => "bad path segment",
+		};
+		f.write_str(s)
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn decode(s: &str, plus_is_space: bool) -> Result<String, UriError> {
+	let decoded = percent_decode(s.as_bytes(), plus_is_space).ok_or(UriError::BadEscape)?;
+	String::from_utf8(decoded).map_err(|_| UriError::NotUtf8)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn paths() {
+		let cases = [
+			("/", "/"),
+			("/index.html", "/index.html"),
+			("/a/b/", "/a/b/"),
+			("//a///b", "/a/b"),
+			("/a/./b/.", "/a/b"),
+			("/./", "/"),
+			("/%7euser/caf%C3%A9", "/~user/café"),
+			("/a%20b/c+d", "/a b/c+d"),
+			("/%2e%2e.txt", "/...txt"),
+			("/...", "/..."),
+			("/%25", "/%"),
+		];
+		for &(path, expected) in cases.iter() {
+			assert_eq!(decode_path(path), Ok(String::from(expected)), "{}", path);
+		}
+	}
+
+	#[test]
+	fn bad_paths() {
+		let cases = [
+			("", UriError::NotAbsolute),
+			("*", UriError::NotAbsolute),
+			("a/b", UriError::NotAbsolute),
+			("/a/../b", UriError::DotDotSegment),
+			("/..", UriError::DotDotSegment),
+			("/a/%2e%2E/b", UriError::DotDotSegment),
+			("/.%2e", UriError::DotDotSegment),
+			("/a%2fb", UriError::BadSegment),
+			("/a%5C..%5Cb", UriError::BadSegment),
+			("/a%00", UriError::BadSegment),
+			("/%", UriError::BadEscape),
+			("/%4", UriError::BadEscape),
+			("/%g0", UriError::BadEscape),
+			("/%C3", UriError::NotUtf8),
+			("/%ff", UriError::NotUtf8),
+		];
+		for &(path, expected) in cases.iter() {
+			assert_eq!(decode_path(path), Err(expected), "{}", path);
+		}
+	}
+
+	#[test]
+	fn queries() {
+		let query = decode_query("a=1&b=x+y%21&a=2&&flag&empty=&c=%3D=").unwrap();
+		assert_eq!(
+			query.iter().collect::<Vec<_>>(),
+			vec![
+				("a", "1"),
+				("b", "x y!"),
+				("a", "2"),
+				("flag", ""),
+				("empty", ""),
+				("c", "=="),
+			]
+		);
+		assert_eq!(query.len(), 6);
+		assert_eq!(query.get("a"), Some("1"));
+		assert_eq!(query.get_all("a").collect::<Vec<_>>(), vec!["1", "2"]);
+		assert_eq!(query.get("b"), Some("x y!"));
+		assert!(query.contains("flag"));
+		assert!(!query.contains("missing"));
+		assert_eq!(query.get("missing"), None);
+		assert_eq!(query.get_all("missing").count(), 0);
+		assert!(decode_query("").unwrap().is_empty());
+		assert_eq!(decode_query("a=%"), Err(UriError::BadEscape));
+		assert_eq!(decode_query("%zz=1"), Err(UriError::BadEscape));
+		assert_eq!(decode_query("a=%80"), Err(UriError::NotUtf8));
+	}
+
+	#[test]
+	fn percent() {
+		assert_eq!(percent_decode(b"a+b%2B", false), Some(b"a+b+".to_vec()));
+		assert_eq!(percent_decode(b"a+b%2B", true), Some(b"a b+".to_vec()));
+		assert_eq!(percent_decode(b"%Ff%00", false), Some(vec![0xff, 0]));
+		assert_eq!(percent_decode(b"%f", false), None);
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
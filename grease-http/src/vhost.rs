@@ -0,0 +1,193 @@
+//! # vhost - matching requests to virtual hosts
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! A server can have several virtual hosts, each registered with a pattern.
+//! We work out which host a request was for from the request URI (if it's
+//! in absolute form) or the `Host` header, then pick the most specific
+//! pattern which matches it.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use http;
+
+use super::{HeaderMap, Uri};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Something a virtual host can be registered for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+	/// One particular host name
+	Exact(String),
+	/// Any subdomain of a domain. Holds the domain with a leading dot.
+	Subdomain(String),
+	/// Anything at all
+	Any,
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl HostPattern {
+	/// Parse a pattern like "www.example.com", "*.example.com" or "*".
+	pub fn parse(pattern: &str) -> Option<HostPattern> {
+		if pattern.trim() == "*" {
+			return Some(HostPattern::Any);
+		}
+		let pattern = normalise(pattern);
+		let (wildcard, name) = if pattern.starts_with("*.") {
+			(true, &pattern[2..])
+		} else {
+			(false, &pattern[..])
+		};
+		let valid = !name.is_empty() && name
+			.split('.')
+			.all(|label| {
+				!label.is_empty() && label
+					.bytes()
+					.all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+			});
+		if !valid {
+			None
+		} else if wildcard {
+			Some(HostPattern::Subdomain(format!(".{}", name)))
+		} else {
+			Some(HostPattern::Exact(name.to_owned()))
+		}
+	}
+
+	/// How well this pattern matches the given (normalised) host. None if it
+	/// doesn't match at all, otherwise bigger is better.
+	pub fn score(&self, host: Option<&str>) -> Option<(u8, usize)> {
+		match (self, host) {
+			(&HostPattern::Exact(ref name), Some(host)) if name == host => {
+				Some((2, name.len()))
+			}
+			(&HostPattern::Subdomain(ref domain), Some(host)) if host.ends_with(&domain[..]) => {
+				Some((1, domain.len()))
+			}
+			(&HostPattern::Any, _) => Some((0, 0)),
+			_ => None,
+		}
+	}
+}
+
+/// Work out which host a request is for. Port numbers are removed and the
+/// name is lower-cased.
+pub fn request_host(url: &Uri, headers: &HeaderMap) -> Option<String> {
+	let host = match url.host() {
+		Some(host) => host.to_owned(),
+		None => {
+			let value = headers.get(http::header::HOST)?.to_str().ok()?;
+			if value.starts_with('[') {
+				// IPv6 literal
+				value.split(']').next().map(|h| format!("{}]", h))?
+			} else {
+				value.split(':').next()?.to_owned()
+			}
+		}
+	};
+	let host = normalise(&host);
+	if host.is_empty() {
+		None
+	} else {
+		Some(host)
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Host names are case insensitive and may have a trailing dot.
+fn normalise(host: &str) -> String {
+	host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn host(url: &str, host_header: Option<&str>) -> Option<String> {
+		let mut headers = HeaderMap::new();
+		if let Some(h) = host_header {
+			headers.insert(http::header::HOST, h.parse().unwrap());
+		}
+		request_host(&url.parse().unwrap(), &headers)
+	}
+
+	#[test]
+	fn patterns() {
+		assert_eq!(
+			HostPattern::parse("WWW.Example.com."),
+			Some(HostPattern::Exact(String::from("www.example.com")))
+		);
+		assert_eq!(
+			HostPattern::parse("*.example.com"),
+			Some(HostPattern::Subdomain(String::from(".example.com")))
+		);
+		assert_eq!(HostPattern::parse("*"), Some(HostPattern::Any));
+		assert_eq!(
+			HostPattern::parse("localhost"),
+			Some(HostPattern::Exact(String::from("localhost")))
+		);
+		for bad in &["", "*.", "www.*.com", "a..b", "example.com:80", "ex ample.com", "**"] {
+			assert_eq!(HostPattern::parse(bad), None, "{}", bad);
+		}
+	}
+
+	#[test]
+	fn hosts() {
+		assert_eq!(host("/", Some("Example.COM")), Some(String::from("example.com")));
+		assert_eq!(host("/", Some("example.com:8080")), Some(String::from("example.com")));
+		assert_eq!(host("/", Some("[::1]:8080")), Some(String::from("[::1]")));
+		assert_eq!(host("/", None), None);
+		assert_eq!(host("/", Some("")), None);
+		// The absolute form wins over the header
+		assert_eq!(
+			host("http://other.org:81/x", Some("example.com")),
+			Some(String::from("other.org"))
+		);
+	}
+
+	#[test]
+	fn scoring() {
+		let exact = HostPattern::parse("www.example.com").unwrap();
+		let sub = HostPattern::parse("*.example.com").unwrap();
+		let subsub = HostPattern::parse("*.www.example.com").unwrap();
+		let any = HostPattern::parse("*").unwrap();
+		let best = |host: &str| {
+			let mut patterns = vec![&exact, &sub, &subsub, &any];
+			patterns.retain(|p| p.score(Some(host)).is_some());
+			patterns.into_iter().max_by_key(|p| p.score(Some(host))).cloned()
+		};
+		assert_eq!(best("www.example.com"), Some(exact.clone()));
+		assert_eq!(best("a.www.example.com"), Some(subsub.clone()));
+		assert_eq!(best("mail.example.com"), Some(sub.clone()));
+		assert_eq!(best("example.com"), Some(any.clone()));
+		assert_eq!(best("notexample.com"), Some(any.clone()));
+		assert_eq!(exact.score(None), None);
+		assert_eq!(any.score(None), Some((0, 0)));
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
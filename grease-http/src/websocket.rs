@@ -0,0 +1,485 @@
+//! # websocket - RFC 6455 handshake and framing
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! The http task uses this module once a connection has been upgraded. The
+//! `Decoder` is fed raw bytes from the socket and hands back whole
+//! messages - it unmasks client frames, reassembles fragmented messages
+//! (control frames may arrive in the middle of them) and checks the things
+//! RFC 6455 says we must check. When it finds a problem it gives back the
+//! close code we should send before dropping the connection.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use base64;
+use sha1;
+
+use super::{HeaderMap, Method, WsMessage};
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Normal closure
+pub const WS_CLOSE_NORMAL: u16 = 1000;
+/// The endpoint is going away
+pub const WS_CLOSE_GOING_AWAY: u16 = 1001;
+/// The peer broke the protocol
+pub const WS_CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Text message that wasn't valid UTF-8
+pub const WS_CLOSE_INVALID_DATA: u16 = 1007;
+/// Message was larger than we will accept
+pub const WS_CLOSE_TOO_BIG: u16 = 1009;
+
+/// The largest message (after reassembly) we will accept from a client.
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Appended to the client's key before hashing, as per RFC 6455 section 4.2.2
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Turns a stream of bytes from a client into messages.
+pub struct Decoder {
+	/// Bytes received but not yet decoded
+	buffer: Vec<u8>,
+	/// The opcode and data so far of a fragmented message
+	fragments: Option<(u8, Vec<u8>)>,
+}
+
+/// One decoded frame
+struct Frame {
+	fin: bool,
+	opcode: u8,
+	payload: Vec<u8>,
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// If this request is a valid WebSocket upgrade, return the client's
+/// `Sec-WebSocket-Key`.
+pub fn upgrade_key(method: &Method, headers: &HeaderMap) -> Option<String> {
+	if *method != Method::GET {
+		return None;
+	}
+	let has_token = |name: &str, token: &str| {
+		headers.get_all(name).iter().any(|v| {
+			v.to_str().ok().map_or(false, |v| {
+				v.split(',')
+					.any(|t| t.trim().eq_ignore_ascii_case(token))
+			})
+		})
+	};
+	if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+		return None;
+	}
+	if headers
+		.get("Sec-WebSocket-Version")
+		.map_or(true, |v| v.as_bytes() != b"13")
+	{
+		return None;
+	}
+	headers
+		.get("Sec-WebSocket-Key")
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.trim().to_owned())
+		.filter(|v| !v.is_empty())
+}
+
+/// Work out the `Sec-WebSocket-Accept` value for a client's key.
+pub fn accept_key(key: &str) -> String {
+	let mut hash = sha1::Sha1::new();
+	hash.update(key.as_bytes());
+	hash.update(ACCEPT_GUID.as_bytes());
+	base64::encode(&hash.digest().bytes())
+}
+
+/// Render a message as a single (unmasked, server to client) frame.
+pub fn encode(message: &WsMessage) -> Vec<u8> {
+	let (opcode, payload): (u8, ::std::borrow::Cow<[u8]>) = match *message {
+		WsMessage::Text(ref s) => (OPCODE_TEXT, s.as_bytes().into()),
+		WsMessage::Binary(ref b) => (OPCODE_BINARY, b[..].into()),
+		WsMessage::Ping(ref b) => (OPCODE_PING, b[..].into()),
+		WsMessage::Pong(ref b) => (OPCODE_PONG, b[..].into()),
+		WsMessage::Close(None, _) => (OPCODE_CLOSE, Vec::new().into()),
+		WsMessage::Close(Some(code), ref reason) => {
+			let mut p = vec![(code >> 8) as u8, code as u8];
+			p.extend_from_slice(reason.as_bytes());
+			(OPCODE_CLOSE, p.into())
+		}
+	};
+	let mut frame = Vec::with_capacity(payload.len() + 10);
+	frame.push(0x80 | opcode);
+	let len = payload.len();
+	if len < 126 {
+		frame.push(len as u8);
+	} else if len <= 0xFFFF {
+		frame.push(126);
+		frame.push((len >> 8) as u8);
+		frame.push(len as u8);
+	} else {
+		frame.push(127);
+		for shift in (0..8).rev() {
+			frame.push(((len as u64) >> (shift * 8)) as u8);
+		}
+	}
+	frame.extend_from_slice(&payload);
+	frame
+}
+
+/// Check a message is one we're allowed to send. Control frames can't be
+/// fragmented, so they must fit in 125 bytes.
+pub fn valid_to_send(message: &WsMessage) -> bool {
+	match *message {
+		WsMessage::Text(_) | WsMessage::Binary(_) => true,
+		WsMessage::Ping(ref b) | WsMessage::Pong(ref b) => b.len() <= 125,
+		WsMessage::Close(None, ref reason) => reason.is_empty(),
+		WsMessage::Close(Some(code), ref reason) => {
+			valid_close_code(code) && reason.len() <= 123
+		}
+	}
+}
+
+impl Decoder {
+	/// Create a new, empty, Decoder
+	pub fn new() -> Decoder {
+		Decoder {
+			buffer: Vec::new(),
+			fragments: None,
+		}
+	}
+
+	/// Add some more bytes from the client.
+	pub fn push(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	/// Get the next complete message, if there is one. On error, returns
+	/// the close code to send.
+	pub fn next_message(&mut self) -> Result<Option<WsMessage>, u16> {
+		loop {
+			let frame = match self.next_frame()? {
+				Some(f) => f,
+				None => return Ok(None),
+			};
+			match frame.opcode {
+				OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+					// Control frames can come in the middle of a fragmented
+					// message, but can't be fragmented themselves.
+					if !frame.fin || frame.payload.len() > 125 {
+						return Err(WS_CLOSE_PROTOCOL_ERROR);
+					}
+					return Self::control(frame).map(Some);
+				}
+				OPCODE_TEXT | OPCODE_BINARY => {
+					if self.fragments.is_some() {
+						return Err(WS_CLOSE_PROTOCOL_ERROR);
+					}
+					if frame.fin {
+						return Self::data(frame.opcode, frame.payload).map(Some);
+					}
+					self.fragments = Some((frame.opcode, frame.payload));
+				}
+				OPCODE_CONTINUATION => {
+					let (opcode, mut data) = match self.fragments.take() {
+						Some(x) => x,
+						None => return Err(WS_CLOSE_PROTOCOL_ERROR),
+					};
+					if data.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+						return Err(WS_CLOSE_TOO_BIG);
+					}
+					data.extend_from_slice(&frame.payload);
+					if frame.fin {
+						return Self::data(opcode, data).map(Some);
+					}
+					self.fragments = Some((opcode, data));
+				}
+				_ => return Err(WS_CLOSE_PROTOCOL_ERROR),
+			}
+		}
+	}
+
+	/// Pull one frame out of the buffer, if it's all there.
+	fn next_frame(&mut self) -> Result<Option<Frame>, u16> {
+		if self.buffer.len() < 2 {
+			return Ok(None);
+		}
+		let b0 = self.buffer[0];
+		let b1 = self.buffer[1];
+		if b0 & 0x70 != 0 {
+			// We haven't negotiated any extensions, so RSV bits must be clear
+			return Err(WS_CLOSE_PROTOCOL_ERROR);
+		}
+		if b1 & 0x80 == 0 {
+			// Clients must mask every frame
+			return Err(WS_CLOSE_PROTOCOL_ERROR);
+		}
+		let (len, mut offset) = match b1 & 0x7F {
+			126 => {
+				if self.buffer.len() < 4 {
+					return Ok(None);
+				}
+				let len = (u64::from(self.buffer[2]) << 8) | u64::from(self.buffer[3]);
+				(len, 4)
+			}
+			127 => {
+				if self.buffer.len() < 10 {
+					return Ok(None);
+				}
+				let len = self.buffer[2..10]
+					.iter()
+					.fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+				(len, 10)
+			}
+			n => (u64::from(n), 2),
+		};
+		if len > MAX_MESSAGE_LEN as u64 {
+			return Err(WS_CLOSE_TOO_BIG);
+		}
+		let len = len as usize;
+		if self.buffer.len() < offset + 4 + len {
+			return Ok(None);
+		}
+		let mask = [
+			self.buffer[offset],
+			self.buffer[offset + 1],
+			self.buffer[offset + 2],
+			self.buffer[offset + 3],
+		];
+		offset += 4;
+		let payload = self.buffer[offset..offset + len]
+			.iter()
+			.enumerate()
+			.map(|(i, b)| b ^ mask[i % 4])
+			.collect();
+		self.buffer.drain(..offset + len);
+		Ok(Some(Frame {
+			fin: b0 & 0x80 != 0,
+			opcode: b0 & 0x0F,
+			payload,
+		}))
+	}
+
+	fn control(frame: Frame) -> Result<WsMessage, u16> {
+		match frame.opcode {
+			OPCODE_PING => Ok(WsMessage::Ping(frame.payload)),
+			OPCODE_PONG => Ok(WsMessage::Pong(frame.payload)),
+			_ => match frame.payload.len() {
+				0 => Ok(WsMessage::Close(None, String::new())),
+				1 => Err(WS_CLOSE_PROTOCOL_ERROR),
+				_ => {
+					let code = (u16::from(frame.payload[0]) << 8) | u16::from(frame.payload[1]);
+					if !valid_close_code(code) {
+						return Err(WS_CLOSE_PROTOCOL_ERROR);
+					}
+					match String::from_utf8(frame.payload[2..].to_vec()) {
+						Ok(reason) => Ok(WsMessage::Close(Some(code), reason)),
+						Err(_) => Err(WS_CLOSE_INVALID_DATA),
+					}
+				}
+			},
+		}
+	}
+
+	fn data(opcode: u8, payload: Vec<u8>) -> Result<WsMessage, u16> {
+		if opcode == OPCODE_TEXT {
+			String::from_utf8(payload)
+				.map(WsMessage::Text)
+				.map_err(|_| WS_CLOSE_INVALID_DATA)
+		} else {
+			Ok(WsMessage::Binary(payload))
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Codes which may appear in a close frame (RFC 6455 section 7.4).
+fn valid_close_code(code: u16) -> bool {
+	match code {
+		1000..=1003 | 1007..=1011 | 3000..=4999 => true,
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Build a masked client frame
+	fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+		let mask = [0x37, 0xfa, 0x21, 0x3d];
+		let mut frame = vec![if fin { 0x80 } else { 0x00 } | opcode];
+		if payload.len() < 126 {
+			frame.push(0x80 | payload.len() as u8);
+		} else {
+			frame.push(0x80 | 126);
+			frame.push((payload.len() >> 8) as u8);
+			frame.push(payload.len() as u8);
+		}
+		frame.extend_from_slice(&mask);
+		frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+		frame
+	}
+
+	#[test]
+	fn handshake() {
+		// The example from RFC 6455 section 1.3
+		assert_eq!(
+			accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+			"s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+		);
+		let mut headers = HeaderMap::new();
+		headers.insert("Upgrade", "WebSocket".parse().unwrap());
+		headers.insert("Connection", "keep-alive, Upgrade".parse().unwrap());
+		headers.insert("Sec-WebSocket-Version", "13".parse().unwrap());
+		headers.insert("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap());
+		assert_eq!(
+			upgrade_key(&Method::GET, &headers),
+			Some(String::from("dGhlIHNhbXBsZSBub25jZQ=="))
+		);
+		assert_eq!(upgrade_key(&Method::POST, &headers), None);
+		headers.insert("Sec-WebSocket-Version", "8".parse().unwrap());
+		assert_eq!(upgrade_key(&Method::GET, &headers), None);
+		headers.insert("Sec-WebSocket-Version", "13".parse().unwrap());
+		headers.insert("Connection", "close".parse().unwrap());
+		assert_eq!(upgrade_key(&Method::GET, &headers), None);
+	}
+
+	#[test]
+	fn decode_masked() {
+		// The masked "Hello" from RFC 6455 section 5.7, fed in two halves
+		let input = [
+			0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+		];
+		let mut d = Decoder::new();
+		d.push(&input[0..4]);
+		assert_eq!(d.next_message(), Ok(None));
+		d.push(&input[4..]);
+		assert_eq!(d.next_message(), Ok(Some(WsMessage::Text(String::from("Hello")))));
+		assert_eq!(d.next_message(), Ok(None));
+	}
+
+	#[test]
+	fn decode_fragmented() {
+		let mut d = Decoder::new();
+		d.push(&client_frame(false, OPCODE_TEXT, b"Hel"));
+		d.push(&client_frame(true, OPCODE_PING, b"are you there?"));
+		d.push(&client_frame(true, OPCODE_CONTINUATION, b"lo"));
+		let big = vec![0x55u8; 300];
+		d.push(&client_frame(true, OPCODE_BINARY, &big));
+		assert_eq!(
+			d.next_message(),
+			Ok(Some(WsMessage::Ping(b"are you there?".to_vec())))
+		);
+		assert_eq!(d.next_message(), Ok(Some(WsMessage::Text(String::from("Hello")))));
+		assert_eq!(d.next_message(), Ok(Some(WsMessage::Binary(big))));
+		assert_eq!(d.next_message(), Ok(None));
+	}
+
+	#[test]
+	fn decode_close() {
+		let mut d = Decoder::new();
+		d.push(&client_frame(true, OPCODE_CLOSE, b"\x03\xe8bye"));
+		assert_eq!(
+			d.next_message(),
+			Ok(Some(WsMessage::Close(Some(WS_CLOSE_NORMAL), String::from("bye"))))
+		);
+		d.push(&client_frame(true, OPCODE_CLOSE, b""));
+		assert_eq!(d.next_message(), Ok(Some(WsMessage::Close(None, String::new()))));
+		let mut d = Decoder::new();
+		d.push(&client_frame(true, OPCODE_CLOSE, b"\x03\xed"));
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+	}
+
+	#[test]
+	fn decode_errors() {
+		// Unmasked
+		let mut d = Decoder::new();
+		d.push(&[0x81, 0x01, b'a']);
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+		// Reserved bits
+		let mut d = Decoder::new();
+		let mut frame = client_frame(true, OPCODE_TEXT, b"a");
+		frame[0] |= 0x40;
+		d.push(&frame);
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+		// Bad UTF-8
+		let mut d = Decoder::new();
+		d.push(&client_frame(true, OPCODE_TEXT, b"\xff\xfe"));
+		assert_eq!(d.next_message(), Err(WS_CLOSE_INVALID_DATA));
+		// Continuation with nothing to continue
+		let mut d = Decoder::new();
+		d.push(&client_frame(true, OPCODE_CONTINUATION, b"a"));
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+		// Fragmented ping
+		let mut d = Decoder::new();
+		d.push(&client_frame(false, OPCODE_PING, b"a"));
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+		// New message in the middle of a fragmented one
+		let mut d = Decoder::new();
+		d.push(&client_frame(false, OPCODE_TEXT, b"a"));
+		d.push(&client_frame(true, OPCODE_TEXT, b"b"));
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+		// Unknown opcode
+		let mut d = Decoder::new();
+		d.push(&client_frame(true, 0x3, b""));
+		assert_eq!(d.next_message(), Err(WS_CLOSE_PROTOCOL_ERROR));
+		// Too big
+		let mut d = Decoder::new();
+		d.push(&[0x82, 0xFF, 0, 0, 0, 0, 0xFF, 0, 0, 0]);
+		assert_eq!(d.next_message(), Err(WS_CLOSE_TOO_BIG));
+	}
+
+	#[test]
+	fn encoding() {
+		assert_eq!(
+			encode(&WsMessage::Text(String::from("Hello"))),
+			b"\x81\x05Hello".to_vec()
+		);
+		assert_eq!(
+			encode(&WsMessage::Close(Some(WS_CLOSE_GOING_AWAY), String::new())),
+			b"\x88\x02\x03\xe9".to_vec()
+		);
+		let frame = encode(&WsMessage::Binary(vec![0; 256]));
+		assert_eq!(&frame[0..4], b"\x82\x7e\x01\x00");
+		assert_eq!(frame.len(), 4 + 256);
+		let frame = encode(&WsMessage::Binary(vec![0; 70_000]));
+		assert_eq!(&frame[0..10], b"\x82\x7f\x00\x00\x00\x00\x00\x01\x11\x70");
+		assert!(valid_to_send(&WsMessage::Ping(vec![0; 125])));
+		assert!(!valid_to_send(&WsMessage::Ping(vec![0; 126])));
+		assert!(!valid_to_send(&WsMessage::Close(Some(1005), String::new())));
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,187 @@
+//! # json - a grease example serving JSON with the http task
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate env_logger;
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+extern crate grease_json as json;
+extern crate grease_socket as socket;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+use std::collections::HashMap;
+use std::net;
+use std::sync::mpsc;
+
+use grease::prelude::*;
+use grease::Context;
+use http::StatusCode;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+struct Handle(mpsc::Sender<Incoming>);
+
+app_map! {
+	generate: Incoming,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd)
+	}
+}
+
+/// What we want POSTed to us
+#[derive(Debug, Deserialize)]
+struct Greeting {
+	name: String,
+	times: u32,
+}
+
+/// What we send back
+#[derive(Debug, Serialize)]
+struct Reply {
+	test: usize,
+	url: String,
+	greetings: Vec<String>,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Start of our example program
+fn main() {
+	env_logger::init();
+	let bind_addr: net::SocketAddr = "0.0.0.0:8000".parse().unwrap();
+
+	info!("Hello, this is the grease JSON example.");
+	info!("Running HTTP server on {}", bind_addr);
+
+	let socket_thread = socket::make_task();
+	let http_thread = http::make_task(socket_thread.clone());
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx);
+
+	http_thread.send_request(
+		http::ReqBind {
+			addr: bind_addr,
+			context: Context::default(),
+			config: http::ServerConfig::default(),
+		}.into(),
+		&handle,
+	);
+
+	let mut n: Context = Context::default();
+	let mut tests = 0;
+	// Requests whose bodies are on their way, and the URL they were for
+	let mut decoders: HashMap<http::ConnHandle, (json::JsonDecoder<Greeting>, String)> =
+		HashMap::new();
+
+	for msg in rx.iter() {
+		let response = match msg {
+			Incoming::HttpInd(http::Indication::RxRequest(ind)) => {
+				info!("Got HTTP request {:?} {}", ind.method, ind.url);
+				let conn = ind.connection_handle;
+				if !ind.has_body() {
+					Some(reply(conn, n.take(), &mut tests, &ind.url.to_string(), None))
+				} else {
+					match json::JsonDecoder::new(&ind.headers, json::DEFAULT_MAX_BYTES) {
+						Ok(decoder) => {
+							decoders.insert(conn, (decoder, ind.url.to_string()));
+							None
+						}
+						Err(e) => Some(json::JsonResponse::problem(conn, n.take(), &e.problem())),
+					}
+				}
+			}
+			Incoming::HttpInd(http::Indication::RxBody(ind)) => {
+				let conn = ind.handle;
+				let result = match decoders.get_mut(&conn) {
+					Some(&mut (ref mut decoder, _)) => decoder.decode(&ind.data, ind.last),
+					None => continue,
+				};
+				match result {
+					Ok(None) => None,
+					Ok(Some(greeting)) => {
+						let (_, url) = decoders.remove(&conn).unwrap();
+						Some(reply(conn, n.take(), &mut tests, &url, Some(greeting)))
+					}
+					Err(e) => {
+						info!("Bad body: {}", e);
+						decoders.remove(&conn);
+						Some(json::JsonResponse::problem(conn, n.take(), &e.problem()))
+					}
+				}
+			}
+			Incoming::HttpInd(http::Indication::Closed(ind)) => {
+				decoders.remove(&ind.handle);
+				None
+			}
+			_ => None,
+		};
+		if let Some(response) = response {
+			response.send(&http_thread, &handle);
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Answer a request, greeting whoever was POSTed
+fn reply(
+	conn: http::ConnHandle,
+	ctx: Context,
+	tests: &mut usize,
+	url: &str,
+	greeting: Option<Greeting>,
+) -> json::JsonResponse {
+	*tests += 1;
+	let greetings = greeting.map_or(Vec::new(), |g| {
+		(0..g.times).map(|_| format!("Hello, {}!", g.name)).collect()
+	});
+	let reply = Reply {
+		test: *tests,
+		url: url.to_owned(),
+		greetings,
+	};
+	json::JsonResponse::new(conn, ctx, StatusCode::OK, &reply).unwrap()
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
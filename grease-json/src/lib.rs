@@ -0,0 +1,401 @@
+//! # json - JSON request and response bodies
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! Helpers for endpoints which speak JSON, on top of the `http` task (or
+//! any task which passes on its `IndRxRequest`s). This isn't a task - the
+//! helpers run on yours.
+//!
+//! When you get an `IndRxRequest` with a body, make a `JsonDecoder` for it
+//! from the request headers, then feed it the data from each `IndRxBody`.
+//! With the last piece you get the body back as your own type,
+//! deserialized with serde. If that can't be done, the `JsonError` says
+//! why, and its `problem()` is an RFC 7807 problem report to answer the
+//! request with: `415` if the request isn't JSON, `413` if it's bigger than
+//! you allow, `400` if it isn't valid JSON and `422` if it is, but doesn't
+//! fit your type.
+//!
+//! A `JsonResponse` is a serialized value (or `Problem`), as the
+//! `ReqResponseStart` and `ReqResponseBody` which send it, with the
+//! `Content-Type` and length filled in. Add any headers you want to the
+//! `start` and then `send` it. Statuses the http task can't send go as the
+//! generic status of their class (e.g. `400` for a `421`), as
+//! `HttpResponseStatus::from_code_or_class` gives.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate grease;
+extern crate grease_http as http;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use http::{HeaderMap, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Decodes one JSON request body, however it's split up
+#[derive(Debug)]
+pub struct JsonDecoder<T> {
+	max_bytes: usize,
+	buffer: Vec<u8>,
+	marker: PhantomData<T>,
+}
+
+/// Why a request body couldn't be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+	/// The request's `Content-Type` isn't JSON
+	NotJson,
+	/// The body is bigger than the decoder allows
+	TooLarge,
+	/// The body isn't valid JSON. Says what's wrong, and where.
+	Syntax(String),
+	/// The body is valid JSON, but doesn't fit the type. Says what's
+	/// wrong, and where.
+	Data(String),
+}
+
+/// An RFC 7807 problem report, sent as `application/problem+json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+	/// A URI which identifies the type of problem. `"about:blank"` means
+	/// the status says it all.
+	#[serde(rename = "type")]
+	pub problem_type: String,
+	/// A short summary of the type of problem
+	pub title: String,
+	/// The status the problem is sent with
+	pub status: u16,
+	/// What went wrong this time
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub detail: Option<String>,
+}
+
+/// A response with a JSON body, ready to send to the http task
+#[derive(Debug)]
+pub struct JsonResponse {
+	pub start: http::ReqResponseStart,
+	pub body: http::ReqResponseBody,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// A reasonable limit on the size of a request body
+pub const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+
+/// The `Content-Type` of JSON responses
+pub const JSON: &str = "application/json";
+
+/// The `Content-Type` of problem reports
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl<T: DeserializeOwned> JsonDecoder<T> {
+	/// Make a decoder for a request with these headers, which takes bodies
+	/// of up to `max_bytes`. Fails with `NotJson` unless the `Content-Type`
+	/// is `application/json` or another `+json` type, or `TooLarge` if the
+	/// `Content-Length` is more than `max_bytes`.
+	pub fn new(headers: &HeaderMap, max_bytes: usize) -> Result<JsonDecoder<T>, JsonError> {
+		let media_type = headers
+			.get("content-type")
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.split(';').next())
+			.map(|v| v.trim().to_ascii_lowercase())
+			.ok_or(JsonError::NotJson)?;
+		if media_type != JSON
+			&& !(media_type.starts_with("application/") && media_type.ends_with("+json"))
+		{
+			return Err(JsonError::NotJson);
+		}
+		let length = headers
+			.get("content-length")
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.trim().parse::<u64>().ok());
+		if length.map_or(false, |length| length > max_bytes as u64) {
+			return Err(JsonError::TooLarge);
+		}
+		Ok(JsonDecoder {
+			max_bytes,
+			buffer: Vec::new(),
+			marker: PhantomData,
+		})
+	}
+
+	/// Take some more of the body. Set `last` on the final piece (as in
+	/// `IndRxBody::last`), and you get the decoded body back.
+	pub fn decode(&mut self, data: &[u8], last: bool) -> Result<Option<T>, JsonError> {
+		if self.buffer.len() + data.len() > self.max_bytes {
+			return Err(JsonError::TooLarge);
+		}
+		self.buffer.extend_from_slice(data);
+		if !last {
+			return Ok(None);
+		}
+		serde_json::from_slice(&self.buffer)
+			.map(Some)
+			.map_err(JsonError::from)
+	}
+}
+
+impl JsonError {
+	/// The status to refuse the request with
+	pub fn status(&self) -> StatusCode {
+		match *self {
+			JsonError::NotJson => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+			JsonError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+			JsonError::Syntax(_) => StatusCode::BAD_REQUEST,
+			JsonError::Data(_) => StatusCode::UNPROCESSABLE_ENTITY,
+		}
+	}
+
+	/// The problem report to refuse the request with
+	pub fn problem(&self) -> Problem {
+		Problem::new(self.status()).detail(&self.to_string())
+	}
+}
+
+impl fmt::Display for JsonError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			JsonError::NotJson => f.write_str("the request body must be JSON"),
+			JsonError::TooLarge => f.write_str("the request body is too large"),
+			JsonError::Syntax(ref e) | JsonError::Data(ref e) => f.write_str(e),
+		}
+	}
+}
+
+impl From<serde_json::Error> for JsonError {
+	fn from(e: serde_json::Error) -> JsonError {
+		match e.classify() {
+			serde_json::error::Category::Data => JsonError::Data(e.to_string()),
+			_ => JsonError::Syntax(e.to_string()),
+		}
+	}
+}
+
+impl Problem {
+	/// A problem which the status says it all about
+	pub fn new(status: StatusCode) -> Problem {
+		Problem {
+			problem_type: String::from("about:blank"),
+			title: status.canonical_reason().unwrap_or("Unknown").to_owned(),
+			status: status.as_u16(),
+			detail: None,
+		}
+	}
+
+	/// Say what went wrong this time
+	pub fn detail(mut self, detail: &str) -> Problem {
+		self.detail = Some(detail.to_owned());
+		self
+	}
+}
+
+impl JsonResponse {
+	/// Serialize a value to answer the request on `handle` with. `context`
+	/// is used for both the `ReqResponseStart` and the `ReqResponseBody`.
+	pub fn new<T: Serialize>(
+		handle: http::ConnHandle,
+		context: Context,
+		status: StatusCode,
+		value: &T,
+	) -> Result<JsonResponse, serde_json::Error> {
+		let data = serde_json::to_vec(value)?;
+		Ok(JsonResponse::with_body(handle, context, status, JSON, data))
+	}
+
+	/// Answer the request on `handle` with a problem report
+	pub fn problem(handle: http::ConnHandle, context: Context, problem: &Problem) -> JsonResponse {
+		let status = StatusCode::from_u16(problem.status)
+			.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+		// A problem is always serializable
+		let data = serde_json::to_vec(problem).unwrap_or_default();
+		JsonResponse::with_body(handle, context, status, PROBLEM_JSON, data)
+	}
+
+	/// Send the response to the http task
+	pub fn send(
+		self,
+		http: &grease::ServiceProvider<http::Service>,
+		reply_to: &grease::ServiceUser<http::Service>,
+	) {
+		http.send_request(self.start.into(), reply_to);
+		http.send_request(self.body.into(), reply_to);
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl JsonResponse {
+	fn with_body(
+		handle: http::ConnHandle,
+		context: Context,
+		status: StatusCode,
+		content_type: &str,
+		data: Vec<u8>,
+	) -> JsonResponse {
+		JsonResponse {
+			start: http::ReqResponseStart {
+				handle,
+				context,
+				status: http::HttpResponseStatus::from_code_or_class(status),
+				content_type: content_type.to_owned(),
+				length: Some(data.len()),
+				headers: HeaderMap::new(),
+				compress: None,
+			},
+			body: http::ReqResponseBody {
+				handle,
+				context,
+				data,
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[derive(Debug, PartialEq, Deserialize)]
+	struct Widget {
+		name: String,
+		count: u32,
+	}
+
+	fn headers(content_type: &str, length: Option<usize>) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert("content-type", content_type.parse().unwrap());
+		if let Some(length) = length {
+			headers.insert("content-length", length.to_string().parse().unwrap());
+		}
+		headers
+	}
+
+	fn decode(content_type: &str, pieces: &[&str]) -> Result<Option<Widget>, JsonError> {
+		let mut decoder = JsonDecoder::new(&headers(content_type, None), 64)?;
+		for piece in &pieces[..pieces.len() - 1] {
+			assert_eq!(decoder.decode(piece.as_bytes(), false)?, None);
+		}
+		decoder.decode(pieces[pieces.len() - 1].as_bytes(), true)
+	}
+
+	#[test]
+	fn decoding() {
+		let widget = Widget {
+			name: String::from("sprocket"),
+			count: 3,
+		};
+		assert_eq!(
+			decode(JSON, &["{\"name\": \"spr", "ocket\",", " \"count\": 3}"]),
+			Ok(Some(widget))
+		);
+		let vendor = "application/vnd.widget+json; charset=utf-8";
+		assert!(decode(vendor, &["{\"name\": \"\", \"count\": 0}"]).is_ok());
+
+		let status = |result: Result<Option<Widget>, JsonError>| result.unwrap_err().status();
+		assert_eq!(status(decode("text/plain", &["{}"])), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+		assert_eq!(status(decode(JSON, &["{\"name\": "])), StatusCode::BAD_REQUEST);
+		assert_eq!(status(decode(JSON, &[""])), StatusCode::BAD_REQUEST);
+		assert_eq!(
+			status(decode(JSON, &["{\"name\": \"x\", \"count\": 1} {}"])),
+			StatusCode::BAD_REQUEST
+		);
+		assert_eq!(
+			status(decode(JSON, &["{\"name\": \"x\"}"])),
+			StatusCode::UNPROCESSABLE_ENTITY
+		);
+		assert_eq!(
+			status(decode(JSON, &["{\"name\": \"x\", \"count\": -1}"])),
+			StatusCode::UNPROCESSABLE_ENTITY
+		);
+		assert_eq!(
+			status(decode(JSON, &[&format!("{{\"name\": \"{}\"", "x".repeat(60)), "}"])),
+			StatusCode::PAYLOAD_TOO_LARGE
+		);
+		assert_eq!(
+			JsonDecoder::<Widget>::new(&headers(JSON, Some(65)), 64).unwrap_err(),
+			JsonError::TooLarge
+		);
+		assert!(JsonDecoder::<Widget>::new(&HeaderMap::new(), 64).is_err());
+
+		let problem = decode(JSON, &["{\"name\": 7, \"count\": 1}"])
+			.unwrap_err()
+			.problem();
+		assert_eq!(problem.status, 422);
+		assert_eq!(problem.title, "Unprocessable Entity");
+		assert!(problem.detail.unwrap().contains("line 1 column"));
+	}
+
+	#[test]
+	fn responses() {
+		let handle = Context::new(10);
+		let response =
+			JsonResponse::new(handle, Context::new(20), StatusCode::CREATED, &vec![1, 2, 3])
+				.unwrap();
+		assert_eq!(response.start.handle, handle);
+		assert_eq!(response.start.status, http::HttpResponseStatus::Created);
+		assert_eq!(response.start.content_type, JSON);
+		assert_eq!(response.start.length, Some(7));
+		assert_eq!(response.body.context, Context::new(20));
+		assert_eq!(response.body.data, b"[1,2,3]");
+
+		let problem = Problem::new(StatusCode::UNPROCESSABLE_ENTITY).detail("no \"count\"");
+		let response = JsonResponse::problem(handle, Context::new(21), &problem);
+		assert_eq!(response.start.status, http::HttpResponseStatus::UnprocessableEntity);
+		assert_eq!(response.start.content_type, PROBLEM_JSON);
+		assert_eq!(
+			String::from_utf8(response.body.data).unwrap(),
+			"{\"type\":\"about:blank\",\"title\":\"Unprocessable Entity\",\"status\":422,\
+			 \"detail\":\"no \\\"count\\\"\"}"
+		);
+		let problem = Problem::new(StatusCode::MISDIRECTED_REQUEST);
+		let response = JsonResponse::problem(handle, Context::new(22), &problem);
+		assert_eq!(response.start.status, http::HttpResponseStatus::BadRequest);
+		let json = serde_json::to_string(&Problem::new(StatusCode::NOT_FOUND)).unwrap();
+		assert_eq!(
+			json,
+			"{\"type\":\"about:blank\",\"title\":\"Not Found\",\"status\":404}"
+		);
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,2006 @@
+//! # proxy - An HTTP reverse proxy task
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! This task sits on top of the `http` task, and passes the requests it
+//! gets on to other (upstream) HTTP servers using the `http_client` task.
+//! A `ReqBind` asks us to bind an HTTP server, with a `ProxyConfig` giving
+//! the `Route`s. Each route covers a path prefix, optionally on particular
+//! hosts (with the same patterns as `http::ReqAddHost`), and has a list of
+//! upstream servers. A request goes to the route with the best matching
+//! host and then the longest matching prefix, and to the next of that
+//! route's upstreams in turn. Requests which match no route get a `404`.
+//!
+//! We remove the hop-by-hop headers (`Connection` and the headers it
+//! lists, `Keep-Alive`, `TE` and so on) in both directions, as they only
+//! apply to one connection. The upstream gets its own `Host`, and the
+//! original is passed on in `X-Forwarded-Host`, along with the client's
+//! address on the end of `X-Forwarded-For` and `X-Forwarded-Proto`. If the
+//! request came through one of the server's `trusted_proxies`, any
+//! `X-Forwarded-Host` and `X-Forwarded-Proto` it gave are kept.
+//!
+//! Bodies are streamed both ways. Request bodies are sent upstream a piece
+//! at a time, and only read from the client as fast as the upstream takes
+//! them. Response bodies are only read from the upstream as fast as the
+//! http task sends them on. If the upstream can't be reached we
+//! send a `502`. If it fails part way through the response, we abort the
+//! connection, so the client can tell the response is incomplete.
+//!
+//! With a `HealthCheck` in the config, we regularly fetch a path from each
+//! upstream. An upstream which fails `fall` checks in a row (errors,
+//! timeouts or statuses other than 2xx and 3xx) stops getting requests
+//! until it passes `rise` checks in a row, and whoever bound the server is
+//! told with an `IndUpstreamHealth`. If a route has no healthy upstreams,
+//! its requests get a `503`.
+//!
+//! WebSocket upgrades aren't proxied, and as the `http_client` task can't
+//! cancel a request, an upstream request carries on (and its response is
+//! thrown away) if the client goes away. Statuses the http task can't send
+//! are passed on as the generic status of their class (e.g. `400` for a
+//! `421`), as `HttpResponseStatus::from_code_or_class` gives.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#![cfg_attr(feature = "cargo-clippy", allow(if_not_else))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+extern crate grease_http_client as http_client;
+#[macro_use]
+extern crate log;
+
+use std::collections::{HashMap, VecDeque};
+use std::net;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, HeaderValue, HttpResponseStatus, Method, StatusCode, Uri};
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Messages
+//
+// ****************************************************************************
+
+/// Offers the `grease::Service` for this module.
+pub struct Service;
+
+impl grease::Service for Service {
+	type Request = Request;
+	type Confirm = Confirm;
+	type Indication = Indication;
+	type Response = ();
+}
+
+/// Requests that can be sent to the proxy task.
+#[derive(Debug)]
+pub enum Request {
+	/// Start a proxying HTTP server on a given port
+	Bind(ReqBind),
+}
+
+make_wrapper!(ReqBind, Request, Request::Bind);
+
+/// Confirms sent back from the proxy task.
+#[derive(Debug)]
+pub enum Confirm {
+	/// Whether the ReqBind was successful
+	Bind(CfmBind),
+}
+
+make_wrapper!(CfmBind, Confirm, Confirm::Bind);
+
+/// Indications that come out of the proxy task.
+#[derive(Debug)]
+pub enum Indication {
+	/// An upstream server has passed or failed its health checks
+	UpstreamHealth(IndUpstreamHealth),
+}
+
+make_wrapper!(IndUpstreamHealth, Indication, Indication::UpstreamHealth);
+
+/// Start an HTTP server on a given port, which proxies its requests.
+#[derive(Debug)]
+pub struct ReqBind {
+	/// Which address to bind.
+	pub addr: net::SocketAddr,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Passed on to the http task, with `body_flow_control` set
+	pub config: http::ServerConfig,
+	/// Where requests go
+	pub proxy: ProxyConfig,
+}
+
+/// Whether the `ReqBind` was successful
+#[derive(Debug)]
+pub struct CfmBind {
+	pub context: Context,
+	pub result: Result<ServerHandle, Error>,
+}
+
+/// An upstream server has changed state, having passed `rise` or failed
+/// `fall` health checks in a row.
+#[derive(Debug)]
+pub struct IndUpstreamHealth {
+	/// The server whose route has this upstream
+	pub server: ServerHandle,
+	/// The upstream, as given in the `Route`
+	pub upstream: Uri,
+	/// Whether it will now be sent requests
+	pub healthy: bool,
+}
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Represents something a proxy service user can hold on to to send us
+/// message.
+pub struct Handle(mpsc::Sender<Incoming>);
+
+/// Servers are identified by the handle the http task gave us
+pub type ServerHandle = http::ServerHandle;
+
+/// Where a server's requests go
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+	/// Where requests go, depending on their host and path
+	pub routes: Vec<Route>,
+	/// How to check the upstreams are working. None to assume they are.
+	pub health_check: Option<HealthCheck>,
+}
+
+/// Some requests, and where they go
+#[derive(Debug, Clone)]
+pub struct Route {
+	/// Which hosts this route is for, as a pattern like `"www.example.com"`,
+	/// `"*.example.com"` or `"*"`. None is the same as `"*"`.
+	pub host: Option<String>,
+	/// Which paths this route is for. `"/api"` covers `"/api"` and
+	/// everything under `"/api/"`, but not `"/apis"`.
+	pub prefix: String,
+	/// Whether to take the prefix off the path before passing it on
+	pub strip_prefix: bool,
+	/// The servers to pass requests to, in turn, like
+	/// `"http://10.0.0.1:8080"`. The path of each request is added to the
+	/// upstream's path.
+	pub upstreams: Vec<Uri>,
+}
+
+/// How to check the upstreams are working
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+	/// What to fetch from each upstream, after the upstream's own path
+	pub path: String,
+	/// How often to check each upstream
+	pub interval: Duration,
+	/// How long to wait for the response to start
+	pub timeout: Duration,
+	/// How many checks in a row a healthy upstream must fail to be taken
+	/// out of service
+	pub fall: u32,
+	/// How many checks in a row an unhealthy upstream must pass to be put
+	/// back into service
+	pub rise: u32,
+}
+
+/// All possible proxy task errors
+#[derive(Debug)]
+pub enum Error {
+	/// The `ProxyConfig` has a route or health check which doesn't make
+	/// sense
+	BadConfig,
+	/// The http task rejected our request
+	Http(http::Error),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+service_map! {
+	generate: Incoming,
+	service: Service,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd),
+		http_client: (Service, ClientCfm, ClientInd)
+	}
+}
+
+struct Server {
+	config: ProxyConfig,
+	/// The pattern for each route's host
+	patterns: Vec<http::HostPattern>,
+	/// The upstream each route uses next
+	next: Vec<usize>,
+	/// How each upstream is doing, by URI
+	health: HashMap<String, Health>,
+	/// The peers whose `X-Forwarded-*` headers we keep, as given in the
+	/// `http::ServerConfig`
+	trusted_proxies: Vec<net::IpAddr>,
+	/// Who to tell about upstream health
+	ind_to: grease::ServiceUserHandle<Service>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Health {
+	healthy: bool,
+	/// How many checks in a row have said otherwise
+	run: u32,
+}
+
+/// A request we've passed on
+struct Exchange {
+	server: ServerHandle,
+	conn: http::ConnHandle,
+	/// The `http_client` handle, once it has confirmed the request
+	request: Option<http_client::RequestHandle>,
+	/// Request body waiting to go upstream
+	body: VecDeque<(Vec<u8>, bool)>,
+	/// Whether a `ReqRequestBody` is waiting for its cfm
+	sending: bool,
+	/// Whether a `RspRxBody` is due once that cfm comes
+	rx_owed: bool,
+	/// Whether the request was a HEAD
+	head: bool,
+	/// Whether the response has started
+	started: bool,
+	/// Whether the response has no length, and so must be ended with an
+	/// empty `ReqResponseBody`
+	unbounded: bool,
+	/// Whether to throw the response body away, because it mustn't have
+	/// one or there's no one to send it to
+	discard: bool,
+	/// Whether a `RspResponseBody` is due once the http task confirms the
+	/// body we've sent
+	owed: bool,
+}
+
+/// A health check in progress
+struct Probe {
+	server: ServerHandle,
+	upstream: Uri,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Timer {
+	/// Time to check a server's upstreams
+	Check(ServerHandle),
+	/// A health check has taken too long
+	Probe(Context),
+}
+
+struct TaskContext {
+	/// Who we send http messages to
+	http: grease::ServiceProviderHandle<http::Service>,
+	/// Who we send http_client messages to
+	client: grease::ServiceProviderHandle<http_client::Service>,
+	/// How the tasks we use send messages to us
+	reply_to: Handle,
+	/// Binds we're waiting for, indexed by the context we sent down
+	pending_binds: HashMap<Context, (ReplyContext, ProxyConfig, Vec<net::IpAddr>)>,
+	/// The servers we've bound
+	servers: HashMap<ServerHandle, Server>,
+	/// Requests we've passed on, by the context of the `ReqHttpRequest`.
+	/// This is also the context of everything we send the http task about
+	/// the response.
+	exchanges: HashMap<Context, Exchange>,
+	/// The exchange for each connection
+	connections: HashMap<http::ConnHandle, Context>,
+	/// Health checks we're waiting to hear about, by the context of the
+	/// `ReqHttpRequest`
+	probes: HashMap<Context, Probe>,
+	/// The exchange or health check for each `http_client` handle. Those
+	/// which aren't exchanges are only drained.
+	requests: HashMap<http_client::RequestHandle, Context>,
+	/// When things need doing
+	timers: HashMap<Timer, Instant>,
+	/// The next context we use for downward messages
+	next_ctx: Context,
+}
+
+type ReplyContext = grease::ReplyContext<Service>;
+
+/// Headers which only apply to one connection, so aren't passed on
+const HOP_BY_HOP: [&str; 9] = [
+	"connection",
+	"keep-alive",
+	"proxy-authenticate",
+	"proxy-authorization",
+	"proxy-connection",
+	"te",
+	"trailer",
+	"transfer-encoding",
+	"upgrade",
+];
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Creates a new proxy task. Returns an object that can be used to send
+/// this task messages.
+pub fn make_task(
+	http: grease::ServiceProviderHandle<http::Service>,
+	client: grease::ServiceProviderHandle<http_client::Service>,
+) -> Handle {
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx.clone());
+	std::thread::spawn(move || {
+		let mut t = TaskContext::new(http, client, handle);
+		loop {
+			let msg = match t.next_timeout() {
+				Some(timeout) => match rx.recv_timeout(timeout) {
+					Ok(msg) => Some(msg),
+					Err(mpsc::RecvTimeoutError::Timeout) => None,
+					Err(mpsc::RecvTimeoutError::Disconnected) => break,
+				},
+				None => match rx.recv() {
+					Ok(msg) => Some(msg),
+					Err(_) => break,
+				},
+			};
+			if let Some(msg) = msg {
+				t.handle(msg);
+			}
+			t.check_timers();
+		}
+		panic!("This task should never die!");
+	});
+	Handle(tx)
+}
+
+impl ProxyConfig {
+	/// A config with these routes, and no health checks
+	pub fn new(routes: Vec<Route>) -> ProxyConfig {
+		ProxyConfig {
+			routes,
+			health_check: None,
+		}
+	}
+
+	/// Check every route has upstreams we can use, and the health check
+	/// (if any) can happen
+	fn is_valid(&self) -> bool {
+		let routes_ok = self.routes.iter().all(|route| {
+			route.prefix.starts_with('/') && !route.upstreams.is_empty()
+				&& route
+					.host
+					.as_ref()
+					.map_or(true, |h| http::HostPattern::parse(h).is_some())
+				&& route.upstreams.iter().all(|u| {
+					u.scheme_part().map(|s| s.as_str()) == Some("http") && u.host().is_some()
+						&& u.query().is_none()
+				})
+		});
+		let check_ok = self.health_check.as_ref().map_or(true, |c| {
+			c.path.starts_with('/') && c.interval > Duration::from_secs(0)
+				&& c.timeout > Duration::from_secs(0) && c.fall > 0 && c.rise > 0
+		});
+		routes_ok && check_ok
+	}
+}
+
+impl Route {
+	/// A route for every host, which passes on paths as they are
+	pub fn new(prefix: &str, upstreams: Vec<Uri>) -> Route {
+		Route {
+			host: None,
+			prefix: prefix.to_owned(),
+			strip_prefix: false,
+			upstreams,
+		}
+	}
+
+	/// Does this route cover the given path?
+	fn covers(&self, path: &str) -> bool {
+		let prefix = self.prefix.trim_end_matches('/');
+		path == prefix || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+	}
+
+	/// Where a request for `url` goes on the given upstream
+	fn target(&self, upstream: &Uri, url: &Uri) -> Option<Uri> {
+		let path = url.path();
+		let rest = if self.strip_prefix {
+			&path[self.prefix.trim_end_matches('/').len()..]
+		} else {
+			path
+		};
+		let mut target = format!(
+			"http://{}{}{}",
+			upstream.authority_part()?,
+			upstream.path().trim_end_matches('/'),
+			if rest.is_empty() { "/" } else { rest }
+		);
+		if let Some(query) = url.query() {
+			target.push('?');
+			target.push_str(query);
+		}
+		target.parse().ok()
+	}
+}
+
+impl Default for HealthCheck {
+	fn default() -> HealthCheck {
+		HealthCheck {
+			path: String::from("/"),
+			interval: Duration::from_secs(10),
+			timeout: Duration::from_secs(2),
+			fall: 3,
+			rise: 2,
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl Server {
+	fn new(
+		config: ProxyConfig,
+		trusted_proxies: Vec<net::IpAddr>,
+		ind_to: grease::ServiceUserHandle<Service>,
+	) -> Server {
+		let patterns = config
+			.routes
+			.iter()
+			.map(|r| match r.host {
+				Some(ref host) => http::HostPattern::parse(host).unwrap(),
+				None => http::HostPattern::Any,
+			})
+			.collect();
+		let health = config
+			.routes
+			.iter()
+			.flat_map(|r| r.upstreams.iter())
+			.map(|u| {
+				(
+					u.to_string(),
+					Health {
+						healthy: true,
+						run: 0,
+					},
+				)
+			})
+			.collect();
+		Server {
+			next: vec![0; config.routes.len()],
+			config,
+			patterns,
+			health,
+			trusted_proxies,
+			ind_to,
+		}
+	}
+
+	/// Which route a request goes to: the best host match, then the
+	/// longest prefix, then the first listed.
+	fn route(&self, host: Option<&str>, path: &str) -> Option<usize> {
+		self.config
+			.routes
+			.iter()
+			.zip(self.patterns.iter())
+			.enumerate()
+			.rev()
+			.filter(|&(_, (route, _))| route.covers(path))
+			.filter_map(|(i, (route, pattern))| {
+				pattern
+					.score(host)
+					.map(|score| ((score, route.prefix.trim_end_matches('/').len()), i))
+			})
+			.max_by_key(|&(key, _)| key)
+			.map(|(_, i)| i)
+	}
+
+	/// The next healthy upstream for a route
+	fn pick(&mut self, route: usize) -> Option<Uri> {
+		let upstreams = &self.config.routes[route].upstreams;
+		let start = self.next[route];
+		for i in 0..upstreams.len() {
+			let index = (start + i) % upstreams.len();
+			let healthy = self.health
+				.get(&upstreams[index].to_string())
+				.map_or(true, |h| h.healthy);
+			if healthy {
+				self.next[route] = index + 1;
+				return Some(upstreams[index].clone());
+			}
+		}
+		None
+	}
+}
+
+/// All our handler functions are methods on this `TaskContext` structure.
+impl TaskContext {
+	/// Create a new TaskContext
+	fn new(
+		http: grease::ServiceProviderHandle<http::Service>,
+		client: grease::ServiceProviderHandle<http_client::Service>,
+		us: Handle,
+	) -> Self {
+		Self {
+			http,
+			client,
+			reply_to: us,
+			pending_binds: HashMap::new(),
+			servers: HashMap::new(),
+			exchanges: HashMap::new(),
+			connections: HashMap::new(),
+			probes: HashMap::new(),
+			requests: HashMap::new(),
+			timers: HashMap::new(),
+			// This number is arbitrary
+			next_ctx: grease::Context::new(7_000),
+		}
+	}
+
+	/// Handle an incoming message. It might a `Request` for us,
+	/// or it might be a `Confirm` or `Indication` from a lower layer.
+	fn handle(&mut self, msg: Incoming) {
+		match msg {
+			// We only handle our own requests and responses
+			Incoming::Request(x, reply_to) => {
+				debug!("Rx: {:?}", x);
+				self.handle_proxy_req(x, reply_to);
+			}
+			Incoming::HttpCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_cfm(x);
+			}
+			Incoming::HttpInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_ind(x);
+			}
+			Incoming::ClientCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_client_cfm(x);
+			}
+			Incoming::ClientInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_client_ind(x);
+			}
+			Incoming::Response(x) => {
+				debug!("Rx: {:?}", x);
+			}
+		}
+	}
+
+	fn handle_proxy_req(&mut self, req: Request, reply_to: grease::ServiceUserHandle<Service>) {
+		match req {
+			Request::Bind(x) => self.handle_bind(x, reply_to),
+		}
+	}
+
+	fn handle_http_cfm(&mut self, cfm: http::Confirm) {
+		match cfm {
+			http::Confirm::Bind(x) => self.handle_http_cfm_bind(x),
+			http::Confirm::ResponseStart(x) => self.handle_http_cfm_response_start(x),
+			http::Confirm::ResponseBody(x) => self.handle_http_cfm_response_body(x),
+			// There's nothing more to do if these fail
+			http::Confirm::Continue(_) | http::Confirm::Abort(_) => {}
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_http_ind(&mut self, ind: http::Indication) {
+		match ind {
+			http::Indication::RxRequest(x) => self.handle_http_ind_rx_request(x),
+			http::Indication::RxBody(x) => self.handle_http_ind_rx_body(x),
+			http::Indication::Closed(x) => self.handle_http_ind_closed(x),
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_client_cfm(&mut self, cfm: http_client::Confirm) {
+		match cfm {
+			http_client::Confirm::HttpRequest(x) => self.handle_client_cfm_http_request(x),
+			http_client::Confirm::RequestBody(x) => self.handle_client_cfm_request_body(x),
+		}
+	}
+
+	fn handle_client_ind(&mut self, ind: http_client::Indication) {
+		match ind {
+			http_client::Indication::ResponseStart(x) => {
+				self.handle_client_ind_response_start(x)
+			}
+			http_client::Indication::ResponseBody(x) => self.handle_client_ind_response_body(x),
+			http_client::Indication::Failed(x) => self.handle_client_ind_failed(x),
+		}
+	}
+
+	fn handle_bind(&mut self, req_bind: ReqBind, reply_to: grease::ServiceUserHandle<Service>) {
+		if !req_bind.proxy.is_valid() {
+			reply_to.send_confirm(
+				CfmBind {
+					context: req_bind.context,
+					result: Err(Error::BadConfig),
+				}.into(),
+			);
+			return;
+		}
+		let ctx = self.next_ctx.take();
+		self.pending_binds.insert(
+			ctx,
+			(
+				ReplyContext {
+					context: req_bind.context,
+					reply_to,
+				},
+				req_bind.proxy,
+				req_bind.config.trusted_proxies.clone(),
+			),
+		);
+		self.http.send_request(
+			http::ReqBind {
+				addr: req_bind.addr,
+				context: ctx,
+				config: http::ServerConfig {
+					body_flow_control: true,
+					..req_bind.config
+				},
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	fn handle_http_cfm_bind(&mut self, cfm_bind: http::CfmBind) {
+		if let Some((reply_ctx, config, trusted_proxies)) =
+			self.pending_binds.remove(&cfm_bind.context)
+		{
+			let result = match cfm_bind.result {
+				Ok(server) => {
+					if config.health_check.is_some() {
+						// Check straight away
+						self.timers.insert(Timer::Check(server), Instant::now());
+					}
+					self.servers.insert(
+						server,
+						Server::new(config, trusted_proxies, reply_ctx.reply_to.clone()),
+					);
+					Ok(server)
+				}
+				Err(e) => Err(Error::Http(e)),
+			};
+			reply_ctx.reply_to.send_confirm(
+				CfmBind {
+					context: reply_ctx.context,
+					result,
+				}.into(),
+			);
+		} else {
+			warn!("Context {} not found", cfm_bind.context);
+		}
+	}
+
+	fn handle_http_cfm_response_start(&mut self, cfm: http::CfmResponseStart) {
+		if let Err(e) = cfm.result {
+			// The head went nowhere, so the client gets nothing
+			warn!("Response on {} failed: {:?}", cfm.handle, e);
+			self.abort(cfm.handle);
+			self.detach(cfm.context);
+		}
+	}
+
+	fn handle_http_cfm_response_body(&mut self, cfm: http::CfmResponseBody) {
+		if cfm.result.is_err() {
+			self.detach(cfm.context);
+		} else if let Some(ex) = self.exchanges.get_mut(&cfm.context) {
+			if ex.owed {
+				ex.owed = false;
+				if let Some(request) = ex.request {
+					self.client
+						.send_response(http_client::RspResponseBody { handle: request }.into());
+				}
+			}
+		}
+	}
+
+	fn handle_http_ind_rx_request(&mut self, ind: http::IndRxRequest) {
+		if let Some(old) = self.connections.get(&ind.connection_handle).cloned() {
+			// The last response on this connection is done with
+			self.detach(old);
+		}
+		let target = match self.servers.get_mut(&ind.server_handle) {
+			Some(server) => {
+				let host = http::request_host(&ind.url, &ind.headers);
+				match server.route(host.as_ref().map(|h| &h[..]), ind.url.path()) {
+					Some(route) => match server.pick(route) {
+						Some(upstream) => server.config.routes[route]
+							.target(&upstream, &ind.url)
+							.ok_or(HttpResponseStatus::BadGateway),
+						None => Err(HttpResponseStatus::ServiceUnavailable),
+					},
+					None => Err(HttpResponseStatus::NotFound),
+				}
+			}
+			None => {
+				warn!("Request for unknown server {}", ind.server_handle);
+				return;
+			}
+		};
+		let uri = match target {
+			Ok(uri) => uri,
+			Err(status) => {
+				debug!("Answering {} on {} with {}", ind.url, ind.connection_handle, status);
+				self.send_error(ind.connection_handle, status);
+				return;
+			}
+		};
+		if ind.expects_continue() {
+			self.http.send_request(
+				http::ReqContinue {
+					handle: ind.connection_handle,
+					context: self.next_ctx.take(),
+					accept: true,
+				}.into(),
+				&self.reply_to,
+			);
+		}
+		let headers = forward_headers(&ind, &self.servers[&ind.server_handle].trusted_proxies);
+		let ctx = self.next_ctx.take();
+		debug!("Sending {} on {} to {}", ind.url, ind.connection_handle, uri);
+		self.client.send_request(
+			http_client::ReqHttpRequest {
+				context: ctx,
+				method: ind.method.clone(),
+				uri,
+				headers,
+				body: None,
+				stream_body: ind.has_body(),
+			}.into(),
+			&self.reply_to,
+		);
+		self.connections.insert(ind.connection_handle, ctx);
+		self.exchanges.insert(
+			ctx,
+			Exchange {
+				server: ind.server_handle,
+				conn: ind.connection_handle,
+				request: None,
+				body: VecDeque::new(),
+				sending: false,
+				rx_owed: false,
+				head: ind.method == Method::HEAD,
+				started: false,
+				unbounded: false,
+				discard: false,
+				owed: false,
+			},
+		);
+	}
+
+	fn handle_http_ind_rx_body(&mut self, ind: http::IndRxBody) {
+		if let Some(&ctx) = self.connections.get(&ind.handle) {
+			if let Some(ex) = self.exchanges.get_mut(&ctx) {
+				ex.body.push_back((ind.data, ind.last));
+			}
+			self.send_request_body(ctx);
+		}
+	}
+
+	fn handle_http_ind_closed(&mut self, ind: http::IndClosed) {
+		if let Some(ctx) = self.connections.get(&ind.handle).cloned() {
+			self.detach(ctx);
+		}
+	}
+
+	fn handle_client_cfm_http_request(&mut self, cfm: http_client::CfmHttpRequest) {
+		if !self.exchanges.contains_key(&cfm.context) {
+			// A health check, which may have timed out already
+			match cfm.result {
+				Ok(request) => {
+					// Even if no one wants it, the response must be drained
+					self.requests.insert(request, cfm.context);
+				}
+				Err(e) => {
+					debug!("Health check {} failed: {:?}", cfm.context, e);
+					self.end_probe(cfm.context, false);
+				}
+			}
+			return;
+		}
+		match cfm.result {
+			Ok(request) => {
+				if let Some(ex) = self.exchanges.get_mut(&cfm.context) {
+					ex.request = Some(request);
+					self.requests.insert(request, cfm.context);
+				}
+				self.send_request_body(cfm.context);
+			}
+			Err(e) => {
+				if let Some(ex) = self.exchanges.get(&cfm.context) {
+					warn!("Upstream request for {} failed: {:?}", ex.conn, e);
+					if !ex.discard {
+						self.send_error(ex.conn, HttpResponseStatus::BadGateway);
+					}
+				}
+				self.finish(cfm.context);
+			}
+		}
+	}
+
+	fn handle_client_cfm_request_body(&mut self, cfm: http_client::CfmRequestBody) {
+		if let Some(ex) = self.exchanges.get_mut(&cfm.context) {
+			ex.sending = false;
+			if let Err(e) = cfm.result {
+				// The response says how it went
+				debug!("Request body for {} not sent: {:?}", ex.conn, e);
+				ex.body.clear();
+			}
+			if ex.rx_owed {
+				// Now the client can send some more
+				ex.rx_owed = false;
+				self.http
+					.send_response(http::RspRxBody { handle: ex.conn }.into());
+			}
+		}
+		self.send_request_body(cfm.context);
+	}
+
+	fn handle_client_ind_response_start(&mut self, ind: http_client::IndResponseStart) {
+		if self.probes.contains_key(&ind.context) {
+			let ok = ind.status.is_success() || ind.status.is_redirection();
+			if !ok {
+				debug!("Health check got {}", ind.status);
+			}
+			self.end_probe(ind.context, ok);
+			return;
+		}
+		let (conn, head) = match self.exchanges.get_mut(&ind.context) {
+			Some(ex) => {
+				ex.started = true;
+				if ex.discard {
+					return;
+				}
+				(ex.conn, ex.head)
+			}
+			None => return,
+		};
+		let mut headers = ind.headers;
+		remove_hop_by_hop(&mut headers);
+		let content_type = headers
+			.remove("content-type")
+			.and_then(|v| v.to_str().ok().map(|s| s.to_owned()))
+			.unwrap_or_default();
+		let bodiless = head || ind.status.is_informational()
+			|| ind.status == StatusCode::NO_CONTENT
+			|| ind.status == StatusCode::NOT_MODIFIED;
+		// A HEAD or 304 gives the length a GET would have had, so that
+		// goes as it is
+		if !(head || ind.status == StatusCode::NOT_MODIFIED) {
+			headers.remove("content-length");
+		}
+		let length = if bodiless { Some(0) } else { ind.length };
+		if let Some(ex) = self.exchanges.get_mut(&ind.context) {
+			ex.unbounded = length.is_none();
+			ex.discard = length == Some(0);
+		}
+		self.http.send_request(
+			http::ReqResponseStart {
+				handle: conn,
+				context: ind.context,
+				status: HttpResponseStatus::from_code_or_class(ind.status),
+				content_type,
+				length,
+				headers,
+				compress: None,
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	fn handle_client_ind_response_body(&mut self, ind: http_client::IndResponseBody) {
+		let ctx = match self.requests.get(&ind.handle) {
+			Some(&ctx) => ctx,
+			None => {
+				warn!("Response body for unknown request {}", ind.handle);
+				return;
+			}
+		};
+		let (conn, discard, unbounded) = match self.exchanges.get(&ctx) {
+			Some(ex) => (ex.conn, ex.discard, ex.unbounded),
+			None => {
+				// A health check's body, which we don't need
+				if ind.last {
+					self.requests.remove(&ind.handle);
+				} else {
+					self.client
+						.send_response(http_client::RspResponseBody { handle: ind.handle }.into());
+				}
+				return;
+			}
+		};
+		if ind.last {
+			if !discard {
+				if !ind.data.is_empty() {
+					self.send_response_body(conn, ctx, ind.data);
+				}
+				if unbounded {
+					self.send_response_body(conn, ctx, Vec::new());
+				}
+			}
+			self.finish(ctx);
+		} else if discard || ind.data.is_empty() {
+			self.client
+				.send_response(http_client::RspResponseBody { handle: ind.handle }.into());
+		} else {
+			self.send_response_body(conn, ctx, ind.data);
+			if let Some(ex) = self.exchanges.get_mut(&ctx) {
+				ex.owed = true;
+			}
+		}
+	}
+
+	fn handle_client_ind_failed(&mut self, ind: http_client::IndFailed) {
+		let ctx = match self.requests.get(&ind.handle) {
+			Some(&ctx) => ctx,
+			None => return,
+		};
+		if !self.exchanges.contains_key(&ctx) {
+			debug!("Health check {} failed: {:?}", ctx, ind.error);
+			self.end_probe(ctx, false);
+			self.requests.remove(&ind.handle);
+			return;
+		}
+		if let Some(ex) = self.exchanges.get(&ctx) {
+			warn!("Upstream failed for {}: {:?}", ex.conn, ind.error);
+			if ex.discard {
+				// No one to tell, or they've had all they were getting
+			} else if !ex.started {
+				self.send_error(ex.conn, HttpResponseStatus::BadGateway);
+			} else {
+				// Too late for an error page
+				self.abort(ex.conn);
+			}
+		}
+		self.finish(ctx);
+	}
+
+	/// Send the next piece of request body upstream, if we can
+	fn send_request_body(&mut self, ctx: Context) {
+		if let Some(ex) = self.exchanges.get_mut(&ctx) {
+			if let (Some(handle), false) = (ex.request, ex.sending) {
+				if let Some((data, last)) = ex.body.pop_front() {
+					ex.sending = true;
+					ex.rx_owed = !last;
+					self.client.send_request(
+						http_client::ReqRequestBody {
+							handle,
+							context: ctx,
+							data,
+							last,
+						}.into(),
+						&self.reply_to,
+					);
+				}
+			}
+		}
+	}
+
+	fn send_response_body(&mut self, conn: http::ConnHandle, ctx: Context, data: Vec<u8>) {
+		self.http.send_request(
+			http::ReqResponseBody {
+				handle: conn,
+				context: ctx,
+				data,
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	/// Answer a request ourselves
+	fn send_error(&mut self, handle: http::ConnHandle, status: HttpResponseStatus) {
+		let ctx = self.next_ctx.take();
+		http::send_error(&*self.http, &self.reply_to, handle, ctx, status, HeaderMap::new());
+	}
+
+	/// Reset a connection whose response has gone wrong
+	fn abort(&mut self, handle: http::ConnHandle) {
+		self.http.send_request(
+			http::ReqAbort {
+				handle,
+				context: self.next_ctx.take(),
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	/// The downstream connection has gone (or moved on), so throw the rest
+	/// of the response away
+	fn detach(&mut self, ctx: Context) {
+		if let Some(ex) = self.exchanges.get_mut(&ctx) {
+			if self.connections.get(&ex.conn) == Some(&ctx) {
+				self.connections.remove(&ex.conn);
+			}
+			ex.discard = true;
+			ex.unbounded = false;
+			ex.body.clear();
+			if ex.owed {
+				ex.owed = false;
+				if let Some(request) = ex.request {
+					self.client
+						.send_response(http_client::RspResponseBody { handle: request }.into());
+				}
+			}
+		}
+	}
+
+	/// The exchange is over
+	fn finish(&mut self, ctx: Context) {
+		if let Some(ex) = self.exchanges.remove(&ctx) {
+			if self.connections.get(&ex.conn) == Some(&ctx) {
+				self.connections.remove(&ex.conn);
+			}
+			if let Some(request) = ex.request {
+				self.requests.remove(&request);
+			}
+			trace!("Finished {} on server {}", ctx, ex.server);
+		}
+	}
+
+	/// Start a health check of an upstream
+	fn start_probe(&mut self, server: ServerHandle, upstream: Uri, check: &HealthCheck) {
+		let uri = format!(
+			"http://{}{}{}",
+			upstream.authority_part().unwrap(),
+			upstream.path().trim_end_matches('/'),
+			check.path
+		);
+		let uri = match uri.parse() {
+			Ok(uri) => uri,
+			Err(_) => {
+				warn!("Can't check {}{}", upstream, check.path);
+				return;
+			}
+		};
+		let ctx = self.next_ctx.take();
+		self.client.send_request(
+			http_client::ReqHttpRequest {
+				context: ctx,
+				method: Method::GET,
+				uri,
+				headers: HeaderMap::new(),
+				body: None,
+				stream_body: false,
+			}.into(),
+			&self.reply_to,
+		);
+		self.timers
+			.insert(Timer::Probe(ctx), Instant::now() + check.timeout);
+		self.probes.insert(
+			ctx,
+			Probe {
+				server,
+				upstream,
+			},
+		);
+	}
+
+	/// We know how a health check went, so the upstream can be checked
+	/// again. Anything more we hear about the check is only drained.
+	fn end_probe(&mut self, ctx: Context, ok: bool) {
+		self.timers.remove(&Timer::Probe(ctx));
+		let (server_handle, upstream) = match self.probes.remove(&ctx) {
+			Some(probe) => (probe.server, probe.upstream),
+			None => return,
+		};
+		let server = match self.servers.get_mut(&server_handle) {
+			Some(server) => server,
+			None => return,
+		};
+		let threshold = match server.config.health_check {
+			Some(ref check) => {
+				if server.health[&upstream.to_string()].healthy {
+					check.fall
+				} else {
+					check.rise
+				}
+			}
+			None => return,
+		};
+		let health = server.health.get_mut(&upstream.to_string()).unwrap();
+		if ok == health.healthy {
+			health.run = 0;
+			return;
+		}
+		health.run += 1;
+		if health.run >= threshold {
+			health.healthy = ok;
+			health.run = 0;
+			if ok {
+				info!("Upstream {} is back", upstream);
+			} else {
+				warn!("Upstream {} is down", upstream);
+			}
+			server.ind_to.send_indication(
+				IndUpstreamHealth {
+					server: server_handle,
+					upstream,
+					healthy: ok,
+				}.into(),
+			);
+		}
+	}
+
+	/// How long until the next timer goes off
+	fn next_timeout(&self) -> Option<Duration> {
+		let now = Instant::now();
+		self.timers
+			.values()
+			.min()
+			.map(|t| if *t > now { *t - now } else { Duration::from_secs(0) })
+	}
+
+	/// Deal with any timers which have gone off.
+	fn check_timers(&mut self) {
+		let now = Instant::now();
+		let expired: Vec<Timer> = self.timers
+			.iter()
+			.filter(|&(_, t)| *t <= now)
+			.map(|(k, _)| *k)
+			.collect();
+		for timer in expired {
+			self.timers.remove(&timer);
+			match timer {
+				Timer::Check(server) => self.check_server(server),
+				Timer::Probe(ctx) => {
+					debug!("Health check {} timed out", ctx);
+					self.end_probe(ctx, false);
+				}
+			}
+		}
+	}
+
+	/// Check every upstream of a server which isn't already being checked
+	fn check_server(&mut self, server_handle: ServerHandle) {
+		let (check, upstreams) = match self.servers.get(&server_handle) {
+			Some(server) => match server.config.health_check {
+				Some(ref check) => {
+					let mut upstreams: Vec<Uri> = Vec::new();
+					for upstream in server.config.routes.iter().flat_map(|r| r.upstreams.iter()) {
+						let busy = self.probes
+							.values()
+							.any(|p| p.server == server_handle && p.upstream == *upstream);
+						if !busy && !upstreams.contains(upstream) {
+							upstreams.push(upstream.clone());
+						}
+					}
+					(check.clone(), upstreams)
+				}
+				None => return,
+			},
+			None => return,
+		};
+		for upstream in upstreams {
+			self.start_probe(server_handle, upstream, &check);
+		}
+		self.timers
+			.insert(Timer::Check(server_handle), Instant::now() + check.interval);
+	}
+}
+
+/// The headers to send upstream: the request's own, less the hop-by-hop
+/// ones and `Host`, plus the `X-Forwarded-*` headers
+fn forward_headers(ind: &http::IndRxRequest, trusted_proxies: &[net::IpAddr]) -> HeaderMap {
+	let mut headers = ind.headers.clone();
+	remove_hop_by_hop(&mut headers);
+	// The http_client task gives the upstream's Host, and we can't do
+	// anything with a 100 Continue
+	let host = headers.remove("host");
+	headers.remove("expect");
+	let trusted = trusted_proxies.contains(&ind.peer.ip());
+
+	let mut forwarded_for: Vec<String> = headers
+		.get_all("x-forwarded-for")
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.map(|v| v.to_owned())
+		.collect();
+	forwarded_for.push(ind.peer.ip().to_string());
+	if let Ok(value) = HeaderValue::from_str(&forwarded_for.join(", ")) {
+		headers.insert("x-forwarded-for", value);
+	}
+	if !trusted || !headers.contains_key("x-forwarded-proto") {
+		headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+	}
+	if !trusted || !headers.contains_key("x-forwarded-host") {
+		match host {
+			Some(host) => {
+				headers.insert("x-forwarded-host", host);
+			}
+			None => {
+				headers.remove("x-forwarded-host");
+			}
+		}
+	}
+	headers
+}
+
+/// Remove the headers which only apply to one connection, including any
+/// listed in the `Connection` header
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+	let listed: Vec<String> = headers
+		.get_all("connection")
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.map(|name| name.trim().to_ascii_lowercase())
+		.filter(|name| !name.is_empty())
+		.collect();
+	for name in listed.iter().map(|n| &n[..]).chain(HOP_BY_HOP.iter().cloned()) {
+		headers.remove(name);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::mpsc;
+
+	use grease::prelude::*;
+
+	enum TestIncoming {
+		ProxyCfm(Confirm),
+		ProxyInd(Indication),
+		HttpReq(http::Request, grease::ServiceUserHandle<http::Service>),
+		HttpRsp(http::Response),
+		ClientReq(http_client::Request, grease::ServiceUserHandle<http_client::Service>),
+		ClientRsp(http_client::Response),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::ProxyCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: Indication) {
+			self.0.send(TestIncoming::ProxyInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<http::Service> for TestHandle {
+		fn send_request(&self, req: http::Request, reply_to: &grease::ServiceUser<http::Service>) {
+			self.0
+				.send(TestIncoming::HttpReq(req, reply_to.clone()))
+				.unwrap();
+		}
+		fn send_response(&self, rsp: http::Response) {
+			self.0.send(TestIncoming::HttpRsp(rsp)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceProviderHandle<http::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<http_client::Service> for TestHandle {
+		fn send_request(
+			&self,
+			req: http_client::Request,
+			reply_to: &grease::ServiceUser<http_client::Service>,
+		) {
+			self.0
+				.send(TestIncoming::ClientReq(req, reply_to.clone()))
+				.unwrap();
+		}
+		fn send_response(&self, rsp: http_client::Response) {
+			self.0.send(TestIncoming::ClientRsp(rsp)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceProviderHandle<http_client::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
+		let (test_tx, rx) = mpsc::channel();
+		(TestHandle(test_tx), rx)
+	}
+
+	fn uris(list: &[&str]) -> Vec<Uri> {
+		list.iter().map(|u| u.parse().unwrap()).collect()
+	}
+
+	/// Bind a server through the proxy task, using ourselves as the http
+	/// task.
+	fn bind(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		proxy: &Handle,
+		server: ServerHandle,
+		config: ProxyConfig,
+	) -> grease::ServiceUserHandle<http::Service> {
+		proxy.send_request(
+			ReqBind {
+				addr: "127.0.0.1:8000".parse().unwrap(),
+				context: Context::new(1),
+				config: http::ServerConfig::default(),
+				proxy: config,
+			}.into(),
+			this_thread,
+		);
+		let http_south = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::Bind(ref x), ref reply_to) => {
+				assert!(x.config.body_flow_control);
+				reply_to.send_confirm(
+					http::CfmBind {
+						context: x.context,
+						result: Ok(server),
+					}.into(),
+				);
+				(*reply_to).clone()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ProxyCfm(Confirm::Bind(ref x)) => {
+				assert_eq!(x.context, Context::new(1));
+				assert_eq!(x.result.as_ref().unwrap(), &server);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		http_south
+	}
+
+	fn rx_request(
+		server: ServerHandle,
+		conn: http::ConnHandle,
+		method: Method,
+		url: &str,
+		headers: HeaderMap,
+	) -> http::IndRxRequest {
+		let body = headers.contains_key("content-length");
+		http::IndRxRequest {
+			server_handle: server,
+			connection_handle: conn,
+			peer: "192.0.2.1:56789".parse().unwrap(),
+			client: "192.0.2.1".parse().unwrap(),
+			body,
+			..http::IndRxRequest::for_test(method, url, headers)
+		}
+	}
+
+	/// The proxy should send an upstream request; return it and who to
+	/// answer
+	fn expect_upstream(
+		test_rx: &mpsc::Receiver<TestIncoming>,
+	) -> (
+		http_client::ReqHttpRequest,
+		grease::ServiceUserHandle<http_client::Service>,
+	) {
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientReq(http_client::Request::HttpRequest(x), reply_to) => {
+				(x, reply_to)
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	/// The proxy should answer a request itself
+	fn expect_error(test_rx: &mpsc::Receiver<TestIncoming>, status: HttpResponseStatus) {
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.status, status);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(ref x), _) => {
+				assert_eq!(x.data, format!("{}\r\n", status).into_bytes());
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	/// Answer a request with an empty 200
+	fn answer(
+		reply_to: &grease::ServiceUserHandle<http_client::Service>,
+		ctx: Context,
+		handle: http_client::RequestHandle,
+	) {
+		reply_to.send_confirm(
+			http_client::CfmHttpRequest {
+				context: ctx,
+				result: Ok(handle),
+			}.into(),
+		);
+		reply_to.send_indication(
+			http_client::IndResponseStart {
+				handle,
+				context: ctx,
+				status: StatusCode::OK,
+				headers: HeaderMap::new(),
+				length: Some(0),
+			}.into(),
+		);
+		reply_to.send_indication(
+			http_client::IndResponseBody {
+				handle,
+				data: Vec::new(),
+				last: true,
+			}.into(),
+		);
+	}
+
+	#[test]
+	fn routing() {
+		let (reply_to, _test_rx) = make_test_channel();
+		let config = ProxyConfig::new(vec![
+			Route::new("/", uris(&["http://default"])),
+			Route {
+				strip_prefix: true,
+				..Route::new("/api/", uris(&["http://api-1/v1", "http://api-2:81/"]))
+			},
+			Route::new("/api/admin", uris(&["http://admin"])),
+			Route {
+				host: Some(String::from("*.example.com")),
+				..Route::new("/", uris(&["http://example"]))
+			},
+		]);
+		assert!(config.is_valid());
+		let mut server = Server::new(config, Vec::new(), grease::ServiceUser::clone(&reply_to));
+		assert_eq!(server.route(None, "/"), Some(0));
+		assert_eq!(server.route(None, "/apis"), Some(0));
+		assert_eq!(server.route(None, "/api"), Some(1));
+		assert_eq!(server.route(Some("localhost"), "/api/x"), Some(1));
+		assert_eq!(server.route(None, "/api/admin/users"), Some(2));
+		assert_eq!(server.route(Some("www.example.com"), "/api/x"), Some(3));
+
+		// Round robin, skipping unhealthy upstreams
+		assert_eq!(server.pick(1), Some("http://api-1/v1".parse().unwrap()));
+		assert_eq!(server.pick(1), Some("http://api-2:81/".parse().unwrap()));
+		assert_eq!(server.pick(1), Some("http://api-1/v1".parse().unwrap()));
+		server.health.get_mut("http://api-1/v1").unwrap().healthy = false;
+		assert_eq!(server.pick(1), Some("http://api-2:81/".parse().unwrap()));
+		assert_eq!(server.pick(1), Some("http://api-2:81/".parse().unwrap()));
+		server.health.get_mut("http://api-2:81/").unwrap().healthy = false;
+		assert_eq!(server.pick(1), None);
+
+		let target = |route: usize, upstream: &str, url: &str| {
+			server.config.routes[route]
+				.target(&upstream.parse().unwrap(), &url.parse().unwrap())
+				.unwrap()
+				.to_string()
+		};
+		assert_eq!(target(1, "http://api-1/v1", "/api/x?a=b"), "http://api-1/v1/x?a=b");
+		assert_eq!(target(1, "http://api-2:81/", "/api"), "http://api-2:81/");
+		assert_eq!(target(2, "http://admin", "/api/admin"), "http://admin/api/admin");
+
+		let bad = vec![
+			Route::new("api", uris(&["http://api"])),
+			Route::new("/", Vec::new()),
+			Route::new("/", uris(&["https://api"])),
+			Route::new("/", uris(&["/relative"])),
+			Route {
+				host: Some(String::from("bad host")),
+				..Route::new("/", uris(&["http://api"]))
+			},
+		];
+		for route in bad {
+			assert!(!ProxyConfig::new(vec![route]).is_valid());
+		}
+		let config = ProxyConfig {
+			health_check: Some(HealthCheck {
+				fall: 0,
+				..HealthCheck::default()
+			}),
+			..ProxyConfig::new(Vec::new())
+		};
+		assert!(!config.is_valid());
+	}
+
+	#[test]
+	fn headers() {
+		let mut headers = HeaderMap::new();
+		headers.insert("host", HeaderValue::from_static("www.example.com"));
+		headers.insert("connection", HeaderValue::from_static("keep-alive, X-Secret"));
+		headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+		headers.insert("x-secret", HeaderValue::from_static("shh"));
+		headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+		headers.insert("expect", HeaderValue::from_static("100-continue"));
+		headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+		headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+		headers.insert("accept", HeaderValue::from_static("*/*"));
+		let mut ind = rx_request(Context::new(10), Context::new(20), Method::GET, "/", headers);
+		let forwarded = forward_headers(&ind, &[]);
+		let mut names: Vec<&str> = forwarded.keys().map(|k| k.as_str()).collect();
+		names.sort();
+		assert_eq!(
+			names,
+			vec!["accept", "x-forwarded-for", "x-forwarded-host", "x-forwarded-proto"]
+		);
+		assert_eq!(forwarded["x-forwarded-for"], "203.0.113.7, 192.0.2.1");
+		// The peer isn't trusted, so it doesn't get to say
+		assert_eq!(forwarded["x-forwarded-proto"], "http");
+		assert_eq!(forwarded["x-forwarded-host"], "www.example.com");
+
+		// From a trusted proxy
+		let trusted = ["192.0.2.1".parse().unwrap()];
+		ind.headers.remove("host");
+		let forwarded = forward_headers(&ind, &trusted);
+		assert_eq!(forwarded["x-forwarded-proto"], "https");
+		assert!(!forwarded.contains_key("x-forwarded-host"));
+
+		// Which is trusted even if it doesn't say who its client was
+		ind.headers.remove("x-forwarded-for");
+		ind.headers
+			.insert("x-forwarded-host", HeaderValue::from_static("www.example.org"));
+		let forwarded = forward_headers(&ind, &trusted);
+		assert_eq!(forwarded["x-forwarded-for"], "192.0.2.1");
+		assert_eq!(forwarded["x-forwarded-proto"], "https");
+		assert_eq!(forwarded["x-forwarded-host"], "www.example.org");
+	}
+
+	#[test]
+	fn proxying() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(
+			grease::ServiceProvider::clone(&reply_to),
+			grease::ServiceProvider::clone(&reply_to),
+		);
+		let config = ProxyConfig::new(vec![
+			Route {
+				strip_prefix: true,
+				..Route::new("/api", uris(&["http://10.0.0.1:8080/v1"]))
+			},
+			Route::new("/files", uris(&["http://files-1", "http://files-2"])),
+		]);
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &task, server, config);
+
+		// A request with a body goes upstream
+		let mut headers = HeaderMap::new();
+		headers.insert("host", HeaderValue::from_static("proxy.test"));
+		headers.insert("content-length", HeaderValue::from_static("5"));
+		headers.insert("connection", HeaderValue::from_static("keep-alive"));
+		let conn = Context::new(20);
+		http_south.send_indication(
+			rx_request(server, conn, Method::POST, "/api/items?x=1", headers).into(),
+		);
+		let (req, client_south) = expect_upstream(&test_rx);
+		assert_eq!(req.uri, "http://10.0.0.1:8080/v1/items?x=1");
+		assert_eq!(req.method, Method::POST);
+		assert!(req.stream_body);
+		assert!(req.body.is_none());
+		assert_eq!(req.headers["content-length"], "5");
+		assert_eq!(req.headers["x-forwarded-host"], "proxy.test");
+		assert!(!req.headers.contains_key("connection"));
+		assert!(!req.headers.contains_key("host"));
+
+		// The body waits for the cfm
+		http_south.send_indication(
+			http::IndRxBody {
+				handle: conn,
+				data: b"hel".to_vec(),
+				last: false,
+			}.into(),
+		);
+		http_south.send_indication(
+			http::IndRxBody {
+				handle: conn,
+				data: b"lo".to_vec(),
+				last: true,
+			}.into(),
+		);
+		let handle = Context::new(30);
+		client_south.send_confirm(
+			http_client::CfmHttpRequest {
+				context: req.context,
+				result: Ok(handle),
+			}.into(),
+		);
+		for &(data, last) in &[(&b"hel"[..], false), (&b"lo"[..], true)] {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::ClientReq(http_client::Request::RequestBody(ref x), _) => {
+					assert_eq!(x.handle, handle);
+					assert_eq!(x.data, data);
+					assert_eq!(x.last, last);
+					client_south.send_confirm(
+						http_client::CfmRequestBody {
+							handle,
+							context: x.context,
+							result: Ok(()),
+						}.into(),
+					);
+				}
+				_ => panic!("Unexpected message"),
+			}
+			if !last {
+				// The upstream has it, so the client can send more
+				match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+					TestIncoming::HttpRsp(http::Response::RxBody(ref x)) => {
+						assert_eq!(x.handle, conn);
+					}
+					_ => panic!("Unexpected message"),
+				}
+			}
+		}
+
+		// The response comes back, a piece at a time
+		let mut headers = HeaderMap::new();
+		headers.insert("content-type", HeaderValue::from_static("text/plain"));
+		headers.insert("content-length", HeaderValue::from_static("6"));
+		headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+		headers.insert("x-upstream", HeaderValue::from_static("yes"));
+		client_south.send_indication(
+			http_client::IndResponseStart {
+				handle,
+				context: req.context,
+				status: StatusCode::CREATED,
+				headers,
+				length: Some(6),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.handle, conn);
+				assert_eq!(x.status, HttpResponseStatus::Created);
+				assert_eq!(x.content_type, "text/plain");
+				assert_eq!(x.length, Some(6));
+				let names: Vec<&str> = x.headers.keys().map(|k| k.as_str()).collect();
+				assert_eq!(names, vec!["x-upstream"]);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		client_south.send_indication(
+			http_client::IndResponseBody {
+				handle,
+				data: b"abc".to_vec(),
+				last: false,
+			}.into(),
+		);
+		let http_ctx = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(ref x), _) => {
+				assert_eq!(x.data, b"abc");
+				x.context
+			}
+			_ => panic!("Unexpected message"),
+		};
+		// No more until the http task has sent it
+		assert!(test_rx.recv_timeout(Duration::from_millis(50)).is_err());
+		http_south.send_confirm(
+			http::CfmResponseBody {
+				handle: conn,
+				context: http_ctx,
+				result: Ok(()),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientRsp(http_client::Response::ResponseBody(ref x)) => {
+				assert_eq!(x.handle, handle);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		client_south.send_indication(
+			http_client::IndResponseBody {
+				handle,
+				data: b"def".to_vec(),
+				last: true,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(ref x), _) => {
+				assert_eq!(x.data, b"def");
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// Nowhere to go
+		http_south.send_indication(
+			rx_request(server, Context::new(21), Method::GET, "/nowhere", HeaderMap::new())
+				.into(),
+		);
+		expect_error(&test_rx, HttpResponseStatus::NotFound);
+
+		// Round robin, and an upstream which can't be reached
+		http_south.send_indication(
+			rx_request(server, Context::new(22), Method::GET, "/files/a", HeaderMap::new())
+				.into(),
+		);
+		let (req, client_south) = expect_upstream(&test_rx);
+		assert_eq!(req.uri, "http://files-1/files/a");
+		client_south.send_confirm(
+			http_client::CfmHttpRequest {
+				context: req.context,
+				result: Err(http_client::Error::Resolve),
+			}.into(),
+		);
+		expect_error(&test_rx, HttpResponseStatus::BadGateway);
+
+		// An upstream which fails half way through, with an unknown length
+		let conn = Context::new(23);
+		http_south.send_indication(
+			rx_request(server, conn, Method::GET, "/files/b", HeaderMap::new()).into(),
+		);
+		let (req, client_south) = expect_upstream(&test_rx);
+		assert_eq!(req.uri, "http://files-2/files/b");
+		assert!(!req.stream_body);
+		let handle = Context::new(31);
+		client_south.send_confirm(
+			http_client::CfmHttpRequest {
+				context: req.context,
+				result: Ok(handle),
+			}.into(),
+		);
+		client_south.send_indication(
+			http_client::IndResponseStart {
+				handle,
+				context: req.context,
+				status: StatusCode::MISDIRECTED_REQUEST,
+				headers: HeaderMap::new(),
+				length: None,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.status, HttpResponseStatus::BadRequest);
+				assert_eq!(x.length, None);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		client_south.send_indication(
+			http_client::IndFailed {
+				handle,
+				error: http_client::Error::Dropped,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::Abort(ref x), _) => {
+				assert_eq!(x.handle, conn);
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// If the client goes, the response is drained
+		let conn = Context::new(24);
+		http_south.send_indication(
+			rx_request(server, conn, Method::GET, "/api", HeaderMap::new()).into(),
+		);
+		let (req, client_south) = expect_upstream(&test_rx);
+		let handle = Context::new(32);
+		client_south.send_confirm(
+			http_client::CfmHttpRequest {
+				context: req.context,
+				result: Ok(handle),
+			}.into(),
+		);
+		http_south.send_indication(http::IndClosed { handle: conn }.into());
+		client_south.send_indication(
+			http_client::IndResponseStart {
+				handle,
+				context: req.context,
+				status: StatusCode::OK,
+				headers: HeaderMap::new(),
+				length: None,
+			}.into(),
+		);
+		client_south.send_indication(
+			http_client::IndResponseBody {
+				handle,
+				data: b"ignored".to_vec(),
+				last: false,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientRsp(http_client::Response::ResponseBody(ref x)) => {
+				assert_eq!(x.handle, handle);
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	fn health_checks() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(
+			grease::ServiceProvider::clone(&reply_to),
+			grease::ServiceProvider::clone(&reply_to),
+		);
+		let config = ProxyConfig {
+			health_check: Some(HealthCheck {
+				path: String::from("/health"),
+				interval: Duration::from_millis(500),
+				timeout: Duration::from_millis(50),
+				fall: 1,
+				rise: 1,
+			}),
+			..ProxyConfig::new(vec![
+				Route::new("/", uris(&["http://up-1", "http://up-2/base/"])),
+			])
+		};
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &task, server, config);
+
+		// Both are checked straight away. One passes, and the other never
+		// answers.
+		let (probe_1, client_south) = expect_upstream(&test_rx);
+		assert_eq!(probe_1.uri, "http://up-1/health");
+		let (probe_2, _) = expect_upstream(&test_rx);
+		assert_eq!(probe_2.uri, "http://up-2/base/health");
+		answer(&client_south, probe_1.context, Context::new(40));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ProxyInd(Indication::UpstreamHealth(ref x)) => {
+				assert_eq!(x.server, server);
+				assert_eq!(x.upstream, "http://up-2/base/");
+				assert!(!x.healthy);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		client_south.send_confirm(
+			http_client::CfmHttpRequest {
+				context: probe_2.context,
+				result: Err(http_client::Error::Resolve),
+			}.into(),
+		);
+
+		// So everything goes to the other one
+		for conn in 20..22 {
+			http_south.send_indication(
+				rx_request(server, Context::new(conn), Method::GET, "/", HeaderMap::new())
+					.into(),
+			);
+			let (req, _) = expect_upstream(&test_rx);
+			assert_eq!(req.uri, "http://up-1/");
+		}
+
+		// Until it passes a check
+		let (probe_1, _) = expect_upstream(&test_rx);
+		assert_eq!(probe_1.uri, "http://up-1/health");
+		let (probe_2, _) = expect_upstream(&test_rx);
+		assert_eq!(probe_2.uri, "http://up-2/base/health");
+		answer(&client_south, probe_1.context, Context::new(41));
+		answer(&client_south, probe_2.context, Context::new(42));
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ProxyInd(Indication::UpstreamHealth(ref x)) => {
+				assert_eq!(x.upstream, "http://up-2/base/");
+				assert!(x.healthy);
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	/// An upstream which takes the checks but never answers is taken out of
+	/// service once `fall` of them have timed out
+	fn unanswered_health_checks() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(
+			grease::ServiceProvider::clone(&reply_to),
+			grease::ServiceProvider::clone(&reply_to),
+		);
+		let config = ProxyConfig {
+			health_check: Some(HealthCheck {
+				interval: Duration::from_millis(300),
+				timeout: Duration::from_millis(50),
+				fall: 2,
+				rise: 1,
+				..HealthCheck::default()
+			}),
+			..ProxyConfig::new(vec![Route::new("/", uris(&["http://up-1"]))])
+		};
+		let server = Context::new(10);
+		bind(&reply_to, &test_rx, &task, server, config);
+
+		// Each interval brings a new check, as the last one has timed out
+		let mut probes = Vec::new();
+		for handle in 40..42 {
+			let (probe, client_south) = expect_upstream(&test_rx);
+			assert_eq!(probe.uri, "http://up-1/");
+			client_south.send_confirm(
+				http_client::CfmHttpRequest {
+					context: probe.context,
+					result: Ok(Context::new(handle)),
+				}.into(),
+			);
+			probes.push((probe.context, Context::new(handle), client_south));
+		}
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ProxyInd(Indication::UpstreamHealth(ref x)) => {
+				assert_eq!(x.upstream, "http://up-1");
+				assert!(!x.healthy);
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// A late answer is drained, and doesn't count
+		let (context, handle, ref client_south) = probes[0];
+		client_south.send_indication(
+			http_client::IndResponseStart {
+				handle,
+				context,
+				status: StatusCode::OK,
+				headers: HeaderMap::new(),
+				length: None,
+			}.into(),
+		);
+		client_south.send_indication(
+			http_client::IndResponseBody {
+				handle,
+				data: b"ok".to_vec(),
+				last: false,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ClientRsp(http_client::Response::ResponseBody(ref x)) => {
+				assert_eq!(x.handle, handle);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		client_south.send_indication(
+			http_client::IndResponseBody {
+				handle,
+				data: Vec::new(),
+				last: true,
+			}.into(),
+		);
+		let (probe, _) = expect_upstream(&test_rx);
+		assert_eq!(probe.uri, "http://up-1/");
+	}
+
+	#[test]
+	fn bad_config() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(
+			grease::ServiceProvider::clone(&reply_to),
+			grease::ServiceProvider::clone(&reply_to),
+		);
+		task.send_request(
+			ReqBind {
+				addr: "127.0.0.1:8000".parse().unwrap(),
+				context: Context::new(1),
+				config: http::ServerConfig::default(),
+				proxy: ProxyConfig::new(vec![Route::new("/", Vec::new())]),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::ProxyCfm(Confirm::Bind(ref x)) => {
+				assert!(match x.result {
+					Err(Error::BadConfig) => true,
+					_ => false,
+				});
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,148 @@
+//! # router - a grease example using the router, http and sockets
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate env_logger;
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+extern crate grease_router as router;
+extern crate grease_socket as socket;
+#[macro_use]
+extern crate log;
+
+use std::net;
+use std::sync::mpsc;
+
+use grease::prelude::*;
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+struct Handle(mpsc::Sender<Incoming>);
+
+app_map! {
+	generate: Incoming,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd),
+		router: (Service, RouterCfm, RouterInd)
+	}
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Start of our example program
+fn main() {
+	env_logger::init();
+	let bind_addr: net::SocketAddr = "0.0.0.0:8000".parse().unwrap();
+
+	info!("Hello, this is the grease router example.");
+	info!("Running HTTP server on {}", bind_addr);
+
+	let socket_thread = socket::make_task();
+	let http_thread = http::make_task(socket_thread.clone());
+	let router_thread = router::make_task(grease::ServiceProvider::clone(&http_thread));
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx);
+
+	router_thread.send_request(
+		router::ReqBind {
+			addr: bind_addr,
+			context: Context::default(),
+			config: http::ServerConfig::default(),
+		}.into(),
+		&handle,
+	);
+
+	let mut n: Context = Context::default();
+
+	for msg in rx.iter() {
+		match msg {
+			Incoming::RouterCfm(router::Confirm::Bind(cfm)) => {
+				let server = cfm.result.unwrap();
+				for pattern in &["/", "/hello/:name", "/files/*path"] {
+					router_thread.send_request(
+						router::ReqAddRoute {
+							server,
+							context: n.take(),
+							method: http::Method::GET,
+							pattern: String::from(*pattern),
+						}.into(),
+						&handle,
+					);
+				}
+			}
+			Incoming::RouterInd(router::Indication::RxRequest(ind)) => {
+				info!(
+					"Got HTTP request {:?} {} {:?}",
+					ind.request.method, ind.request.url, ind.params
+				);
+				let body_msg = match ind.params.get("name") {
+					Some(name) => format!("Hello, {}!\r\n", name),
+					None => format!("You used URL '{}'\r\n", ind.request.url),
+				};
+				let ctx = n.take();
+				let start = http::ReqResponseStart {
+					status: http::HttpResponseStatus::OK,
+					handle: ind.request.connection_handle,
+					context: ctx,
+					content_type: String::from("text/plain"),
+					length: Some(body_msg.len()),
+					headers: http::HeaderMap::new(),
+					compress: None,
+				};
+				http_thread.send_request(start.into(), &handle);
+				let body = http::ReqResponseBody {
+					handle: ind.request.connection_handle,
+					context: ctx,
+					data: body_msg.into_bytes(),
+				};
+				http_thread.send_request(body.into(), &handle);
+			}
+			_ => {}
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1232 @@
+//! # router - A URL routing task
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! This task sits on top of the `http` task and works out which of its users
+//! should handle each HTTP request. It is the "WebServ" layer from the
+//! README.
+//!
+//! A `ReqBind` asks the router to bind an HTTP server. Any number of service
+//! users can then send a `ReqAddRoute` against that server, giving a method
+//! and a path pattern. When the http task indicates a new request, the
+//! router finds the best matching route and sends an `IndRxRequest` to
+//! whoever added it, along with any parameters extracted from the path. If
+//! no route matches the path we answer with a 404, and if routes match the
+//! path but not the method, we answer with a 405 and an `Allow` header.
+//!
+//! If the server is bound with `auto.options` set, the router answers
+//! `OPTIONS` requests itself, listing the methods of the routes which match
+//! the path (or of every route, for `OPTIONS *`). A route added for
+//! `OPTIONS` still takes precedence. With `auto.head` set, the http task
+//! sends HEAD requests to your GET routes, and `HEAD` is listed wherever
+//! `GET` is.
+//!
+//! Patterns are split into segments on `/`. Each segment is either:
+//!
+//! * some literal text, which must match exactly,
+//! * `:name`, which matches any one non-empty segment, or
+//! * `*name` (or just `*`), which matches all the remaining segments, and
+//!   must therefore come last.
+//!
+//! So `/users/:id/files/*path` matches `/users/42/files/a/b.txt` with `id`
+//! set to `42` and `path` set to `a/b.txt`. Parameters are not
+//! percent-decoded. If more than one route matches, the most specific wins -
+//! a route without a wildcard beats one with, and then literal segments beat
+//! parameters, which beat wildcards, comparing from the left. So `/files`
+//! gets `/files` even though `/files/*` matches it too.
+//!
+//! The router only handles the indications. Route users should send their
+//! `ReqResponseStart` and `ReqResponseBody` straight to the http task, using
+//! the connection handle in the `http::IndRxRequest`. The router will forward
+//! the request body, in `IndRxBody`, and the `IndClosed` when that connection
+//! closes.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#![cfg_attr(feature = "cargo-clippy", allow(if_not_else))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+#[macro_use]
+extern crate log;
+
+use std::collections::HashMap;
+use std::net;
+use std::sync::mpsc;
+
+use http::{HeaderMap, HttpResponseStatus, Method};
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Messages
+//
+// ****************************************************************************
+
+/// Offers the `grease::Service` for this module.
+pub struct Service;
+
+impl grease::Service for Service {
+	type Request = Request;
+	type Confirm = Confirm;
+	type Indication = Indication;
+	type Response = ();
+}
+
+/// Requests that can be sent to the router task.
+#[derive(Debug)]
+pub enum Request {
+	/// Start an HTTP server on a given port
+	Bind(ReqBind),
+	/// Add a route to a server
+	AddRoute(ReqAddRoute),
+	/// Remove a route from a server
+	RemoveRoute(ReqRemoveRoute),
+}
+
+make_wrapper!(ReqBind, Request, Request::Bind);
+make_wrapper!(ReqAddRoute, Request, Request::AddRoute);
+make_wrapper!(ReqRemoveRoute, Request, Request::RemoveRoute);
+
+/// Confirms sent back from the router task.
+#[derive(Debug)]
+pub enum Confirm {
+	/// Whether the ReqBind was successful
+	Bind(CfmBind),
+	/// Whether the ReqAddRoute was successful
+	AddRoute(CfmAddRoute),
+	/// Whether the ReqRemoveRoute was successful
+	RemoveRoute(CfmRemoveRoute),
+}
+
+make_wrapper!(CfmBind, Confirm, Confirm::Bind);
+make_wrapper!(CfmAddRoute, Confirm, Confirm::AddRoute);
+make_wrapper!(CfmRemoveRoute, Confirm, Confirm::RemoveRoute);
+
+/// Indications that come out of the router task.
+#[derive(Debug)]
+pub enum Indication {
+	/// A new HTTP request has matched one of your routes
+	RxRequest(IndRxRequest),
+	/// The connection for a request you were sent has been dropped
+	Closed(IndClosed),
+	/// Some of the body of a request you were sent
+	RxBody(IndRxBody),
+}
+
+make_wrapper!(IndRxRequest, Indication, Indication::RxRequest);
+make_wrapper!(IndClosed, Indication, Indication::Closed);
+make_wrapper!(IndRxBody, Indication, Indication::RxBody);
+
+/// Start an HTTP server on a given port. Routes can then be added to it.
+#[derive(Debug)]
+pub struct ReqBind {
+	/// Which address to bind.
+	pub addr: net::SocketAddr,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Passed on to the http task
+	pub config: http::ServerConfig,
+}
+
+/// Add a route. Matching requests are indicated to whoever sends this
+/// request.
+#[derive(Debug)]
+pub struct ReqAddRoute {
+	/// The server (from a `CfmBind`) to add the route to
+	pub server: ServerHandle,
+	/// Reflected back in the cfm, and in each `IndRxRequest` for this route
+	pub context: Context,
+	/// The method this route handles
+	pub method: Method,
+	/// The path pattern, e.g. "/users/:id"
+	pub pattern: String,
+}
+
+/// Remove a route added with `ReqAddRoute`.
+#[derive(Debug)]
+pub struct ReqRemoveRoute {
+	/// The route (from a `CfmAddRoute`) to remove
+	pub handle: RouteHandle,
+	/// Reflected back in the cfm
+	pub context: Context,
+}
+
+/// Whether the `ReqBind` was successful
+#[derive(Debug)]
+pub struct CfmBind {
+	pub context: Context,
+	pub result: Result<ServerHandle, Error>,
+}
+
+/// Whether the `ReqAddRoute` was successful
+#[derive(Debug)]
+pub struct CfmAddRoute {
+	pub context: Context,
+	pub result: Result<RouteHandle, Error>,
+}
+
+/// Whether the `ReqRemoveRoute` was successful
+#[derive(Debug)]
+pub struct CfmRemoveRoute {
+	pub context: Context,
+	pub result: Result<(), Error>,
+}
+
+/// A new HTTP request has matched one of your routes. Send the response to
+/// the http task, using `request.connection_handle`.
+#[derive(Debug)]
+pub struct IndRxRequest {
+	/// The route that matched
+	pub route: RouteHandle,
+	/// Reflected from the `ReqAddRoute`
+	pub context: Context,
+	/// The parameters extracted from the path
+	pub params: HashMap<String, String>,
+	/// The request, as received from the http task
+	pub request: http::IndRxRequest,
+}
+
+/// The connection for a request you were sent has been dropped
+#[derive(Debug)]
+pub struct IndClosed {
+	pub handle: http::ConnHandle,
+}
+
+/// Some of the body of a request you were sent, as received from the http
+/// task
+#[derive(Debug)]
+pub struct IndRxBody {
+	pub body: http::IndRxBody,
+}
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Represents something a router service user can hold on to to send us
+/// message.
+pub struct Handle(mpsc::Sender<Incoming>);
+
+/// Servers are identified by the handle the http task gave us
+pub type ServerHandle = http::ServerHandle;
+
+/// A new one of these is allocated for every route
+pub type RouteHandle = Context;
+
+/// All possible router task errors
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+	/// The server or route handle was not recognised
+	BadHandle,
+	/// The route pattern could not be parsed
+	BadPattern,
+	/// The http task rejected our request
+	Http(http::Error),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+service_map! {
+	generate: Incoming,
+	service: Service,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd)
+	}
+}
+
+/// One segment of a route pattern
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+	/// Must match exactly
+	Literal(String),
+	/// Matches any one non-empty segment, captured under this name
+	Param(String),
+	/// Matches all the remaining segments, captured under this name
+	Wildcard(String),
+}
+
+/// A parsed route pattern
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern {
+	segments: Vec<Segment>,
+}
+
+struct Route {
+	/// The handle by which the upper layer refers to this route
+	our_handle: RouteHandle,
+	/// The server this route is on
+	server: ServerHandle,
+	/// Reflected in each IndRxRequest
+	context: Context,
+	method: Method,
+	pattern: Pattern,
+	/// Who to tell about matching requests
+	ind_to: grease::ServiceUserHandle<Service>,
+}
+
+struct TaskContext {
+	/// Who we send http messages to
+	http: grease::ServiceProviderHandle<http::Service>,
+	/// How the tasks we use send messages to us
+	reply_to: Handle,
+	/// Binds we're waiting for, indexed by the context we sent down
+	pending_binds: HashMap<Context, (ReplyContext, http::AutoResponses)>,
+	/// All the routes, in the order they were added
+	routes: Vec<Route>,
+	/// The servers we've bound, and what they were asked to answer
+	/// automatically
+	servers: HashMap<ServerHandle, http::AutoResponses>,
+	/// Who to tell when each connection closes
+	connections: HashMap<http::ConnHandle, grease::ServiceUserHandle<Service>>,
+	/// The next context we use for downward messages
+	next_ctx: Context,
+}
+
+type ReplyContext = grease::ReplyContext<Service>;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Creates a new router task. Returns an object that can be used
+/// to send this task messages.
+pub fn make_task(http: grease::ServiceProviderHandle<http::Service>) -> Handle {
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx.clone());
+	std::thread::spawn(move || {
+		let mut t = TaskContext::new(http, handle);
+		for msg in rx.iter() {
+			t.handle(msg);
+		}
+		panic!("This task should never die!");
+	});
+	Handle(tx)
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// All our handler functions are methods on this `TaskContext` structure.
+impl TaskContext {
+	/// Create a new TaskContext
+	fn new(http: grease::ServiceProviderHandle<http::Service>, us: Handle) -> Self {
+		Self {
+			http,
+			reply_to: us,
+			pending_binds: HashMap::new(),
+			routes: Vec::new(),
+			servers: HashMap::new(),
+			connections: HashMap::new(),
+			// This number is arbitrary
+			next_ctx: grease::Context::new(4_000),
+		}
+	}
+
+	/// Handle an incoming message. It might a `Request` for us,
+	/// or it might be a `Confirm` or `Indication` from a lower layer.
+	fn handle(&mut self, msg: Incoming) {
+		match msg {
+			// We only handle our own requests and responses
+			Incoming::Request(x, reply_to) => {
+				debug!("Rx: {:?}", x);
+				self.handle_router_req(x, reply_to);
+			}
+			Incoming::HttpCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_cfm(x);
+			}
+			Incoming::HttpInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_ind(x);
+			}
+			Incoming::Response(x) => {
+				debug!("Rx: {:?}", x);
+			}
+		}
+	}
+
+	fn handle_router_req(&mut self, req: Request, reply_to: grease::ServiceUserHandle<Service>) {
+		match req {
+			Request::Bind(x) => self.handle_bind(x, reply_to),
+			Request::AddRoute(x) => self.handle_add_route(x, reply_to),
+			Request::RemoveRoute(x) => self.handle_remove_route(x, reply_to),
+		}
+	}
+
+	fn handle_http_cfm(&mut self, cfm: http::Confirm) {
+		match cfm {
+			http::Confirm::Bind(x) => self.handle_http_cfm_bind(x),
+			// These are for the error responses we send
+			http::Confirm::ResponseStart(_) | http::Confirm::ResponseBody(_) => {}
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_http_ind(&mut self, ind: http::Indication) {
+		match ind {
+			http::Indication::RxRequest(x) => self.handle_http_ind_rx_request(x),
+			http::Indication::Closed(x) => self.handle_http_ind_closed(x),
+			http::Indication::RxBody(x) => self.handle_http_ind_rx_body(x),
+			// WebSocket messages go straight to whoever accepted the upgrade
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_bind(&mut self, req_bind: ReqBind, reply_to: grease::ServiceUserHandle<Service>) {
+		let ctx = self.next_ctx.take();
+		let mut config = req_bind.config;
+		let auto = config.auto.clone();
+		// We know the routes, so we answer OPTIONS, not the http task
+		config.auto.options = false;
+		self.pending_binds.insert(
+			ctx,
+			(
+				ReplyContext {
+					context: req_bind.context,
+					reply_to,
+				},
+				auto,
+			),
+		);
+		self.http.send_request(
+			http::ReqBind {
+				addr: req_bind.addr,
+				context: ctx,
+				config,
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	fn handle_http_cfm_bind(&mut self, cfm_bind: http::CfmBind) {
+		if let Some((reply_ctx, auto)) = self.pending_binds.remove(&cfm_bind.context) {
+			let result = match cfm_bind.result {
+				Ok(server) => {
+					self.servers.insert(server, auto);
+					Ok(server)
+				}
+				Err(e) => Err(Error::Http(e)),
+			};
+			reply_ctx.reply_to.send_confirm(
+				CfmBind {
+					context: reply_ctx.context,
+					result,
+				}.into(),
+			);
+		} else {
+			warn!("Context {} not found", cfm_bind.context);
+		}
+	}
+
+	fn handle_add_route(
+		&mut self,
+		req_add: ReqAddRoute,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let result = if !self.servers.contains_key(&req_add.server) {
+			Err(Error::BadHandle)
+		} else if let Some(pattern) = Pattern::parse(&req_add.pattern) {
+			let route = Route {
+				our_handle: self.next_ctx.take(),
+				server: req_add.server,
+				context: req_add.context,
+				method: req_add.method,
+				pattern,
+				ind_to: reply_to.clone(),
+			};
+			let handle = route.our_handle;
+			self.routes.push(route);
+			Ok(handle)
+		} else {
+			Err(Error::BadPattern)
+		};
+		reply_to.send_confirm(
+			CfmAddRoute {
+				context: req_add.context,
+				result,
+			}.into(),
+		);
+	}
+
+	fn handle_remove_route(
+		&mut self,
+		req_remove: ReqRemoveRoute,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let before = self.routes.len();
+		self.routes.retain(|r| r.our_handle != req_remove.handle);
+		let result = if self.routes.len() != before {
+			Ok(())
+		} else {
+			Err(Error::BadHandle)
+		};
+		reply_to.send_confirm(
+			CfmRemoveRoute {
+				context: req_remove.context,
+				result,
+			}.into(),
+		);
+	}
+
+	/// Find the most specific route for this request. If there isn't one,
+	/// return the methods that would have matched the path (which might be
+	/// none).
+	fn find_route(
+		&self,
+		ind: &http::IndRxRequest,
+	) -> Result<(&Route, HashMap<String, String>), Vec<Method>> {
+		let mut best: Option<(&Route, HashMap<String, String>)> = None;
+		let mut allowed = Vec::new();
+		let path = ind.url.path();
+		for route in self.routes
+			.iter()
+			.filter(|r| r.server == ind.server_handle)
+		{
+			if let Some(params) = route.pattern.matches(path) {
+				if route.method != ind.method {
+					if !allowed.contains(&route.method) {
+						allowed.push(route.method.clone());
+					}
+					continue;
+				}
+				let better = match best {
+					Some((ref b, _)) => route.pattern.specificity() > b.pattern.specificity(),
+					None => true,
+				};
+				if better {
+					best = Some((route, params));
+				}
+			}
+		}
+		best.ok_or(allowed)
+	}
+
+	/// The methods of every route on a server, for `OPTIONS *`.
+	fn all_methods(&self, server: ServerHandle) -> Vec<Method> {
+		let mut methods = Vec::new();
+		for route in self.routes.iter().filter(|r| r.server == server) {
+			if !methods.contains(&route.method) {
+				methods.push(route.method.clone());
+			}
+		}
+		methods
+	}
+
+	/// Format an `Allow` header, adding the methods the server deals with
+	/// automatically.
+	fn allow_header(&self, server: ServerHandle, mut methods: Vec<Method>) -> HeaderMap {
+		if let Some(auto) = self.servers.get(&server) {
+			if auto.head && methods.contains(&Method::GET) && !methods.contains(&Method::HEAD) {
+				methods.push(Method::HEAD);
+			}
+			if auto.options && !methods.contains(&Method::OPTIONS) {
+				methods.push(Method::OPTIONS);
+			}
+		}
+		let allow = methods
+			.iter()
+			.map(|m| m.as_str())
+			.collect::<Vec<_>>()
+			.join(", ");
+		let mut headers = HeaderMap::new();
+		headers.insert("Allow", allow.parse().unwrap());
+		headers
+	}
+
+	fn handle_http_ind_rx_request(&mut self, ind: http::IndRxRequest) {
+		let auto_options = ind.method == Method::OPTIONS
+			&& self.servers
+				.get(&ind.server_handle)
+				.map_or(false, |a| a.options);
+		let everything = auto_options && ind.url == "*";
+		let result = if everything {
+			Err(self.all_methods(ind.server_handle))
+		} else {
+			self.find_route(&ind).map(|(route, params)| {
+				(route.our_handle, route.context, route.ind_to.clone(), params)
+			})
+		};
+		match result {
+			Ok((route, context, ind_to, params)) => {
+				debug!("{} {} matched route {}", ind.method, ind.url, route);
+				self.connections
+					.insert(ind.connection_handle, ind_to.clone());
+				ind_to.send_indication(
+					IndRxRequest {
+						route,
+						context,
+						params,
+						request: ind,
+					}.into(),
+				);
+			}
+			Err(ref allowed) if allowed.is_empty() && !everything => {
+				debug!("{} {} matched no routes", ind.method, ind.url);
+				self.send_error(
+					ind.connection_handle,
+					HttpResponseStatus::NotFound,
+					HeaderMap::new(),
+				);
+			}
+			Err(allowed) if auto_options => {
+				debug!("{} {} answered with {:?}", ind.method, ind.url, allowed);
+				let headers = self.allow_header(ind.server_handle, allowed);
+				self.http.send_request(
+					http::ReqResponseStart {
+						handle: ind.connection_handle,
+						context: self.next_ctx.take(),
+						status: HttpResponseStatus::OK,
+						content_type: String::from("text/plain"),
+						length: Some(0),
+						headers,
+						compress: None,
+					}.into(),
+					&self.reply_to,
+				);
+			}
+			Err(allowed) => {
+				debug!("{} {} matched routes for {:?}", ind.method, ind.url, allowed);
+				let headers = self.allow_header(ind.server_handle, allowed);
+				self.send_error(
+					ind.connection_handle,
+					HttpResponseStatus::MethodNotAllowed,
+					headers,
+				);
+			}
+		}
+	}
+
+	fn handle_http_ind_rx_body(&mut self, ind: http::IndRxBody) {
+		if let Some(ind_to) = self.connections.get(&ind.handle) {
+			ind_to.send_indication(IndRxBody { body: ind }.into());
+		}
+	}
+
+	fn handle_http_ind_closed(&mut self, ind: http::IndClosed) {
+		if let Some(ind_to) = self.connections.remove(&ind.handle) {
+			ind_to.send_indication(IndClosed { handle: ind.handle }.into());
+		}
+	}
+
+	/// Answer a request we couldn't route.
+	fn send_error(
+		&mut self,
+		handle: http::ConnHandle,
+		status: HttpResponseStatus,
+		headers: HeaderMap,
+	) {
+		let ctx = self.next_ctx.take();
+		http::send_error(&*self.http, &self.reply_to, handle, ctx, status, headers);
+	}
+}
+
+impl Pattern {
+	/// Parse a pattern like "/users/:id/files/*path".
+	fn parse(pattern: &str) -> Option<Pattern> {
+		if !pattern.starts_with('/') {
+			return None;
+		}
+		let parts = split_path(pattern);
+		let mut segments = Vec::new();
+		for (idx, part) in parts.iter().enumerate() {
+			let segment = if part.starts_with(':') {
+				if part.len() == 1 {
+					return None;
+				}
+				Segment::Param(part[1..].to_owned())
+			} else if part.starts_with('*') {
+				if idx != parts.len() - 1 {
+					// Wildcards must come last
+					return None;
+				}
+				Segment::Wildcard(if part.len() == 1 {
+					String::from("*")
+				} else {
+					part[1..].to_owned()
+				})
+			} else {
+				Segment::Literal((*part).to_owned())
+			};
+			segments.push(segment);
+		}
+		Some(Pattern { segments })
+	}
+
+	/// If this path matches, return the parameters.
+	fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+		let parts = split_path(path);
+		let mut params = HashMap::new();
+		for (idx, segment) in self.segments.iter().enumerate() {
+			match *segment {
+				Segment::Literal(ref s) => {
+					if parts.get(idx) != Some(&s.as_str()) {
+						return None;
+					}
+				}
+				Segment::Param(ref name) => match parts.get(idx) {
+					Some(part) if !part.is_empty() => {
+						params.insert(name.clone(), (*part).to_owned());
+					}
+					_ => return None,
+				},
+				Segment::Wildcard(ref name) => {
+					let rest = if idx < parts.len() {
+						parts[idx..].join("/")
+					} else {
+						String::new()
+					};
+					params.insert(name.clone(), rest);
+					return Some(params);
+				}
+			}
+		}
+		if parts.len() == self.segments.len() {
+			Some(params)
+		} else {
+			None
+		}
+	}
+
+	/// Used to pick between two matching patterns - bigger is more
+	/// specific. A pattern without a wildcard only matches paths of its own
+	/// length, so it beats any wildcard (which can match zero segments, as
+	/// `/files/*` does `/files`). After that, segments are compared in turn.
+	fn specificity(&self) -> (bool, Vec<u8>) {
+		let mut no_wildcard = true;
+		let segments = self.segments
+			.iter()
+			.map(|s| match *s {
+				Segment::Literal(_) => 2,
+				Segment::Param(_) => 1,
+				Segment::Wildcard(_) => {
+					no_wildcard = false;
+					0
+				}
+			})
+			.collect();
+		(no_wildcard, segments)
+	}
+}
+
+/// Split a path into segments, ignoring the leading `/`. The path "/" has
+/// no segments.
+fn split_path(path: &str) -> Vec<&str> {
+	match path {
+		"" | "/" => Vec::new(),
+		_ => path.split('/').skip(1).collect(),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::mpsc;
+
+	use grease::prelude::*;
+
+	enum TestIncoming {
+		RouterCfm(Confirm),
+		RouterInd(Indication),
+		HttpReq(http::Request, grease::ServiceUserHandle<http::Service>),
+		HttpRsp(http::Response),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::RouterCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: Indication) {
+			self.0.send(TestIncoming::RouterInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<http::Service> for TestHandle {
+		fn send_request(&self, req: http::Request, reply_to: &grease::ServiceUser<http::Service>) {
+			self.0
+				.send(TestIncoming::HttpReq(req, reply_to.clone()))
+				.unwrap();
+		}
+		fn send_response(&self, rsp: http::Response) {
+			self.0.send(TestIncoming::HttpRsp(rsp)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceProviderHandle<http::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
+		let (test_tx, rx) = mpsc::channel();
+		(TestHandle(test_tx), rx)
+	}
+
+	/// Bind a server through the router, using ourselves as the http task.
+	fn bind(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		router: &Handle,
+		server: ServerHandle,
+	) -> grease::ServiceUserHandle<http::Service> {
+		bind_with_config(
+			this_thread,
+			test_rx,
+			router,
+			server,
+			http::ServerConfig::default(),
+		)
+	}
+
+	fn bind_with_config(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		router: &Handle,
+		server: ServerHandle,
+		config: http::ServerConfig,
+	) -> grease::ServiceUserHandle<http::Service> {
+		router.send_request(
+			ReqBind {
+				addr: "127.0.0.1:8000".parse().unwrap(),
+				context: Context::new(1),
+				config,
+			}.into(),
+			this_thread,
+		);
+		let http_south = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::Bind(ref x), ref reply_to) => {
+				// The router answers OPTIONS, not the http task
+				assert!(!x.config.auto.options);
+				reply_to.send_confirm(
+					http::CfmBind {
+						context: x.context,
+						result: Ok(server),
+					}.into(),
+				);
+				(*reply_to).clone()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterCfm(Confirm::Bind(ref x)) => {
+				assert_eq!(x.context, Context::new(1));
+				assert_eq!(x.result.unwrap(), server);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		http_south
+	}
+
+	fn add_route(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		router: &Handle,
+		server: ServerHandle,
+		method: Method,
+		pattern: &str,
+		ctx: Context,
+	) -> RouteHandle {
+		router.send_request(
+			ReqAddRoute {
+				server,
+				context: ctx,
+				method,
+				pattern: String::from(pattern),
+			}.into(),
+			this_thread,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterCfm(Confirm::AddRoute(ref x)) => {
+				assert_eq!(x.context, ctx);
+				x.result.unwrap()
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	fn rx_request(
+		http_south: &grease::ServiceUserHandle<http::Service>,
+		server: ServerHandle,
+		conn: http::ConnHandle,
+		method: Method,
+		url: &str,
+	) {
+		http_south.send_indication(
+			http::IndRxRequest {
+				server_handle: server,
+				connection_handle: conn,
+				..http::IndRxRequest::for_test(method, url, HeaderMap::new())
+			}.into(),
+		);
+	}
+
+	#[test]
+	fn patterns() {
+		let p = Pattern::parse("/users/:id/files/*path").unwrap();
+		let params = p.matches("/users/42/files/a/b.txt").unwrap();
+		assert_eq!(params["id"], "42");
+		assert_eq!(params["path"], "a/b.txt");
+		let params = p.matches("/users/42/files").unwrap();
+		assert_eq!(params["path"], "");
+		assert!(p.matches("/users//files/a").is_none());
+		assert!(p.matches("/groups/42/files/a").is_none());
+
+		let p = Pattern::parse("/").unwrap();
+		assert!(p.matches("/").is_some());
+		assert!(p.matches("/foo").is_none());
+
+		let p = Pattern::parse("/foo/").unwrap();
+		assert!(p.matches("/foo/").is_some());
+		assert!(p.matches("/foo").is_none());
+
+		let p = Pattern::parse("/static/*").unwrap();
+		assert_eq!(p.matches("/static/css/x.css").unwrap()["*"], "css/x.css");
+
+		assert!(Pattern::parse("foo").is_none());
+		assert!(Pattern::parse("/:").is_none());
+		assert!(Pattern::parse("/*rest/foo").is_none());
+
+		let literal = Pattern::parse("/users/me").unwrap();
+		let param = Pattern::parse("/users/:id").unwrap();
+		let wildcard = Pattern::parse("/users/*").unwrap();
+		assert!(literal.specificity() > param.specificity());
+		assert!(param.specificity() > wildcard.specificity());
+		let files = Pattern::parse("/files").unwrap();
+		let files_wildcard = Pattern::parse("/files/*").unwrap();
+		assert!(files.specificity() > files_wildcard.specificity());
+	}
+
+	#[test]
+	/// A wildcard can match nothing, but an exact route for that path wins.
+	fn exact_beats_wildcard() {
+		let (reply_to, test_rx) = make_test_channel();
+		let router = make_task(grease::ServiceProvider::clone(&reply_to));
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &router, server);
+
+		let wildcard = add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::GET,
+			"/files/*",
+			Context::new(20),
+		);
+		let exact = add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::GET,
+			"/files",
+			Context::new(21),
+		);
+
+		rx_request(&http_south, server, Context::new(30), Method::GET, "/files");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.route, exact);
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		rx_request(&http_south, server, Context::new(31), Method::GET, "/files/a.txt");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.route, wildcard);
+				assert_eq!(x.params["*"], "a.txt");
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	fn dispatch() {
+		let (reply_to, test_rx) = make_test_channel();
+		let router = make_task(grease::ServiceProvider::clone(&reply_to));
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &router, server);
+
+		let by_id = add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::GET,
+			"/users/:id",
+			Context::new(20),
+		);
+		let me = add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::GET,
+			"/users/me",
+			Context::new(21),
+		);
+
+		rx_request(&http_south, server, Context::new(30), Method::GET, "/users/42?x=y");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.route, by_id);
+				assert_eq!(x.context, Context::new(20));
+				assert_eq!(x.params["id"], "42");
+				assert_eq!(x.request.connection_handle, Context::new(30));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		rx_request(&http_south, server, Context::new(31), Method::GET, "/users/me");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.route, me);
+				assert!(x.params.is_empty());
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// The close for a routed connection is passed on
+		http_south.send_indication(
+			http::IndClosed {
+				handle: Context::new(30),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, Context::new(30));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// Once removed, the more general route gets it
+		router.send_request(
+			ReqRemoveRoute {
+				handle: me,
+				context: Context::new(22),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterCfm(Confirm::RemoveRoute(ref x)) => {
+				assert_eq!(x.context, Context::new(22));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Unexpected message"),
+		}
+		rx_request(&http_south, server, Context::new(32), Method::GET, "/users/me");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.route, by_id);
+				assert_eq!(x.params["id"], "me");
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	#[test]
+	fn not_found_and_not_allowed() {
+		let (reply_to, test_rx) = make_test_channel();
+		let router = make_task(grease::ServiceProvider::clone(&reply_to));
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &router, server);
+		add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::GET,
+			"/things",
+			Context::new(20),
+		);
+		add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::POST,
+			"/things",
+			Context::new(21),
+		);
+
+		rx_request(&http_south, server, Context::new(30), Method::GET, "/nothing");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.handle, Context::new(30));
+				assert_eq!(x.status, HttpResponseStatus::NotFound);
+				assert!(!x.headers.contains_key("Allow"));
+			}
+			_ => panic!("Unexpected message"),
+		}
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(ref x), _) => {
+				assert_eq!(x.handle, Context::new(30));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		rx_request(&http_south, server, Context::new(31), Method::DELETE, "/things");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.handle, Context::new(31));
+				assert_eq!(x.status, HttpResponseStatus::MethodNotAllowed);
+				assert_eq!(x.headers.get("Allow").unwrap(), "GET, POST");
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// Adding a route to an unknown server fails
+		router.send_request(
+			ReqAddRoute {
+				server: Context::new(99),
+				context: Context::new(22),
+				method: Method::GET,
+				pattern: String::from("/"),
+			}.into(),
+			&reply_to,
+		);
+		loop {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::RouterCfm(Confirm::AddRoute(ref x)) => {
+					assert_eq!(x.context, Context::new(22));
+					assert!(x.result.is_err());
+					break;
+				}
+				TestIncoming::HttpReq(http::Request::ResponseBody(_), _) => {}
+				_ => panic!("Unexpected message"),
+			}
+		}
+	}
+
+	#[test]
+	fn automatic_options() {
+		let (reply_to, test_rx) = make_test_channel();
+		let router = make_task(grease::ServiceProvider::clone(&reply_to));
+		let server = Context::new(10);
+		let http_south = bind_with_config(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			http::ServerConfig {
+				auto: http::AutoResponses {
+					head: true,
+					options: true,
+					..Default::default()
+				},
+				..Default::default()
+			},
+		);
+		add_route(&reply_to, &test_rx, &router, server, Method::GET, "/things", Context::new(20));
+		add_route(&reply_to, &test_rx, &router, server, Method::POST, "/things", Context::new(21));
+		add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::DELETE,
+			"/other/:id",
+			Context::new(22),
+		);
+		add_route(
+			&reply_to,
+			&test_rx,
+			&router,
+			server,
+			Method::OPTIONS,
+			"/other/:id",
+			Context::new(23),
+		);
+		let expect_allow = |conn: http::ConnHandle, allow: &str| {
+			match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+				TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+					assert_eq!(x.handle, conn);
+					assert_eq!(x.status, HttpResponseStatus::OK);
+					assert_eq!(x.length, Some(0));
+					assert_eq!(x.headers.get("Allow").unwrap(), allow);
+				}
+				_ => panic!("Unexpected message"),
+			}
+		};
+
+		rx_request(&http_south, server, Context::new(30), Method::OPTIONS, "/things");
+		expect_allow(Context::new(30), "GET, POST, HEAD, OPTIONS");
+
+		rx_request(&http_south, server, Context::new(31), Method::OPTIONS, "*");
+		expect_allow(Context::new(31), "GET, POST, DELETE, OPTIONS, HEAD");
+
+		// A route for OPTIONS takes precedence
+		rx_request(&http_south, server, Context::new(32), Method::OPTIONS, "/other/1");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::RouterInd(Indication::RxRequest(ref x)) => {
+				assert_eq!(x.context, Context::new(23));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// Nothing here at all
+		rx_request(&http_south, server, Context::new(33), Method::OPTIONS, "/nothing");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.status, HttpResponseStatus::NotFound);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseBody(_), _) => {}
+			_ => panic!("Unexpected message"),
+		}
+
+		// The automatic methods are listed in a 405 too
+		rx_request(&http_south, server, Context::new(34), Method::PUT, "/things");
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::ResponseStart(ref x), _) => {
+				assert_eq!(x.status, HttpResponseStatus::MethodNotAllowed);
+				assert_eq!(x.headers.get("Allow").unwrap(), "GET, POST, HEAD, OPTIONS");
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1173 @@
+//! # session - A cookie and session task
+//!
+//! Copyright (c) Cambridge Consultants 2018
+//! See the top-level COPYRIGHT file for further information and licensing
+//!
+//! This task sits on top of the `http` task and gives each browser a
+//! session. A `ReqBind` asks us to bind an HTTP server, with a
+//! `SessionConfig` saying how the session cookie should look. Every request
+//! the http task indicates is passed on to whoever bound the server, in an
+//! `IndRxRequest` which also carries the request's cookies and its
+//! `Session` - the one named by its session cookie, or a new one if it
+//! didn't have a valid cookie.
+//!
+//! Session cookies hold a random ID and an HMAC of it, made with the key in
+//! the `SessionConfig`, so a client can't make up an ID. A session expires
+//! `max_age` after the last request which used it, and each `IndRxRequest`
+//! for an existing session has a `Set-Cookie` header which renews the
+//! cookie's `Max-Age`; add it to your response.
+//!
+//! The data in a session is a map of strings. To change it, change the
+//! `Session` you were given and send it back in a `ReqSave`. New sessions
+//! have no ID, and aren't stored, until they are first saved with some
+//! data; the `CfmSave` for that carries the `Set-Cookie` header which issues
+//! the cookie. That way we don't store a session, or set a cookie, for
+//! every client which passes by. A `ReqDestroy`
+//! removes a session (to log out, say), and gives back the `Set-Cookie`
+//! header which deletes the cookie. Sessions are kept in a `SessionStore`.
+//! `MemoryStore` keeps them in memory; implement the trait yourself to keep
+//! them somewhere else.
+//!
+//! As with the router, send your responses straight to the http task, using
+//! the connection handle in the `http::IndRxRequest`. We forward the request
+//! body, in `IndRxBody`, and the `IndClosed` when the connection closes.
+
+#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![cfg_attr(feature = "cargo-clippy", allow(large_enum_variant))]
+#![cfg_attr(feature = "cargo-clippy", allow(if_not_else))]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate base64;
+#[macro_use]
+extern crate grease;
+extern crate grease_http as http;
+extern crate hmac;
+#[macro_use]
+extern crate log;
+extern crate rand;
+extern crate sha1;
+
+use std::collections::HashMap;
+use std::net;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderValue};
+use rand::Rng;
+use sha1::Sha1;
+
+use grease::Context;
+
+// ****************************************************************************
+//
+// Public Messages
+//
+// ****************************************************************************
+
+/// Offers the `grease::Service` for this module.
+pub struct Service;
+
+impl grease::Service for Service {
+	type Request = Request;
+	type Confirm = Confirm;
+	type Indication = Indication;
+	type Response = ();
+}
+
+/// Requests that can be sent to the session task.
+#[derive(Debug)]
+pub enum Request {
+	/// Start an HTTP server on a given port
+	Bind(ReqBind),
+	/// Store changes to a session
+	Save(ReqSave),
+	/// Remove a session
+	Destroy(ReqDestroy),
+}
+
+make_wrapper!(ReqBind, Request, Request::Bind);
+make_wrapper!(ReqSave, Request, Request::Save);
+make_wrapper!(ReqDestroy, Request, Request::Destroy);
+
+/// Confirms sent back from the session task.
+#[derive(Debug)]
+pub enum Confirm {
+	/// Whether the ReqBind was successful
+	Bind(CfmBind),
+	/// Whether the ReqSave was successful
+	Save(CfmSave),
+	/// Whether the ReqDestroy was successful
+	Destroy(CfmDestroy),
+}
+
+make_wrapper!(CfmBind, Confirm, Confirm::Bind);
+make_wrapper!(CfmSave, Confirm, Confirm::Save);
+make_wrapper!(CfmDestroy, Confirm, Confirm::Destroy);
+
+/// Indications that come out of the session task.
+#[derive(Debug)]
+pub enum Indication {
+	/// A new HTTP request has been received
+	RxRequest(IndRxRequest),
+	/// Some of the body of a request you were sent
+	RxBody(IndRxBody),
+	/// The connection for a request you were sent has been dropped
+	Closed(IndClosed),
+}
+
+make_wrapper!(IndRxRequest, Indication, Indication::RxRequest);
+make_wrapper!(IndRxBody, Indication, Indication::RxBody);
+make_wrapper!(IndClosed, Indication, Indication::Closed);
+
+/// Start an HTTP server on a given port, with sessions.
+#[derive(Debug)]
+pub struct ReqBind {
+	/// Which address to bind.
+	pub addr: net::SocketAddr,
+	/// Reflected back in the cfm
+	pub context: Context,
+	/// Passed on to the http task
+	pub config: http::ServerConfig,
+	/// How the session cookie should look
+	pub session: SessionConfig,
+}
+
+/// Replace the data in a session with `session.data`. The session's expiry
+/// is pushed back, as if it had been used. A new session is only stored if
+/// it has some data, and is given an ID when it is.
+#[derive(Debug)]
+pub struct ReqSave {
+	pub session: Session,
+	/// Reflected back in the cfm
+	pub context: Context,
+}
+
+/// Remove a session. Any further requests with its cookie get a new one.
+#[derive(Debug)]
+pub struct ReqDestroy {
+	pub session: Session,
+	/// Reflected back in the cfm
+	pub context: Context,
+}
+
+/// Whether the `ReqBind` was successful
+#[derive(Debug)]
+pub struct CfmBind {
+	pub context: Context,
+	pub result: Result<ServerHandle, Error>,
+}
+
+/// Whether the `ReqSave` was successful. If it stored a new session, here's
+/// the `Set-Cookie` header which issues its cookie.
+#[derive(Debug)]
+pub struct CfmSave {
+	pub context: Context,
+	pub result: Result<Option<HeaderValue>, Error>,
+}
+
+/// Whether the `ReqDestroy` was successful. If so, here's a `Set-Cookie`
+/// header which deletes the cookie from the browser.
+#[derive(Debug)]
+pub struct CfmDestroy {
+	pub context: Context,
+	pub result: Result<HeaderValue, Error>,
+}
+
+/// A new HTTP request has been received. Send the response to the http
+/// task, using `request.connection_handle`, and include `set_cookie` (if
+/// any) as a `Set-Cookie` header.
+#[derive(Debug)]
+pub struct IndRxRequest {
+	/// The request's session
+	pub session: Session,
+	/// Whether the session was made for this request
+	pub new_session: bool,
+	/// Every cookie the request sent (including the session cookie)
+	pub cookies: HashMap<String, String>,
+	/// Renews the session cookie. New sessions have none until they are
+	/// saved.
+	pub set_cookie: Option<HeaderValue>,
+	/// The request, as received from the http task
+	pub request: http::IndRxRequest,
+}
+
+/// Some of the body of a request you were sent, as received from the http
+/// task
+#[derive(Debug)]
+pub struct IndRxBody {
+	pub body: http::IndRxBody,
+}
+
+/// The connection for a request you were sent has been dropped
+#[derive(Debug)]
+pub struct IndClosed {
+	pub handle: http::ConnHandle,
+}
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Represents something a session service user can hold on to to send us
+/// message.
+pub struct Handle(mpsc::Sender<Incoming>);
+
+/// Servers are identified by the handle the http task gave us
+pub type ServerHandle = http::ServerHandle;
+
+/// What's kept in a session
+pub type SessionData = HashMap<String, String>;
+
+/// A browser's session
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+	/// The server the session belongs to
+	pub server: ServerHandle,
+	/// The session ID, or empty if the session hasn't been saved yet. This
+	/// is a secret - anyone with it can use the session.
+	pub id: String,
+	pub data: SessionData,
+}
+
+/// How a server's session cookie should look
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+	/// The cookie's name
+	pub cookie_name: String,
+	/// The key cookies are signed with. At least 16 bytes, and kept secret.
+	pub key: Vec<u8>,
+	/// How long a session lasts after it was last used. This is also the
+	/// cookie's `Max-Age`.
+	pub max_age: Duration,
+	/// The cookie's `Path`
+	pub path: String,
+	/// Set `HttpOnly`, so scripts can't see the cookie
+	pub http_only: bool,
+	/// Set `Secure`, so the cookie is only sent over HTTPS
+	pub secure: bool,
+	/// The cookie's `SameSite`, if any
+	pub same_site: Option<SameSite>,
+}
+
+/// Values for a cookie's `SameSite` attribute
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SameSite {
+	Strict,
+	Lax,
+	/// Needs `secure` to be set
+	None,
+}
+
+/// Somewhere to keep sessions. Expired sessions must not be loaded, but
+/// needn't be thrown away until `expire` is called.
+pub trait SessionStore: Send {
+	/// The data for a session, if it exists and hasn't expired
+	fn load(&mut self, id: &str, now: SystemTime) -> Option<SessionData>;
+	/// Create or replace a session, which expires at `expires`
+	fn save(&mut self, id: &str, data: &SessionData, expires: SystemTime);
+	/// Remove a session, if it exists
+	fn remove(&mut self, id: &str);
+	/// Throw away every session which expired before `now`
+	fn expire(&mut self, now: SystemTime);
+}
+
+/// Keeps sessions in memory
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+	sessions: HashMap<String, (SessionData, SystemTime)>,
+}
+
+/// All possible session task errors
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+	/// The server handle was not recognised
+	BadHandle,
+	/// The `SessionConfig` isn't usable - say the key is too short, the
+	/// cookie name isn't a token, or `SameSite::None` is set without
+	/// `secure`.
+	BadConfig,
+	/// The session has expired or been destroyed
+	NoSession,
+	/// The http task rejected our request
+	Http(http::Error),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The shortest signing key we'll take
+pub const MIN_KEY_LEN: usize = 16;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+service_map! {
+	generate: Incoming,
+	service: Service,
+	handle: Handle,
+	used: {
+		http: (Service, HttpCfm, HttpInd)
+	}
+}
+
+struct Server {
+	config: SessionConfig,
+	/// Who to tell about requests
+	ind_to: grease::ServiceUserHandle<Service>,
+}
+
+struct TaskContext {
+	/// Who we send http messages to
+	http: grease::ServiceProviderHandle<http::Service>,
+	/// How the tasks we use send messages to us
+	reply_to: Handle,
+	/// Binds we're waiting for, indexed by the context we sent down
+	pending_binds: HashMap<Context, (ReplyContext, SessionConfig)>,
+	/// The servers we've bound
+	servers: HashMap<ServerHandle, Server>,
+	/// Who to tell when each connection closes
+	connections: HashMap<http::ConnHandle, grease::ServiceUserHandle<Service>>,
+	/// Where the sessions are kept
+	store: Box<SessionStore>,
+	/// Makes session IDs
+	rng: rand::OsRng,
+	/// When we next throw away expired sessions
+	next_sweep: Instant,
+	/// The next context we use for downward messages
+	next_ctx: Context,
+}
+
+type ReplyContext = grease::ReplyContext<Service>;
+
+/// How long a session ID is, in bytes
+const ID_LEN: usize = 16;
+
+/// How often we throw away expired sessions
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Creates a new session task, which keeps its sessions in `store`.
+/// Returns an object that can be used to send this task messages.
+pub fn make_task(
+	http: grease::ServiceProviderHandle<http::Service>,
+	store: Box<SessionStore>,
+) -> Handle {
+	let (tx, rx) = mpsc::channel();
+	let handle = Handle(tx.clone());
+	std::thread::spawn(move || {
+		let mut t = TaskContext::new(http, handle, store);
+		loop {
+			match rx.recv_timeout(SWEEP_INTERVAL) {
+				Ok(msg) => t.handle(msg),
+				Err(mpsc::RecvTimeoutError::Timeout) => {}
+				Err(mpsc::RecvTimeoutError::Disconnected) => break,
+			}
+			t.sweep();
+		}
+		panic!("This task should never die!");
+	});
+	Handle(tx)
+}
+
+/// Get the cookies from a request's `Cookie` headers. If a cookie appears
+/// more than once, the first one wins.
+pub fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+	let mut cookies = HashMap::new();
+	for value in headers.get_all("cookie") {
+		let value = match value.to_str() {
+			Ok(value) => value,
+			Err(_) => continue,
+		};
+		for pair in value.split(';') {
+			let mut parts = pair.splitn(2, '=');
+			let name = parts.next().unwrap_or("").trim();
+			let value = match parts.next() {
+				Some(value) => value.trim(),
+				None => continue,
+			};
+			let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+				&value[1..value.len() - 1]
+			} else {
+				value
+			};
+			if !name.is_empty() && !cookies.contains_key(name) {
+				cookies.insert(name.to_owned(), value.to_owned());
+			}
+		}
+	}
+	cookies
+}
+
+impl Default for SessionConfig {
+	/// A one hour session, with an `HttpOnly`, `SameSite=Lax` cookie called
+	/// `session`. You must supply the key.
+	fn default() -> SessionConfig {
+		SessionConfig {
+			cookie_name: String::from("session"),
+			key: Vec::new(),
+			max_age: Duration::from_secs(60 * 60),
+			path: String::from("/"),
+			http_only: true,
+			secure: false,
+			same_site: Some(SameSite::Lax),
+		}
+	}
+}
+
+impl SessionConfig {
+	/// The `Set-Cookie` header for a cookie with this value, which lasts
+	/// for `max_age`.
+	pub fn set_cookie(&self, value: &str, max_age: Duration) -> Option<HeaderValue> {
+		let mut cookie = format!(
+			"{}={}; Path={}; Max-Age={}",
+			self.cookie_name,
+			value,
+			self.path,
+			max_age.as_secs()
+		);
+		if self.http_only {
+			cookie.push_str("; HttpOnly");
+		}
+		if self.secure {
+			cookie.push_str("; Secure");
+		}
+		match self.same_site {
+			Some(SameSite::Strict) => cookie.push_str("; SameSite=Strict"),
+			Some(SameSite::Lax) => cookie.push_str("; SameSite=Lax"),
+			Some(SameSite::None) => cookie.push_str("; SameSite=None"),
+			None => {}
+		}
+		HeaderValue::from_str(&cookie).ok()
+	}
+
+	/// Check the config can make a valid cookie
+	fn is_valid(&self) -> bool {
+		let token = |s: &str| {
+			!s.is_empty() && s.bytes().all(|b| {
+				b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+			})
+		};
+		self.key.len() >= MIN_KEY_LEN && token(&self.cookie_name) && self.path.starts_with('/')
+			&& !self.path.contains(|c: char| c == ';' || c.is_control())
+			&& (self.same_site != Some(SameSite::None) || self.secure)
+			&& self.set_cookie("x", self.max_age).is_some()
+	}
+}
+
+impl SessionStore for MemoryStore {
+	fn load(&mut self, id: &str, now: SystemTime) -> Option<SessionData> {
+		match self.sessions.get(id) {
+			Some(&(ref data, expires)) if expires > now => Some(data.clone()),
+			_ => None,
+		}
+	}
+
+	fn save(&mut self, id: &str, data: &SessionData, expires: SystemTime) {
+		self.sessions
+			.insert(id.to_owned(), (data.clone(), expires));
+	}
+
+	fn remove(&mut self, id: &str) {
+		self.sessions.remove(id);
+	}
+
+	fn expire(&mut self, now: SystemTime) {
+		self.sessions
+			.retain(|_, &mut (_, expires)| expires > now);
+	}
+}
+
+impl MemoryStore {
+	pub fn new() -> MemoryStore {
+		MemoryStore::default()
+	}
+
+	/// How many sessions we're holding, including expired ones we haven't
+	/// thrown away yet
+	pub fn len(&self) -> usize {
+		self.sessions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.sessions.is_empty()
+	}
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// All our handler functions are methods on this `TaskContext` structure.
+impl TaskContext {
+	/// Create a new TaskContext
+	fn new(
+		http: grease::ServiceProviderHandle<http::Service>,
+		us: Handle,
+		store: Box<SessionStore>,
+	) -> Self {
+		Self {
+			http,
+			reply_to: us,
+			pending_binds: HashMap::new(),
+			servers: HashMap::new(),
+			connections: HashMap::new(),
+			store,
+			rng: rand::OsRng::new().expect("No OS random number generator"),
+			next_sweep: Instant::now() + SWEEP_INTERVAL,
+			// This number is arbitrary
+			next_ctx: grease::Context::new(5_000),
+		}
+	}
+
+	/// Handle an incoming message. It might a `Request` for us,
+	/// or it might be a `Confirm` or `Indication` from a lower layer.
+	fn handle(&mut self, msg: Incoming) {
+		match msg {
+			// We only handle our own requests and responses
+			Incoming::Request(x, reply_to) => {
+				debug!("Rx: {:?}", x);
+				self.handle_session_req(x, reply_to);
+			}
+			Incoming::HttpCfm(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_cfm(x);
+			}
+			Incoming::HttpInd(x) => {
+				debug!("Rx: {:?}", x);
+				self.handle_http_ind(x);
+			}
+			Incoming::Response(x) => {
+				debug!("Rx: {:?}", x);
+			}
+		}
+	}
+
+	fn handle_session_req(&mut self, req: Request, reply_to: grease::ServiceUserHandle<Service>) {
+		match req {
+			Request::Bind(x) => self.handle_bind(x, reply_to),
+			Request::Save(x) => self.handle_save(x, reply_to),
+			Request::Destroy(x) => self.handle_destroy(x, reply_to),
+		}
+	}
+
+	fn handle_http_cfm(&mut self, cfm: http::Confirm) {
+		match cfm {
+			http::Confirm::Bind(x) => self.handle_http_cfm_bind(x),
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_http_ind(&mut self, ind: http::Indication) {
+		match ind {
+			http::Indication::RxRequest(x) => self.handle_http_ind_rx_request(x),
+			http::Indication::RxBody(x) => self.handle_http_ind_rx_body(x),
+			http::Indication::Closed(x) => self.handle_http_ind_closed(x),
+			// WebSocket messages go straight to whoever accepted the upgrade
+			x => warn!("Unexpected {:?}", x),
+		}
+	}
+
+	fn handle_bind(&mut self, req_bind: ReqBind, reply_to: grease::ServiceUserHandle<Service>) {
+		if !req_bind.session.is_valid() {
+			reply_to.send_confirm(
+				CfmBind {
+					context: req_bind.context,
+					result: Err(Error::BadConfig),
+				}.into(),
+			);
+			return;
+		}
+		let ctx = self.next_ctx.take();
+		self.pending_binds.insert(
+			ctx,
+			(
+				ReplyContext {
+					context: req_bind.context,
+					reply_to,
+				},
+				req_bind.session,
+			),
+		);
+		self.http.send_request(
+			http::ReqBind {
+				addr: req_bind.addr,
+				context: ctx,
+				config: req_bind.config,
+			}.into(),
+			&self.reply_to,
+		);
+	}
+
+	fn handle_http_cfm_bind(&mut self, cfm_bind: http::CfmBind) {
+		if let Some((reply_ctx, config)) = self.pending_binds.remove(&cfm_bind.context) {
+			let result = match cfm_bind.result {
+				Ok(server) => {
+					self.servers.insert(
+						server,
+						Server {
+							config,
+							ind_to: reply_ctx.reply_to.clone(),
+						},
+					);
+					Ok(server)
+				}
+				Err(e) => Err(Error::Http(e)),
+			};
+			reply_ctx.reply_to.send_confirm(
+				CfmBind {
+					context: reply_ctx.context,
+					result,
+				}.into(),
+			);
+		} else {
+			warn!("Context {} not found", cfm_bind.context);
+		}
+	}
+
+	fn handle_save(&mut self, req_save: ReqSave, reply_to: grease::ServiceUserHandle<Service>) {
+		let now = SystemTime::now();
+		let result = match self.servers.get(&req_save.session.server) {
+			Some(server) => {
+				let config = &server.config;
+				let id = &req_save.session.id;
+				let expires = now + config.max_age;
+				if id.is_empty() {
+					// A new session - there's nothing to keep unless it has
+					// some data
+					if req_save.session.data.is_empty() {
+						Ok(None)
+					} else {
+						let id = new_id(&mut self.rng);
+						self.store.save(&id, &req_save.session.data, expires);
+						config
+							.set_cookie(&sign(&config.key, &id), config.max_age)
+							.map(Some)
+							.ok_or(Error::BadConfig)
+					}
+				} else if self.store.load(id, now).is_some() {
+					self.store.save(id, &req_save.session.data, expires);
+					Ok(None)
+				} else {
+					Err(Error::NoSession)
+				}
+			}
+			None => Err(Error::BadHandle),
+		};
+		reply_to.send_confirm(
+			CfmSave {
+				context: req_save.context,
+				result,
+			}.into(),
+		);
+	}
+
+	fn handle_destroy(
+		&mut self,
+		req_destroy: ReqDestroy,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		let result = match self.servers.get(&req_destroy.session.server) {
+			Some(server) => {
+				self.store.remove(&req_destroy.session.id);
+				// An empty cookie which has already expired
+				server
+					.config
+					.set_cookie("", Duration::from_secs(0))
+					.ok_or(Error::BadConfig)
+			}
+			None => Err(Error::BadHandle),
+		};
+		reply_to.send_confirm(
+			CfmDestroy {
+				context: req_destroy.context,
+				result,
+			}.into(),
+		);
+	}
+
+	fn handle_http_ind_rx_request(&mut self, ind: http::IndRxRequest) {
+		let now = SystemTime::now();
+		let (config, ind_to) = match self.servers.get(&ind.server_handle) {
+			Some(server) => (server.config.clone(), server.ind_to.clone()),
+			None => {
+				warn!("Request for unknown server {}", ind.server_handle);
+				return;
+			}
+		};
+		let cookies = parse_cookies(&ind.headers);
+		let existing = cookies
+			.get(&config.cookie_name)
+			.and_then(|value| verify(&config.key, value))
+			.and_then(|id| self.store.load(&id, now).map(|data| (id, data)));
+		let (id, data, set_cookie) = match existing {
+			Some((id, data)) => {
+				self.store.save(&id, &data, now + config.max_age);
+				match config.set_cookie(&sign(&config.key, &id), config.max_age) {
+					Some(set_cookie) => (id, data, Some(set_cookie)),
+					None => return,
+				}
+			}
+			// Not stored until it's saved with some data
+			None => (String::new(), SessionData::new(), None),
+		};
+		let new_session = set_cookie.is_none();
+		self.connections
+			.insert(ind.connection_handle, ind_to.clone());
+		ind_to.send_indication(
+			IndRxRequest {
+				session: Session {
+					server: ind.server_handle,
+					id,
+					data,
+				},
+				new_session,
+				cookies,
+				set_cookie,
+				request: ind,
+			}.into(),
+		);
+	}
+
+	fn handle_http_ind_rx_body(&mut self, ind: http::IndRxBody) {
+		if let Some(ind_to) = self.connections.get(&ind.handle) {
+			ind_to.send_indication(IndRxBody { body: ind }.into());
+		}
+	}
+
+	fn handle_http_ind_closed(&mut self, ind: http::IndClosed) {
+		if let Some(ind_to) = self.connections.remove(&ind.handle) {
+			ind_to.send_indication(IndClosed { handle: ind.handle }.into());
+		}
+	}
+
+	/// Throw away expired sessions, if it's time to.
+	fn sweep(&mut self) {
+		let now = Instant::now();
+		if now >= self.next_sweep {
+			self.store.expire(SystemTime::now());
+			self.next_sweep = now + SWEEP_INTERVAL;
+		}
+	}
+}
+
+/// A new random session ID
+fn new_id(rng: &mut rand::OsRng) -> String {
+	let mut id = [0u8; ID_LEN];
+	rng.fill_bytes(&mut id);
+	base64::encode_config(&id, base64::URL_SAFE_NO_PAD)
+}
+
+/// The cookie value for a session ID - the ID, a dot, and its signature
+fn sign(key: &[u8], id: &str) -> String {
+	let mac = mac_for(key, id).result().code();
+	format!("{}.{}", id, base64::encode_config(&mac, base64::URL_SAFE_NO_PAD))
+}
+
+/// Check a cookie value's signature, and return the session ID from it
+fn verify(key: &[u8], value: &str) -> Option<String> {
+	let dot = value.rfind('.')?;
+	let id = &value[..dot];
+	let mac = base64::decode_config(&value[dot + 1..], base64::URL_SAFE_NO_PAD).ok()?;
+	// This comparison takes the same time however much matched
+	mac_for(key, id).verify(&mac).ok()?;
+	Some(id.to_owned())
+}
+
+/// An HMAC-SHA1 of the session ID
+fn mac_for(key: &[u8], id: &str) -> Hmac<Sha1> {
+	let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC takes keys of any length");
+	mac.input(id.as_bytes());
+	mac
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::mpsc;
+
+	use grease::prelude::*;
+
+	enum TestIncoming {
+		SessionCfm(Confirm),
+		SessionInd(Indication),
+		HttpReq(http::Request, grease::ServiceUserHandle<http::Service>),
+		HttpRsp(http::Response),
+	}
+
+	struct TestHandle(mpsc::Sender<TestIncoming>);
+
+	const DEFAULT_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+	impl grease::ServiceUser<Service> for TestHandle {
+		fn send_confirm(&self, cfm: Confirm) {
+			self.0.send(TestIncoming::SessionCfm(cfm)).unwrap();
+		}
+		fn send_indication(&self, ind: Indication) {
+			self.0.send(TestIncoming::SessionInd(ind)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceUserHandle<Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	impl grease::ServiceProvider<http::Service> for TestHandle {
+		fn send_request(&self, req: http::Request, reply_to: &grease::ServiceUser<http::Service>) {
+			self.0
+				.send(TestIncoming::HttpReq(req, reply_to.clone()))
+				.unwrap();
+		}
+		fn send_response(&self, rsp: http::Response) {
+			self.0.send(TestIncoming::HttpRsp(rsp)).unwrap();
+		}
+		fn clone(&self) -> grease::ServiceProviderHandle<http::Service> {
+			Box::new(TestHandle(self.0.clone()))
+		}
+	}
+
+	fn make_test_channel() -> (TestHandle, mpsc::Receiver<TestIncoming>) {
+		let (test_tx, rx) = mpsc::channel();
+		(TestHandle(test_tx), rx)
+	}
+
+	fn config() -> SessionConfig {
+		SessionConfig {
+			key: b"0123456789abcdef".to_vec(),
+			..Default::default()
+		}
+	}
+
+	/// Bind a server through the session task, using ourselves as the http
+	/// task.
+	fn bind(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		session: &Handle,
+		server: ServerHandle,
+	) -> grease::ServiceUserHandle<http::Service> {
+		session.send_request(
+			ReqBind {
+				addr: "127.0.0.1:8000".parse().unwrap(),
+				context: Context::new(1),
+				config: http::ServerConfig::default(),
+				session: config(),
+			}.into(),
+			this_thread,
+		);
+		let http_south = match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::HttpReq(http::Request::Bind(ref x), ref reply_to) => {
+				reply_to.send_confirm(
+					http::CfmBind {
+						context: x.context,
+						result: Ok(server),
+					}.into(),
+				);
+				(*reply_to).clone()
+			}
+			_ => panic!("Unexpected message"),
+		};
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionCfm(Confirm::Bind(ref x)) => {
+				assert_eq!(x.context, Context::new(1));
+				assert_eq!(x.result.unwrap(), server);
+			}
+			_ => panic!("Unexpected message"),
+		}
+		http_south
+	}
+
+	/// Send a request with these `Cookie` headers, and get back what the
+	/// session task makes of it.
+	fn rx_request(
+		http_south: &grease::ServiceUserHandle<http::Service>,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		server: ServerHandle,
+		conn: http::ConnHandle,
+		cookies: &[&str],
+	) -> IndRxRequest {
+		let mut headers = HeaderMap::new();
+		for cookie in cookies {
+			headers.append("cookie", cookie.parse().unwrap());
+		}
+		http_south.send_indication(
+			http::IndRxRequest {
+				server_handle: server,
+				connection_handle: conn,
+				..http::IndRxRequest::for_test(http::Method::GET, "/", headers)
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionInd(Indication::RxRequest(x)) => x,
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	/// Save a session, and get back the result
+	fn save(
+		this_thread: &TestHandle,
+		test_rx: &mpsc::Receiver<TestIncoming>,
+		session: &Handle,
+		data: Session,
+	) -> Result<Option<HeaderValue>, Error> {
+		session.send_request(
+			ReqSave {
+				session: data,
+				context: Context::new(40),
+			}.into(),
+			this_thread,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionCfm(Confirm::Save(x)) => {
+				assert_eq!(x.context, Context::new(40));
+				x.result
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+
+	/// The `name=value` part of a `Set-Cookie` header
+	fn cookie_pair(set_cookie: &HeaderValue) -> String {
+		set_cookie
+			.to_str()
+			.unwrap()
+			.split(';')
+			.next()
+			.unwrap()
+			.to_owned()
+	}
+
+	#[test]
+	fn cookies() {
+		let mut headers = HeaderMap::new();
+		headers.append("cookie", "a=1; b=\"two\"; c=".parse().unwrap());
+		headers.append("cookie", "a=ignored;junk;  d = 4 ".parse().unwrap());
+		let cookies = parse_cookies(&headers);
+		assert_eq!(cookies.len(), 4);
+		assert_eq!(cookies["a"], "1");
+		assert_eq!(cookies["b"], "two");
+		assert_eq!(cookies["c"], "");
+		assert_eq!(cookies["d"], "4");
+
+		let config = SessionConfig {
+			secure: true,
+			same_site: Some(SameSite::Strict),
+			max_age: Duration::from_secs(600),
+			..config()
+		};
+		assert_eq!(
+			config.set_cookie("abc", config.max_age).unwrap(),
+			"session=abc; Path=/; Max-Age=600; HttpOnly; Secure; SameSite=Strict"
+		);
+		assert!(config.is_valid());
+		let bad = [
+			SessionConfig {
+				key: b"short".to_vec(),
+				..config.clone()
+			},
+			SessionConfig {
+				cookie_name: String::from("bad name"),
+				..config.clone()
+			},
+			SessionConfig {
+				path: String::from("/a;b"),
+				..config.clone()
+			},
+			SessionConfig {
+				secure: false,
+				same_site: Some(SameSite::None),
+				..config.clone()
+			},
+		];
+		for config in bad.iter() {
+			assert!(!config.is_valid());
+		}
+	}
+
+	#[test]
+	fn signing() {
+		let key = b"0123456789abcdef";
+		let value = sign(key, "some-id");
+		assert!(value.starts_with("some-id."));
+		assert_eq!(verify(key, &value), Some(String::from("some-id")));
+		assert_eq!(verify(b"fedcba9876543210", &value), None);
+		assert_eq!(verify(key, &value.replace("some", "same")), None);
+		assert_eq!(verify(key, &value[..value.len() - 1]), None);
+		assert_eq!(verify(key, "some-id"), None);
+		assert_eq!(verify(key, ""), None);
+	}
+
+	#[test]
+	fn sessions() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(
+			grease::ServiceProvider::clone(&reply_to),
+			Box::new(MemoryStore::new()),
+		);
+		let server = Context::new(10);
+		let http_south = bind(&reply_to, &test_rx, &task, server);
+
+		// No cookie, so a new session, which has no ID or cookie yet
+		let ind = rx_request(&http_south, &test_rx, server, Context::new(30), &["other=x"]);
+		assert!(ind.new_session);
+		assert!(ind.session.id.is_empty());
+		assert!(ind.session.data.is_empty());
+		assert!(ind.set_cookie.is_none());
+		assert_eq!(ind.cookies["other"], "x");
+
+		// Saving it empty doesn't store it
+		let mut session = ind.session;
+		assert_eq!(save(&reply_to, &test_rx, &task, session.clone()).unwrap(), None);
+
+		// Put something in it, and it gets a cookie
+		session
+			.data
+			.insert(String::from("user"), String::from("fred"));
+		let set_cookie = save(&reply_to, &test_rx, &task, session.clone())
+			.unwrap()
+			.unwrap();
+		assert!(
+			set_cookie
+				.to_str()
+				.unwrap()
+				.ends_with("; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax")
+		);
+		let cookie = cookie_pair(&set_cookie);
+		assert!(cookie.starts_with("session="));
+
+		// The cookie brings it back, and renews itself
+		let ind = rx_request(&http_south, &test_rx, server, Context::new(31), &[&cookie]);
+		assert!(!ind.new_session);
+		assert_eq!(ind.session.data, session.data);
+		assert_eq!(cookie_pair(&ind.set_cookie.unwrap()), cookie);
+		let session = ind.session;
+		assert!(!session.id.is_empty());
+
+		// Saving it again doesn't issue another cookie
+		assert_eq!(save(&reply_to, &test_rx, &task, session.clone()).unwrap(), None);
+
+		// The body and close are passed on
+		http_south.send_indication(
+			http::IndRxBody {
+				handle: Context::new(31),
+				data: b"abc".to_vec(),
+				last: true,
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionInd(Indication::RxBody(ref x)) => {
+				assert_eq!(x.body.data, b"abc");
+			}
+			_ => panic!("Unexpected message"),
+		}
+		http_south.send_indication(
+			http::IndClosed {
+				handle: Context::new(31),
+			}.into(),
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionInd(Indication::Closed(ref x)) => {
+				assert_eq!(x.handle, Context::new(31));
+			}
+			_ => panic!("Unexpected message"),
+		}
+
+		// A forged cookie gets a new session
+		let forged = format!("session={}.AAAA", session.id);
+		let ind = rx_request(&http_south, &test_rx, server, Context::new(32), &[&forged]);
+		assert!(ind.new_session);
+		assert!(ind.session.id.is_empty());
+
+		// Once it's destroyed, the cookie is no good
+		task.send_request(
+			ReqDestroy {
+				session: session.clone(),
+				context: Context::new(41),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionCfm(Confirm::Destroy(ref x)) => {
+				assert_eq!(x.context, Context::new(41));
+				let set_cookie = x.result.as_ref().unwrap().to_str().unwrap();
+				assert!(set_cookie.starts_with("session=; Path=/; Max-Age=0"));
+			}
+			_ => panic!("Unexpected message"),
+		}
+		let ind = rx_request(&http_south, &test_rx, server, Context::new(33), &[&cookie]);
+		assert!(ind.new_session);
+		assert!(match save(&reply_to, &test_rx, &task, session) {
+			Err(Error::NoSession) => true,
+			_ => false,
+		});
+	}
+
+	#[test]
+	fn expiry() {
+		let mut store = MemoryStore::new();
+		let now = SystemTime::now();
+		let data = SessionData::new();
+		store.save("old", &data, now - Duration::from_secs(1));
+		store.save("new", &data, now + Duration::from_secs(60));
+		assert_eq!(store.load("old", now), None);
+		assert_eq!(store.load("new", now), Some(data));
+		assert_eq!(store.len(), 2);
+		store.expire(now);
+		assert_eq!(store.len(), 1);
+		store.remove("new");
+		assert!(store.is_empty());
+	}
+
+	#[test]
+	fn bad_config() {
+		let (reply_to, test_rx) = make_test_channel();
+		let task = make_task(
+			grease::ServiceProvider::clone(&reply_to),
+			Box::new(MemoryStore::new()),
+		);
+		task.send_request(
+			ReqBind {
+				addr: "127.0.0.1:8000".parse().unwrap(),
+				context: Context::new(1),
+				config: http::ServerConfig::default(),
+				session: SessionConfig::default(),
+			}.into(),
+			&reply_to,
+		);
+		match test_rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SessionCfm(Confirm::Bind(ref x)) => {
+				assert!(match x.result {
+					Err(Error::BadConfig) => true,
+					_ => false,
+				});
+			}
+			_ => panic!("Unexpected message"),
+		}
+	}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
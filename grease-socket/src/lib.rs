@@ -36,6 +36,7 @@ use std::io;
 use std::io::prelude::*;
 use std::net;
 use std::thread;
+use std::time::Duration;
 
 use grease::Context;
 
@@ -60,6 +61,8 @@ impl grease::Service for Service {
 pub enum Request {
 	/// A Bind Request - Bind a listen socket
 	Bind(ReqBind),
+	/// A Connect Request - Open a connection to a remote socket
+	Connect(ReqConnect),
 	/// A Close request - Close an open connection
 	Close(ReqClose),
 	/// A Send request - Send something on a connection
@@ -67,6 +70,7 @@ pub enum Request {
 }
 
 make_wrapper!(ReqBind, Request, Request::Bind);
+make_wrapper!(ReqConnect, Request, Request::Connect);
 make_wrapper!(ReqClose, Request, Request::Close);
 make_wrapper!(ReqSend, Request, Request::Send);
 
@@ -75,6 +79,8 @@ make_wrapper!(ReqSend, Request, Request::Send);
 pub enum Confirm {
 	/// A Bind Confirm - Bound a listen socket
 	Bind(CfmBind),
+	/// A Connect Confirm - Opened a connection to a remote socket
+	Connect(CfmConnect),
 	/// A Close Confirm - Closed an open connection
 	Close(CfmClose),
 	/// A Send Confirm - Sent something on a connection
@@ -82,6 +88,7 @@ pub enum Confirm {
 }
 
 make_wrapper!(CfmBind, Confirm, Confirm::Bind);
+make_wrapper!(CfmConnect, Confirm, Confirm::Connect);
 make_wrapper!(CfmClose, Confirm, Confirm::Close);
 make_wrapper!(CfmSend, Confirm, Confirm::Send);
 
@@ -125,13 +132,31 @@ pub struct ReqBind {
 	pub conn_type: ConnectionType,
 }
 
-/// Close an open connection
+/// Open a connection to a remote socket. Any subsequent indications for the
+/// new connection are sent to whoever sent this request.
+#[derive(Debug)]
+pub struct ReqConnect {
+	/// The address to connect to
+	pub addr: net::SocketAddr,
+	/// Reflected in the cfm
+	pub context: Context,
+	/// Type of connection to make
+	pub conn_type: ConnectionType,
+}
+
+/// Close an open connection, or stop listening on a bound socket.
+/// Connections already accepted on a listener are left open.
 #[derive(Debug)]
 pub struct ReqClose {
-	/// The handle from a IndConnected
+	/// The handle from a IndConnected, or the ListenHandle from a CfmBind
 	pub handle: ConnHandle,
 	/// Reflected in the cfm
 	pub context: Context,
+	/// Reset the connection (with a TCP RST) rather than closing it
+	/// gracefully. Anything not yet sent is thrown away, and the far end
+	/// sees an error rather than the end of the stream. Ignored for
+	/// listeners.
+	pub reset: bool,
 }
 
 /// Send something on a connection
@@ -153,6 +178,16 @@ pub struct CfmBind {
 	pub context: Context,
 }
 
+/// Reply to a `ReqConnect`. Only sent once the connection has been
+/// established (or has failed).
+#[derive(Debug)]
+pub struct CfmConnect {
+	/// Either a new ConnHandle or an error
+	pub result: Result<ConnHandle, SocketError>,
+	/// Reflected from the req
+	pub context: Context,
+}
+
 /// Reply to a `ReqClose`. Will flush out all
 /// existing data.
 #[derive(Debug)]
@@ -186,6 +221,8 @@ pub struct IndConnected {
 	pub conn_handle: ConnHandle,
 	/// Details about who connected
 	pub peer: net::SocketAddr,
+	/// The local address the connection was accepted on
+	pub local: net::SocketAddr,
 }
 
 /// Indicates that a socket has been dropped.
@@ -281,7 +318,8 @@ struct PendingWrite {
 	reply_to: grease::ServiceUserHandle<Service>,
 }
 
-/// Created for every connection receieved on a `ListenSocket`
+/// Created for every connection receieved on a `ListenSocket`, or opened
+/// with a `ReqConnect`
 struct ConnectedSocket {
 	// parent: ListenHandle,
 	ind_to: grease::ServiceUserHandle<Service>,
@@ -291,6 +329,9 @@ struct ConnectedSocket {
 	outstanding: bool,
 	/// Queue of pending writes
 	pending_writes: VecDeque<PendingWrite>,
+	/// If we're still waiting for a `ReqConnect` to complete, the context
+	/// to put in the `CfmConnect`
+	connecting: Option<Context>,
 }
 
 /// One instance per task. Stores all the task data.
@@ -367,6 +408,15 @@ impl TaskContext {
 		let token = event.token();
 		debug!("ready: {:?}, token: {:?}", ready, token);
 		let handle = Context::new(token.0);
+		let connecting = self.connections
+			.get(&handle)
+			.map_or(false, |cs| cs.connecting.is_some());
+		if connecting {
+			debug!("Connect finished on handle {}", handle);
+			if !self.connect_finished(handle) {
+				return;
+			}
+		}
 		if ready.is_readable() {
 			if token == MESSAGE_TOKEN {
 				// Empty the whole message queue
@@ -440,6 +490,11 @@ impl TaskContext {
 		// We know this exists because we checked it before we got here
 		let ls = &self.listeners[&ls_handle];
 		if let Ok((stream, conn_addr)) = ls.listener.accept() {
+			// Fall back to the listen address if the OS won't tell us
+			let local_addr = match stream.local_addr() {
+				Ok(addr) => addr,
+				Err(_) => ls.listener.local_addr().unwrap_or(conn_addr),
+			};
 			let cs = ConnectedSocket {
 				// parent: ls.handle,
 				handle: self.next_handle.take(),
@@ -447,6 +502,7 @@ impl TaskContext {
 				connection: stream,
 				outstanding: false,
 				pending_writes: VecDeque::new(),
+				connecting: None,
 			};
 			self.poll
 				.register(
@@ -460,6 +516,7 @@ impl TaskContext {
 				listen_handle: ls.handle,
 				conn_handle: cs.handle,
 				peer: conn_addr,
+				local: local_addr,
 			};
 			self.connections.insert(cs.handle, cs);
 			ls.ind_to.send_indication(Indication::Connected(ind));
@@ -468,6 +525,42 @@ impl TaskContext {
 		}
 	}
 
+	/// A connection opened with a `ReqConnect` has either been established or
+	/// has failed. Either way, the user gets a `CfmConnect`. Returns true if
+	/// the connection is now usable.
+	fn connect_finished(&mut self, cs_handle: ConnHandle) -> bool {
+		let result = {
+			// We know this exists because we checked it before we got here
+			let cs = &self.connections[&cs_handle];
+			match cs.connection.take_error() {
+				Ok(None) => cs.connection.peer_addr().map(|_| ()),
+				Ok(Some(err)) | Err(err) => Err(err),
+			}
+		};
+		match result {
+			Ok(()) => {
+				let cs = self.connections.get_mut(&cs_handle).unwrap();
+				let cfm = CfmConnect {
+					result: Ok(cs_handle),
+					context: cs.connecting.take().unwrap(),
+				};
+				cs.ind_to.send_confirm(Confirm::Connect(cfm));
+				true
+			}
+			Err(err) => {
+				warn!("Connect error on handle: {}, err: {}", cs_handle, err);
+				let mut cs = self.connections.remove(&cs_handle).unwrap();
+				self.poll.deregister(&cs.connection).unwrap();
+				let cfm = CfmConnect {
+					result: Err(err.into()),
+					context: cs.connecting.take().unwrap(),
+				};
+				cs.ind_to.send_confirm(Confirm::Connect(cfm));
+				false
+			}
+		}
+	}
+
 	/// Data can be sent a connected socket. Send what we have
 	fn pending_writes(&mut self, cs_handle: ConnHandle) {
 		// We know this exists because we checked it before we got here
@@ -573,6 +666,7 @@ impl TaskContext {
 	) {
 		match req {
 			Request::Bind(x) => self.handle_bind(x, reply_to),
+			Request::Connect(x) => self.handle_connect(x, reply_to),
 			Request::Close(x) => self.handle_close(x, reply_to),
 			Request::Send(x) => self.handle_send(x, reply_to),
 		}
@@ -629,9 +723,92 @@ impl TaskContext {
 		reply_to.send_confirm(Confirm::Bind(cfm));
 	}
 
+	/// Open a new connection to a remote socket.
+	fn handle_connect(
+		&mut self,
+		req_connect: ReqConnect,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		info!(
+			"Connecting {:?} to {}...",
+			req_connect.conn_type, req_connect.addr
+		);
+		match req_connect.conn_type {
+			ConnectionType::Stream => self.handle_stream_connect(req_connect, reply_to),
+		}
+	}
+
+	/// The connect completes asynchronously - we send the `CfmConnect` when
+	/// mio tells us the socket is writable.
+	fn handle_stream_connect(
+		&mut self,
+		req_connect: ReqConnect,
+		reply_to: grease::ServiceUserHandle<Service>,
+	) {
+		match mio::tcp::TcpStream::connect(&req_connect.addr) {
+			Ok(stream) => {
+				let cs = ConnectedSocket {
+					handle: self.next_handle.take(),
+					// We assume any future indications should be sent
+					// to the same place we send the CfmConnect.
+					ind_to: reply_to,
+					connection: stream,
+					outstanding: false,
+					pending_writes: VecDeque::new(),
+					connecting: Some(req_connect.context),
+				};
+				match self.poll.register(
+					&cs.connection,
+					mio::Token(cs.handle.as_usize()),
+					mio::Ready::readable() | mio::Ready::writable(),
+					mio::PollOpt::edge(),
+				) {
+					Ok(_) => {
+						debug!("Allocated connection handle: {}", cs.handle);
+						self.connections.insert(cs.handle, cs);
+					}
+					Err(io_error) => {
+						let cfm = CfmConnect {
+							result: Err(io_error.into()),
+							context: req_connect.context,
+						};
+						cs.ind_to.send_confirm(Confirm::Connect(cfm));
+					}
+				}
+			}
+			Err(io_error) => {
+				let cfm = CfmConnect {
+					result: Err(io_error.into()),
+					context: req_connect.context,
+				};
+				reply_to.send_confirm(Confirm::Connect(cfm));
+			}
+		}
+	}
+
 	/// Handle a ReqClose
 	fn handle_close(&mut self, req_close: ReqClose, reply_to: grease::ServiceUserHandle<Service>) {
-		let found = self.connections.remove(&req_close.handle).is_some();
+		let found = match self.connections.remove(&req_close.handle) {
+			Some(cs) => {
+				if req_close.reset {
+					// A zero linger time makes the close send a RST
+					if let Err(err) = cs.connection.set_linger(Some(Duration::from_secs(0))) {
+						warn!("Can't reset handle: {}, err: {}", cs.handle, err);
+					}
+				}
+				true
+			}
+			None => match self.listeners.remove(&req_close.handle) {
+				Some(ls) => {
+					info!("No longer listening on handle: {}", ls.handle);
+					if let Err(err) = self.poll.deregister(&ls.listener) {
+						warn!("Can't deregister handle: {}, err: {}", ls.handle, err);
+					}
+					true
+				}
+				None => false,
+			},
+		};
 
 		let cfm = CfmClose {
 			result: if found {
@@ -930,6 +1107,7 @@ mod test {
 			TestIncoming::SocketInd(Indication::Connected(ref x)) => {
 				assert_eq!(x.listen_handle, listen_handle);
 				assert_eq!(x.peer, stream.local_addr().unwrap());
+				assert_eq!(x.local, stream.peer_addr().unwrap());
 				x.conn_handle
 			}
 			_ => panic!("Bad match"),
@@ -946,6 +1124,103 @@ mod test {
 		};
 	}
 
+	#[test]
+	/// Accepts a connection and resets it
+	fn close_reset() {
+		let socket_thread = make_task();
+		let (handle, rx) = make_test_channel();
+
+		let port = allocate_test_port();
+		let bind_req = ReqBind {
+			addr: port,
+			context: Context::new(5678),
+			conn_type: ConnectionType::Stream,
+		};
+		socket_thread.send_request(bind_req.into(), &handle);
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Bind(ref x)) => assert!(x.result.is_ok()),
+			_ => panic!("Bad match"),
+		};
+
+		// Make a TCP connection
+		let mut stream = net::TcpStream::connect(port).unwrap();
+		let conn_handle = match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketInd(Indication::Connected(ref x)) => x.conn_handle,
+			_ => panic!("Bad match"),
+		};
+
+		let close_req = ReqClose {
+			handle: conn_handle,
+			context: Context::new(1234),
+			reset: true,
+		};
+		socket_thread.send_request(close_req.into(), &handle);
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Close(ref x)) => {
+				assert_eq!(x.handle, conn_handle);
+				assert_eq!(x.context, Context::new(1234));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Bad match"),
+		};
+
+		// We see an error, not the end of the stream
+		stream.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+		let mut buffer = [0u8; 16];
+		match stream.read(&mut buffer) {
+			Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+			x => panic!("Expected a reset, got {:?}", x),
+		}
+	}
+
+	#[test]
+	/// Stops listening, but keeps the connection it already accepted
+	fn close_listener() {
+		let socket_thread = make_task();
+		let (handle, rx) = make_test_channel();
+
+		let port = allocate_test_port();
+		let bind_req = ReqBind {
+			addr: port,
+			context: Context::new(5678),
+			conn_type: ConnectionType::Stream,
+		};
+		socket_thread.send_request(bind_req.into(), &handle);
+		let listen_handle = match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Bind(ref x)) => x.result.unwrap(),
+			_ => panic!("Bad match"),
+		};
+		let stream = net::TcpStream::connect(port).unwrap();
+		let conn_handle = match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketInd(Indication::Connected(ref x)) => x.conn_handle,
+			_ => panic!("Bad match"),
+		};
+
+		let close_req = ReqClose {
+			handle: listen_handle,
+			context: Context::new(1234),
+			reset: false,
+		};
+		socket_thread.send_request(close_req.into(), &handle);
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Close(ref x)) => {
+				assert_eq!(x.handle, listen_handle);
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Bad match"),
+		};
+		assert!(net::TcpStream::connect(port).is_err());
+
+		// The connection is still there
+		stream.shutdown(net::Shutdown::Both).unwrap();
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketInd(Indication::Dropped(ref x)) => {
+				assert_eq!(x.handle, conn_handle);
+			}
+			_ => panic!("Bad match"),
+		};
+	}
+
 	#[test]
 	/// Makes two connections and sends random data
 	fn two_connections() {
@@ -1027,6 +1302,84 @@ mod test {
 		};
 	}
 
+	#[test]
+	/// Connects out to a listening socket and sends data
+	fn connect_out() {
+		let socket_thread = make_task();
+		let (handle, rx) = make_test_channel();
+		let port = allocate_test_port();
+		let listener = net::TcpListener::bind(&port).unwrap();
+
+		let connect_req = ReqConnect {
+			addr: port.clone(),
+			context: Context::new(4321),
+			conn_type: ConnectionType::Stream,
+		};
+		socket_thread.send_request(connect_req.into(), &handle);
+		let (mut stream, _) = listener.accept().unwrap();
+
+		// Check we get a CfmConnect
+		let conn_handle = match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Connect(ref x)) => {
+				assert_eq!(x.context, Context::new(4321));
+				x.result.unwrap()
+			}
+			_ => panic!("Bad match"),
+		};
+
+		socket_thread.send_request(
+			ReqSend {
+				handle: conn_handle,
+				context: Context::new(1234),
+				data: Vec::from("Hello"),
+			}.into(),
+			&handle,
+		);
+
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Send(ref x)) => {
+				assert_eq!(x.handle, conn_handle);
+				assert_eq!(x.context, Context::new(1234));
+				assert!(x.result.is_ok());
+			}
+			_ => panic!("Bad match"),
+		};
+
+		let mut part = [0u8; 5];
+		stream.read_exact(&mut part).unwrap();
+		assert_eq!(&part, b"Hello");
+
+		stream.shutdown(net::Shutdown::Both).unwrap();
+
+		// Check we get an IndDropped
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketInd(Indication::Dropped(ref x)) => {
+				assert_eq!(x.handle, conn_handle);
+			}
+			_ => panic!("Bad match"),
+		};
+	}
+
+	#[test]
+	/// Fails to connect to a port nobody is listening on
+	fn connect_fail() {
+		let socket_thread = make_task();
+		let (handle, rx) = make_test_channel();
+		let connect_req = ReqConnect {
+			addr: allocate_test_port(),
+			context: Context::new(8765),
+			conn_type: ConnectionType::Stream,
+		};
+		socket_thread.send_request(connect_req.into(), &handle);
+		match rx.recv_timeout(DEFAULT_TIMEOUT).unwrap() {
+			TestIncoming::SocketCfm(Confirm::Connect(ref x)) => {
+				assert_eq!(x.context, Context::new(8765));
+				assert!(x.result.is_err());
+			}
+			_ => panic!("Bad match"),
+		}
+	}
+
 	#[test]
 	/// Uses a port to send random data
 	/// With the changes to remove hup and error, this test no longer passes